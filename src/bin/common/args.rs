@@ -0,0 +1,37 @@
+//! Command-line parsing, kept to what the benchmarks need
+
+use std::process;
+use std::str::FromStr;
+
+/// Parses the value following flag
+pub fn value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("bad value for {}: {}", flag, value))
+}
+
+/// Exits after printing what was wrong with the arguments and how to use the binary
+pub fn fail(error: &str, usage: &str) -> ! {
+    eprintln!("{}\n\n{}", error, usage);
+    process::exit(2)
+}
+
+/// Parses the comma-separated list following flag
+pub fn list<T: FromStr>(flag: &str, values: Option<String>) -> Result<Vec<T>, String> {
+    let values: String = value(flag, values)?;
+    values.split(',')
+        .map(|v| v.trim().parse().map_err(|_| format!("bad value for {}: {}", flag, v)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lists() {
+        assert_eq!(Ok(vec![1, 2, 4]), list::<u32>("--n", Some("1, 2,4".to_string())));
+        assert_eq!(Err("bad value for --n: x".to_string()),
+                   list::<u32>("--n", Some("1,x".to_string())));
+        assert!(list::<u32>("--n", None).is_err());
+    }
+}
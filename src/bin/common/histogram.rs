@@ -0,0 +1,148 @@
+//! Latency samples counted in buckets, like an HDR histogram
+//!
+//! Values are bucketed by their highest set bit, then by the next SUB_BITS
+//! bits below it, so each bucket is within 1/128 of its values, a bit better
+//! than two significant digits, at any magnitude. Recording is a couple of
+//! shifts and an increment, cheap enough for the consumers to do inline.
+
+use std::cmp;
+use std::io::{self, Write};
+
+const SUB_BITS: u32 = 7;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+fn bucket_of(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let high = 63 - value.leading_zeros();
+    let sub = (value >> (high - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+    (high - SUB_BITS + 1) as usize * SUB_BUCKETS + sub
+}
+
+/// The largest value that lands in bucket
+fn upper_bound(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        return bucket as u64;
+    }
+    let high = (bucket / SUB_BUCKETS) as u32 + SUB_BITS - 1;
+    let sub = (bucket % SUB_BUCKETS) as u64;
+    let low = (1 << high) | (sub << (high - SUB_BITS));
+    low + ((1 << (high - SUB_BITS)) - 1)
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket_of(value)] += 1;
+        self.total += 1;
+        self.max = cmp::max(self.max, value);
+    }
+
+    /// Adds in everything other recorded
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, &more) in self.counts.iter_mut().zip(&other.counts) {
+            *count += more;
+        }
+        self.total += other.total;
+        self.max = cmp::max(self.max, other.max);
+    }
+
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The value at or below which percentile percent of the samples fall,
+    /// rounded up to the top of its bucket
+    pub fn value_at(&self, percentile: f64) -> u64 {
+        let rank = cmp::max(1, (self.total as f64 * percentile / 100.0).ceil() as u64);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return cmp::min(upper_bound(bucket), self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Writes out every bucket in the text format of HdrHistogram's
+    /// percentile distributions, which its plotting tools take
+    pub fn write_hgrm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{:>12} {:>14} {:>10} {:>14}\n", "Value", "Percentile", "TotalCount", "1/(1-Percentile)")?;
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            seen += count;
+            let fraction = seen as f64 / self.total as f64;
+            let value = cmp::min(upper_bound(bucket), self.max);
+            if seen == self.total {
+                writeln!(out, "{:12.3} {:14.12} {:10} {:>14}", value as f64, fraction, seen, "inf")?;
+            } else {
+                writeln!(out, "{:12.3} {:14.12} {:10} {:14.2}", value as f64, fraction, seen, 1.0 / (1.0 - fraction))?;
+            }
+        }
+        writeln!(out, "#[Max = {:12.3}, Total count = {:12}]", self.max as f64, self.total)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_bound_their_values() {
+        let mut last = 0;
+        for &value in &[0, 1, 127, 128, 129, 255, 256, 1000, 123_456, 1 << 40, u64::MAX] {
+            let bucket = bucket_of(value);
+            assert!(bucket >= last);
+            assert!(upper_bound(bucket) >= value, "{} -> {}", value, bucket);
+            assert!(upper_bound(bucket) - value <= value / SUB_BUCKETS as u64, "{} -> {}", value, bucket);
+            last = bucket;
+        }
+        assert!(bucket_of(u64::MAX) < BUCKETS);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut low = Histogram::new();
+        let mut high = Histogram::new();
+        for i in 1..10001 {
+            if i <= 5000 { &mut low } else { &mut high }.record(i * 1000);
+        }
+        low.merge(&high);
+        assert_eq!(10000, low.len());
+        let close = |value: u64, want: u64| value >= want && value <= want + want / 128;
+        assert!(close(low.value_at(50.0), 5_000_000), "{}", low.value_at(50.0));
+        assert!(close(low.value_at(99.9), 9_990_000), "{}", low.value_at(99.9));
+        assert_eq!(10_000_000, low.value_at(100.0));
+        assert_eq!(10_000_000, low.max());
+
+        let mut out = Vec::new();
+        low.write_hgrm(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.lines().any(|line| line.contains(" 10000 ") && line.ends_with("inf")), "{}", out);
+        assert!(out.ends_with("Total count =        10000]\n"), "{}", out);
+    }
+}
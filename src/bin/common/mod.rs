@@ -0,0 +1,6 @@
+//! Pieces the benchmark binaries share
+
+pub mod args;
+pub mod histogram;
+pub mod pin;
+pub mod report;
@@ -0,0 +1,33 @@
+//! Pinning benchmark threads to cores, so runs can be repeated with the same placement
+
+#[cfg(feature = "affinity")]
+use pipeline::affinity;
+
+/// Pins the calling thread, the index'th of its kind, to one of cores in turn.
+/// Does nothing if cores is empty, and panics if pinning fails
+#[cfg(feature = "affinity")]
+pub fn pin(cores: &[usize], index: usize) {
+    if cores.is_empty() {
+        return;
+    }
+    let core = cores[index % cores.len()];
+    if let Err(e) = affinity::pin_current(&[core]) {
+        panic!("failed to pin to core {}: {}", core, e);
+    }
+}
+
+#[cfg(not(feature = "affinity"))]
+pub fn pin(cores: &[usize], _index: usize) {
+    assert!(cores.is_empty(), "pinning needs the affinity feature");
+}
+
+/// Whether pinning works in this build, for rejecting pin flags up front
+pub fn supported() -> bool {
+    cfg!(feature = "affinity")
+}
+
+/// Cores as a comma-separated list, for reports
+pub fn describe(cores: &[usize]) -> String {
+    let cores: Vec<String> = cores.iter().map(|core| core.to_string()).collect();
+    cores.join(",")
+}
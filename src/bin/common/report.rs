@@ -0,0 +1,153 @@
+//! Printing results as text for people, or as CSV or JSON for scripts
+//!
+//! Each run prints one or more rows, each the run's configuration followed
+//! by what it measured. CSV gets a header before the first row, and JSON
+//! is one object per line.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Csv,
+    Json,
+}
+
+pub enum Value {
+    Int(u64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+/// Prints rows in one format
+pub struct Report {
+    format: Format,
+    rows: usize,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Format, ()> {
+        match s {
+            "text" => Ok(Format::Text),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(x) => write!(f, "{}", x),
+            Value::Float(x) => write!(f, "{:.2}", x),
+            Value::Bool(x) => write!(f, "{}", x),
+            Value::Str(ref x) => write!(f, "{}", x),
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Report {
+    pub fn new(format: Format) -> Report {
+        Report {
+            format: format,
+            rows: 0,
+        }
+    }
+
+    /// Renders one row, without its trailing newline. For CSV, the first
+    /// row comes with the header
+    pub fn render(&mut self, row: &[(&str, Value)]) -> String {
+        self.rows += 1;
+        match self.format {
+            Format::Text => {
+                let width = row.iter().map(|&(key, _)| key.len()).max().unwrap_or(0);
+                let lines: Vec<String> = row.iter().map(|&(key, ref value)| format!("{:<2$} {}", key, value, width)).collect();
+                // Rows after the first are set apart by a blank line
+                if self.rows > 1 {
+                    format!("\n{}", lines.join("\n"))
+                } else {
+                    lines.join("\n")
+                }
+            }
+            Format::Csv => {
+                let values: Vec<String> = row.iter()
+                    .map(|(_, value)| match *value {
+                        Value::Str(ref s) if s.contains([',', '"', '\n']) => {
+                            format!("\"{}\"", s.replace('"', "\"\""))
+                        }
+                        ref value => value.to_string(),
+                    })
+                    .collect();
+                if self.rows == 1 {
+                    let keys: Vec<&str> = row.iter().map(|&(key, _)| key).collect();
+                    format!("{}\n{}", keys.join(","), values.join(","))
+                } else {
+                    values.join(",")
+                }
+            }
+            Format::Json => {
+                let fields: Vec<String> = row.iter()
+                    .map(|&(key, ref value)| {
+                        let value = match *value {
+                            Value::Str(ref s) => json_string(s),
+                            Value::Float(x) if !x.is_finite() => "null".to_string(),
+                            Value::Float(x) => format!("{}", x),
+                            ref value => value.to_string(),
+                        };
+                        format!("{}:{}", json_string(key), value)
+                    })
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+        }
+    }
+
+    pub fn print(&mut self, row: &[(&str, Value)]) {
+        println!("{}", self.render(row));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rows(format: Format) -> String {
+        let mut report = Report::new(format);
+        let first = report.render(&[("name", Value::Str("a \"b\", c".to_string())),
+                                    ("items", Value::Int(3)),
+                                    ("rate", Value::Float(1.5))]);
+        let second = report.render(&[("name", Value::Str("d".to_string())),
+                                     ("items", Value::Int(4)),
+                                     ("rate", Value::Float(2.0))]);
+        format!("{}\n{}", first, second)
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!("name  a \"b\", c\nitems 3\nrate  1.50\n\nname  d\nitems 4\nrate  2.00", rows(Format::Text));
+        assert_eq!("name,items,rate\n\"a \"\"b\"\", c\",3,1.50\nd,4,2.00", rows(Format::Csv));
+        assert_eq!("{\"name\":\"a \\\"b\\\", c\",\"items\":3,\"rate\":1.5}\n{\"name\":\"d\",\"items\":4,\"rate\":2}",
+                   rows(Format::Json));
+    }
+}
@@ -1,87 +1,359 @@
 extern crate crossbeam;
 extern crate pipeline;
-extern crate time;
 
+// Each binary uses only part of what's shared
+#[allow(dead_code)]
+mod common;
+
+use common::args::{fail, list, value};
+use common::histogram::Histogram;
+use common::pin::{self, pin};
+use common::report::{Format, Report, Value};
 use pipeline::queue::multiqueue::{MultiReader, MultiWriter, multiqueue};
 
 use crossbeam::scope;
 
-use std::sync::atomic::{AtomicUsize, Ordering, fence};
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::process;
 use std::sync::Barrier;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 
-//prevent any inlining shenanigans
-#[inline(never)]
-fn precise_time_ns() -> u64 {
-    time::precise_time_ns()
+const USAGE: &str = "\
+Measures how long items take to get from producers to consumers through a queue
+
+Producers send on a fixed schedule, and latencies are counted from when each
+message was meant to be sent rather than when it was, so a producer held up
+by a full queue doesn't hide the wait. The raw figures, counted from when
+each push went through, are reported alongside
+
+With --ping-pong, one producer sends each message to one consumer, which
+sends it straight back on a second queue, and the producer waits for it
+before sending the next. The latencies are then whole round trips, with
+both queues' cache lines moving back and forth as they would for requests
+and responses
+
+Usage: latency [options]
+
+Options:
+    --capacity N     queue capacity (default 20000)
+    --messages N     messages each producer sends (default 100000)
+    --rate N         messages a second each producer sends, or 0 for
+                     as fast as it can (default 25000)
+    --producers N    producer threads (default 1)
+    --consumers N    consumer threads (default 1)
+    --warmup N       messages each producer sends before measuring starts,
+                     on the same schedule (default 10000)
+    --broadcast      give each consumer a stream of its own, rather than
+                     having them share one
+    --ping-pong      measure round trips through a queue each way, with
+                     one producer and one consumer
+    --histogram FILE write the full corrected latency distribution to
+                     FILE, in the format HdrHistogram's plotter reads
+    --pin-producer N,..
+                     cores to pin producer threads to, taking turns
+    --pin-consumer N,..
+                     cores to pin consumer threads to, taking turns
+    --format F       text, csv or json (default text)
+    --help           print this";
+
+struct Config {
+    capacity: u16,
+    messages: usize,
+    rate: u64,
+    producers: usize,
+    consumers: usize,
+    warmup: usize,
+    broadcast: bool,
+    ping_pong: bool,
+    histogram: Option<String>,
+    pin_producer: Vec<usize>,
+    pin_consumer: Vec<usize>,
+    format: Format,
 }
 
+impl Config {
+    fn from_args() -> Result<Config, String> {
+        let mut config = Config {
+            capacity: 20000,
+            messages: 100000,
+            rate: 25000,
+            producers: 1,
+            consumers: 1,
+            warmup: 10000,
+            broadcast: false,
+            ping_pong: false,
+            histogram: None,
+            pin_producer: Vec::new(),
+            pin_consumer: Vec::new(),
+            format: Format::Text,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--capacity" => config.capacity = value(&arg, args.next())?,
+                "--messages" => config.messages = value(&arg, args.next())?,
+                "--rate" => config.rate = value(&arg, args.next())?,
+                "--producers" => config.producers = value(&arg, args.next())?,
+                "--consumers" => config.consumers = value(&arg, args.next())?,
+                "--warmup" => config.warmup = value(&arg, args.next())?,
+                "--broadcast" => config.broadcast = true,
+                "--ping-pong" => config.ping_pong = true,
+                "--histogram" => config.histogram = Some(value(&arg, args.next())?),
+                "--pin-producer" => config.pin_producer = list(&arg, args.next())?,
+                "--pin-consumer" => config.pin_consumer = list(&arg, args.next())?,
+                "--format" => config.format = value(&arg, args.next())?,
+                "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        if config.capacity == 0 || config.producers == 0 || config.consumers == 0 {
+            return Err("capacity, producers and consumers must be at least 1".to_string());
+        }
+        if config.ping_pong && (config.producers > 1 || config.consumers > 1 || config.broadcast) {
+            return Err("ping-pong takes one producer and one consumer on one stream".to_string());
+        }
+        let pinned = !config.pin_producer.is_empty() || !config.pin_consumer.is_empty();
+        if pinned && !pin::supported() {
+            return Err("pinning threads needs the affinity feature".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// What each message carries, in nanoseconds since the run started
+#[derive(Clone, Copy)]
+struct Stamp {
+    /// When the schedule said to send it
+    intended: u64,
+    /// When the push that went through started
+    pushed: u64,
+    /// Sent before measuring starts, so not counted
+    warmup: bool,
+}
+
+//prevent any inlining shenanigans
 #[inline(never)]
-fn waste_50_ns(val: &AtomicUsize) {
-    val.store(0, Ordering::Release);
-    fence(Ordering::SeqCst);
+fn precise_time_ns(epoch: Instant) -> u64 {
+    epoch.elapsed().as_nanos() as u64
 }
 
-fn recv(bar: &Barrier, reader: MultiReader<Option<u64>>) {
+// What reading the clock twice costs, to take out of each sample
+fn clock_overhead(epoch: Instant) -> u64 {
     let mut total_time = 0;
-    let mut succ = 0;
     let tries = 10000;
     for _ in 0..tries {
-        let start = precise_time_ns();
-        let end = precise_time_ns();
-        if (end >= start) {
-            succ += 1;
-            total_time += (end - start);
-        }
+        let start = precise_time_ns(epoch);
+        let end = precise_time_ns(epoch);
+        total_time += end - start;
     }
-    let to_subtract = total_time / succ;
+    total_time / tries
+}
+
+// Pops until this consumer has seen its share, or for shared streams
+// until every message has been seen by someone. Returns the corrected
+// latencies followed by the raw ones
+fn recv(bar: &Barrier,
+        reader: MultiReader<Stamp>,
+        epoch: Instant,
+        config: &Config,
+        seen: &AtomicUsize)
+        -> (Histogram, Histogram) {
+    let to_subtract = clock_overhead(epoch);
+    let total = (config.messages + config.warmup) * config.producers;
+    let mut popped = 0;
+    let mut corrected = Histogram::new();
+    let mut raw = Histogram::new();
     bar.wait();
-    let mut v = Vec::with_capacity(100000);
     loop {
-        if let Some(popped) = reader.pop() {
-            match popped {
-                None => break,
-                Some(pushed) => {
-                    let current_time = precise_time_ns();
-                    if (current_time >= pushed) {
-                        let diff = current_time - pushed;
-                        if (diff > to_subtract) {
-                            v.push(diff - to_subtract);
-                        }
-                    }
+        if let Some(stamp) = reader.pop() {
+            let now = precise_time_ns(epoch);
+            if !stamp.warmup {
+                corrected.record(now.saturating_sub(stamp.intended).saturating_sub(to_subtract));
+                raw.record(now.saturating_sub(stamp.pushed).saturating_sub(to_subtract));
+            }
+            popped += 1;
+            if config.broadcast {
+                if popped == total {
+                    break;
                 }
+            } else {
+                seen.fetch_add(1, Ordering::Relaxed);
             }
+        } else if !config.broadcast && seen.load(Ordering::Relaxed) >= total {
+            break;
         }
     }
-    for val in v {
-         println!("{}", val);
-    }
+    (corrected, raw)
 }
 
-fn Send(bar: &Barrier, writer: MultiWriter<Option<u64>>, num_push: usize, num_us: usize) {
+fn send(bar: &Barrier, writer: MultiWriter<Stamp>, epoch: Instant, config: &Config) {
+    // No rate means no waiting between sends, and no schedule to fall behind
+    let interval = 1_000_000_000u64.checked_div(config.rate).unwrap_or(0);
     bar.wait();
-    let val: AtomicUsize = AtomicUsize::new(0);
-    for _ in 0..num_push {
+    let mut next = precise_time_ns(epoch);
+    for i in 0..config.warmup + config.messages {
+        while precise_time_ns(epoch) < next {}
+        let warmup = i < config.warmup;
+        let intended = if interval == 0 { precise_time_ns(epoch) } else { next };
         loop {
-            let topush = Some(precise_time_ns());
-            if let Ok(_) =  writer.push(topush) {
+            let stamp = Stamp {
+                intended: intended,
+                pushed: precise_time_ns(epoch),
+                warmup: warmup,
+            };
+            if writer.push(stamp).is_ok() {
                 break;
             }
         }
-        for _ in 0..(num_us*20) {
-            waste_50_ns(&val);
+        next += interval;
+    }
+}
+
+// Sends each message and waits for it to come back before sending the next.
+// Returns the corrected round trips followed by the raw ones
+fn ping(bar: &Barrier,
+        writer: MultiWriter<Stamp>,
+        reader: MultiReader<Stamp>,
+        epoch: Instant,
+        config: &Config)
+        -> (Histogram, Histogram) {
+    let to_subtract = clock_overhead(epoch);
+    let interval = 1_000_000_000u64.checked_div(config.rate).unwrap_or(0);
+    let mut corrected = Histogram::new();
+    let mut raw = Histogram::new();
+    bar.wait();
+    let mut next = precise_time_ns(epoch);
+    for i in 0..config.warmup + config.messages {
+        while precise_time_ns(epoch) < next {}
+        let intended = if interval == 0 { precise_time_ns(epoch) } else { next };
+        let stamp = Stamp {
+            intended: intended,
+            pushed: precise_time_ns(epoch),
+            warmup: i < config.warmup,
+        };
+        while writer.push(stamp).is_err() {}
+        let back = loop {
+            if let Some(back) = reader.pop() {
+                break back;
+            }
+        };
+        let now = precise_time_ns(epoch);
+        if !back.warmup {
+            corrected.record(now.saturating_sub(back.intended).saturating_sub(to_subtract));
+            raw.record(now.saturating_sub(back.pushed).saturating_sub(to_subtract));
         }
+        next += interval;
     }
-    writer.push(None);
+    (corrected, raw)
 }
 
-fn main() {
-    let (writer, reader) = multiqueue(20000);
+// Sends every message straight back
+fn pong(bar: &Barrier, reader: MultiReader<Stamp>, writer: MultiWriter<Stamp>, config: &Config) {
+    bar.wait();
+    for _ in 0..config.warmup + config.messages {
+        let stamp = loop {
+            if let Some(stamp) = reader.pop() {
+                break stamp;
+            }
+        };
+        while writer.push(stamp).is_err() {}
+    }
+}
+
+fn ping_pong(config: &Config, epoch: Instant) -> (Histogram, Histogram) {
+    let (requests, requested) = multiqueue(config.capacity);
+    let (responses, responded) = multiqueue(config.capacity);
     let bar = Barrier::new(2);
     let bref = &bar;
     scope(|scope| {
         scope.spawn(move || {
-            Send(bref, writer, 100000, 40);
+            pin(&config.pin_consumer, 0);
+            pong(bref, requested, responses, config)
+        });
+        let pinger = scope.spawn(move || {
+            pin(&config.pin_producer, 0);
+            ping(bref, requests, responded, epoch, config)
         });
-        recv(bref, reader);
-    });
-}
\ No newline at end of file
+        pinger.join()
+    })
+}
+
+fn one_way(config: &Config, epoch: Instant) -> (Histogram, Histogram) {
+    let (writer, reader) = multiqueue(config.capacity);
+    let mut readers = vec![reader];
+    for _ in 1..config.consumers {
+        let next = if config.broadcast { readers[0].stream().add_stream().into_reader() } else { readers[0].clone() };
+        readers.push(next);
+    }
+    let mut writers = vec![writer];
+    for _ in 1..config.producers {
+        let next = writers[0].clone();
+        writers.push(next);
+    }
+    let bar = Barrier::new(config.producers + config.consumers);
+    let seen = AtomicUsize::new(0);
+    let (bref, cref, sref) = (&bar, config, &seen);
+    scope(|scope| {
+        for (producer, writer) in writers.into_iter().enumerate() {
+            scope.spawn(move || {
+                pin(&cref.pin_producer, producer);
+                send(bref, writer, epoch, cref)
+            });
+        }
+        let consumers: Vec<_> = readers.into_iter()
+            .enumerate()
+            .map(|(consumer, reader)| {
+                scope.spawn(move || {
+                    pin(&cref.pin_consumer, consumer);
+                    recv(bref, reader, epoch, cref, sref)
+                })
+            })
+            .collect();
+        let mut latencies = Histogram::new();
+        let mut raw = Histogram::new();
+        for consumer in consumers {
+            let (corrected, uncorrected) = consumer.join();
+            latencies.merge(&corrected);
+            raw.merge(&uncorrected);
+        }
+        (latencies, raw)
+    })
+}
+
+fn main() {
+    let config = Config::from_args().unwrap_or_else(|e| fail(&e, USAGE));
+    let epoch = Instant::now();
+    let (latencies, raw) = if config.ping_pong { ping_pong(&config, epoch) } else { one_way(&config, epoch) };
+    Report::new(config.format).print(&[("capacity", Value::Int(config.capacity as u64)),
+                                       ("messages", Value::Int(config.messages as u64)),
+                                       ("rate", Value::Int(config.rate)),
+                                       ("producers", Value::Int(config.producers as u64)),
+                                       ("consumers", Value::Int(config.consumers as u64)),
+                                       ("warmup", Value::Int(config.warmup as u64)),
+                                       ("broadcast", Value::Bool(config.broadcast)),
+                                       ("ping_pong", Value::Bool(config.ping_pong)),
+                                       ("producer_cores", Value::Str(pin::describe(&config.pin_producer))),
+                                       ("consumer_cores", Value::Str(pin::describe(&config.pin_consumer))),
+                                       ("samples", Value::Int(latencies.len())),
+                                       ("p50_ns", Value::Int(latencies.value_at(50.0))),
+                                       ("p90_ns", Value::Int(latencies.value_at(90.0))),
+                                       ("p99_ns", Value::Int(latencies.value_at(99.0))),
+                                       ("p99.9_ns", Value::Int(latencies.value_at(99.9))),
+                                       ("max_ns", Value::Int(latencies.max())),
+                                       ("raw_p50_ns", Value::Int(raw.value_at(50.0))),
+                                       ("raw_p99_ns", Value::Int(raw.value_at(99.0))),
+                                       ("raw_max_ns", Value::Int(raw.max()))]);
+    if let Some(ref path) = config.histogram {
+        let written = File::create(path).and_then(|file| latencies.write_hgrm(&mut BufWriter::new(file)));
+        if let Err(e) = written {
+            eprintln!("failed to write {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
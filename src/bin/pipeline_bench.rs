@@ -0,0 +1,243 @@
+extern crate pipeline;
+
+// Each binary uses only part of what's shared
+#[allow(dead_code)]
+mod common;
+
+use common::args::{fail, list, value};
+use common::histogram::Histogram;
+use common::pin;
+use common::report::{Format, Report, Value};
+use pipeline::pipeline::{DEFAULT_CAPACITY, Pipeline, Runnable};
+
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::mem;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const USAGE: &str = "\
+Measures how long items take to get through a whole pipeline, and how many
+get through a second, since what a queue does on its own says little about
+a chain of them
+
+The source sends on a fixed schedule, each stage spends a set time on every
+item, and latencies are counted from when each item was meant to be sent
+until the sink gets it, so a source held up by a full queue doesn't hide
+the wait. Throughput is counted from the first measured send to the last
+arrival
+
+Usage: pipeline_bench [options]
+
+Options:
+    --stages N       stages between the source and the sink (default 4)
+    --work N         nanoseconds each stage spends on each item (default 0)
+    --capacity N     capacity of each queue (default 1024)
+    --messages N     messages the source sends (default 1000000)
+    --rate N         messages a second the source sends, or 0 for as
+                     fast as it can (default 0)
+    --warmup N       messages sent before measuring starts, on the same
+                     schedule (default 10000)
+    --histogram FILE write the full latency distribution to FILE, in the
+                     format HdrHistogram's plotter reads
+    --pin N,..       cores to pin the source, each stage and the sink to,
+                     in that order, taking turns
+    --format F       text, csv or json (default text)
+    --help           print this";
+
+struct Config {
+    stages: usize,
+    work: u64,
+    capacity: u16,
+    messages: usize,
+    rate: u64,
+    warmup: usize,
+    histogram: Option<String>,
+    pin: Vec<usize>,
+    format: Format,
+}
+
+impl Config {
+    fn from_args() -> Result<Config, String> {
+        let mut config = Config {
+            stages: 4,
+            work: 0,
+            capacity: DEFAULT_CAPACITY,
+            messages: 1000000,
+            rate: 0,
+            warmup: 10000,
+            histogram: None,
+            pin: Vec::new(),
+            format: Format::Text,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--stages" => config.stages = value(&arg, args.next())?,
+                "--work" => config.work = value(&arg, args.next())?,
+                "--capacity" => config.capacity = value(&arg, args.next())?,
+                "--messages" => config.messages = value(&arg, args.next())?,
+                "--rate" => config.rate = value(&arg, args.next())?,
+                "--warmup" => config.warmup = value(&arg, args.next())?,
+                "--histogram" => config.histogram = Some(value(&arg, args.next())?),
+                "--pin" => config.pin = list(&arg, args.next())?,
+                "--format" => config.format = value(&arg, args.next())?,
+                "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        if config.capacity == 0 || config.messages == 0 {
+            return Err("capacity and messages must be at least 1".to_string());
+        }
+        if !config.pin.is_empty() && !pin::supported() {
+            return Err("pinning threads needs the affinity feature".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// What each message carries, in nanoseconds since the run started
+#[derive(Clone, Copy)]
+struct Stamp {
+    /// When the schedule said to send it
+    intended: u64,
+    /// Sent before measuring starts, so not counted
+    warmup: bool,
+}
+
+/// What the sink found, handed over once it has every message
+struct Results {
+    latencies: Histogram,
+    first_sent: u64,
+    last_arrived: u64,
+}
+
+//prevent any inlining shenanigans
+#[inline(never)]
+fn precise_time_ns(epoch: Instant) -> u64 {
+    epoch.elapsed().as_nanos() as u64
+}
+
+// Keeps a stage busy for work nanoseconds, standing in for real processing
+fn busy(epoch: Instant, work: u64) {
+    if work == 0 {
+        return;
+    }
+    let until = precise_time_ns(epoch) + work;
+    while precise_time_ns(epoch) < until {}
+}
+
+// Pins the last part added to the index'th of cores, taking turns
+#[cfg(feature = "affinity")]
+fn pin_part(pipeline: Pipeline<Stamp>, cores: &[usize], index: usize) -> Pipeline<Stamp> {
+    if cores.is_empty() { pipeline } else { pipeline.pin(&[cores[index % cores.len()]]) }
+}
+
+#[cfg(not(feature = "affinity"))]
+fn pin_part(pipeline: Pipeline<Stamp>, _cores: &[usize], _index: usize) -> Pipeline<Stamp> {
+    pipeline
+}
+
+#[cfg(feature = "affinity")]
+fn pin_sink(runnable: Runnable, cores: &[usize], index: usize) -> Runnable {
+    if cores.is_empty() { runnable } else { runnable.pin(&[cores[index % cores.len()]]) }
+}
+
+#[cfg(not(feature = "affinity"))]
+fn pin_sink(runnable: Runnable, _cores: &[usize], _index: usize) -> Runnable {
+    runnable
+}
+
+fn build(config: &Config, epoch: Instant, results: Arc<Mutex<Option<Results>>>) -> Runnable {
+    // No rate means no waiting between sends, and no schedule to fall behind
+    let interval = 1_000_000_000u64.checked_div(config.rate).unwrap_or(0);
+    let total = config.warmup + config.messages;
+    let warmup = config.warmup;
+    let mut sent = 0;
+    let mut next = None;
+    let source = move || {
+        if sent == total {
+            return None;
+        }
+        let now = precise_time_ns(epoch);
+        let due = *next.get_or_insert(now);
+        while precise_time_ns(epoch) < due {}
+        let intended = if interval == 0 { precise_time_ns(epoch) } else { due };
+        next = Some(due + interval);
+        sent += 1;
+        Some(Stamp {
+            intended: intended,
+            warmup: sent <= warmup,
+        })
+    };
+    let pipeline = Pipeline::from_source_with_capacity(config.capacity, source).name("pipeline_bench");
+    let mut pipeline = pin_part(pipeline, &config.pin, 0);
+    for stage in 0..config.stages {
+        let work = config.work;
+        pipeline = pipeline.stage(move |stamp| {
+            busy(epoch, work);
+            stamp
+        });
+        pipeline = pin_part(pipeline, &config.pin, stage + 1);
+    }
+    let mut latencies = Histogram::new();
+    let mut first_sent = None;
+    let mut arrived = 0;
+    let sink = pipeline.sink(move |stamp: Stamp| {
+        let now = precise_time_ns(epoch);
+        arrived += 1;
+        if !stamp.warmup {
+            latencies.record(now.saturating_sub(stamp.intended));
+            first_sent.get_or_insert(stamp.intended);
+        }
+        if arrived == total {
+            *results.lock().unwrap() = Some(Results {
+                latencies: mem::replace(&mut latencies, Histogram::new()),
+                first_sent: first_sent.unwrap_or(now),
+                last_arrived: now,
+            });
+        }
+    });
+    pin_sink(sink, &config.pin, config.stages + 1)
+}
+
+fn main() {
+    let config = Config::from_args().unwrap_or_else(|e| fail(&e, USAGE));
+    let epoch = Instant::now();
+    let results = Arc::new(Mutex::new(None));
+    let handle = build(&config, epoch, results.clone()).run();
+    if handle.join().is_err() {
+        eprintln!("the pipeline panicked");
+        process::exit(1);
+    }
+    let results = results.lock().unwrap().take().expect("the sink finished without every message");
+    let elapsed = results.last_arrived.saturating_sub(results.first_sent).max(1);
+    let latencies = results.latencies;
+    Report::new(config.format).print(&[("stages", Value::Int(config.stages as u64)),
+                                       ("work_ns", Value::Int(config.work)),
+                                       ("capacity", Value::Int(config.capacity as u64)),
+                                       ("messages", Value::Int(config.messages as u64)),
+                                       ("rate", Value::Int(config.rate)),
+                                       ("warmup", Value::Int(config.warmup as u64)),
+                                       ("cores", Value::Str(pin::describe(&config.pin))),
+                                       ("samples", Value::Int(latencies.len())),
+                                       ("items_per_sec",
+                                        Value::Float(config.messages as f64 * 1e9 / elapsed as f64)),
+                                       ("p50_ns", Value::Int(latencies.value_at(50.0))),
+                                       ("p90_ns", Value::Int(latencies.value_at(90.0))),
+                                       ("p99_ns", Value::Int(latencies.value_at(99.0))),
+                                       ("p99.9_ns", Value::Int(latencies.value_at(99.9))),
+                                       ("max_ns", Value::Int(latencies.max()))]);
+    if let Some(ref path) = config.histogram {
+        let written = File::create(path).and_then(|file| latencies.write_hgrm(&mut BufWriter::new(file)));
+        if let Err(e) = written {
+            eprintln!("failed to write {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
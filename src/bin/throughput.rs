@@ -1,61 +1,472 @@
 extern crate crossbeam;
 extern crate pipeline;
-extern crate time;
 
-use pipeline::queue::multiqueue::{MultiReader, MultiWriter, multiqueue};
+// Each binary uses only part of what's shared
+#[allow(dead_code)]
+mod common;
 
-use time::precise_time_ns;
+use common::args::{fail, list, value};
+use common::pin::{self, pin};
+use common::report::{Format, Report, Value};
+use pipeline::queue::multiqueue::{MultiQueueBuilder, MultiReader, MultiWriter};
 
 use crossbeam::scope;
+use crossbeam::sync::SegQueue;
 
-use std::sync::atomic::{AtomicUsize, Ordering, fence};
-use std::sync::Barrier;
+use std::env;
+use std::hint;
+use std::iter;
+use std::mem;
+use std::process;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-fn recv(bar: &Barrier, reader: MultiReader<Option<u64>>) -> u64 {
-    bar.wait();
-    let start = precise_time_ns();
-    let mut cur = 0; 
-    loop {
-        if let Some(popped) = reader.pop() {
-            match popped {
-                None => break,
-                Some(pushed) => {
-                    if (cur != pushed) {
-                        panic!("Dang");
+const USAGE: &str = "\
+Measures how fast items go through a queue, for every mix of producers,
+consumers and streams asked for. The same runs can go through std's mpsc
+channel and crossbeam's SegQueue to compare against, skipping any mix they
+can't do: mpsc has only one consumer and neither has more than one stream.
+A multiqueue built with spsc can be run too, for one producer and consumer
+SegQueue is unbounded, so it never pushes back on producers and isn't a like
+for like comparison; its runs are reported with bounded set to false
+
+Messages can be padded out to a payload size, to see what copying them in
+and out of the queue costs, and producers can send in bursts with a pause
+between each, in which case the time counted includes the pauses
+
+Usage: throughput [options]
+
+Options:
+    --capacity N     queue capacity (default 20000)
+    --messages N     messages each producer sends (default 100000)
+    --producers N,.. producer threads to try (default 1)
+    --consumers N,.. consumer threads to try on each stream (default 1)
+    --streams N,..   streams to try, each getting every message (default 1)
+    --payloads N,..  message sizes in bytes to try, out of 8, 16, 32, 64,
+                     128, 256, 512, 1024 and 4096 (default 8)
+    --bursts K,..    burst sizes to try, each burst of K messages sent as
+                     fast as it can be followed by a pause, or 0 for a
+                     steady stream (default 0)
+    --gap N          nanoseconds each producer pauses between bursts
+                     (default 10000)
+    --queues Q,..    queues to run through, out of multiqueue, spsc, mpsc
+                     and segqueue (default multiqueue). segqueue is
+                     unbounded and ignores --capacity
+    --pin-producer N,..
+                     cores to pin producer threads to, taking turns
+    --pin-consumer N,..
+                     cores to pin consumer threads to, taking turns
+    --local-readers  add each stream from its first consumer's core and
+                     keep its reader on that core's NUMA node, to compare
+                     what writers pay checking readers against the default
+    --format F       text, csv or json (default text)
+    --help           print this";
+
+struct Config {
+    capacity: u16,
+    messages: usize,
+    producers: Vec<usize>,
+    consumers: Vec<usize>,
+    streams: Vec<usize>,
+    payloads: Vec<usize>,
+    bursts: Vec<usize>,
+    gap: u64,
+    queues: Vec<Queue>,
+    pin_producer: Vec<usize>,
+    pin_consumer: Vec<usize>,
+    local_readers: bool,
+    format: Format,
+}
+
+/// The queues the same workload can go through
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Queue {
+    Multi,
+    Spsc,
+    Mpsc,
+    Seg,
+}
+
+/// The message sizes that can be measured, in bytes
+const PAYLOADS: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 4096];
+
+/// The sending end of a queue, as far as the benchmark needs
+trait Push<T> {
+    /// Returns false if the queue is full
+    fn push(&self, item: T) -> bool;
+}
+
+/// The receiving end of a queue, as far as the benchmark needs
+trait Pop<T> {
+    fn pop(&self) -> Option<T>;
+}
+
+/// What goes through the queue: an item as made by item, padded out by
+/// PAD bytes of payload
+#[derive(Clone, Copy)]
+struct Message<const PAD: usize> {
+    item: u64,
+    payload: [u8; PAD],
+}
+
+/// One point in the sweep
+#[derive(Clone, Copy)]
+struct Shape {
+    producers: usize,
+    consumers: usize,
+    streams: usize,
+    /// Bytes in each message
+    payload: usize,
+    /// Messages sent between pauses, or 0 for no pauses
+    burst: usize,
+}
+
+impl Config {
+    fn from_args() -> Result<Config, String> {
+        let mut config = Config {
+            capacity: 20000,
+            messages: 100000,
+            producers: vec![1],
+            consumers: vec![1],
+            streams: vec![1],
+            payloads: vec![8],
+            bursts: vec![0],
+            gap: 10000,
+            queues: vec![Queue::Multi],
+            pin_producer: Vec::new(),
+            pin_consumer: Vec::new(),
+            local_readers: false,
+            format: Format::Text,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--capacity" => config.capacity = value(&arg, args.next())?,
+                "--messages" => config.messages = value(&arg, args.next())?,
+                "--producers" => config.producers = list(&arg, args.next())?,
+                "--consumers" => config.consumers = list(&arg, args.next())?,
+                "--streams" => config.streams = list(&arg, args.next())?,
+                "--payloads" => config.payloads = list(&arg, args.next())?,
+                "--bursts" => config.bursts = list(&arg, args.next())?,
+                "--gap" => config.gap = value(&arg, args.next())?,
+                "--queues" => config.queues = list(&arg, args.next())?,
+                "--pin-producer" => config.pin_producer = list(&arg, args.next())?,
+                "--pin-consumer" => config.pin_consumer = list(&arg, args.next())?,
+                "--local-readers" => config.local_readers = true,
+                "--format" => config.format = value(&arg, args.next())?,
+                "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        let mut counts = config.producers.iter().chain(&config.consumers).chain(&config.streams);
+        if config.capacity == 0 || counts.any(|&n| n == 0) {
+            return Err("capacity, producers, consumers and streams must be at least 1".to_string());
+        }
+        if let Some(size) = config.payloads.iter().find(|size| !PAYLOADS.contains(size)) {
+            return Err(format!("no payload size {}, it must be one of {}", size, pin::describe(PAYLOADS)));
+        }
+        if config.messages as u64 >= 1 << 32 {
+            return Err("messages must be below 2^32".to_string());
+        }
+        let pinned = !config.pin_producer.is_empty() || !config.pin_consumer.is_empty();
+        if pinned && !pin::supported() {
+            return Err("pinning threads needs the affinity feature".to_string());
+        }
+        if config.local_readers && !pin::supported() {
+            return Err("local readers need the affinity feature".to_string());
+        }
+        Ok(config)
+    }
+
+    fn shapes(&self) -> Vec<Shape> {
+        let mut shapes = Vec::new();
+        for &producers in &self.producers {
+            for &consumers in &self.consumers {
+                for &streams in &self.streams {
+                    for &payload in &self.payloads {
+                        for &burst in &self.bursts {
+                            shapes.push(Shape {
+                                producers: producers,
+                                consumers: consumers,
+                                streams: streams,
+                                payload: payload,
+                                burst: burst,
+                            });
+                        }
                     }
-                    cur += 1;
                 }
             }
         }
+        shapes
+    }
+}
+
+impl Queue {
+    fn name(self) -> &'static str {
+        match self {
+            Queue::Multi => "multiqueue",
+            Queue::Spsc => "spsc",
+            Queue::Mpsc => "mpsc",
+            Queue::Seg => "segqueue",
+        }
+    }
+
+    /// Whether producers wait on a full queue, which SegQueue never has
+    fn is_bounded(self) -> bool {
+        self != Queue::Seg
     }
+}
+
+impl FromStr for Queue {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Queue, ()> {
+        [Queue::Multi, Queue::Spsc, Queue::Mpsc, Queue::Seg].iter().cloned().find(|queue| queue.name() == s).ok_or(())
+    }
+}
+
+impl<T> Push<T> for MultiWriter<T> {
+    fn push(&self, item: T) -> bool {
+        MultiWriter::push(self, item).is_ok()
+    }
+}
 
-    precise_time_ns() - start
+impl<T> Pop<T> for MultiReader<T> {
+    fn pop(&self) -> Option<T> {
+        MultiReader::pop(self)
+    }
 }
 
-fn Send(bar: &Barrier, writer: MultiWriter<Option<u64>>, num_push: usize) {
+impl<T> Push<T> for SyncSender<T> {
+    fn push(&self, item: T) -> bool {
+        match self.try_send(item) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => panic!("the consumer hung up"),
+        }
+    }
+}
+
+impl<T> Pop<T> for Receiver<T> {
+    fn pop(&self) -> Option<T> {
+        self.try_recv().ok()
+    }
+}
+
+impl<T> Push<T> for Arc<SegQueue<T>> {
+    fn push(&self, item: T) -> bool {
+        SegQueue::push(self, item);
+        true
+    }
+}
+
+impl<T> Pop<T> for Arc<SegQueue<T>> {
+    fn pop(&self) -> Option<T> {
+        self.try_pop()
+    }
+}
+
+// Each item is its producer in the high half and its sequence number in the low
+fn item(producer: usize, seq: u64) -> u64 {
+    (producer as u64) << 32 | seq
+}
+
+// Pops until the stream has handed out every message between its consumers,
+// checking each producer's messages come in the order they were sent
+fn recv<R, const PAD: usize>(bar: &Barrier, reader: R, shape: Shape, total: usize, seen: &AtomicUsize) -> u64
+    where R: Pop<Message<PAD>>
+{
+    let mut next = vec![0; shape.producers];
     bar.wait();
-    for i in 0..num_push as u64 {
-        loop {
-            let topush = Some(i);
-            if let Ok(_) =  writer.push(topush) {
-                break;
+    let start = Instant::now();
+    loop {
+        if let Some(popped) = reader.pop() {
+            // Else the copy of the payload out of the queue could be skipped
+            hint::black_box(&popped.payload);
+            let (producer, seq) = ((popped.item >> 32) as usize, popped.item & 0xffff_ffff);
+            if seq < next[producer] {
+                panic!("producer {} sent {} after {}", producer, seq, next[producer] - 1);
             }
+            next[producer] = seq + 1;
+            seen.fetch_add(1, Ordering::Relaxed);
+        } else if seen.load(Ordering::Relaxed) >= total {
+            break;
         }
     }
-    writer.push(None);
+    start.elapsed().as_nanos() as u64
 }
 
-fn main() {
-    let num_do = 100000;
-    let (writer, reader) = multiqueue(20000);
-    let bar = Barrier::new(2);
-    let bref = &bar;
+fn send<W, const PAD: usize>(bar: &Barrier, writer: W, producer: usize, shape: Shape, config: &Config)
+    where W: Push<Message<PAD>>
+{
+    let gap = Duration::from_nanos(config.gap);
+    bar.wait();
+    for i in 0..config.messages as u64 {
+        if shape.burst > 0 && i > 0 && i % shape.burst as u64 == 0 {
+            let until = Instant::now() + gap;
+            while Instant::now() < until {}
+        }
+        let message = Message {
+            item: item(producer, i),
+            payload: [producer as u8; PAD],
+        };
+        while !writer.push(message) {}
+    }
+}
+
+/// Nanoseconds from the start until the last consumer is done, with each
+/// reader tagged with the stream it pops from
+fn run<W, R, const PAD: usize>(config: &Config, shape: Shape, writers: Vec<W>, readers: Vec<(usize, R)>) -> u64
+    where W: Push<Message<PAD>> + Send,
+          R: Pop<Message<PAD>> + Send
+{
+    let total = config.messages * shape.producers;
+    let seen: Vec<AtomicUsize> = (0..shape.streams).map(|_| AtomicUsize::new(0)).collect();
+    let bar = Barrier::new(shape.producers + shape.consumers * shape.streams);
+    let (bref, sref) = (&bar, &seen);
     scope(|scope| {
-        scope.spawn(move || {
-            Send(bref, writer, num_do);
-        });
-        let ns_spent = recv(bref, reader) as f64;
-        let ns_per_item = ns_spent / (num_do as f64);
-        println!("Time spent doing {} push/pop pairs (without waiting on the popped result!) was {} ns per item", num_do, ns_per_item);
-    });
-}
\ No newline at end of file
+        for (producer, writer) in writers.into_iter().enumerate() {
+            scope.spawn(move || {
+                pin(&config.pin_producer, producer);
+                send(bref, writer, producer, shape, config)
+            });
+        }
+        let consumers: Vec<_> = readers.into_iter()
+            .enumerate()
+            .map(|(consumer, (stream, reader))| {
+                scope.spawn(move || {
+                    pin(&config.pin_consumer, consumer);
+                    recv(bref, reader, shape, total, &sref[stream])
+                })
+            })
+            .collect();
+        consumers.into_iter().map(|consumer| consumer.join()).max().unwrap_or(0)
+    })
+}
+
+#[cfg(feature = "affinity")]
+fn builder(config: &Config) -> MultiQueueBuilder {
+    let builder = MultiQueueBuilder::new(config.capacity);
+    if config.local_readers { builder.local_readers() } else { builder }
+}
+
+#[cfg(not(feature = "affinity"))]
+fn builder(config: &Config) -> MultiQueueBuilder {
+    MultiQueueBuilder::new(config.capacity)
+}
+
+/// Runs shape through queue, or returns None if queue can't take that shape
+fn measure(config: &Config, queue: Queue, shape: Shape) -> Option<u64> {
+    // Each message is 8 bytes of item and the rest payload
+    match shape.payload {
+        8 => measure_padded::<0>(config, queue, shape),
+        16 => measure_padded::<8>(config, queue, shape),
+        32 => measure_padded::<24>(config, queue, shape),
+        64 => measure_padded::<56>(config, queue, shape),
+        128 => measure_padded::<120>(config, queue, shape),
+        256 => measure_padded::<248>(config, queue, shape),
+        512 => measure_padded::<504>(config, queue, shape),
+        1024 => measure_padded::<1016>(config, queue, shape),
+        4096 => measure_padded::<4088>(config, queue, shape),
+        size => unreachable!("no payload size {}", size),
+    }
+}
+
+fn measure_padded<const PAD: usize>(config: &Config, queue: Queue, shape: Shape) -> Option<u64> {
+    assert_eq!(shape.payload, mem::size_of::<Message<PAD>>());
+    match queue {
+        Queue::Multi => {
+            let (writer, reader) = builder(config).build::<Message<PAD>>();
+            let mut streams = vec![reader];
+            for index in 1..shape.streams {
+                let stream = streams[0].stream();
+                let next = if config.local_readers {
+                    // Consumers are numbered stream by stream
+                    let first = index * shape.consumers;
+                    scope(|scope| {
+                            scope.spawn(move || {
+                                    pin(&config.pin_consumer, first);
+                                    stream.add_stream().into_reader()
+                                })
+                                .join()
+                        })
+                } else {
+                    stream.add_stream().into_reader()
+                };
+                streams.push(next);
+            }
+            // Cloning past the first writer and consumer is what moves the queue
+            // off its single writer and single consumer paths
+            let mut readers = Vec::new();
+            for (index, stream) in streams.into_iter().enumerate() {
+                for _ in 1..shape.consumers {
+                    readers.push((index, stream.clone()));
+                }
+                readers.push((index, stream));
+            }
+            let writers = (1..shape.producers).map(|_| writer.clone()).collect::<Vec<_>>();
+            Some(run(config, shape, iter::once(writer).chain(writers).collect(), readers))
+        }
+        Queue::Spsc => {
+            if shape.producers > 1 || shape.consumers > 1 || shape.streams > 1 {
+                return None;
+            }
+            let (writer, reader) = builder(config).spsc().build::<Message<PAD>>();
+            Some(run(config, shape, vec![writer], vec![(0, reader)]))
+        }
+        Queue::Mpsc => {
+            if shape.consumers > 1 || shape.streams > 1 {
+                return None;
+            }
+            let (writer, reader) = mpsc::sync_channel::<Message<PAD>>(config.capacity as usize);
+            let writers = (0..shape.producers).map(|_| writer.clone()).collect();
+            Some(run(config, shape, writers, vec![(0, reader)]))
+        }
+        Queue::Seg => {
+            if shape.streams > 1 {
+                return None;
+            }
+            let queue = Arc::new(SegQueue::<Message<PAD>>::new());
+            let writers = (0..shape.producers).map(|_| queue.clone()).collect::<Vec<_>>();
+            let readers = (0..shape.consumers).map(|_| (0, queue.clone())).collect();
+            Some(run(config, shape, writers, readers))
+        }
+    }
+}
+
+fn main() {
+    let config = Config::from_args().unwrap_or_else(|e| fail(&e, USAGE));
+    let mut report = Report::new(config.format);
+    for &queue in &config.queues {
+        for shape in config.shapes() {
+            let ns_spent = match measure(&config, queue, shape) {
+                Some(ns_spent) => ns_spent as f64,
+                None => continue,
+            };
+            // Time per message sent, however many streams it goes out on
+            let ns_per_item = ns_spent / ((config.messages * shape.producers) as f64);
+            report.print(&[("queue", Value::Str(queue.name().to_string())),
+                           ("bounded", Value::Bool(queue.is_bounded())),
+                           ("capacity", Value::Int(config.capacity as u64)),
+                           ("messages", Value::Int(config.messages as u64)),
+                           ("producers", Value::Int(shape.producers as u64)),
+                           ("consumers", Value::Int(shape.consumers as u64)),
+                           ("streams", Value::Int(shape.streams as u64)),
+                           ("payload_bytes", Value::Int(shape.payload as u64)),
+                           ("burst", Value::Int(shape.burst as u64)),
+                           ("gap_ns", Value::Int(config.gap)),
+                           ("producer_cores", Value::Str(pin::describe(&config.pin_producer))),
+                           ("consumer_cores", Value::Str(pin::describe(&config.pin_consumer))),
+                           ("local_readers", Value::Bool(config.local_readers)),
+                           ("ns_per_item", Value::Float(ns_per_item)),
+                           ("items_per_sec", Value::Float(1e9 / ns_per_item)),
+                           ("mb_per_sec", Value::Float(1e3 * shape.payload as f64 / ns_per_item))]);
+        }
+    }
+}
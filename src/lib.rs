@@ -1,9 +1,38 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![allow(unused_imports)]
 #![allow(dead_code)]
 
+// Without std, paths into std go to core instead, with alloc for the rest
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+extern crate alloc;
+
+#[cfg(all(unix, feature = "libc"))]
+extern crate libc;
+#[cfg(loom)]
+extern crate loom;
+#[cfg(feature = "metrics")]
+extern crate metrics;
+#[cfg(feature = "portable-atomic")]
+extern crate portable_atomic;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(shuttle)]
+extern crate shuttle;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
+
+#[cfg(feature = "std")]
+pub mod pipeline;
 pub mod queue;
 mod util;
 
+#[cfg(feature = "affinity")]
+pub use util::affinity;
+
 
 #[cfg(test)]
 mod tests {
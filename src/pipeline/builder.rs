@@ -0,0 +1,2132 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use pipeline::checkpoint::{Checkpoint, CheckpointStore, TaskCheckpoint};
+use pipeline::link::{Backpressure, Control, Inlet, Merge, Outlet, Receiver, SharedInlet, Tagged,
+                     Ticking, link, link_with, recv};
+use pipeline::dead_letter::{DeadLetter, TryMap, panic_message};
+use pipeline::io::{chunks, write_all};
+use pipeline::join::{Join, Sides};
+use pipeline::pause::{Checkpointer, Slot};
+use pipeline::scale::{CloseOnDrop, Runtime, Scale, Scaled, Scaling, Threads};
+use pipeline::stage::{Stage, map};
+use pipeline::stats::{StageReport, StageStats};
+use pipeline::pool;
+use pipeline::worker::{Job, Partition, Pump, Sink, Source, StageWorker, Tick, Ticker, Work, Worker, spawn, work};
+use queue::multiqueue::MultiWriter;
+use queue::shared::Shared;
+
+/// Capacity of the queues between stages unless told otherwise
+pub const DEFAULT_CAPACITY: u16 = 1024;
+
+/// Something that runs on its own thread once the pipeline starts
+struct Task {
+    name: String,
+    body: Work,
+    // Where the thread may run, or anywhere if empty
+    cores: Vec<usize>,
+    // Where an earlier run left off
+    resume: Option<TaskCheckpoint>,
+    // Starts and stops more threads for the task as it runs
+    scaler: Option<Arc<dyn Scale>>,
+}
+
+/// A pipeline under construction, whose last stage produces T
+///
+/// ```
+/// use pipeline::pipeline::Pipeline;
+/// use std::sync::{Arc, Mutex};
+///
+/// let total = Arc::new(Mutex::new(0));
+/// let sum = total.clone();
+/// Pipeline::from_iter(0..100)
+///     .stage(|x: u64| x * 2)
+///     .sink(move |x| *sum.lock().unwrap() += x)
+///     .run()
+///     .join()
+///     .unwrap();
+/// assert_eq!(9900, *total.lock().unwrap());
+/// ```
+pub struct Pipeline<T> {
+    name: String,
+    capacity: u16,
+    backpressure: Backpressure,
+    tasks: Vec<Task>,
+    stages: Vec<(String, Arc<StageStats>)>,
+    // The tasks writing to output
+    end: Vec<usize>,
+    output: Box<dyn Receiver<T>>,
+}
+
+/// One of the downstream pipelines in broadcast_to, given the items
+/// to start from and returning the rest of its stages and its sink
+pub type Branch<T> = Box<dyn FnOnce(Pipeline<T>) -> Runnable>;
+
+/// A pipeline that ends in a sink and is ready to run
+pub struct Runnable {
+    name: String,
+    tasks: Vec<Task>,
+    stages: Vec<(String, Arc<StageStats>)>,
+    // The sinks
+    end: Vec<usize>,
+    // How often to take checkpoints, and where to put them
+    checkpoints: Option<(Duration, Box<dyn CheckpointStore>)>,
+}
+
+/// Why a pipeline didn't shut down cleanly
+#[derive(Debug)]
+pub enum ShutdownError {
+    /// The deadline passed before everything drained, and the rest was dropped
+    TimedOut,
+    /// A thread panicked, with the first panic's payload
+    Panicked(Box<dyn Any + Send>),
+}
+
+impl fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ShutdownError::TimedOut => write!(f, "pipeline didn't drain before the deadline"),
+            ShutdownError::Panicked(ref payload) => write!(f, "pipeline thread panicked: {}", panic_message(&**payload)),
+        }
+    }
+}
+
+impl Error for ShutdownError {}
+
+/// A running pipeline
+pub struct PipelineHandle {
+    control: Arc<Control>,
+    threads: Threads,
+    stages: Vec<(String, Arc<StageStats>)>,
+    started: Instant,
+    checkpointer: Arc<Checkpointer>,
+    scalers: Vec<(String, Arc<dyn Scale>)>,
+}
+
+impl Pipeline<Vec<u8>> {
+    /// Starts a pipeline from a source reading up to chunk bytes at a time
+    /// from reader, until it's at its end. Panics if chunk is 0
+    pub fn from_reader<R>(reader: R, chunk: usize) -> Pipeline<Vec<u8>>
+        where R: Read + Send + 'static
+    {
+        Pipeline::from_source(chunks(reader, chunk))
+    }
+}
+
+impl Pipeline<Tick> {
+    /// Starts a pipeline from a source with a Tick every interval, the first
+    /// an interval after it starts. Ticks missed while the queue was full are
+    /// skipped. The source only ends when the pipeline is drained or stopped.
+    /// Panics if interval is zero
+    pub fn from_ticks(interval: Duration) -> Pipeline<Tick> {
+        Pipeline::from_ticks_with(DEFAULT_CAPACITY, Backpressure::Block, interval)
+    }
+
+    /// Like from_ticks, but with queues of the given capacity and backpressure policy
+    pub fn from_ticks_with(capacity: u16, backpressure: Backpressure, interval: Duration) -> Pipeline<Tick> {
+        assert!(interval > Duration::from_secs(0), "ticks need a positive interval");
+        Pipeline::from_job("ticks", capacity, backpressure, |outlet| Box::new(Ticker::new(interval, outlet)))
+    }
+}
+
+impl<T: Send + 'static> Pipeline<T> {
+    /// Starts a pipeline from a source, which is called until it returns None
+    pub fn from_source<F>(source: F) -> Pipeline<T>
+        where F: FnMut() -> Option<T> + Send + 'static
+    {
+        Pipeline::from_source_with_capacity(DEFAULT_CAPACITY, source)
+    }
+
+    /// Like from_source, but with queues of the given capacity
+    pub fn from_source_with_capacity<F>(capacity: u16, source: F) -> Pipeline<T>
+        where F: FnMut() -> Option<T> + Send + 'static
+    {
+        Pipeline::from_source_with(capacity, Backpressure::Block, source)
+    }
+
+    /// Like from_source, but with queues of the given capacity and backpressure policy
+    pub fn from_source_with<F>(capacity: u16, backpressure: Backpressure, source: F) -> Pipeline<T>
+        where F: FnMut() -> Option<T> + Send + 'static
+    {
+        Pipeline::from_job("source", capacity, backpressure, |outlet| Box::new(Source::new(source, outlet)))
+    }
+
+    // Starts a pipeline from the job make builds around its first outlet
+    fn from_job<F>(name: &str, capacity: u16, backpressure: Backpressure, make: F) -> Pipeline<T>
+        where F: FnOnce(Outlet<T>) -> Box<dyn Job>
+    {
+        let (outlet, inlet) = link_with(capacity, backpressure);
+        let source = Task {
+            name: name.to_string(),
+            body: Work::Job(make(outlet)),
+            cores: Vec::new(),
+            resume: None,
+            scaler: None,
+        };
+        Pipeline {
+            name: "pipeline".to_string(),
+            capacity: capacity,
+            backpressure: backpressure,
+            tasks: vec![source],
+            stages: Vec::new(),
+            end: vec![0],
+            output: Box::new(inlet),
+        }
+    }
+
+    /// Starts a pipeline that feeds it every item of iter
+    pub fn from_iter<I>(iter: I) -> Pipeline<T>
+        where I: IntoIterator<Item = T>,
+              I::IntoIter: Send + 'static
+    {
+        let mut iter = iter.into_iter();
+        Pipeline::from_source(move || iter.next())
+    }
+
+    /// Hands a Tick to the next stage every interval, between items, until
+    /// the items run out. Every worker of a parallel stage gets its own ticks.
+    /// Panics if interval is zero
+    ///
+    /// ```
+    /// use pipeline::pipeline::{Either, Pipeline};
+    /// use std::sync::{Arc, Mutex};
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let batches = Arc::new(Mutex::new(Vec::new()));
+    /// let out = batches.clone();
+    /// let mut batch = Vec::new();
+    /// Pipeline::from_iter(0..20)
+    ///     .stage(|x| {
+    ///         thread::sleep(Duration::from_millis(1));
+    ///         x
+    ///     })
+    ///     .tick_every(Duration::from_millis(5))
+    ///     .sink(move |item| match item {
+    ///         Either::Left(x) => batch.push(x),
+    ///         // Flushes whatever came in since the last tick
+    ///         Either::Right(_) => out.lock().unwrap().push(std::mem::take(&mut batch)),
+    ///     })
+    ///     .run()
+    ///     .join()
+    ///     .unwrap();
+    /// assert!(batches.lock().unwrap().len() > 1);
+    /// ```
+    pub fn tick_every(self, interval: Duration) -> Pipeline<Either<T, Tick>> {
+        assert!(interval > Duration::from_secs(0), "ticks need a positive interval");
+        let Pipeline { name, capacity, backpressure, tasks, stages, end, output } = self;
+        Pipeline {
+            name: name,
+            capacity: capacity,
+            backpressure: backpressure,
+            tasks: tasks,
+            stages: stages,
+            end: end,
+            output: Box::new(Ticking::new(output, interval)),
+        }
+    }
+
+    /// Names the pipeline, which prefixes the names of its threads
+    pub fn name(mut self, name: &str) -> Pipeline<T> {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Sets the capacity of the queues created after this
+    pub fn capacity(mut self, capacity: u16) -> Pipeline<T> {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets what the stages added after this do when their output queue is full.
+    /// The default is to block
+    pub fn backpressure(mut self, backpressure: Backpressure) -> Pipeline<T> {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Appends a stage that turns each item into exactly one output
+    pub fn stage<U, F>(self, stage: F) -> Pipeline<U>
+        where U: Send + 'static,
+              F: FnMut(T) -> U + Send + 'static
+    {
+        self.then(map(stage))
+    }
+
+    /// Appends a stage on its own thread, reading from the current end of the pipeline
+    pub fn then<U, S>(self, stage: S) -> Pipeline<U>
+        where U: Send + 'static,
+              S: Stage<T, U>
+    {
+        let mut stage = Some(stage);
+        self.then_parallel(1, move |_| stage.take().unwrap())
+    }
+
+    /// Like stage, but with a pool of worker threads sharing the input
+    ///
+    /// Items are processed in whatever order the workers get to them,
+    /// so the output isn't necessarily in input order
+    pub fn stage_parallel<U, F>(self, workers: usize, stage: F) -> Pipeline<U>
+        where U: Send + 'static,
+              F: FnMut(T) -> U + Clone + Send + 'static
+    {
+        self.then_parallel(workers, move |_| map(stage.clone()))
+    }
+
+    /// Like then, with a pool of worker threads sharing the input.
+    /// make is called with each worker's index to build its copy of the stage
+    ///
+    /// Items are processed in whatever order the workers get to them,
+    /// so the output isn't necessarily in input order. Panics if workers is 0
+    pub fn then_parallel<U, S, F>(self, workers: usize, make: F) -> Pipeline<U>
+        where U: Send + 'static,
+              S: Stage<T, U>,
+              F: FnMut(usize) -> S
+    {
+        let mut make = make;
+        self.add_workers(workers, move |worker| StageWorker::new(make(worker)))
+    }
+
+    /// Like stage_parallel, with workers added and let go as the pipeline
+    /// runs, according to scaling
+    pub fn stage_scaled<U, F>(self, scaling: Scaling, stage: F) -> Pipeline<U>
+        where U: Send + 'static,
+              F: FnMut(T) -> U + Clone + Send + 'static
+    {
+        let Pipeline { name, capacity, backpressure, mut tasks, mut stages, output, .. } = self;
+        let (outlet, inlet) = link_with(capacity, backpressure);
+        let index = tasks.len();
+        let stats = Arc::new(StageStats::new());
+        stages.push((format!("stage-{}", index), stats.clone()));
+        let make = move || StageWorker::new(map(stage.clone()));
+        let mut first = make();
+        let scaled: Arc<dyn Scale> =
+            Arc::new(Scaled::new(scaling, make, output.split(), outlet.clone(), stats.clone()));
+        let closer = scaled.clone();
+        let mut input = output;
+        tasks.push(Task {
+            name: format!("stage-{}", index),
+            body: Work::Body(Box::new(move |control: &Control, slot: &Slot| {
+                // Once the first worker is done, no more are started
+                let _close = CloseOnDrop(closer);
+                work(&mut first, &mut *input, outlet, &stats, control, slot)
+            })),
+            cores: Vec::new(),
+            resume: None,
+            scaler: Some(scaled),
+        });
+        Pipeline {
+            name: name,
+            capacity: capacity,
+            backpressure: backpressure,
+            tasks: tasks,
+            stages: stages,
+            end: vec![index],
+            output: Box::new(inlet),
+        }
+    }
+
+    /// Appends a stage whose failures don't stop it. When f returns an error
+    /// or panics, the item goes to dead_letters with what went wrong
+    ///
+    /// If dead_letters fills up, the stage waits for it to be read like it
+    /// would for its output
+    pub fn try_stage<U, E, F>(self,
+                              dead_letters: MultiWriter<DeadLetter<T, E>>,
+                              f: F)
+                              -> Pipeline<U>
+        where U: Send + 'static,
+              E: 'static,
+              F: FnMut(&T) -> Result<U, E> + Send + 'static
+    {
+        let mut parts = Some((f, dead_letters));
+        self.add_workers(1, move |_| {
+            let (f, dead_letters) = parts.take().unwrap();
+            TryMap::new(f, dead_letters)
+        })
+    }
+
+    /// Like try_stage, with a pool of worker threads sharing the input
+    pub fn try_stage_parallel<U, E, F>(self,
+                                       workers: usize,
+                                       dead_letters: MultiWriter<DeadLetter<T, E>>,
+                                       f: F)
+                                       -> Pipeline<U>
+        where U: Send + 'static,
+              E: 'static,
+              F: FnMut(&T) -> Result<U, E> + Clone + Send + 'static
+    {
+        self.add_workers(workers, move |_| TryMap::new(f.clone(), dead_letters.clone()))
+    }
+
+    fn add_workers<U, W, F>(self, workers: usize, make: F) -> Pipeline<U>
+        where U: Send + 'static,
+              W: Worker<T, U>,
+              F: FnMut(usize) -> W
+    {
+        assert!(workers > 0, "a stage needs at least one worker");
+        let Pipeline { name, capacity, backpressure, mut tasks, mut stages, output, .. } = self;
+        let (outlet, inlet) = link_with(capacity, backpressure);
+        let mut make = make;
+        let index = tasks.len();
+        let stats = Arc::new(StageStats::new());
+        stages.push((format!("stage-{}", index), stats.clone()));
+        let mut inputs = Vec::with_capacity(workers);
+        for _ in 1..workers {
+            inputs.push(output.split());
+        }
+        inputs.insert(0, output);
+        for (worker, input) in inputs.into_iter().enumerate() {
+            let stage = make(worker);
+            tasks.push(Task {
+                name: if workers == 1 {
+                    format!("stage-{}", index)
+                } else {
+                    format!("stage-{}-worker-{}", index, worker)
+                },
+                body: Work::Job(Box::new(Pump::new(stage, input, outlet.clone(), stats.clone()))),
+                cores: Vec::new(),
+                resume: None,
+                scaler: None,
+            });
+        }
+        Pipeline {
+            name: name,
+            capacity: capacity,
+            backpressure: backpressure,
+            end: (index..tasks.len()).collect(),
+            tasks: tasks,
+            stages: stages,
+            output: Box::new(inlet),
+        }
+    }
+
+    /// Joins several pipelines into one, whose next stage reads
+    /// from each of their ends in turn. It runs dry once all of them have
+    ///
+    /// The name, queue capacity, and backpressure are taken from the first pipeline.
+    /// Panics if pipelines is empty
+    pub fn merge_from(pipelines: Vec<Pipeline<T>>) -> Pipeline<T> {
+        let mut pipelines = pipelines.into_iter();
+        let first = pipelines.next().expect("merge_from needs at least one pipeline");
+        let Pipeline { name, capacity, backpressure, mut tasks, mut stages, mut end, output } = first;
+        let mut inputs = vec![output];
+        for pipeline in pipelines {
+            let offset = tasks.len();
+            end.extend(pipeline.end.iter().map(|task| task + offset));
+            tasks.extend(pipeline.tasks);
+            stages.extend(pipeline.stages);
+            inputs.push(pipeline.output);
+        }
+        Pipeline {
+            name: name,
+            capacity: capacity,
+            backpressure: backpressure,
+            tasks: tasks,
+            stages: stages,
+            end: end,
+            output: Box::new(Merge::new(inputs)),
+        }
+    }
+
+    /// Splits the stream into lanes by the hash of each item, runs the
+    /// stages lane appends to each one, and reads from all of them in turn.
+    /// Items with the same hash go down the same lane and come out in order,
+    /// so a stateful stage per lane sees every item for its keys
+    ///
+    /// Each lane starts with its own queue, and a full one holds back the
+    /// rest. Panics if lanes is 0
+    ///
+    /// ```
+    /// use pipeline::pipeline::Pipeline;
+    /// use std::collections::HashMap;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let last = Arc::new(Mutex::new(HashMap::new()));
+    /// let seen = last.clone();
+    /// Pipeline::from_iter((0..100u64).map(|i| (i % 7, i)))
+    ///     .partition_by(|&(key, _): &(u64, u64)| key, 3, |_, lane| {
+    ///         // Counts the items for each key, which only this lane sees
+    ///         let mut counts = HashMap::new();
+    ///         lane.stage(move |(key, i): (u64, u64)| {
+    ///             *counts.entry(key).or_insert(0) += 1;
+    ///             (key, i, counts[&key])
+    ///         })
+    ///     })
+    ///     .sink(move |(key, i, count)| {
+    ///         let mut seen = seen.lock().unwrap();
+    ///         let previous = seen.insert(key, (i, count));
+    ///         assert!(previous.map_or(true, |(j, _)| j < i));
+    ///     })
+    ///     .run()
+    ///     .join()
+    ///     .unwrap();
+    /// assert_eq!(Some(&(98, 15)), last.lock().unwrap().get(&0));
+    /// ```
+    pub fn partition_by<U, H, F>(self, hash: H, lanes: usize, mut lane: F) -> Pipeline<U>
+        where U: Send + 'static,
+              H: FnMut(&T) -> u64 + Send + 'static,
+              F: FnMut(usize, Pipeline<T>) -> Pipeline<U>
+    {
+        assert!(lanes > 0, "partition_by needs at least one lane");
+        let Pipeline { name, capacity, backpressure, mut tasks, mut stages, output, .. } = self;
+        let (task, inlets) = partitioner("partition".to_string(), output, hash, capacity, backpressure, lanes);
+        let index = tasks.len();
+        tasks.push(task);
+        let mut end = Vec::new();
+        let mut outputs = Vec::with_capacity(lanes);
+        for (i, inlet) in inlets.into_iter().enumerate() {
+            let start = Pipeline {
+                name: name.clone(),
+                capacity: capacity,
+                backpressure: backpressure,
+                tasks: Vec::new(),
+                stages: Vec::new(),
+                end: Vec::new(),
+                output: Box::new(inlet),
+            };
+            let lane = lane(i, start);
+            let offset = tasks.len();
+            if lane.tasks.is_empty() {
+                // A lane with nothing in it ends at the partition itself
+                end.push(index);
+            } else {
+                end.extend(lane.end.iter().map(|task| task + offset));
+            }
+            tasks.extend(lane.tasks.into_iter().map(|mut task| {
+                task.name = format!("partition-{}-{}", i, task.name);
+                task
+            }));
+            stages.extend(lane.stages
+                .into_iter()
+                .map(|(stage, stats)| (format!("partition-{}-{}", i, stage), stats)));
+            outputs.push(lane.output);
+        }
+        end.dedup();
+        Pipeline {
+            name: name,
+            capacity: capacity,
+            backpressure: backpressure,
+            tasks: tasks,
+            stages: stages,
+            end: end,
+            output: Box::new(Merge::new(outputs)),
+        }
+    }
+
+    /// Joins another pipeline, of a different type, to this one. The next
+    /// stage reads from each of their ends in turn, and is told which one
+    /// every item came from
+    pub fn either<B>(self, other: Pipeline<B>) -> Pipeline<Either<T, B>>
+        where B: Send + 'static
+    {
+        self.beside(other, either_of)
+    }
+
+    /// Pairs each item with every item of other that has the same key,
+    /// as given by left_key for this pipeline's items and right_key for
+    /// other's. Items with no match are dropped
+    ///
+    /// Everything from other is kept in memory, and pairs only start coming
+    /// out once it has all arrived, so other has to end. The table isn't saved
+    /// in pipeline checkpoints
+    ///
+    /// ```
+    /// use pipeline::pipeline::Pipeline;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let names = Pipeline::from_iter(vec![(1, "one"), (2, "two"), (2, "deux")]);
+    /// let out = Arc::new(Mutex::new(Vec::new()));
+    /// let sink_out = out.clone();
+    /// Pipeline::from_iter(vec![2, 3, 1])
+    ///     .join(names, |&x: &u32| x, |&(x, _): &(u32, &str)| x)
+    ///     .sink(move |(x, (_, name))| sink_out.lock().unwrap().push((x, name)))
+    ///     .run()
+    ///     .join()
+    ///     .unwrap();
+    /// assert_eq!(vec![(2, "two"), (2, "deux"), (1, "one")], *out.lock().unwrap());
+    /// ```
+    pub fn join<B, K, KA, KB>(self, other: Pipeline<B>, left_key: KA, right_key: KB) -> Pipeline<(T, B)>
+        where T: Clone,
+              B: Clone + Send + 'static,
+              K: Hash + Eq + Send + 'static,
+              KA: FnMut(&T) -> K + Send + 'static,
+              KB: FnMut(&B) -> K + Send + 'static
+    {
+        let mut keys = Some((left_key, right_key));
+        self.beside(other, |left, right| Box::new(Sides::new(left, right)))
+            .add_workers(1, move |_| {
+                let (left_key, right_key) = keys.take().unwrap();
+                Join::new(left_key, right_key)
+            })
+    }
+
+    // Runs other's tasks alongside this pipeline's, reading from whatever
+    // combine makes of their two ends
+    fn beside<B, U, F>(self, other: Pipeline<B>, combine: F) -> Pipeline<U>
+        where F: FnOnce(Box<dyn Receiver<T>>, Box<dyn Receiver<B>>) -> Box<dyn Receiver<U>>
+    {
+        let Pipeline { name, capacity, backpressure, mut tasks, mut stages, mut end, output } = self;
+        let offset = tasks.len();
+        end.extend(other.end.iter().map(|task| task + offset));
+        tasks.extend(other.tasks);
+        stages.extend(other.stages);
+        Pipeline {
+            name: name,
+            capacity: capacity,
+            backpressure: backpressure,
+            tasks: tasks,
+            stages: stages,
+            end: end,
+            output: combine(output, other.output),
+        }
+    }
+
+    /// Ends the pipeline by handing every item to each of several branches,
+    /// each reading its own stream of one queue
+    ///
+    /// A slow branch holds back the others once the queue fills up,
+    /// and if one branch panics the rest are cut off as well. Panics if branches is empty
+    pub fn broadcast_to(self, branches: Vec<Branch<T>>) -> Runnable
+        where T: Clone + Send + Sync
+    {
+        assert!(!branches.is_empty(), "broadcast_to needs at least one branch");
+        let Pipeline { name, capacity, backpressure, mut tasks, mut stages, output, .. } = self;
+        let (task, streams) = broadcaster("broadcast".to_string(), output, capacity, branches.len());
+        tasks.push(task);
+        let mut end = Vec::with_capacity(branches.len());
+        for (i, (branch, stream)) in branches.into_iter().zip(streams).enumerate() {
+            let start = Pipeline {
+                name: name.clone(),
+                capacity: capacity,
+                backpressure: backpressure,
+                tasks: Vec::new(),
+                stages: Vec::new(),
+                end: Vec::new(),
+                output: Box::new(SharedInlet::new(stream)),
+            };
+            let branch = branch(start);
+            let offset = tasks.len();
+            end.extend(branch.end.iter().map(|task| task + offset));
+            tasks.extend(branch.tasks.into_iter().map(|mut task| {
+                task.name = format!("branch-{}-{}", i, task.name);
+                task
+            }));
+            stages.extend(branch.stages
+                .into_iter()
+                .map(|(stage, stats)| (format!("branch-{}-{}", i, stage), stats)));
+        }
+        Runnable {
+            name: name,
+            tasks: tasks,
+            stages: stages,
+            end: end,
+            checkpoints: None,
+        }
+    }
+
+    /// Ends the pipeline by writing every item to writer, which is flushed
+    /// once they've all been written
+    pub fn write_to<W>(self, writer: W) -> Runnable
+        where T: AsRef<[u8]>,
+              W: Write + Send + 'static
+    {
+        self.then(write_all(writer)).sink(|()| ())
+    }
+
+    /// Ends the pipeline with a sink that consumes every item
+    pub fn sink<F>(self, sink: F) -> Runnable
+        where F: FnMut(T) + Send + 'static
+    {
+        let Pipeline { name, mut tasks, mut stages, output, .. } = self;
+        let stats = Arc::new(StageStats::new());
+        stages.push(("sink".to_string(), stats.clone()));
+        let end = vec![tasks.len()];
+        tasks.push(Task {
+            name: "sink".to_string(),
+            body: Work::Job(Box::new(Sink::new(sink, output, stats))),
+            cores: Vec::new(),
+            resume: None,
+            scaler: None,
+        });
+        Runnable {
+            name: name,
+            tasks: tasks,
+            stages: stages,
+            end: end,
+            checkpoints: None,
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<T: Send + 'static> Pipeline<T> {
+    /// Appends a stage that runs f on the global Rayon pool rather than on
+    /// threads of its own, for work that's worth spreading over every core
+    ///
+    /// Like a stage with a worker pool, outputs come in whatever order they finish
+    pub fn par_stage<U, F>(self, f: F) -> Pipeline<U>
+        where U: Send + 'static,
+              F: Fn(T) -> U + Send + Sync + 'static
+    {
+        self.par_stage_on(None, f)
+    }
+
+    /// Like par_stage, on the given pool
+    pub fn par_stage_in<U, F>(self, pool: Arc<::rayon::ThreadPool>, f: F) -> Pipeline<U>
+        where U: Send + 'static,
+              F: Fn(T) -> U + Send + Sync + 'static
+    {
+        self.par_stage_on(Some(pool), f)
+    }
+
+    fn par_stage_on<U, F>(self, pool: Option<Arc<::rayon::ThreadPool>>, f: F) -> Pipeline<U>
+        where U: Send + 'static,
+              F: Fn(T) -> U + Send + Sync + 'static
+    {
+        use rayon::iter::{ParallelBridge, ParallelIterator};
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering::SeqCst;
+
+        let Pipeline { name, capacity, backpressure, mut tasks, mut stages, mut output, .. } = self;
+        let (outlet, inlet) = link_with(capacity, backpressure);
+        let stats = Arc::new(StageStats::new());
+        let name_in_stats = format!("stage-{}-rayon", tasks.len());
+        stages.push((name_in_stats.clone(), stats.clone()));
+        tasks.push(Task {
+            name: name_in_stats,
+            body: Work::Body(Box::new(move |control: &Control, slot: &Slot| {
+                let output = &mut output;
+                let stats = &stats;
+                // Items taken from the input and not yet written to the output
+                let in_flight = &AtomicUsize::new(0);
+                // For seeing what the jobs' outlets are holding back
+                let probe = outlet.clone();
+                let run = move || {
+                    let items = ::std::iter::from_fn(move || {
+                        let mut settle = || in_flight.load(SeqCst) == 0 && probe.spilled() == 0;
+                        let item = recv(&mut **output, control, slot, &mut settle);
+                        if item.is_some() {
+                            in_flight.fetch_add(1, SeqCst);
+                        }
+                        stats.set_depth(output.depth());
+                        item
+                    });
+                    let outlet = ParOutlet {
+                        outlet: outlet,
+                        control: control,
+                    };
+                    let _ = items.par_bridge().try_for_each_with(outlet, |outlet, item| {
+                        let start = Instant::now();
+                        let out = f(item);
+                        stats.record(start.elapsed());
+                        let sent = outlet.outlet.send(out, outlet.control);
+                        in_flight.fetch_sub(1, SeqCst);
+                        if sent { Ok(()) } else { Err(()) }
+                    });
+                };
+                match pool {
+                    Some(pool) => pool.install(run),
+                    None => run(),
+                }
+            })),
+            cores: Vec::new(),
+            resume: None,
+            scaler: None,
+        });
+        Pipeline {
+            name: name,
+            capacity: capacity,
+            backpressure: backpressure,
+            end: vec![tasks.len() - 1],
+            tasks: tasks,
+            stages: stages,
+            output: Box::new(inlet),
+        }
+    }
+}
+
+#[cfg(feature = "affinity")]
+impl<T> Pipeline<T> {
+    /// Pins the threads of the last thing added, be it the source or a stage,
+    /// to the given cores. Workers sharing a stage may each run on any of them
+    ///
+    /// A stage that reads what another just wrote runs best on a core sharing
+    /// a cache with the writer's. After merge_from this pins the last part
+    /// of every merged pipeline. Threads that can't be pinned panic when the
+    /// pipeline starts, which join reports
+    pub fn pin(mut self, cores: &[usize]) -> Pipeline<T> {
+        for &task in &self.end {
+            self.tasks[task].cores = cores.to_vec();
+        }
+        self
+    }
+}
+
+#[cfg(feature = "affinity")]
+impl Runnable {
+    /// Pins the sink, or the sinks of every branch, to the given cores
+    pub fn pin(mut self, cores: &[usize]) -> Runnable {
+        for &task in &self.end {
+            self.tasks[task].cores = cores.to_vec();
+        }
+        self
+    }
+}
+
+/// Gives each Rayon job its own outlet, flushed when the job is done with it
+#[cfg(feature = "rayon")]
+struct ParOutlet<'a, T> {
+    outlet: Outlet<T>,
+    control: &'a Control,
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, T> Clone for ParOutlet<'a, T> {
+    fn clone(&self) -> ParOutlet<'a, T> {
+        ParOutlet {
+            outlet: self.outlet.clone(),
+            control: self.control,
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, T> Drop for ParOutlet<'a, T> {
+    fn drop(&mut self) {
+        self.outlet.flush(self.control);
+    }
+}
+
+impl Runnable {
+    /// Has the pipeline carry on from checkpoint, taken while running the same
+    /// pipeline before. Sources skip the items they produced before the
+    /// checkpoint, and stages start from the state they saved
+    ///
+    /// Panics if the checkpoint's tasks aren't this pipeline's
+    pub fn resume_from(mut self, checkpoint: &Checkpoint) -> Runnable {
+        assert!(self.tasks.len() == checkpoint.tasks.len() &&
+                self.tasks.iter().zip(&checkpoint.tasks).all(|(task, saved)| task.name == saved.name),
+                "the checkpoint is of a different pipeline");
+        for (task, saved) in self.tasks.iter_mut().zip(&checkpoint.tasks) {
+            task.resume = Some(saved.clone());
+        }
+        self
+    }
+
+    /// Takes a checkpoint every interval while the pipeline runs, saving it to
+    /// store. A checkpoint that can't be taken within an interval is skipped,
+    /// and an error saving one panics the checkpointing thread
+    pub fn checkpoint_every<S: CheckpointStore>(mut self, interval: Duration, store: S) -> Runnable {
+        self.checkpoints = Some((interval, Box::new(store)));
+        self
+    }
+
+    /// Spawns a thread for the source, each stage, and the sink
+    pub fn run(self) -> PipelineHandle {
+        self.run_on(None)
+    }
+
+    /// Runs the pipeline on a pool of the given number of threads, which
+    /// take turns at whichever parts have something to do, rather than on
+    /// a thread for each. Panics if threads is 0
+    ///
+    /// Suits pipelines of many light stages. Rayon stages, scaled stages,
+    /// and anything pinned to cores still get threads of their own
+    pub fn run_pooled(self, threads: usize) -> PipelineHandle {
+        assert!(threads > 0, "a pool needs at least one thread");
+        self.run_on(Some(threads))
+    }
+
+    fn run_on(self, pool: Option<usize>) -> PipelineHandle {
+        let control = Arc::new(Control::new());
+        let started = Instant::now();
+        let threads: Threads = Arc::new(Mutex::new(Vec::with_capacity(self.tasks.len() + 1)));
+        let mut slots = Vec::with_capacity(self.tasks.len());
+        let mut scalers = Vec::new();
+        let mut pooled = Vec::new();
+        for task in self.tasks {
+            let slot = Arc::new(match task.resume {
+                Some(saved) => Slot::new(saved.consumed as usize, saved.state),
+                None => Slot::new(0, None),
+            });
+            slots.push((task.name.clone(), slot.clone()));
+            let body = match task.body {
+                Work::Job(job) if pool.is_some() && task.cores.is_empty() => {
+                    pooled.push((job, slot));
+                    continue;
+                }
+                body => body.into_body(),
+            };
+            let name = format!("{}-{}", self.name, task.name);
+            let thread = spawn(name.clone(), task.cores.clone(), control.clone(), slot.clone(), body);
+            threads.lock().unwrap().push(thread);
+            if let Some(scaler) = task.scaler {
+                scaler.start(Runtime {
+                    control: control.clone(),
+                    threads: threads.clone(),
+                    slot: slot,
+                    name: name.clone(),
+                    cores: task.cores,
+                });
+                scalers.push((name, scaler));
+            }
+        }
+        if let Some(size) = pool {
+            if !pooled.is_empty() {
+                threads.lock().unwrap().extend(pool::start(&self.name, size, &control, pooled));
+            }
+        }
+        let checkpointer = Arc::new(Checkpointer::new(slots));
+        if !scalers.is_empty() {
+            let control = control.clone();
+            let checkpointer = checkpointer.clone();
+            let scalers = scalers.clone();
+            let thread = thread::Builder::new()
+                .name(format!("{}-scaler", self.name))
+                .spawn(move || {
+                    while !control.is_stopped() && !checkpointer.is_finished() {
+                        let now = Instant::now();
+                        for (_, scaler) in &scalers {
+                            scaler.tick(now);
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                })
+                .expect("failed to spawn pipeline thread");
+            threads.lock().unwrap().push(thread);
+        }
+        if let Some((interval, mut store)) = self.checkpoints {
+            let control = control.clone();
+            let checkpointer = checkpointer.clone();
+            let thread = thread::Builder::new()
+                .name(format!("{}-checkpoint", self.name))
+                .spawn(move || {
+                    loop {
+                        let next = Instant::now() + interval;
+                        while Instant::now() < next {
+                            if control.is_draining() || checkpointer.is_finished() {
+                                return;
+                            }
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                        if let Some(checkpoint) = checkpointer.take(&control, interval) {
+                            if let Err(e) = store.save(&checkpoint) {
+                                panic!("failed to save a checkpoint: {}", e);
+                            }
+                        }
+                    }
+                })
+                .expect("failed to spawn pipeline thread");
+            threads.lock().unwrap().push(thread);
+        }
+        let name = self.name;
+        PipelineHandle {
+            control: control,
+            threads: threads,
+            stages: self.stages
+                .into_iter()
+                .map(|(stage, stats)| (format!("{}-{}", name, stage), stats))
+                .collect(),
+            started: started,
+            checkpointer: checkpointer,
+            scalers: scalers,
+        }
+    }
+}
+
+impl PipelineHandle {
+    /// How each stage has done so far, in the order they were added.
+    /// A stage's workers share one report, named like their threads
+    /// without the worker number
+    pub fn stats(&self) -> Vec<StageReport> {
+        self.stages
+            .iter()
+            .map(|(name, stats)| stats.report(name.clone(), self.started))
+            .collect()
+    }
+
+    /// Pauses the sources, waits for what they produced to drain through, and
+    /// records where every task is. Returns None if that takes longer than
+    /// timeout, in which case the pipeline just carries on
+    pub fn checkpoint(&self, timeout: Duration) -> Option<Checkpoint> {
+        self.checkpointer.take(&self.control, timeout)
+    }
+
+    /// Whether every thread in the pipeline has exited
+    pub fn is_finished(&self) -> bool {
+        self.threads.lock().unwrap().iter().all(|thread| thread.is_finished())
+    }
+
+    /// Sets how many workers a stage added with stage_scaled runs, within
+    /// its Scaling's bounds. Stages are named as in stats(). Returns the
+    /// number it was set to, or None if there's no such scaled stage
+    pub fn set_workers(&self, stage: &str, workers: usize) -> Option<usize> {
+        self.scaler(stage).map(|scaler| scaler.resize(workers))
+    }
+
+    /// How many workers a stage added with stage_scaled is running
+    pub fn workers(&self, stage: &str) -> Option<usize> {
+        self.scaler(stage).map(|scaler| scaler.workers())
+    }
+
+    fn scaler(&self, stage: &str) -> Option<&Arc<dyn Scale>> {
+        self.scalers.iter().find(|(name, _)| name == stage).map(|(_, scaler)| scaler)
+    }
+
+    /// Waits for the source to run dry and everything after it to drain.
+    /// If any thread panicked, returns the first panic
+    pub fn join(self) -> Result<(), Box<dyn Any + Send>> {
+        let mut rval = Ok(());
+        // Scaled stages can start more threads until their first worker exits
+        loop {
+            let threads = mem::take(&mut *self.threads.lock().unwrap());
+            if threads.is_empty() {
+                return rval;
+            }
+            for thread in threads {
+                if let Err(e) = thread.join() {
+                    if rval.is_ok() {
+                        rval = Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stops every thread as soon as it gets to the item it's on,
+    /// dropping whatever is still queued, and waits for them to exit
+    pub fn stop(self) -> Result<(), Box<dyn Any + Send>> {
+        self.control.stop();
+        self.join()
+    }
+
+    /// Stops the sources and lets everything already in the pipeline flow
+    /// through to the sinks. If that takes longer than timeout, stops
+    /// the pipeline the way stop does and returns TimedOut
+    ///
+    /// Sources are only checked between items, so one stuck waiting
+    /// on its own input can hold the pipeline up until the deadline
+    pub fn shutdown(self, timeout: Duration) -> Result<(), ShutdownError> {
+        let deadline = Instant::now() + timeout;
+        self.control.drain();
+        let mut timed_out = false;
+        while !self.is_finished() {
+            if Instant::now() >= deadline {
+                self.control.stop();
+                timed_out = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        match self.join() {
+            Err(e) => Err(ShutdownError::Panicked(e)),
+            Ok(()) if timed_out => Err(ShutdownError::TimedOut),
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+/// Wraps each item to be read by every stream of a broadcast link
+struct Broadcast {
+    streams: usize,
+}
+
+impl<T: Send + Sync + 'static> Worker<T, Shared<T>> for Broadcast {
+    fn process(&mut self, item: T, emit: &mut dyn FnMut(Shared<T>), _control: &Control) {
+        emit(Shared::new(Arc::new(item), self.streams))
+    }
+}
+
+/// A task handing every item of output to each of count streams
+fn broadcaster<T>(name: String,
+                  output: Box<dyn Receiver<T>>,
+                  capacity: u16,
+                  count: usize)
+                  -> (Task, Vec<Inlet<Shared<T>>>)
+    where T: Clone + Send + Sync + 'static
+{
+    let (outlet, inlet) = link::<Shared<T>>(capacity);
+    // The link's own stream goes first, since streams are never removed
+    // and one left unread would hold the writer back forever
+    let mut streams = Vec::with_capacity(count);
+    for _ in 1..count {
+        streams.push(inlet.add_stream());
+    }
+    streams.insert(0, inlet);
+    let task = Task {
+        name: name,
+        body: Work::Job(Box::new(Pump::new(Broadcast { streams: count },
+                                           output,
+                                           outlet,
+                                           Arc::new(StageStats::new())))),
+        cores: Vec::new(),
+        resume: None,
+        scaler: None,
+    };
+    (task, streams)
+}
+
+/// A task sending each item of output down one of count new links, by its hash
+fn partitioner<T, H>(name: String,
+                     output: Box<dyn Receiver<T>>,
+                     hash: H,
+                     capacity: u16,
+                     backpressure: Backpressure,
+                     count: usize)
+                     -> (Task, Vec<Inlet<T>>)
+    where T: Send + 'static,
+          H: FnMut(&T) -> u64 + Send + 'static
+{
+    let (outlets, inlets) = (0..count).map(|_| link_with(capacity, backpressure)).unzip();
+    let task = Task {
+        name: name,
+        body: Work::Job(Box::new(Partition::new(hash, output, outlets))),
+        cores: Vec::new(),
+        resume: None,
+        scaler: None,
+    };
+    (task, inlets)
+}
+
+// Reads from left and right in turn, saying which one each item came from
+fn either_of<A, B>(left: Box<dyn Receiver<A>>, right: Box<dyn Receiver<B>>) -> Box<dyn Receiver<Either<A, B>>>
+    where A: Send + 'static,
+          B: Send + 'static
+{
+    let inputs: Vec<Box<dyn Receiver<Either<A, B>>>> =
+        vec![Box::new(Tagged::new(left, Either::Left)), Box::new(Tagged::new(right, Either::Right))];
+    Box::new(Merge::new(inputs))
+}
+
+/// Either of the two kinds of item coming out of Graph::either and Pipeline::either
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// A pipeline shaped like a graph rather than a line, with any number of
+/// sources and sinks, and streams that fan out and come back together
+///
+/// Each stream is a Port carrying the type of its items, and anything reading
+/// one takes it by value, so a stream can't be read twice or fed into a
+/// stage expecting something else. A port left unconnected is caught when
+/// the graph is built
+///
+/// ```
+/// use pipeline::pipeline::{Either, Graph};
+/// use std::sync::{Arc, Mutex};
+///
+/// let out = Arc::new(Mutex::new(Vec::new()));
+/// let sink_out = out.clone();
+/// let mut graph = Graph::new("words");
+/// let words = graph.from_iter(vec!["a", "bb", "ccc"]);
+/// let mut copies = graph.broadcast(words, 2);
+/// let lengths = graph.pipe(copies.pop().unwrap(), |p| p.stage(|s: &str| s.len()));
+/// let both = graph.either(copies.pop().unwrap(), lengths);
+/// graph.sink(both, move |x| sink_out.lock().unwrap().push(x));
+/// graph.build().run().join().unwrap();
+///
+/// let out = out.lock().unwrap();
+/// assert_eq!(6, out.len());
+/// assert!(out.contains(&Either::Left("bb")));
+/// assert!(out.contains(&Either::Right(2)));
+/// ```
+///
+/// Stages only connect to ports of the type they take
+///
+/// ```compile_fail
+/// use pipeline::pipeline::Graph;
+/// use pipeline::pipeline::stage::map;
+///
+/// let mut graph = Graph::new("mismatch");
+/// let numbers = graph.from_iter(0..10u32);
+/// let text = graph.then(numbers, map(|x: u32| x.to_string()));
+/// let doubled = graph.then(text, map(|x: u32| x * 2));
+/// ```
+pub struct Graph {
+    id: usize,
+    name: String,
+    capacity: u16,
+    backpressure: Backpressure,
+    tasks: Vec<Task>,
+    stages: Vec<(String, Arc<StageStats>)>,
+    sinks: Vec<usize>,
+    // Ports handed out and not yet connected
+    open: usize,
+}
+
+/// A stream of T in a Graph, waiting for something to read it
+pub struct Port<T> {
+    graph: usize,
+    // The tasks writing to output
+    end: Vec<usize>,
+    output: Box<dyn Receiver<T>>,
+}
+
+static GRAPHS: AtomicUsize = AtomicUsize::new(0);
+
+impl Graph {
+    pub fn new(name: &str) -> Graph {
+        Graph {
+            id: GRAPHS.fetch_add(1, Ordering::Relaxed),
+            name: name.to_string(),
+            capacity: DEFAULT_CAPACITY,
+            backpressure: Backpressure::Block,
+            tasks: Vec::new(),
+            stages: Vec::new(),
+            sinks: Vec::new(),
+            open: 0,
+        }
+    }
+
+    /// Sets the capacity of the queues created after this
+    pub fn capacity(&mut self, capacity: u16) -> &mut Graph {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets what the parts added after this do when their output queue is full
+    pub fn backpressure(&mut self, backpressure: Backpressure) -> &mut Graph {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Adds a source, which is called until it returns None
+    pub fn source<T, F>(&mut self, source: F) -> Port<T>
+        where T: Send + 'static,
+              F: FnMut() -> Option<T> + Send + 'static
+    {
+        let source = Pipeline::from_source_with(self.capacity, self.backpressure, source);
+        self.start(source)
+    }
+
+    /// Adds a source with a Tick every interval, like Pipeline::from_ticks
+    pub fn ticks(&mut self, interval: Duration) -> Port<Tick> {
+        let ticks = Pipeline::from_ticks_with(self.capacity, self.backpressure, interval);
+        self.start(ticks)
+    }
+
+    /// Adds a source that produces every item of iter
+    pub fn from_iter<T, I>(&mut self, iter: I) -> Port<T>
+        where T: Send + 'static,
+              I: IntoIterator<Item = T>,
+              I::IntoIter: Send + 'static
+    {
+        let mut iter = iter.into_iter();
+        self.source(move || iter.next())
+    }
+
+    /// Runs a stage on what comes out of port
+    pub fn then<T, U, S>(&mut self, port: Port<T>, stage: S) -> Port<U>
+        where T: Send + 'static,
+              U: Send + 'static,
+              S: Stage<T, U>
+    {
+        self.pipe(port, |pipeline| pipeline.then(stage))
+    }
+
+    /// Extends port with whatever build appends to the pipeline it's given,
+    /// which starts out with the graph's queue capacity and backpressure.
+    /// Its name is the graph's, and renaming it has no effect
+    pub fn pipe<T, U, F>(&mut self, port: Port<T>, build: F) -> Port<U>
+        where T: Send + 'static,
+              U: Send + 'static,
+              F: FnOnce(Pipeline<T>) -> Pipeline<U>
+    {
+        let Pipeline { tasks, stages, end, output, .. } = build(self.resume(port));
+        self.tasks = tasks;
+        self.stages = stages;
+        self.open(end, output)
+    }
+
+    /// Hands every item of port to each of count new ports.
+    /// Panics if count is 0
+    pub fn broadcast<T>(&mut self, port: Port<T>, count: usize) -> Vec<Port<T>>
+        where T: Clone + Send + Sync + 'static
+    {
+        assert!(count > 0, "a broadcast needs at least one port");
+        self.close(&port);
+        let name = self.unique("broadcast");
+        let (task, streams) = broadcaster(name, port.output, self.capacity, count);
+        let index = self.tasks.len();
+        self.tasks.push(task);
+        streams.into_iter()
+            .map(|stream| {
+                let output: Box<dyn Receiver<T>> = Box::new(SharedInlet::new(stream));
+                self.open(vec![index], output)
+            })
+            .collect()
+    }
+
+    /// Sends each item of port down one of lanes new ports, picked by its
+    /// hash, so items with the same hash come out of the same port in order.
+    /// Panics if lanes is 0
+    pub fn partition_by<T, H>(&mut self, port: Port<T>, hash: H, lanes: usize) -> Vec<Port<T>>
+        where T: Send + 'static,
+              H: FnMut(&T) -> u64 + Send + 'static
+    {
+        assert!(lanes > 0, "a partition needs at least one lane");
+        self.close(&port);
+        let name = self.unique("partition");
+        let (task, inlets) = partitioner(name, port.output, hash, self.capacity, self.backpressure, lanes);
+        let index = self.tasks.len();
+        self.tasks.push(task);
+        inlets.into_iter()
+            .map(|inlet| {
+                let output: Box<dyn Receiver<T>> = Box::new(inlet);
+                self.open(vec![index], output)
+            })
+            .collect()
+    }
+
+    /// Joins ports of the same type into one, which reads from each of them
+    /// in turn. Panics if ports is empty
+    pub fn merge<T>(&mut self, ports: Vec<Port<T>>) -> Port<T>
+        where T: Send + 'static
+    {
+        assert!(!ports.is_empty(), "a merge needs at least one port");
+        let mut end = Vec::new();
+        let mut inputs = Vec::with_capacity(ports.len());
+        for port in ports {
+            self.close(&port);
+            end.extend(port.end);
+            inputs.push(port.output);
+        }
+        self.open(end, Box::new(Merge::new(inputs)))
+    }
+
+    /// Joins two ports of different types into one, which reads from each
+    /// in turn and says which one every item came from
+    pub fn either<A, B>(&mut self, left: Port<A>, right: Port<B>) -> Port<Either<A, B>>
+        where A: Send + 'static,
+              B: Send + 'static
+    {
+        self.close(&left);
+        self.close(&right);
+        let mut end = left.end;
+        end.extend(right.end);
+        self.open(end, either_of(left.output, right.output))
+    }
+
+    /// Pairs each item of left with every item of right that has the same key.
+    /// Like Pipeline::join, right has to end before anything comes out
+    pub fn join<A, B, K, KA, KB>(&mut self,
+                                 left: Port<A>,
+                                 right: Port<B>,
+                                 left_key: KA,
+                                 right_key: KB)
+                                 -> Port<(A, B)>
+        where A: Clone + Send + 'static,
+              B: Clone + Send + 'static,
+              K: Hash + Eq + Send + 'static,
+              KA: FnMut(&A) -> K + Send + 'static,
+              KB: FnMut(&B) -> K + Send + 'static
+    {
+        self.close(&left);
+        self.close(&right);
+        let mut end = left.end;
+        end.extend(right.end);
+        let sides = self.open(end, Box::new(Sides::new(left.output, right.output)));
+        let mut keys = Some((left_key, right_key));
+        self.pipe(sides, move |pipeline| {
+            pipeline.add_workers(1, move |_| {
+                let (left_key, right_key) = keys.take().unwrap();
+                Join::new(left_key, right_key)
+            })
+        })
+    }
+
+    /// Ends port with a sink that consumes every item
+    pub fn sink<T, F>(&mut self, port: Port<T>, sink: F)
+        where T: Send + 'static,
+              F: FnMut(T) + Send + 'static
+    {
+        let name = self.unique("sink");
+        let Runnable { mut tasks, mut stages, end, .. } = self.resume(port).sink(sink);
+        tasks.last_mut().unwrap().name = name.clone();
+        stages.last_mut().unwrap().0 = name;
+        self.tasks = tasks;
+        self.stages = stages;
+        self.sinks.extend(end);
+    }
+
+    /// Finishes the graph, ready to run. Panics if some port hasn't been
+    /// connected to anything, or if nothing was added
+    pub fn build(self) -> Runnable {
+        assert!(self.open == 0, "{} ports of graph {} were never connected", self.open, self.name);
+        assert!(!self.tasks.is_empty(), "graph {} is empty", self.name);
+        Runnable {
+            name: self.name,
+            tasks: self.tasks,
+            stages: self.stages,
+            end: self.sinks,
+            checkpoints: None,
+        }
+    }
+
+    fn open<T>(&mut self, end: Vec<usize>, output: Box<dyn Receiver<T>>) -> Port<T> {
+        self.open += 1;
+        Port {
+            graph: self.id,
+            end: end,
+            output: output,
+        }
+    }
+
+    // Adds the source a pipeline starts with
+    fn start<T>(&mut self, source: Pipeline<T>) -> Port<T> {
+        let Pipeline { tasks, output, .. } = source;
+        let index = self.tasks.len();
+        for mut task in tasks {
+            task.name = self.unique(&task.name);
+            self.tasks.push(task);
+        }
+        self.open(vec![index], output)
+    }
+
+    fn close<T>(&mut self, port: &Port<T>) {
+        assert!(port.graph == self.id, "port is from a different graph");
+        self.open -= 1;
+    }
+
+    // A pipeline holding every task so far and reading port, so what's
+    // appended to it is numbered the same as in a pipeline
+    fn resume<T>(&mut self, port: Port<T>) -> Pipeline<T> {
+        self.close(&port);
+        Pipeline {
+            name: self.name.clone(),
+            capacity: self.capacity,
+            backpressure: self.backpressure,
+            tasks: mem::take(&mut self.tasks),
+            stages: mem::take(&mut self.stages),
+            end: port.end,
+            output: port.output,
+        }
+    }
+
+    // name, or name with a task number if a task already has that name
+    fn unique(&self, name: &str) -> String {
+        if self.tasks.iter().any(|task| task.name == name) {
+            format!("{}-{}", name, self.tasks.len())
+        } else {
+            name.to_string()
+        }
+    }
+}
+
+impl<T> fmt::Debug for Pipeline<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("name", &self.name)
+            .field("capacity", &self.capacity)
+            .field("tasks", &self.tasks.iter().map(|t| &t.name).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use pipeline::stage::filter;
+
+    #[test]
+    fn chained_stages_keep_order() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink_seen = seen.clone();
+        Pipeline::from_iter(0..1000)
+            .capacity(4)
+            .stage(|x: usize| x + 1)
+            .stage(|x: usize| x.to_string())
+            .sink(move |s: String| sink_seen.lock().unwrap().push(s))
+            .run()
+            .join()
+            .unwrap();
+        let expected: Vec<String> = (1..1001).map(|x: usize| x.to_string()).collect();
+        assert_eq!(expected, *seen.lock().unwrap());
+    }
+
+    #[test]
+    fn stop_ends_an_endless_source() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let sink_count = count.clone();
+        let handle = Pipeline::from_source(|| Some(1))
+            .name("endless")
+            .sink(move |x: usize| {
+                sink_count.fetch_add(x, Ordering::Relaxed);
+            })
+            .run();
+        while count.load(Ordering::Relaxed) < 100 {
+            thread::yield_now();
+        }
+        assert!(!handle.is_finished());
+        handle.stop().unwrap();
+    }
+
+    fn collect<T: Send + 'static>(into: &Arc<Mutex<Vec<T>>>) -> impl FnMut(T) + Send + 'static {
+        let into = into.clone();
+        move |val| into.lock().unwrap().push(val)
+    }
+
+    #[test]
+    fn broadcast_reaches_every_branch() {
+        let upper = Arc::new(Mutex::new(Vec::new()));
+        let lengths = Arc::new(Mutex::new(Vec::new()));
+        let (upper_sink, lengths_sink) = (collect(&upper), collect(&lengths));
+        let words = vec!["one", "two", "three"];
+        Pipeline::from_iter(words.clone())
+            .capacity(2)
+            .stage(|s: &str| s.to_string())
+            .broadcast_to(vec![Box::new(move |p: Pipeline<String>| {
+                                   p.stage(|s: String| s.to_uppercase()).sink(upper_sink)
+                               }),
+                               Box::new(move |p: Pipeline<String>| {
+                                   p.stage(|s: String| s.len()).sink(lengths_sink)
+                               })])
+            .run()
+            .join()
+            .unwrap();
+        assert_eq!(vec!["ONE", "TWO", "THREE"], *upper.lock().unwrap());
+        assert_eq!(vec![3, 3, 5], *lengths.lock().unwrap());
+    }
+
+    #[test]
+    fn merge_drains_every_input() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        Pipeline::merge_from(vec![Pipeline::from_iter(0..100),
+                                  Pipeline::from_iter(100..150).stage(|x: u32| x),
+                                  Pipeline::from_iter(150..200)])
+            .sink(collect(&seen))
+            .run()
+            .join()
+            .unwrap();
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!((0..200).collect::<Vec<u32>>(), seen);
+    }
+
+    #[test]
+    fn workers_share_a_stage() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let names = threads.clone();
+        Pipeline::from_iter(0..1000)
+            .name("pool")
+            .stage_parallel(4, move |x: u32| {
+                let name = thread::current().name().unwrap().to_string();
+                let mut names = names.lock().unwrap();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+                x.to_string()
+            })
+            .stage(|s: String| s.parse::<u32>().unwrap())
+            .sink(collect(&seen))
+            .run()
+            .join()
+            .unwrap();
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!((0..1000).collect::<Vec<u32>>(), seen);
+        for name in threads.lock().unwrap().iter() {
+            assert!(name.starts_with("pool-stage-1-worker-"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn shutdown_drains_what_was_produced() {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = produced.clone();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handle = Pipeline::from_source(move || Some(counter.fetch_add(1, Ordering::SeqCst)))
+            .capacity(16)
+            .stage(|x: usize| {
+                thread::sleep(Duration::from_micros(10));
+                x
+            })
+            .sink(collect(&seen))
+            .run();
+        while seen.lock().unwrap().len() < 50 {
+            thread::yield_now();
+        }
+        handle.shutdown(Duration::from_secs(10)).unwrap();
+        // Everything the source handed over made it to the sink, in order
+        let seen = seen.lock().unwrap();
+        assert_eq!((0..produced.load(Ordering::SeqCst)).collect::<Vec<_>>(), *seen);
+    }
+
+    #[test]
+    fn shutdown_times_out_on_a_stuck_stage() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handle = Pipeline::from_iter(0..10)
+            .stage(|x: u32| {
+                thread::sleep(Duration::from_millis(20));
+                x
+            })
+            .sink(collect(&seen))
+            .run();
+        while seen.lock().unwrap().is_empty() {
+            thread::yield_now();
+        }
+        match handle.shutdown(Duration::from_millis(5)) {
+            Err(e @ ShutdownError::TimedOut) => {
+                assert_eq!("pipeline didn't drain before the deadline", e.to_string())
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn failures_go_to_dead_letters() {
+        use pipeline::dead_letter::Failure;
+        use queue::multiqueue::multiqueue;
+
+        let (dead_writer, dead_reader) = multiqueue::<DeadLetter<&str, ::std::num::ParseIntError>>(16);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        Pipeline::from_iter(vec!["1", "x", "3", "0"])
+            .name("parse")
+            .try_stage(dead_writer, |s: &&str| {
+                let n = s.parse::<u32>()?;
+                if n == 0 {
+                    panic!("zero");
+                }
+                Ok(10 / n)
+            })
+            .sink(collect(&seen))
+            .run()
+            .join()
+            .unwrap();
+        assert_eq!(vec![10, 3], *seen.lock().unwrap());
+
+        let bad = dead_reader.pop().unwrap();
+        assert_eq!("x", bad.item);
+        assert_eq!("parse-stage-1", bad.stage);
+        match bad.failure {
+            Failure::Error(ref e) => assert_eq!("invalid digit found in string", e.to_string()),
+            ref other => panic!("unexpected {:?}", other),
+        }
+        let source = Error::source(&bad.failure).map(|e| e.to_string());
+        assert_eq!(Some("invalid digit found in string".to_string()), source);
+        let zero = dead_reader.pop().unwrap();
+        assert_eq!("0", zero.item);
+        match zero.failure {
+            Failure::Panic(ref msg) => assert_eq!("zero", msg),
+            ref other => panic!("unexpected {:?}", other),
+        }
+        assert!(dead_reader.pop().is_none());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn rayon_stage() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let pool = Arc::new(::rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap());
+        Pipeline::from_iter(0..1000u64)
+            .capacity(16)
+            .par_stage(|x| x * 2)
+            .par_stage_in(pool, |x| x + 1)
+            .sink(collect(&seen))
+            .run()
+            .join()
+            .unwrap();
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!((0..1000).map(|x| x * 2 + 1).collect::<Vec<u64>>(), seen);
+    }
+
+    #[test]
+    fn join_reports_a_panic() {
+        let handle = Pipeline::from_iter(0..10)
+            .stage(|x: u32| {
+                if x == 5 {
+                    panic!("bad item");
+                }
+                x
+            })
+            .sink(|_| ())
+            .run();
+        assert!(handle.join().is_err());
+    }
+
+    #[cfg(all(feature = "affinity", target_os = "linux"))]
+    #[test]
+    fn pinned_stages() {
+        use util::affinity;
+
+        let core = affinity::current().unwrap()[0];
+        let cores = Arc::new(Mutex::new(Vec::new()));
+        let (stage_cores, sink_cores) = (cores.clone(), cores.clone());
+        Pipeline::from_iter(0..10)
+            .pin(&[core])
+            .stage_parallel(2, move |x: u32| {
+                stage_cores.lock().unwrap().push(affinity::current().unwrap());
+                x
+            })
+            .pin(&[core])
+            .sink(move |_| sink_cores.lock().unwrap().push(affinity::current().unwrap()))
+            .pin(&[core])
+            .run()
+            .join()
+            .unwrap();
+        let cores = cores.lock().unwrap();
+        assert_eq!(20, cores.len());
+        assert!(cores.iter().all(|set| *set == vec![core]));
+
+        let bad = Pipeline::from_iter(0..10).pin(&[1 << 20]).sink(|_: u32| ()).run();
+        assert!(bad.join().is_err());
+    }
+
+    /// Emits the sum of everything so far
+    struct RunningSum(u64);
+
+    impl Stage<u64, u64> for RunningSum {
+        fn process(&mut self, item: u64, emit: &mut dyn FnMut(u64)) {
+            self.0 += item;
+            emit(self.0)
+        }
+
+        fn checkpoint(&self) -> Option<Vec<u8>> {
+            Some(self.0.to_le_bytes().to_vec())
+        }
+
+        fn restore(&mut self, state: &[u8]) {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(state);
+            self.0 = u64::from_le_bytes(bytes);
+        }
+    }
+
+    fn summing() -> Pipeline<u64> {
+        Pipeline::from_iter(0..5000u64)
+            .capacity(8)
+            .stage_parallel(2, |x: u64| {
+                thread::sleep(Duration::from_micros(20));
+                x
+            })
+            .backpressure(Backpressure::Spill)
+            .then(RunningSum(0))
+    }
+
+    #[test]
+    fn resume_from_a_checkpoint() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handle = summing().sink(collect(&seen)).run();
+        while seen.lock().unwrap().len() < 100 {
+            thread::yield_now();
+        }
+        let checkpoint = handle.checkpoint(Duration::from_secs(10)).unwrap();
+        handle.stop().unwrap();
+
+        // Everything the source produced before the checkpoint was summed and sunk
+        let names: Vec<&str> = checkpoint.tasks.iter().map(|t| &t.name[..]).collect();
+        assert_eq!(vec!["source", "stage-1-worker-0", "stage-1-worker-1", "stage-3", "sink"],
+                   names);
+        let produced = checkpoint.tasks[0].consumed;
+        assert!((100..5000).contains(&produced), "{:?}", checkpoint);
+        assert_eq!(produced,
+                   checkpoint.tasks[1].consumed + checkpoint.tasks[2].consumed);
+        assert_eq!(produced, checkpoint.tasks[3].consumed);
+        assert_eq!(produced, checkpoint.tasks[4].consumed);
+        let sum = (0..produced).sum::<u64>();
+        assert_eq!(Some(sum.to_le_bytes().to_vec()), checkpoint.tasks[3].state);
+
+        let resumed = Arc::new(Mutex::new(Vec::new()));
+        summing().sink(collect(&resumed)).resume_from(&checkpoint).run().join().unwrap();
+        let resumed = resumed.lock().unwrap();
+        assert_eq!(5000 - produced as usize, resumed.len());
+        assert_eq!(Some(&(0..5000).sum::<u64>()), resumed.last());
+    }
+
+    #[test]
+    fn periodic_checkpoints() {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let store = saved.clone();
+        // A short queue drains quickly enough for each checkpoint to go through
+        Pipeline::from_iter(0..1000u64)
+            .capacity(8)
+            .stage(|x: u64| {
+                thread::sleep(Duration::from_micros(200));
+                x
+            })
+            .sink(|_| ())
+            .checkpoint_every(Duration::from_millis(10), move |checkpoint: &Checkpoint| {
+                store.lock().unwrap().push(checkpoint.clone());
+                Ok(())
+            })
+            .run()
+            .join()
+            .unwrap();
+        let saved = saved.lock().unwrap();
+        assert!(!saved.is_empty());
+        for checkpoint in saved.iter() {
+            let consumed: Vec<u64> = checkpoint.tasks.iter().map(|t| t.consumed).collect();
+            assert!(consumed.iter().all(|&c| c == consumed[0]), "{:?}", consumed);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "different pipeline")]
+    fn resume_checks_the_pipeline() {
+        let checkpoint = Checkpoint {
+            tasks: vec![TaskCheckpoint {
+                            name: "source".to_string(),
+                            consumed: 1,
+                            state: None,
+                        }],
+        };
+        let _ = summing().sink(|_| ()).resume_from(&checkpoint);
+    }
+
+    #[test]
+    fn stats_cover_every_stage() {
+        let handle = Pipeline::from_iter(0..200)
+            .name("stats")
+            .capacity(8)
+            .stage_parallel(2, |x: u32| x + 1)
+            .stage(|x: u32| {
+                thread::sleep(Duration::from_micros(200));
+                x
+            })
+            .sink(|_| ())
+            .run();
+        let stats = handle.stats();
+        let names: Vec<&str> = stats.iter().map(|s| &s.name[..]).collect();
+        assert_eq!(vec!["stats-stage-1", "stats-stage-3", "stats-sink"], names);
+        while !handle.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let stats = handle.stats();
+        handle.join().unwrap();
+        for stage in &stats {
+            assert_eq!(200, stage.processed, "{}", stage);
+            assert!(stage.throughput > 0.0, "{}", stage);
+            assert_eq!(0, stage.depth, "{}", stage);
+        }
+        let slow = &stats[1];
+        assert!(slow.p50 >= Duration::from_micros(200), "{}", slow);
+        assert!(slow.busy >= Duration::from_millis(40), "{}", slow);
+    }
+
+    #[test]
+    fn scaled_stage_by_hand() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let (sink_out, stage_names) = (out.clone(), names.clone());
+        let handle = Pipeline::from_iter(0..2000u32)
+            .name("scaled")
+            .capacity(8)
+            .stage_scaled(Scaling::manual(1, 4), move |x: u32| {
+                let name = thread::current().name().unwrap().to_string();
+                let mut names = stage_names.lock().unwrap();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+                drop(names);
+                thread::sleep(Duration::from_micros(50));
+                x + 1
+            })
+            .sink(move |x| sink_out.lock().unwrap().push(x))
+            .run();
+        assert_eq!(Some(1), handle.workers("scaled-stage-1"));
+        assert_eq!(None, handle.workers("scaled-sink"));
+        assert_eq!(Some(3), handle.set_workers("scaled-stage-1", 3));
+        assert_eq!(Some(3), handle.workers("scaled-stage-1"));
+        assert_eq!(Some(4), handle.set_workers("scaled-stage-1", 10));
+        assert_eq!(Some(1), handle.set_workers("scaled-stage-1", 0));
+        assert_eq!(Some(1), handle.workers("scaled-stage-1"));
+        handle.join().unwrap();
+
+        let mut out = out.lock().unwrap();
+        out.sort();
+        assert_eq!((1..2001).collect::<Vec<_>>(), *out);
+        let names = names.lock().unwrap();
+        assert!(names.iter().all(|name| name.starts_with("scaled-stage-1")), "{:?}", names);
+    }
+
+    #[test]
+    fn scaled_stage_grows_on_lag() {
+        let out = Arc::new(AtomicUsize::new(0));
+        let sink_out = out.clone();
+        let handle = Pipeline::from_iter(0..1000u32)
+            .name("lag")
+            .capacity(64)
+            .stage_scaled(Scaling::on_lag(1, 3, 4).interval(Duration::from_millis(1)),
+                          |x: u32| {
+                              thread::sleep(Duration::from_micros(200));
+                              x
+                          })
+            .sink(move |_| {
+                sink_out.fetch_add(1, Ordering::SeqCst);
+            })
+            .run();
+        let mut most = 0;
+        while !handle.is_finished() {
+            most = most.max(handle.workers("lag-stage-1").unwrap());
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.join().unwrap();
+        assert!(most > 1, "never scaled past {} workers", most);
+        assert!(most <= 3);
+        assert_eq!(1000, out.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn graph_diamond() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let sink_out = out.clone();
+        let mut graph = Graph::new("diamond");
+        graph.capacity(8);
+        let numbers = graph.from_iter(0..500u64);
+        let mut copies = graph.broadcast(numbers, 2);
+        let odd = graph.pipe(copies.pop().unwrap(), |p| p.stage(|x: u64| 2 * x + 1));
+        let even = graph.pipe(copies.pop().unwrap(), |p| p.stage_parallel(2, |x: u64| 2 * x));
+        let all = graph.merge(vec![even, odd]);
+        graph.sink(all, move |x| sink_out.lock().unwrap().push(x));
+        let handle = graph.build().run();
+        let names: Vec<String> = handle.stats().into_iter().map(|s| s.name).collect();
+        assert_eq!(vec!["diamond-stage-2", "diamond-stage-3", "diamond-sink"], names);
+        handle.join().unwrap();
+
+        let mut out = out.lock().unwrap();
+        out.sort();
+        assert_eq!((0..1000).collect::<Vec<_>>(), *out);
+    }
+
+    #[test]
+    fn graph_sources_and_sinks() {
+        let lefts = Arc::new(AtomicUsize::new(0));
+        let rights = Arc::new(AtomicUsize::new(0));
+        let strings = Arc::new(AtomicUsize::new(0));
+        let (l, r, s) = (lefts.clone(), rights.clone(), strings.clone());
+        let mut graph = Graph::new("graph");
+        let numbers = graph.from_iter(0..100u32);
+        let text = graph.from_iter(vec!["a".to_string(), "b".to_string()]);
+        let more_text = graph.source(|| None::<String>);
+        let text = graph.merge(vec![text, more_text]);
+        let mut texts = graph.broadcast(text, 2);
+        let both = graph.either(numbers, texts.pop().unwrap());
+        graph.sink(both, move |item| {
+            match item {
+                Either::Left(_) => l.fetch_add(1, Ordering::SeqCst),
+                Either::Right(_) => r.fetch_add(1, Ordering::SeqCst),
+            };
+        });
+        graph.sink(texts.pop().unwrap(), move |_| {
+            s.fetch_add(1, Ordering::SeqCst);
+        });
+        let handle = graph.build().run();
+        let names: Vec<String> = handle.stats().into_iter().map(|s| s.name).collect();
+        assert_eq!(vec!["graph-sink", "graph-sink-5"], names);
+        handle.join().unwrap();
+        assert_eq!(100, lefts.load(Ordering::SeqCst));
+        assert_eq!(2, rights.load(Ordering::SeqCst));
+        assert_eq!(2, strings.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[should_panic(expected = "1 ports of graph loose were never connected")]
+    fn graph_checks_every_port_is_read() {
+        let mut graph = Graph::new("loose");
+        let numbers = graph.from_iter(0..10u32);
+        let mut copies = graph.broadcast(numbers, 2);
+        graph.sink(copies.pop().unwrap(), |_| ());
+        graph.build();
+    }
+
+    #[test]
+    #[should_panic(expected = "port is from a different graph")]
+    fn graph_checks_ports_belong_to_it() {
+        let mut first = Graph::new("first");
+        let mut second = Graph::new("second");
+        let numbers = first.from_iter(0..10u32);
+        second.sink(numbers, |_| ());
+    }
+
+    #[test]
+    fn pooled_runs_every_stage() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::from_iter(0..1000u32).name("pooled").capacity(16);
+        for _ in 0..20 {
+            let names = threads.clone();
+            pipeline = pipeline.stage(move |x: u32| {
+                let name = thread::current().name().unwrap().to_string();
+                let mut names = names.lock().unwrap();
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+                x + 1
+            });
+        }
+        pipeline.sink(collect(&seen)).run_pooled(2).join().unwrap();
+        assert_eq!((20..1020).collect::<Vec<u32>>(), *seen.lock().unwrap());
+        let threads = threads.lock().unwrap();
+        assert!(threads.len() <= 2, "{:?}", threads);
+        for name in threads.iter() {
+            assert!(name.starts_with("pooled-pool-"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn pooled_checkpoints() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handle = summing().sink(collect(&seen)).run_pooled(2);
+        while seen.lock().unwrap().len() < 100 {
+            thread::yield_now();
+        }
+        let checkpoint = handle.checkpoint(Duration::from_secs(10)).unwrap();
+        handle.stop().unwrap();
+        let produced = checkpoint.tasks[0].consumed;
+        assert_eq!(produced,
+                   checkpoint.tasks[1].consumed + checkpoint.tasks[2].consumed);
+        assert_eq!(produced, checkpoint.tasks[3].consumed);
+        assert_eq!(produced, checkpoint.tasks[4].consumed);
+        let sum = (0..produced).sum::<u64>();
+        assert_eq!(Some(sum.to_le_bytes().to_vec()), checkpoint.tasks[3].state);
+
+        let resumed = Arc::new(Mutex::new(Vec::new()));
+        summing().sink(collect(&resumed)).resume_from(&checkpoint).run_pooled(3).join().unwrap();
+        assert_eq!(Some(&(0..5000).sum::<u64>()), resumed.lock().unwrap().last());
+    }
+
+    #[test]
+    fn pooled_panics_reach_join() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let result = Pipeline::from_iter(0..1000u32)
+            .capacity(8)
+            .stage(|x: u32| {
+                assert!(x != 500, "bad item");
+                x
+            })
+            .sink(collect(&seen))
+            .run_pooled(2)
+            .join();
+        let e = result.unwrap_err();
+        assert_eq!(Some(&"bad item"), e.downcast_ref::<&str>());
+        assert_eq!((0..500).collect::<Vec<u32>>(), *seen.lock().unwrap());
+    }
+
+    // Runs 5000 items over 13 keys through 4 lanes, each tagging items with its
+    // number, and checks every key kept to one lane and stayed in order
+    fn partition(pooled: bool) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let runnable = Pipeline::from_iter(0..5000u64)
+            .capacity(8)
+            .partition_by(|x: &u64| x % 13, 4, |i, lane| lane.stage(move |x: u64| (i, x)))
+            .sink(collect(&seen));
+        let handle = if pooled { runnable.run_pooled(2) } else { runnable.run() };
+        let names: Vec<String> = handle.stats().into_iter().map(|s| s.name).collect();
+        let mut expected: Vec<String> = (0..4).map(|i| format!("pipeline-partition-{}-stage-0", i)).collect();
+        expected.push("pipeline-sink".to_string());
+        assert_eq!(expected, names);
+        handle.join().unwrap();
+        let seen = seen.lock().unwrap();
+        assert_eq!(5000, seen.len());
+        for key in 0..13 {
+            let items: Vec<(usize, u64)> = seen.iter().cloned().filter(|&(_, x)| x % 13 == key).collect();
+            assert!(items.iter().all(|&(i, _)| i as u64 == key % 4), "{:?}", items);
+            assert!(items.windows(2).all(|pair| pair[0].1 < pair[1].1));
+        }
+    }
+
+    #[test]
+    fn partition_keeps_keys_together() {
+        partition(false);
+        partition(true);
+    }
+
+    #[test]
+    fn graph_partition() {
+        let lanes: Vec<_> = (0..3).map(|_| Arc::new(Mutex::new(Vec::new()))).collect();
+        let mut graph = Graph::new("graph");
+        let numbers = graph.from_iter(0..300u64);
+        let ports = graph.partition_by(numbers, |x: &u64| x / 10, 3);
+        for (port, lane) in ports.into_iter().zip(&lanes) {
+            graph.sink(port, collect(lane));
+        }
+        graph.build().run().join().unwrap();
+        for (i, lane) in lanes.iter().enumerate() {
+            let expected: Vec<u64> = (0..300).filter(|x| (x / 10) % 3 == i as u64).collect();
+            assert_eq!(expected, *lane.lock().unwrap());
+        }
+    }
+
+    #[test]
+    fn either_reads_both() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        Pipeline::from_iter(0..100u32)
+            .either(Pipeline::from_iter(vec!["a", "b"]).stage(|s: &str| s.to_uppercase()))
+            .sink(collect(&seen))
+            .run()
+            .join()
+            .unwrap();
+        let seen = seen.lock().unwrap();
+        let lefts: Vec<u32> = seen.iter()
+            .filter_map(|item| if let Either::Left(x) = *item { Some(x) } else { None })
+            .collect();
+        assert_eq!((0..100).collect::<Vec<u32>>(), lefts);
+        assert_eq!(102, seen.len());
+        assert!(seen.contains(&Either::Right("B".to_string())));
+    }
+
+    // Joins numbers with their squares, both from one broadcast whose
+    // queues are far too short to hold the right side
+    fn squares(pooled: bool) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut graph = Graph::new("squares");
+        graph.capacity(4);
+        let numbers = graph.from_iter(0..1000u64);
+        let mut copies = graph.broadcast(numbers, 2);
+        let squares = graph.then(copies.pop().unwrap(), map(|x: u64| (x, x * x)));
+        let odd = graph.then(copies.pop().unwrap(), filter(|x: &u64| x % 2 == 1));
+        let joined = graph.join(odd, squares, |&x: &u64| x, |&(x, _): &(u64, u64)| x);
+        graph.sink(joined, collect(&seen));
+        let runnable = graph.build();
+        let handle = if pooled { runnable.run_pooled(2) } else { runnable.run() };
+        handle.join().unwrap();
+        let expected: Vec<(u64, (u64, u64))> = (0..1000).filter(|x| x % 2 == 1).map(|x| (x, (x, x * x))).collect();
+        assert_eq!(expected, *seen.lock().unwrap());
+    }
+
+    #[test]
+    fn join_waits_for_the_right_side() {
+        squares(false);
+        squares(true);
+    }
+
+    fn ticks(pooled: bool) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let runnable = Pipeline::from_ticks(Duration::from_millis(2)).sink(collect(&seen));
+        let started = Instant::now();
+        let handle = if pooled { runnable.run_pooled(1) } else { runnable.run() };
+        while seen.lock().unwrap().len() < 5 {
+            thread::yield_now();
+        }
+        handle.stop().unwrap();
+        let seen = seen.lock().unwrap();
+        for (i, tick) in seen.iter().enumerate() {
+            assert!(i == 0 || tick.count > seen[i - 1].count, "{:?}", *seen);
+            assert!(tick.at >= started + Duration::from_millis(2) * (tick.count as u32 + 1));
+        }
+    }
+
+    #[test]
+    fn ticks_keep_time() {
+        ticks(false);
+        ticks(true);
+    }
+}
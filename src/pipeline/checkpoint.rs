@@ -0,0 +1,248 @@
+//! Snapshots of a running pipeline, for a later run to resume from
+//!
+//! Taking a checkpoint pauses the sources and waits for what they already
+//! produced to make it all the way through. At that point every task has
+//! handled exactly the items before the pause, so the number of items each
+//! one took and the state of each stage are a consistent snapshot. A new run
+//! of the same pipeline started with Runnable::resume_from has its sources
+//! skip what they produced before and its stages start from their saved state.
+//!
+//! The pipeline stalls while a checkpoint is taken. Sources are only checked
+//! between items, so one waiting on its input holds the checkpoint up, as does
+//! a Rayon stage whose output queue spills.
+//!
+//! ```
+//! use pipeline::pipeline::Pipeline;
+//! use std::time::Duration;
+//!
+//! let handle = Pipeline::from_source(|| Some(1u64)).sink(|_| ()).run();
+//! let checkpoint = handle.checkpoint(Duration::from_secs(10)).unwrap();
+//! handle.stop().unwrap();
+//!
+//! // Later, against the same pipeline
+//! let resumed = Pipeline::from_source(|| Some(1u64))
+//!     .sink(|_| ())
+//!     .resume_from(&checkpoint)
+//!     .run();
+//! # resumed.stop().unwrap();
+//! ```
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use util::crc32::crc32;
+
+const MAGIC: &[u8; 4] = b"PLCP";
+const VERSION: u32 = 1;
+
+/// Where every task in a pipeline was at one quiet point
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// In the order the tasks were added to the pipeline
+    pub tasks: Vec<TaskCheckpoint>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskCheckpoint {
+    /// The task's thread name, without the pipeline's name
+    pub name: String,
+    /// How many items a source produced, or how many items anything else
+    /// took from its input, counting those from earlier runs
+    pub consumed: u64,
+    /// What the stage returned from Stage::checkpoint
+    pub state: Option<Vec<u8>>,
+}
+
+/// Somewhere to keep the checkpoints a running pipeline takes
+pub trait CheckpointStore: Send + 'static {
+    fn save(&mut self, checkpoint: &Checkpoint) -> io::Result<()>;
+}
+
+/// Keeps the latest checkpoint in a file, replacing it whole each time
+/// so a crash midway through leaves the previous one intact
+pub struct FileStore {
+    path: PathBuf,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if bytes.len() < len {
+        return Err(invalid("checkpoint is truncated"));
+    }
+    let (head, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(head)
+}
+
+fn take_u32(bytes: &mut &[u8]) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(take(bytes, 4)?);
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn take_u64(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(take(bytes, 8)?);
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl Checkpoint {
+    /// The checkpoint as bytes, ending in a checksum of the rest
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.tasks.len() as u32).to_le_bytes());
+        for task in &self.tasks {
+            out.extend_from_slice(&(task.name.len() as u32).to_le_bytes());
+            out.extend_from_slice(task.name.as_bytes());
+            out.extend_from_slice(&task.consumed.to_le_bytes());
+            match task.state {
+                Some(ref state) => {
+                    out.push(1);
+                    out.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                    out.extend_from_slice(state);
+                }
+                None => out.push(0),
+            }
+        }
+        let sum = crc32(&out);
+        out.extend_from_slice(&sum.to_le_bytes());
+        out
+    }
+
+    /// Reads a checkpoint written by to_bytes
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Checkpoint> {
+        if bytes.len() < 4 {
+            return Err(invalid("checkpoint is truncated"));
+        }
+        let (body, sum) = bytes.split_at(bytes.len() - 4);
+        if crc32(body).to_le_bytes() != sum {
+            return Err(invalid("checkpoint failed its checksum"));
+        }
+        let mut bytes = body;
+        if take(&mut bytes, 4)? != MAGIC {
+            return Err(invalid("not a pipeline checkpoint"));
+        }
+        if take_u32(&mut bytes)? != VERSION {
+            return Err(invalid("unsupported checkpoint version"));
+        }
+        let count = take_u32(&mut bytes)?;
+        let mut tasks = Vec::new();
+        for _ in 0..count {
+            let len = take_u32(&mut bytes)? as usize;
+            let name = String::from_utf8(take(&mut bytes, len)?.to_vec())
+                .map_err(|_| invalid("task name isn't utf-8"))?;
+            let consumed = take_u64(&mut bytes)?;
+            let state = match take(&mut bytes, 1)?[0] {
+                0 => None,
+                _ => {
+                    let len = take_u32(&mut bytes)? as usize;
+                    Some(take(&mut bytes, len)?.to_vec())
+                }
+            };
+            tasks.push(TaskCheckpoint {
+                name: name,
+                consumed: consumed,
+                state: state,
+            });
+        }
+        if !bytes.is_empty() {
+            return Err(invalid("trailing bytes after checkpoint"));
+        }
+        Ok(Checkpoint { tasks: tasks })
+    }
+}
+
+impl FileStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileStore {
+        FileStore { path: path.as_ref().to_path_buf() }
+    }
+
+    /// The last checkpoint saved, or None if there isn't one yet
+    pub fn load(&self) -> io::Result<Option<Checkpoint>> {
+        let mut bytes = Vec::new();
+        match File::open(&self.path) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Checkpoint::from_bytes(&bytes).map(Some)
+    }
+}
+
+impl CheckpointStore for FileStore {
+    fn save(&mut self, checkpoint: &Checkpoint) -> io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&checkpoint.to_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl<F> CheckpointStore for F
+    where F: FnMut(&Checkpoint) -> io::Result<()> + Send + 'static
+{
+    fn save(&mut self, checkpoint: &Checkpoint) -> io::Result<()> {
+        self(checkpoint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    fn sample() -> Checkpoint {
+        Checkpoint {
+            tasks: vec![TaskCheckpoint {
+                            name: "source".to_string(),
+                            consumed: 1 << 40,
+                            state: None,
+                        },
+                        TaskCheckpoint {
+                            name: "stage-1".to_string(),
+                            consumed: 7,
+                            state: Some(vec![1, 2, 3]),
+                        }],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let checkpoint = sample();
+        let bytes = checkpoint.to_bytes();
+        assert_eq!(checkpoint, Checkpoint::from_bytes(&bytes).unwrap());
+
+        for len in 0..bytes.len() {
+            assert!(Checkpoint::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut corrupt = bytes.clone();
+        corrupt[12] ^= 1;
+        assert_eq!(io::ErrorKind::InvalidData,
+                   Checkpoint::from_bytes(&corrupt).unwrap_err().kind());
+    }
+
+    #[test]
+    fn file_store_keeps_the_latest() {
+        let path = env::temp_dir().join(format!("pipeline-checkpoint-{}", process::id()));
+        let mut store = FileStore::new(&path);
+        assert_eq!(None, store.load().unwrap());
+        store.save(&sample()).unwrap();
+        let mut later = sample();
+        later.tasks[1].consumed = 8;
+        store.save(&later).unwrap();
+        assert_eq!(Some(later), store.load().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+//! Somewhere for a stage's failures to go instead of taking down its thread
+//!
+//! A stage built with try_stage hands out results rather than items.
+//! Whenever it returns an error or panics, the item it was working on goes to
+//! a dead-letter queue along with what went wrong, and the stage moves on.
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+use pipeline::link::Control;
+use pipeline::worker::Worker;
+use queue::multiqueue::MultiWriter;
+
+/// An item a stage couldn't process
+#[derive(Debug)]
+pub struct DeadLetter<T, E> {
+    pub item: T,
+    /// Name of the stage's thread, e.g. pipeline-stage-2
+    pub stage: String,
+    pub failure: Failure<E>,
+}
+
+#[derive(Debug)]
+pub enum Failure<E> {
+    /// The stage returned an error
+    Error(E),
+    /// The stage panicked, with the panic message if it had one
+    Panic(String),
+}
+
+/// Runs f on each item, sending failures to dead_letters
+pub struct TryMap<T, E, F> {
+    f: F,
+    dead_letters: MultiWriter<DeadLetter<T, E>>,
+}
+
+impl<T, E, F> TryMap<T, E, F> {
+    pub fn new(f: F, dead_letters: MultiWriter<DeadLetter<T, E>>) -> TryMap<T, E, F> {
+        TryMap {
+            f: f,
+            dead_letters: dead_letters,
+        }
+    }
+}
+
+/// The message a panic was raised with, as caught by catch_unwind or join
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        msg.to_string()
+    } else {
+        "panic with a non-string payload".to_string()
+    }
+}
+
+impl<T, U, E, F> Worker<T, U> for TryMap<T, E, F>
+    where T: 'static,
+          E: 'static,
+          F: FnMut(&T) -> Result<U, E> + Send + 'static
+{
+    fn process(&mut self, item: T, emit: &mut dyn FnMut(U), control: &Control) {
+        let failure = {
+            let f = &mut self.f;
+            match panic::catch_unwind(AssertUnwindSafe(|| f(&item))) {
+                Ok(Ok(out)) => return emit(out),
+                Ok(Err(e)) => Failure::Error(e),
+                Err(payload) => Failure::Panic(panic_message(&*payload)),
+            }
+        };
+        let mut letter = DeadLetter {
+            item: item,
+            stage: thread::current().name().unwrap_or("").to_string(),
+            failure: failure,
+        };
+        // Waits for room like any other queue in the pipeline,
+        // so nothing is lost if the dead letters are read slowly
+        while let Err(back) = self.dead_letters.push(letter) {
+            if control.is_stopped() {
+                return;
+            }
+            letter = back;
+            thread::yield_now();
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for Failure<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Error(ref e) => write!(f, "stage returned an error: {}", e),
+            Failure::Panic(ref msg) => write!(f, "stage panicked: {}", msg),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for Failure<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            Failure::Error(ref e) => Some(e),
+            Failure::Panic(_) => None,
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Files, sockets and anything else that's Read or Write at either end of a pipeline
+//!
+//! A reader becomes a source handing out chunks of bytes, and a writer
+//! becomes a stage writing out every item in turn. Errors other than
+//! interruptions panic the thread that hit them, which join then reports.
+//!
+//! ```
+//! use pipeline::pipeline::Pipeline;
+//! use std::io::Cursor;
+//! use std::sync::{Arc, Mutex};
+//!
+//! struct Shared(Arc<Mutex<Vec<u8>>>);
+//!
+//! impl std::io::Write for Shared {
+//!     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+//!         self.0.lock().unwrap().write(buf)
+//!     }
+//!
+//!     fn flush(&mut self) -> std::io::Result<()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let out = Arc::new(Mutex::new(Vec::new()));
+//! Pipeline::from_reader(Cursor::new(b"hello world".to_vec()), 4)
+//!     .stage(|chunk: Vec<u8>| chunk.to_ascii_uppercase())
+//!     .write_to(Shared(out.clone()))
+//!     .run()
+//!     .join()
+//!     .unwrap();
+//! assert_eq!(b"HELLO WORLD".to_vec(), *out.lock().unwrap());
+//! ```
+
+use std::io::{ErrorKind, Read, Write};
+
+use pipeline::stage::Stage;
+
+/// Writes every item to a writer, and flushes it once the input runs dry
+pub struct WriteAll<W> {
+    writer: W,
+}
+
+/// A source reading up to size bytes at a time from reader, until it's
+/// at its end. Panics if size is 0
+pub fn chunks<R>(mut reader: R, size: usize) -> impl FnMut() -> Option<Vec<u8>> + Send + 'static
+    where R: Read + Send + 'static
+{
+    assert!(size > 0, "chunks need to hold at least one byte");
+    move || {
+        let mut chunk = vec![0; size];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => return None,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    return Some(chunk);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => panic!("failed to read: {}", e),
+            }
+        }
+    }
+}
+
+/// Writes each item to writer. Outputs nothing
+pub fn write_all<W: Write + Send + 'static>(writer: W) -> WriteAll<W> {
+    WriteAll { writer: writer }
+}
+
+impl<T, W> Stage<T, ()> for WriteAll<W>
+    where T: AsRef<[u8]>,
+          W: Write + Send + 'static
+{
+    fn process(&mut self, item: T, _emit: &mut dyn FnMut(())) {
+        if let Err(e) = self.writer.write_all(item.as_ref()) {
+            panic!("failed to write: {}", e);
+        }
+    }
+
+    fn finish(&mut self, _emit: &mut dyn FnMut(())) {
+        if let Err(e) = self.writer.flush() {
+            panic!("failed to flush: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{self, BufWriter};
+
+    /// Reads a byte at a time, interrupted before each
+    struct Trickle {
+        bytes: Vec<u8>,
+        interrupted: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(io::Error::new(ErrorKind::Interrupted, "again"));
+            }
+            if self.bytes.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.bytes.remove(0);
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn chunks_take_what_each_read_gives() {
+        let mut source = chunks(io::Cursor::new(vec![1, 2, 3, 4, 5]), 2);
+        assert_eq!(Some(vec![1, 2]), source());
+        assert_eq!(Some(vec![3, 4]), source());
+        assert_eq!(Some(vec![5]), source());
+        assert_eq!(None, source());
+
+        let mut source = chunks(Trickle {
+                                    bytes: vec![1, 2],
+                                    interrupted: false,
+                                },
+                                8);
+        assert_eq!(Some(vec![1]), source());
+        assert_eq!(Some(vec![2]), source());
+        assert_eq!(None, source());
+    }
+
+    #[test]
+    fn write_all_flushes_at_the_end() {
+        let mut stage = write_all(BufWriter::new(Vec::new()));
+        stage.process(vec![1, 2], &mut |_| ());
+        stage.process(&[3][..], &mut |_| ());
+        assert!(stage.writer.get_ref().is_empty());
+        Stage::<Vec<u8>, ()>::finish(&mut stage, &mut |_| ());
+        assert_eq!(vec![1, 2, 3], *stage.writer.get_ref());
+    }
+}
@@ -0,0 +1,128 @@
+//! Joining two streams on a key
+//!
+//! The right stream is read into a table by key, and each left item is paired
+//! with every right item sharing its key once the right stream has ended.
+//! Left items that arrive before then are held, so the two streams can come
+//! from the same broadcast without holding each other back.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+
+use pipeline::link::{Control, Receiver, Recv};
+use pipeline::worker::Worker;
+
+/// What comes out of Sides
+pub enum Event<A, B> {
+    Left(A),
+    Right(B),
+    /// The right stream is closed and everything on it has been read
+    RightEnded,
+}
+
+/// Reads the right receiver ahead of the left one, saying when it ends
+pub struct Sides<A, B> {
+    left: Box<dyn Receiver<A>>,
+    right: Option<Box<dyn Receiver<B>>>,
+}
+
+/// Pairs up the items of Sides whose keys match
+pub struct Join<A, B, K, KA, KB> {
+    left_key: KA,
+    right_key: KB,
+    table: HashMap<K, Vec<B>>,
+    // Left items waiting for the right stream to end
+    held: Vec<A>,
+    built: bool,
+}
+
+impl<A: 'static, B: 'static> Sides<A, B> {
+    pub fn new(left: Box<dyn Receiver<A>>, right: Box<dyn Receiver<B>>) -> Sides<A, B> {
+        Sides {
+            left: left,
+            right: Some(right),
+        }
+    }
+}
+
+impl<A: 'static, B: 'static> Receiver<Event<A, B>> for Sides<A, B> {
+    fn try_recv(&mut self) -> Recv<Event<A, B>> {
+        if let Some(ref mut right) = self.right {
+            match right.try_recv() {
+                Recv::Item(val) => return Recv::Item(Event::Right(val)),
+                Recv::Closed => {
+                    self.right = None;
+                    return Recv::Item(Event::RightEnded);
+                }
+                Recv::Empty => (),
+            }
+        }
+        match self.left.try_recv() {
+            Recv::Item(val) => Recv::Item(Event::Left(val)),
+            Recv::Closed if self.right.is_none() => Recv::Closed,
+            _ => Recv::Empty,
+        }
+    }
+
+    fn split(&self) -> Box<dyn Receiver<Event<A, B>>> {
+        Box::new(Sides {
+            left: self.left.split(),
+            right: self.right.as_ref().map(|right| right.split()),
+        })
+    }
+
+    fn depth(&self) -> usize {
+        self.left.depth() + self.right.as_ref().map_or(0, |right| right.depth())
+    }
+}
+
+impl<A, B, K, KA, KB> Join<A, B, K, KA, KB> {
+    pub fn new(left_key: KA, right_key: KB) -> Join<A, B, K, KA, KB> {
+        Join {
+            left_key: left_key,
+            right_key: right_key,
+            table: HashMap::new(),
+            held: Vec::new(),
+            built: false,
+        }
+    }
+}
+
+impl<A, B, K, KA, KB> Join<A, B, K, KA, KB>
+    where A: Clone,
+          B: Clone,
+          K: Hash + Eq,
+          KA: FnMut(&A) -> K
+{
+    fn probe(&mut self, item: A, emit: &mut dyn FnMut((A, B))) {
+        if let Some(matches) = self.table.get(&(self.left_key)(&item)) {
+            for other in matches {
+                emit((item.clone(), other.clone()))
+            }
+        }
+    }
+}
+
+impl<A, B, K, KA, KB> Worker<Event<A, B>, (A, B)> for Join<A, B, K, KA, KB>
+    where A: Clone + Send + 'static,
+          B: Clone + Send + 'static,
+          K: Hash + Eq + Send + 'static,
+          KA: FnMut(&A) -> K + Send + 'static,
+          KB: FnMut(&B) -> K + Send + 'static
+{
+    fn process(&mut self, event: Event<A, B>, emit: &mut dyn FnMut((A, B)), _control: &Control) {
+        match event {
+            Event::Right(item) => {
+                self.table.entry((self.right_key)(&item)).or_default().push(item)
+            }
+            Event::RightEnded => {
+                self.built = true;
+                for item in mem::take(&mut self.held) {
+                    self.probe(item, emit)
+                }
+            }
+            Event::Left(item) if self.built => self.probe(item, emit),
+            Event::Left(item) => self.held.push(item),
+        }
+    }
+}
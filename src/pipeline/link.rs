@@ -0,0 +1,904 @@
+//! The ends of a queue between two parts of a pipeline
+//!
+//! Besides the queue itself, each link counts the outlets still writing into it,
+//! so a reader can tell a queue that's momentarily empty from one that
+//! will never see another item, and the inlets still reading each stream, so a
+//! writer doesn't wait forever on a stream whose readers panicked.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release, SeqCst};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pipeline::Either;
+use pipeline::pause::Slot;
+use pipeline::worker::Tick;
+use queue::multiqueue::{MultiReader, MultiWriter, multiqueue};
+use queue::shared::Shared;
+
+/// State shared by every thread in a running pipeline
+pub struct Control {
+    // Sources stop producing, everything else finishes what's queued
+    draining: AtomicBool,
+    // Everything stops at the next item
+    stopped: AtomicBool,
+    // Threads started for the pipeline's tasks
+    tasks: AtomicUsize,
+    // Set while a checkpoint waits for the pipeline to go quiet
+    pausing: AtomicBool,
+    // Tasks waiting out a checkpoint, plus those that have exited
+    parked: AtomicUsize,
+    // Bumped whenever a parked task goes back to work
+    unparks: AtomicUsize,
+}
+
+struct Ends {
+    writers: AtomicUsize,
+    // Items thrown away by a dropping backpressure policy
+    dropped: AtomicUsize,
+    // Set once some stream has lost all of its readers
+    orphaned: AtomicBool,
+    // Items held back by the outlets of a Spill policy
+    spilled: AtomicUsize,
+    // Streams added to the link, which a DropOldest policy needs to be one
+    streams: AtomicUsize,
+}
+
+/// What a writer does when the queue in front of it is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait for room, holding up everything upstream
+    Block,
+    /// Throw away the item being written
+    DropNewest,
+    /// Throw away the oldest item in the queue to make room. A link with
+    /// more than one stream waits for room instead
+    DropOldest,
+    /// Hold items in an unbounded buffer on the writer's side
+    /// and push them in order as room opens up
+    Spill,
+}
+
+enum Policy<T> {
+    Block,
+    DropNewest,
+    DropOldest(Arc<Overflow<T>>),
+    Spill(VecDeque<T>),
+}
+
+/// Where the outlets of a DropOldest link put what doesn't fit in the queue,
+/// to be read after it. Only inlets pop from the queue, so rather than making
+/// room itself an outlet leaves the oldest items owed, for the next inlet to
+/// throw away
+struct Overflow<T> {
+    held: Mutex<Held<T>>,
+    // How many items are held, so inlets only lock when there are some
+    count: AtomicUsize,
+    capacity: usize,
+}
+
+struct Held<T> {
+    items: VecDeque<T>,
+    // Items at the front of the queue that were dropped to make room
+    owed: usize,
+}
+
+/// The writing end of a link. The link closes once every outlet is dropped
+pub struct Outlet<T> {
+    writer: MultiWriter<T>,
+    ends: Arc<Ends>,
+    policy: Policy<T>,
+    // Time spent waiting for room in the queue
+    waited: Cell<Duration>,
+}
+
+/// The reading end of one stream of a link
+pub struct Inlet<T> {
+    reader: MultiReader<T>,
+    ends: Arc<Ends>,
+    consumers: Arc<AtomicUsize>,
+    // What a DropOldest link's outlets held back, for the link's first stream
+    overflow: Option<Arc<Overflow<T>>>,
+}
+
+/// What a receiver has for its reader right now
+pub enum Recv<T> {
+    Item(T),
+    Empty,
+    /// Nothing more will ever arrive
+    Closed,
+}
+
+/// Anything a stage can read its input from
+pub trait Receiver<T>: Send + 'static {
+    fn try_recv(&mut self) -> Recv<T>;
+
+    /// Another receiver that shares this one's items, each going to only one of them
+    fn split(&self) -> Box<dyn Receiver<T>>;
+
+    /// Roughly how many items are waiting to be received
+    fn depth(&self) -> usize;
+}
+
+/// Reads one stream of a broadcast link, handing out owned items
+pub struct SharedInlet<T> {
+    inlet: Inlet<Shared<T>>,
+}
+
+/// Reads from several receivers in turn until all are closed
+pub struct Merge<T> {
+    inputs: Vec<Box<dyn Receiver<T>>>,
+    next: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+/// Passes each item of a receiver through tag on the way out
+pub struct Tagged<T, U> {
+    input: Box<dyn Receiver<T>>,
+    tag: fn(T) -> U,
+}
+
+/// Mixes a Tick into what a receiver hands out every interval
+pub struct Ticking<T> {
+    input: Box<dyn Receiver<T>>,
+    interval: Duration,
+    next: Option<Instant>,
+    count: u64,
+}
+
+pub fn link<T>(capacity: u16) -> (Outlet<T>, Inlet<T>) {
+    link_with(capacity, Backpressure::Block)
+}
+
+/// A link whose outlets handle a full queue according to backpressure
+pub fn link_with<T>(capacity: u16, backpressure: Backpressure) -> (Outlet<T>, Inlet<T>) {
+    let (writer, reader) = multiqueue(capacity);
+    let ends = Arc::new(Ends {
+        writers: AtomicUsize::new(1),
+        dropped: AtomicUsize::new(0),
+        orphaned: AtomicBool::new(false),
+        spilled: AtomicUsize::new(0),
+        streams: AtomicUsize::new(1),
+    });
+    let policy = Policy::new(backpressure, capacity);
+    let overflow = match policy {
+        Policy::DropOldest(ref overflow) => Some(overflow.clone()),
+        _ => None,
+    };
+    (Outlet {
+        writer: writer,
+        ends: ends.clone(),
+        policy: policy,
+        waited: Cell::new(Duration::from_secs(0)),
+    },
+     Inlet {
+        reader: reader,
+        ends: ends,
+        consumers: Arc::new(AtomicUsize::new(1)),
+        overflow: overflow,
+    })
+}
+
+impl Control {
+    pub fn new() -> Control {
+        Control {
+            draining: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            tasks: AtomicUsize::new(0),
+            pausing: AtomicBool::new(false),
+            parked: AtomicUsize::new(0),
+            unparks: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn drain(&self) {
+        self.draining.store(true, Release);
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Release);
+    }
+
+    /// Whether sources should stop producing
+    #[inline(always)]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Acquire) || self.is_stopped()
+    }
+
+    #[inline(always)]
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Acquire)
+    }
+
+    /// Counts another thread running one of the pipeline's tasks
+    pub fn enlist(&self) {
+        self.tasks.fetch_add(1, SeqCst);
+    }
+
+    pub fn tasks(&self) -> usize {
+        self.tasks.load(SeqCst)
+    }
+
+    /// Asks every task to park once it has nothing in hand
+    pub fn pause(&self) {
+        self.pausing.store(true, SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.pausing.store(false, SeqCst);
+    }
+
+    /// Whether a checkpoint is waiting on the pipeline. A draining
+    /// pipeline doesn't pause, since its sources are done anyway
+    #[inline(always)]
+    pub fn is_pausing(&self) -> bool {
+        self.pausing.load(Relaxed) && !self.is_draining()
+    }
+
+    pub fn parked(&self) -> usize {
+        self.parked.load(SeqCst)
+    }
+
+    pub fn unparks(&self) -> usize {
+        self.unparks.load(SeqCst)
+    }
+
+    pub fn park(&self) {
+        self.parked.fetch_add(1, SeqCst);
+    }
+
+    pub fn unpark(&self) {
+        // Bumped first, so a checkpoint that saw everything parked
+        // and then the same count knows nothing woke in between
+        self.unparks.fetch_add(1, SeqCst);
+        self.parked.fetch_sub(1, SeqCst);
+    }
+}
+
+/// Pops the next item from rx, waiting for one. Returns None once rx is
+/// closed and drained, or when the pipeline is stopped
+///
+/// While a checkpoint is being taken, the caller parks once rx is empty
+/// and settle returns true, which it does when the caller has no items
+/// in hand and has saved its state to slot
+pub fn recv<T: 'static>(rx: &mut dyn Receiver<T>,
+                        control: &Control,
+                        slot: &Slot,
+                        settle: &mut dyn FnMut() -> bool)
+                        -> Option<T> {
+    loop {
+        match rx.try_recv() {
+            Recv::Item(val) => {
+                slot.consume();
+                return Some(val);
+            }
+            Recv::Closed => return None,
+            Recv::Empty => {
+                if control.is_stopped() {
+                    return None;
+                }
+                if control.is_pausing() && settle() {
+                    let woken = slot.park(control, || match rx.try_recv() {
+                        Recv::Empty => None,
+                        got => Some(got),
+                    });
+                    match woken {
+                        Some(Recv::Item(val)) => {
+                            slot.consume();
+                            return Some(val);
+                        }
+                        Some(_) => return None,
+                        None => continue,
+                    }
+                }
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+impl<T> Policy<T> {
+    fn new(backpressure: Backpressure, capacity: u16) -> Policy<T> {
+        match backpressure {
+            Backpressure::Block => Policy::Block,
+            Backpressure::DropNewest => Policy::DropNewest,
+            Backpressure::DropOldest => Policy::DropOldest(Arc::new(Overflow::new(capacity as usize))),
+            Backpressure::Spill => Policy::Spill(VecDeque::new()),
+        }
+    }
+}
+
+impl<T> Overflow<T> {
+    fn new(capacity: usize) -> Overflow<T> {
+        Overflow {
+            held: Mutex::new(Held {
+                items: VecDeque::new(),
+                owed: 0,
+            }),
+            count: AtomicUsize::new(0),
+            capacity: capacity,
+        }
+    }
+
+    /// Holds val behind what's in writer's queue, dropping the oldest item
+    /// there is to make room
+    fn hold(&self, val: T, writer: &MultiWriter<T>, dropped: &AtomicUsize) {
+        let mut held = self.held.lock().unwrap();
+        let val = if held.items.is_empty() {
+            // An inlet may have made room since the push failed
+            match writer.push(val) {
+                Ok(_) => return,
+                Err(back) => back,
+            }
+        } else {
+            val
+        };
+        // Inlets may have popped some of what's queued, leaving room for more held
+        let queued = self.capacity.saturating_sub(writer.space_remaining_now());
+        if queued.saturating_sub(held.owed) + held.items.len() >= self.capacity {
+            if held.owed < queued {
+                held.owed += 1;
+            } else {
+                held.items.pop_front();
+            }
+            dropped.fetch_add(1, Relaxed);
+        }
+        held.items.push_back(val);
+        self.count.store(held.items.len(), Release);
+    }
+
+    /// Pops the next item after throwing away what's owed, taking from what's
+    /// held once the queue is empty
+    fn pop(&self, reader: &MultiReader<T>, dropped: &AtomicUsize) -> Option<T> {
+        let mut held = self.held.lock().unwrap();
+        while held.owed > 0 {
+            if reader.pop().is_none() {
+                // Popped by an inlet that didn't know to drop it
+                dropped.fetch_sub(held.owed, Relaxed);
+                held.owed = 0;
+                break;
+            }
+            held.owed -= 1;
+        }
+        if let Some(val) = reader.pop() {
+            return Some(val);
+        }
+        let val = held.items.pop_front();
+        self.count.store(held.items.len(), Release);
+        val
+    }
+}
+
+impl<T> Outlet<T> {
+    /// Pushes val, or handles a full queue according to the link's policy.
+    /// Returns false if the pipeline was stopped or a stream lost its readers
+    /// while waiting, dropping val
+    pub fn send(&mut self, val: T, control: &Control) -> bool {
+        match self.try_send(val) {
+            Ok(()) => true,
+            Err(val) => self.push_blocking(val, control),
+        }
+    }
+
+    /// Like send, but hands val back rather than waiting for room
+    /// when a Block policy finds the queue full, or a DropOldest policy
+    /// can't make room or finds the link orphaned
+    pub fn try_send(&mut self, val: T) -> Result<(), T> {
+        match self.policy {
+            Policy::Block => self.writer.push(val).map(|_| ()),
+            Policy::DropNewest => {
+                if self.writer.push(val).is_err() {
+                    self.ends.dropped.fetch_add(1, Relaxed);
+                }
+                Ok(())
+            }
+            Policy::DropOldest(ref overflow) => {
+                let mut val = val;
+                // Once anything is held, pushing to the queue would jump ahead of it
+                if overflow.count.load(Acquire) == 0 {
+                    match self.writer.push(val) {
+                        Ok(_) => return Ok(()),
+                        Err(back) => val = back,
+                    }
+                }
+                // Only the first stream's inlets know to look at what's held,
+                // and an orphaned link has nobody to take it
+                if self.ends.streams.load(Acquire) > 1 || self.ends.orphaned.load(Acquire) {
+                    return Err(val);
+                }
+                overflow.hold(val, &self.writer, &self.ends.dropped);
+                Ok(())
+            }
+            Policy::Spill(ref mut spilled) => {
+                spilled.push_back(val);
+                self.ends.spilled.fetch_add(1, Relaxed);
+                self.try_flush();
+                Ok(())
+            }
+        }
+    }
+
+    /// Pushes as much of what a Spill policy held back as fits right now.
+    /// Returns whether nothing is held back any more
+    pub fn try_flush(&mut self) -> bool {
+        if let Policy::Spill(ref mut spilled) = self.policy {
+            while let Some(val) = spilled.pop_front() {
+                if let Err(back) = self.writer.push(val) {
+                    spilled.push_front(back);
+                    return false;
+                }
+                self.ends.spilled.fetch_sub(1, Relaxed);
+            }
+        }
+        true
+    }
+
+    /// Waits until everything held back by a Spill policy is in the queue.
+    /// Returns false if the pipeline was stopped or a stream lost its readers first
+    pub fn flush(&mut self, control: &Control) -> bool {
+        let spilled = match self.policy {
+            Policy::Spill(ref mut spilled) => ::std::mem::take(spilled),
+            _ => return true,
+        };
+        self.ends.spilled.fetch_sub(spilled.len(), Relaxed);
+        for val in spilled {
+            if !self.push_blocking(val, control) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn push_blocking(&self, val: T, control: &Control) -> bool {
+        let mut val = val;
+        let mut since = None;
+        let pushed = loop {
+            match self.writer.push(val) {
+                Ok(_) => break true,
+                Err(back) => {
+                    if control.is_stopped() || self.ends.orphaned.load(Acquire) {
+                        break false;
+                    }
+                    since.get_or_insert_with(Instant::now);
+                    val = back;
+                    thread::yield_now();
+                }
+            }
+        };
+        if let Some(since) = since {
+            self.waited.set(self.waited.get() + since.elapsed());
+        }
+        pushed
+    }
+
+    /// Whether some stream of the link has lost all of its readers,
+    /// so a full queue will never have room again
+    pub fn is_orphaned(&self) -> bool {
+        self.ends.orphaned.load(Acquire)
+    }
+
+    /// How long this outlet has spent waiting for room in a full queue
+    pub fn waited(&self) -> Duration {
+        self.waited.get()
+    }
+
+    /// How many items this link's policy has thrown away
+    pub fn dropped(&self) -> usize {
+        self.ends.dropped.load(Relaxed)
+    }
+
+    /// How many items the outlets of this link are holding back
+    pub fn spilled(&self) -> usize {
+        self.ends.spilled.load(Relaxed)
+    }
+}
+
+impl<T> Clone for Outlet<T> {
+    fn clone(&self) -> Outlet<T> {
+        self.ends.writers.fetch_add(1, Release);
+        let policy = match self.policy {
+            Policy::Block => Policy::Block,
+            Policy::DropNewest => Policy::DropNewest,
+            Policy::DropOldest(ref overflow) => Policy::DropOldest(overflow.clone()),
+            Policy::Spill(_) => Policy::Spill(VecDeque::new()),
+        };
+        Outlet {
+            writer: self.writer.clone(),
+            ends: self.ends.clone(),
+            policy: policy,
+            waited: Cell::new(Duration::from_secs(0)),
+        }
+    }
+}
+
+impl<T> Drop for Outlet<T> {
+    fn drop(&mut self) {
+        self.ends.writers.fetch_sub(1, Release);
+    }
+}
+
+impl<T> Inlet<T> {
+    /// Whether every outlet writing into this link is gone
+    pub fn is_closed(&self) -> bool {
+        self.ends.writers.load(Acquire) == 0
+    }
+
+    /// Adds another stream to the link, starting at this inlet's position.
+    /// It sees every item from then on, independently of this one
+    pub fn add_stream(&self) -> Inlet<T> {
+        self.ends.streams.fetch_add(1, Release);
+        Inlet {
+            reader: self.reader.stream().add_stream().into_reader(),
+            ends: self.ends.clone(),
+            consumers: Arc::new(AtomicUsize::new(1)),
+            overflow: None,
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        match self.overflow {
+            Some(ref overflow) if overflow.count.load(Acquire) > 0 => overflow.pop(&self.reader, &self.ends.dropped),
+            _ => self.reader.pop(),
+        }
+    }
+}
+
+impl<T: 'static> Receiver<T> for Inlet<T> {
+    fn try_recv(&mut self) -> Recv<T> {
+        if let Some(val) = self.pop() {
+            return Recv::Item(val);
+        }
+        if self.is_closed() {
+            // Anything pushed before the last outlet left is visible now
+            return match self.pop() {
+                Some(val) => Recv::Item(val),
+                None => Recv::Closed,
+            };
+        }
+        Recv::Empty
+    }
+
+    fn split(&self) -> Box<dyn Receiver<T>> {
+        Box::new(self.clone())
+    }
+
+    fn depth(&self) -> usize {
+        let held = self.overflow.as_ref().map_or(0, |overflow| overflow.count.load(Relaxed));
+        self.reader.lag() + held
+    }
+}
+
+impl<T> Clone for Inlet<T> {
+    fn clone(&self) -> Inlet<T> {
+        self.consumers.fetch_add(1, Release);
+        Inlet {
+            reader: self.reader.clone(),
+            ends: self.ends.clone(),
+            consumers: self.consumers.clone(),
+            overflow: self.overflow.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Inlet<T> {
+    fn drop(&mut self) {
+        if self.consumers.fetch_sub(1, Release) == 1 {
+            self.ends.orphaned.store(true, Release);
+        }
+    }
+}
+
+impl<T> SharedInlet<T> {
+    pub fn new(inlet: Inlet<Shared<T>>) -> SharedInlet<T> {
+        SharedInlet { inlet: inlet }
+    }
+}
+
+impl<T: Clone + 'static> Receiver<T> for SharedInlet<T> {
+    fn try_recv(&mut self) -> Recv<T> {
+        match self.inlet.try_recv() {
+            Recv::Item(shared) => {
+                // Each stream pops a given item exactly once, and owns one reference.
+                // The last stream to get to it takes it without a clone
+                let arc = unsafe { shared.into_arc() };
+                Recv::Item(Arc::try_unwrap(arc).unwrap_or_else(|arc| (*arc).clone()))
+            }
+            Recv::Empty => Recv::Empty,
+            Recv::Closed => Recv::Closed,
+        }
+    }
+
+    fn split(&self) -> Box<dyn Receiver<T>> {
+        Box::new(SharedInlet { inlet: self.inlet.clone() })
+    }
+
+    fn depth(&self) -> usize {
+        self.inlet.depth()
+    }
+}
+
+impl<T> Drop for SharedInlet<T> {
+    fn drop(&mut self) {
+        // Nothing else will pop what's left on the stream, and each item
+        // there still holds this stream's reference
+        if self.inlet.consumers.load(Acquire) == 1 {
+            while let Some(shared) = self.inlet.reader.pop() {
+                drop(unsafe { shared.into_arc() });
+            }
+        }
+    }
+}
+
+impl<T: 'static> Merge<T> {
+    pub fn new(inputs: Vec<Box<dyn Receiver<T>>>) -> Merge<T> {
+        Merge {
+            inputs: inputs,
+            next: 0,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: 'static> Receiver<T> for Merge<T> {
+    fn try_recv(&mut self) -> Recv<T> {
+        // Starts after whichever input produced last, so a busy one can't starve the rest
+        let mut tried = 0;
+        while tried < self.inputs.len() {
+            if self.next >= self.inputs.len() {
+                self.next = 0;
+            }
+            match self.inputs[self.next].try_recv() {
+                Recv::Item(val) => {
+                    self.next += 1;
+                    return Recv::Item(val);
+                }
+                Recv::Empty => {
+                    self.next += 1;
+                    tried += 1;
+                }
+                Recv::Closed => {
+                    self.inputs.swap_remove(self.next);
+                }
+            }
+        }
+        if self.inputs.is_empty() {
+            Recv::Closed
+        } else {
+            Recv::Empty
+        }
+    }
+
+    fn split(&self) -> Box<dyn Receiver<T>> {
+        Box::new(Merge::new(self.inputs.iter().map(|input| input.split()).collect()))
+    }
+
+    fn depth(&self) -> usize {
+        self.inputs.iter().map(|input| input.depth()).sum()
+    }
+}
+
+impl<T: 'static, U: 'static> Tagged<T, U> {
+    pub fn new(input: Box<dyn Receiver<T>>, tag: fn(T) -> U) -> Tagged<T, U> {
+        Tagged {
+            input: input,
+            tag: tag,
+        }
+    }
+}
+
+impl<T: 'static, U: 'static> Receiver<U> for Tagged<T, U> {
+    fn try_recv(&mut self) -> Recv<U> {
+        match self.input.try_recv() {
+            Recv::Item(val) => Recv::Item((self.tag)(val)),
+            Recv::Empty => Recv::Empty,
+            Recv::Closed => Recv::Closed,
+        }
+    }
+
+    fn split(&self) -> Box<dyn Receiver<U>> {
+        Box::new(Tagged::new(self.input.split(), self.tag))
+    }
+
+    fn depth(&self) -> usize {
+        self.input.depth()
+    }
+}
+
+impl<T: 'static> Ticking<T> {
+    pub fn new(input: Box<dyn Receiver<T>>, interval: Duration) -> Ticking<T> {
+        Ticking {
+            input: input,
+            interval: interval,
+            next: None,
+            count: 0,
+        }
+    }
+}
+
+impl<T: 'static> Receiver<Either<T, Tick>> for Ticking<T> {
+    fn try_recv(&mut self) -> Recv<Either<T, Tick>> {
+        // The first tick is an interval after the first look
+        let interval = self.interval;
+        let next = self.next.get_or_insert_with(|| Instant::now() + interval);
+        if let Some(tick) = Tick::due(interval, next, &mut self.count) {
+            return Recv::Item(Either::Right(tick));
+        }
+        match self.input.try_recv() {
+            Recv::Item(val) => Recv::Item(Either::Left(val)),
+            Recv::Empty => Recv::Empty,
+            Recv::Closed => Recv::Closed,
+        }
+    }
+
+    fn split(&self) -> Box<dyn Receiver<Either<T, Tick>>> {
+        Box::new(Ticking::new(self.input.split(), self.interval))
+    }
+
+    fn depth(&self) -> usize {
+        self.input.depth()
+    }
+}
+
+// Items only pass through it on their way from outlets to inlets, as they
+// do through the queue
+unsafe impl<T> Send for Overflow<T> {}
+unsafe impl<T> Sync for Overflow<T> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn drain(inlet: &mut Inlet<u32>) -> Vec<u32> {
+        let mut out = Vec::new();
+        while let Recv::Item(val) = inlet.try_recv() {
+            out.push(val);
+        }
+        out
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queue() {
+        let control = Control::new();
+        let (mut outlet, mut inlet) = link_with(2, Backpressure::DropNewest);
+        for i in 0..5 {
+            assert!(outlet.send(i, &control));
+        }
+        assert_eq!(3, outlet.dropped());
+        assert_eq!(vec![0, 1], drain(&mut inlet));
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest() {
+        let control = Control::new();
+        let (mut outlet, mut inlet) = link_with(2, Backpressure::DropOldest);
+        for i in 0..5 {
+            assert!(outlet.send(i, &control));
+        }
+        assert_eq!(3, outlet.dropped());
+        assert_eq!(vec![3, 4], drain(&mut inlet));
+    }
+
+    #[test]
+    fn drop_oldest_only_drops_what_doesnt_fit() {
+        let control = Control::new();
+        let (mut outlet, mut inlet) = link_with(2, Backpressure::DropOldest);
+        for i in 0..3 {
+            assert!(outlet.send(i, &control));
+        }
+        assert_eq!(3, inlet.depth());
+        // The outlet leaves 0 for the inlet to throw away
+        match inlet.try_recv() {
+            Recv::Item(val) => assert_eq!(1, val),
+            _ => panic!("the inlet should have had an item"),
+        }
+        assert!(outlet.send(3, &control));
+        assert_eq!(vec![2, 3], drain(&mut inlet));
+        assert_eq!(1, outlet.dropped());
+        // With nothing held back, items go straight into the queue again
+        assert!(outlet.send(4, &control));
+        assert_eq!(vec![4], drain(&mut inlet));
+    }
+
+    #[test]
+    fn drop_oldest_gives_up_when_orphaned_or_stopped() {
+        let control = Control::new();
+        let (mut outlet, inlet) = link_with(2, Backpressure::DropOldest);
+        drop(inlet);
+        for i in 0..2 {
+            assert!(outlet.send(i, &control));
+        }
+        assert!(!outlet.send(2, &control));
+        assert_eq!(0, outlet.dropped());
+
+        // Room is only made on a link with one stream
+        let (mut outlet, inlet) = link_with(2, Backpressure::DropOldest);
+        let _other = inlet.add_stream();
+        for i in 0..2 {
+            assert!(outlet.send(i, &control));
+        }
+        control.stop();
+        assert!(!outlet.send(2, &control));
+    }
+
+    #[test]
+    fn spill_keeps_everything_in_order() {
+        let control = Control::new();
+        let (mut outlet, mut inlet) = link_with(2, Backpressure::Spill);
+        for i in 0..5 {
+            assert!(outlet.send(i, &control));
+        }
+        assert_eq!(vec![0, 1], drain(&mut inlet));
+        assert!(outlet.send(5, &control));
+        assert_eq!(vec![2, 3], drain(&mut inlet));
+        drop(outlet.clone());
+        let flusher = thread::spawn(move || {
+            let control = Control::new();
+            outlet.flush(&control)
+        });
+        let mut rest = Vec::new();
+        while rest.len() < 2 {
+            rest.extend(drain(&mut inlet));
+        }
+        assert!(flusher.join().unwrap());
+        assert_eq!(vec![4, 5], rest);
+        assert_eq!(0, inlet.ends.dropped.load(Relaxed));
+    }
+
+    #[test]
+    fn block_gives_up_when_orphaned() {
+        let control = Control::new();
+        let (mut outlet, inlet) = link_with(1, Backpressure::Block);
+        assert!(outlet.send(0, &control));
+        drop(inlet);
+        assert!(!outlet.send(1, &control));
+    }
+
+    #[test]
+    fn depth_and_wait() {
+        let control = Control::new();
+        let (mut outlet, mut inlet) = link_with(2, Backpressure::Block);
+        assert!(outlet.send(0, &control));
+        assert!(outlet.send(1, &control));
+        assert_eq!(2, inlet.depth());
+        assert_eq!(Duration::from_secs(0), outlet.waited());
+        let sender = thread::spawn(move || {
+            assert!(outlet.send(2, &Control::new()));
+            outlet.waited()
+        });
+        thread::sleep(Duration::from_millis(20));
+        let mut got = drain(&mut inlet);
+        while got.len() < 3 {
+            got.extend(drain(&mut inlet));
+        }
+        assert_eq!(vec![0, 1, 2], got);
+        assert!(sender.join().unwrap() > Duration::from_secs(0));
+        assert_eq!(0, inlet.depth());
+    }
+
+    #[test]
+    fn shared_inlets_give_back_what_they_leave() {
+        let item = Arc::new(7);
+        let (mut outlet, inlet) = link::<Shared<Arc<u32>>>(4);
+        let other = inlet.add_stream();
+        for _ in 0..2 {
+            assert!(outlet.try_send(Shared::new(Arc::new(item.clone()), 2)).is_ok());
+        }
+        let (mut first, second) = (SharedInlet::new(inlet), SharedInlet::new(other));
+        let split = first.split();
+        if let Recv::Item(got) = first.try_recv() {
+            assert_eq!(7, *got);
+        } else {
+            panic!("the stream should have had an item");
+        }
+        drop(split);
+        assert_eq!(3, Arc::strong_count(&item));
+        drop(first);
+        assert_eq!(3, Arc::strong_count(&item));
+        drop(second);
+        assert_eq!(1, Arc::strong_count(&item));
+    }
+}
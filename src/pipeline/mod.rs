@@ -0,0 +1,28 @@
+//! Processing pipelines built out of multiqueues
+//!
+//! A pipeline is a source, a chain of stages, and a sink, each running on its
+//! own thread and connected by a queue. Items flow downstream and completion
+//! follows them: once a source runs dry, each stage finishes what's in its input
+//! and then closes its output, until the sink runs out and the pipeline is done.
+
+mod builder;
+pub mod checkpoint;
+pub mod dead_letter;
+pub mod io;
+mod join;
+mod link;
+mod pause;
+mod pool;
+mod scale;
+pub mod stage;
+mod stats;
+pub mod throttle;
+pub mod window;
+mod worker;
+
+pub use self::builder::{Branch, DEFAULT_CAPACITY, Either, Graph, Pipeline, PipelineHandle, Port, Runnable,
+                        ShutdownError};
+pub use self::link::Backpressure;
+pub use self::scale::Scaling;
+pub use self::stats::StageReport;
+pub use self::worker::Tick;
@@ -0,0 +1,232 @@
+//! Bringing a running pipeline to a quiet point for a checkpoint
+//!
+//! The checkpointer asks every task to park. Sources park between items, and
+//! everything else parks once its input is empty and it holds no items,
+//! saving its state as it does. A parked task keeps watching its input and
+//! goes back to work if anything shows up, since what's upstream may still
+//! be draining. Once every task is parked at the same time, every item the
+//! sources produced has been handled by everything downstream of them.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, fence};
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pipeline::checkpoint::{Checkpoint, TaskCheckpoint};
+use pipeline::link::Control;
+
+/// One task's side of a checkpoint, shared by its threads if it has several
+pub struct Slot {
+    // Items taken from the task's input, or produced by a source
+    consumed: AtomicUsize,
+    // Bumped each time a parked thread finds its input still empty
+    polls: AtomicUsize,
+    // Threads that haven't exited yet
+    running: AtomicUsize,
+    // The task's state as of when it last parked
+    state: Mutex<Option<Vec<u8>>>,
+    // State to pick up from when resuming
+    restore: Mutex<Option<Vec<u8>>>,
+}
+
+/// Marks a task finished when dropped, even if it panicked
+pub struct Finish<'a> {
+    slot: &'a Slot,
+    control: &'a Control,
+}
+
+/// Parking without waiting, for a task that shares its thread. Where
+/// Slot::park loops until the checkpoint is over, this is called each time
+/// the task finds nothing to do
+pub struct Parker {
+    parked: bool,
+}
+
+/// Takes checkpoints of the tasks of one running pipeline
+pub struct Checkpointer {
+    slots: Vec<(String, Arc<Slot>)>,
+    // Only one checkpoint can pause the pipeline at a time
+    taking: Mutex<()>,
+}
+
+impl Slot {
+    pub fn new(consumed: usize, restore: Option<Vec<u8>>) -> Slot {
+        Slot {
+            consumed: AtomicUsize::new(consumed),
+            polls: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+            state: Mutex::new(None),
+            restore: Mutex::new(restore),
+        }
+    }
+
+    #[inline(always)]
+    pub fn consume(&self) {
+        self.consumed.fetch_add(1, Relaxed);
+    }
+
+    pub fn consumed(&self) -> usize {
+        self.consumed.load(Relaxed)
+    }
+
+    /// The state this task saved in an earlier run, if it's resuming
+    pub fn take_restore(&self) -> Option<Vec<u8>> {
+        self.restore.lock().unwrap().take()
+    }
+
+    /// Records the task's state for the checkpoint it's about to park for
+    pub fn save(&self, state: Option<Vec<u8>>) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Waits out a checkpoint, calling wake as it goes. Returns what wake
+    /// returned if it woke the task, or None if the checkpoint is over
+    pub fn park<R, F: FnMut() -> Option<R>>(&self, control: &Control, wake: F) -> Option<R> {
+        let mut wake = wake;
+        control.park();
+        let woken = loop {
+            if !control.is_pausing() || control.is_stopped() {
+                break None;
+            }
+            if let Some(woken) = wake() {
+                break Some(woken);
+            }
+            self.poll();
+            thread::yield_now();
+        };
+        control.unpark();
+        woken
+    }
+
+    /// Records that a parked task looked at its input and found it still empty
+    pub fn poll(&self) {
+        fence(SeqCst);
+        self.polls.fetch_add(1, SeqCst);
+    }
+
+    /// Counts another thread running the task
+    pub fn enlist(&self) {
+        self.running.fetch_add(1, SeqCst);
+    }
+
+    /// Whether every thread running the task has exited
+    pub fn is_finished(&self) -> bool {
+        self.running.load(SeqCst) == 0
+    }
+
+    pub fn finish_on_drop<'a>(&'a self, control: &'a Control) -> Finish<'a> {
+        Finish {
+            slot: self,
+            control: control,
+        }
+    }
+}
+
+impl<'a> Drop for Finish<'a> {
+    fn drop(&mut self) {
+        self.slot.finish(self.control);
+    }
+}
+
+impl Slot {
+    /// Marks one of the task's threads as exited
+    pub fn finish(&self, control: &Control) {
+        self.running.fetch_sub(1, SeqCst);
+        // An exited thread never touches another item, so counts as parked for good
+        control.park();
+    }
+}
+
+impl Parker {
+    pub fn new() -> Parker {
+        Parker { parked: false }
+    }
+
+    /// Called when the task has nothing to read and nothing held back.
+    /// Parks it if a checkpoint is waiting, saving what save returns, or
+    /// counts another look at the input if it's already parked
+    pub fn idle<F: FnOnce() -> Option<Vec<u8>>>(&mut self, control: &Control, slot: &Slot, save: F) {
+        if !control.is_pausing() || control.is_stopped() {
+            self.wake(control);
+        } else if self.parked {
+            slot.poll();
+        } else {
+            slot.save(save());
+            control.park();
+            self.parked = true;
+        }
+    }
+
+    /// Called when the task has something to do, or the checkpoint is over
+    pub fn wake(&mut self, control: &Control) {
+        if self.parked {
+            control.unpark();
+            self.parked = false;
+        }
+    }
+}
+
+impl Checkpointer {
+    pub fn new(slots: Vec<(String, Arc<Slot>)>) -> Checkpointer {
+        Checkpointer {
+            slots: slots,
+            taking: Mutex::new(()),
+        }
+    }
+
+    /// Whether every task has exited
+    pub fn is_finished(&self) -> bool {
+        self.slots.iter().all(|(_, slot)| slot.is_finished())
+    }
+
+    /// Pauses the pipeline and records where every task is once it's quiet.
+    /// Returns None if that doesn't happen by timeout
+    pub fn take(&self, control: &Control, timeout: Duration) -> Option<Checkpoint> {
+        let _taking = self.taking.lock().unwrap();
+        let deadline = Instant::now() + timeout;
+        control.pause();
+        let checkpoint = self.wait_for_quiet(control, deadline);
+        control.resume();
+        checkpoint
+    }
+
+    fn wait_for_quiet(&self, control: &Control, deadline: Instant) -> Option<Checkpoint> {
+        'retry: while Instant::now() < deadline && !control.is_stopped() {
+            let unparks = control.unparks();
+            if control.parked() < control.tasks() {
+                thread::yield_now();
+                continue;
+            }
+            // Everything is parked, but a task might not have noticed what its
+            // upstream wrote just before parking. Once each has looked at its
+            // input again with nothing waking it, every queue is empty
+            let polls: Vec<usize> = self.slots.iter().map(|(_, slot)| slot.polls.load(SeqCst)).collect();
+            for ((_, slot), &seen) in self.slots.iter().zip(&polls) {
+                while !slot.is_finished() && slot.polls.load(SeqCst) == seen {
+                    if control.unparks() != unparks {
+                        continue 'retry;
+                    }
+                    if Instant::now() >= deadline || control.is_stopped() {
+                        return None;
+                    }
+                    thread::yield_now();
+                }
+            }
+            let tasks = self.slots
+                .iter()
+                .map(|(name, slot)| {
+                    TaskCheckpoint {
+                        name: name.clone(),
+                        consumed: slot.consumed() as u64,
+                        state: slot.state.lock().unwrap().clone(),
+                    }
+                })
+                .collect();
+            if control.unparks() == unparks {
+                return Some(Checkpoint { tasks: tasks });
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,151 @@
+//! Running a pipeline's tasks on a fixed number of threads
+//!
+//! Each pool thread takes the next job off a shared queue, lets it do what
+//! it can without waiting, and puts it back. A job with nothing to read or no
+//! room to write returns right away, so the threads keep cycling through the
+//! jobs that have work. Jobs get their work from queues the pool can't see, so
+//! once a round finds nothing to do anywhere a thread naps rather than waiting
+//! to be told, a little longer each time it comes up empty, and is woken early
+//! when another thread's job gets something done.
+
+use std::any::Any;
+use std::cmp;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use pipeline::link::Control;
+use pipeline::pause::Slot;
+use pipeline::worker::{Job, Poll};
+
+struct Entry {
+    job: Box<dyn Job>,
+    slot: Arc<Slot>,
+}
+
+/// How long a thread first naps for when there's nothing to do, and the
+/// most it ever does, which is how late an idle pool can be to new work
+const MIN_NAP: Duration = Duration::from_micros(20);
+const MAX_NAP: Duration = Duration::from_millis(1);
+
+struct Pool {
+    queue: Mutex<VecDeque<Entry>>,
+    // Jobs that aren't done yet, including those being stepped
+    remaining: AtomicUsize,
+    // Threads napping on wake
+    napping: AtomicUsize,
+    wake: Condvar,
+}
+
+/// Starts threads sharing the given jobs. A thread whose job panicked
+/// carries on with the others and panics with it once they're all done
+pub fn start(name: &str,
+             threads: usize,
+             control: &Arc<Control>,
+             jobs: Vec<(Box<dyn Job>, Arc<Slot>)>)
+             -> Vec<JoinHandle<()>> {
+    assert!(threads > 0, "a pool needs at least one thread");
+    let pool = Arc::new(Pool {
+        remaining: AtomicUsize::new(jobs.len()),
+        napping: AtomicUsize::new(0),
+        wake: Condvar::new(),
+        queue: Mutex::new(jobs.into_iter()
+            .map(|(job, slot)| {
+                control.enlist();
+                slot.enlist();
+                Entry {
+                    job: job,
+                    slot: slot,
+                }
+            })
+            .collect()),
+    });
+    (0..threads)
+        .map(|i| {
+            let pool = pool.clone();
+            let control = control.clone();
+            thread::Builder::new()
+                .name(format!("{}-pool-{}", name, i))
+                .spawn(move || pool.work(&control))
+                .expect("failed to spawn pipeline thread")
+        })
+        .collect()
+}
+
+impl Pool {
+    fn work(&self, control: &Control) {
+        let mut panicked: Option<Box<dyn Any + Send>> = None;
+        // Jobs in a row that had nothing to do
+        let mut idle = 0;
+        let mut nap = MIN_NAP;
+        loop {
+            let next = self.queue.lock().unwrap().pop_front();
+            let mut entry = match next {
+                Some(entry) => entry,
+                None if self.remaining.load(SeqCst) == 0 => break,
+                None => {
+                    // Every job is with another thread
+                    self.nap(&mut nap);
+                    continue;
+                }
+            };
+            let polled = panic::catch_unwind(AssertUnwindSafe(|| entry.job.step(control, &entry.slot)));
+            match polled {
+                Ok(Poll::Busy) => {
+                    idle = 0;
+                    nap = MIN_NAP;
+                    self.queue.lock().unwrap().push_back(entry);
+                    // What it did may be work for the jobs others are napping on
+                    self.wake();
+                }
+                Ok(Poll::Idle) => {
+                    self.queue.lock().unwrap().push_back(entry);
+                    idle += 1;
+                    if idle >= self.remaining.load(SeqCst) {
+                        idle = 0;
+                        self.nap(&mut nap);
+                    }
+                }
+                Ok(Poll::Done) => self.finish(entry, control),
+                Err(e) => {
+                    self.finish(entry, control);
+                    if panicked.is_none() {
+                        panicked = Some(e);
+                    }
+                }
+            }
+        }
+        if let Some(e) = panicked {
+            panic::resume_unwind(e);
+        }
+    }
+
+    fn finish(&self, entry: Entry, control: &Control) {
+        let Entry { job, slot } = entry;
+        // Closes the job's outlets before anyone sees it finished
+        drop(job);
+        slot.finish(control);
+        self.remaining.fetch_sub(1, SeqCst);
+        self.wake();
+    }
+
+    /// Sleeps for nap or until woken, and doubles nap for next time, up to MAX_NAP
+    fn nap(&self, nap: &mut Duration) {
+        let queue = self.queue.lock().unwrap();
+        self.napping.fetch_add(1, SeqCst);
+        // A wake that comes before the wait is missed, but the nap is short
+        drop(self.wake.wait_timeout(queue, *nap).unwrap());
+        self.napping.fetch_sub(1, SeqCst);
+        *nap = cmp::min(*nap * 2, MAX_NAP);
+    }
+
+    fn wake(&self) {
+        if self.napping.load(SeqCst) > 0 {
+            self.wake.notify_all();
+        }
+    }
+}
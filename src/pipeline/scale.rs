@@ -0,0 +1,253 @@
+//! Stages whose number of workers changes while the pipeline runs
+//!
+//! A scaled stage starts with one worker, which lives as long as the stage
+//! does. The rest come and go: new ones read a split of the stage's input,
+//! and a worker being let go sees its input close, so it finishes and
+//! flushes like any other worker would at the end of the input.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use pipeline::link::{Control, Outlet, Receiver, Recv};
+use pipeline::pause::Slot;
+use pipeline::stats::StageStats;
+use pipeline::worker::{Worker, spawn, work};
+
+/// How many workers a scaled stage runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scaling {
+    min: usize,
+    max: usize,
+    lag_per_worker: Option<usize>,
+    interval: Duration,
+}
+
+/// Threads started for a pipeline after it starts running
+pub type Threads = Arc<Mutex<Vec<JoinHandle<()>>>>;
+
+/// What a scaled stage needs from the running pipeline to start workers
+pub struct Runtime {
+    pub control: Arc<Control>,
+    pub threads: Threads,
+    pub slot: Arc<Slot>,
+    /// The thread name of the stage's first worker
+    pub name: String,
+    pub cores: Vec<usize>,
+}
+
+/// A scaled stage, as seen by the pipeline running it
+pub trait Scale: Send + Sync {
+    fn start(&self, runtime: Runtime);
+
+    /// Checks the stage's input and adjusts its workers, if the stage scales on lag
+    fn tick(&self, now: Instant);
+
+    /// Sets the number of workers, limited to what the stage's Scaling allows.
+    /// Returns the number it was set to
+    fn resize(&self, workers: usize) -> usize;
+
+    fn workers(&self) -> usize;
+
+    /// Lets go of the stage's input and output, once its first worker is done
+    fn close(&self);
+}
+
+/// A worker's input, which closes early once the worker is let go
+struct Retiring<T> {
+    input: Box<dyn Receiver<T>>,
+    retired: Arc<AtomicBool>,
+}
+
+pub struct Scaled<T, U, F> {
+    scaling: Scaling,
+    inner: Mutex<Inner<T, U, F>>,
+}
+
+struct Inner<T, U, F> {
+    make: F,
+    // What new workers read from and write to, until the stage is done
+    ends: Option<(Box<dyn Receiver<T>>, Outlet<U>)>,
+    stats: Arc<StageStats>,
+    runtime: Option<Runtime>,
+    // The flags of every worker but the first, in the order they started
+    extra: Vec<Arc<AtomicBool>>,
+    started: usize,
+    last_tick: Instant,
+}
+
+impl Scaling {
+    /// Between min and max workers, starting with min, changed only through
+    /// PipelineHandle::set_workers. Panics unless 1 <= min <= max
+    pub fn manual(min: usize, max: usize) -> Scaling {
+        assert!(min >= 1 && min <= max, "scaling needs 1 <= min <= max");
+        Scaling {
+            min: min,
+            max: max,
+            lag_per_worker: None,
+            interval: Duration::from_millis(100),
+        }
+    }
+
+    /// Between min and max workers, aiming for one worker for every
+    /// lag_per_worker items waiting in the stage's input. Workers are added
+    /// as soon as they're needed and let go one at a time
+    pub fn on_lag(min: usize, max: usize, lag_per_worker: usize) -> Scaling {
+        assert!(lag_per_worker > 0, "lag_per_worker must be positive");
+        Scaling { lag_per_worker: Some(lag_per_worker), ..Scaling::manual(min, max) }
+    }
+
+    /// How often to check the input's lag. The default is every 100ms
+    pub fn interval(mut self, interval: Duration) -> Scaling {
+        self.interval = interval;
+        self
+    }
+
+    fn clamp(&self, workers: usize) -> usize {
+        workers.max(self.min).min(self.max)
+    }
+}
+
+impl<T: 'static> Receiver<T> for Retiring<T> {
+    fn try_recv(&mut self) -> Recv<T> {
+        if self.retired.load(Relaxed) {
+            return Recv::Closed;
+        }
+        self.input.try_recv()
+    }
+
+    fn split(&self) -> Box<dyn Receiver<T>> {
+        self.input.split()
+    }
+
+    fn depth(&self) -> usize {
+        self.input.depth()
+    }
+}
+
+impl<T, U, W, F> Scaled<T, U, F>
+    where T: 'static,
+          U: Send + 'static,
+          W: Worker<T, U>,
+          F: FnMut() -> W + Send + 'static
+{
+    pub fn new(scaling: Scaling,
+               make: F,
+               input: Box<dyn Receiver<T>>,
+               outlet: Outlet<U>,
+               stats: Arc<StageStats>)
+               -> Scaled<T, U, F> {
+        Scaled {
+            scaling: scaling,
+            inner: Mutex::new(Inner {
+                make: make,
+                ends: Some((input, outlet)),
+                stats: stats,
+                runtime: None,
+                extra: Vec::new(),
+                started: 0,
+                last_tick: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl<T, U, W, F> Inner<T, U, F>
+    where T: 'static,
+          U: Send + 'static,
+          W: Worker<T, U>,
+          F: FnMut() -> W + Send + 'static
+{
+    fn resize(&mut self, workers: usize) {
+        let (input, outlet, runtime) = match (&self.ends, &self.runtime) {
+            (Some((input, outlet)), Some(runtime)) => (input, outlet, runtime),
+            _ => return,
+        };
+        while 1 + self.extra.len() < workers {
+            self.started += 1;
+            let retired = Arc::new(AtomicBool::new(false));
+            let mut input = Retiring {
+                input: input.split(),
+                retired: retired.clone(),
+            };
+            let outlet = outlet.clone();
+            let stats = self.stats.clone();
+            let mut worker = (self.make)();
+            let thread = spawn(format!("{}-worker-{}", runtime.name, self.started),
+                               runtime.cores.clone(),
+                               runtime.control.clone(),
+                               runtime.slot.clone(),
+                               Box::new(move |control: &Control, slot: &Slot| {
+                                   work(&mut worker, &mut input, outlet, &stats, control, slot)
+                               }));
+            runtime.threads.lock().unwrap().push(thread);
+            self.extra.push(retired);
+        }
+        while 1 + self.extra.len() > workers {
+            self.extra.pop().unwrap().store(true, Relaxed);
+        }
+    }
+}
+
+impl<T, U, W, F> Scale for Scaled<T, U, F>
+    where T: 'static,
+          U: Send + 'static,
+          W: Worker<T, U>,
+          F: FnMut() -> W + Send + 'static
+{
+    fn start(&self, runtime: Runtime) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.runtime = Some(runtime);
+        inner.resize(self.scaling.min);
+    }
+
+    fn tick(&self, now: Instant) {
+        let per_worker = match self.scaling.lag_per_worker {
+            Some(per_worker) => per_worker,
+            None => return,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        if now < inner.last_tick + self.scaling.interval {
+            return;
+        }
+        inner.last_tick = now;
+        let lag = match inner.ends {
+            Some((ref input, _)) => input.depth(),
+            None => return,
+        };
+        let current = 1 + inner.extra.len();
+        let wanted = self.scaling.clamp(lag.div_ceil(per_worker));
+        if wanted > current {
+            inner.resize(wanted);
+        } else if wanted < current {
+            inner.resize(current - 1);
+        }
+    }
+
+    fn resize(&self, workers: usize) -> usize {
+        let workers = self.scaling.clamp(workers);
+        self.inner.lock().unwrap().resize(workers);
+        workers
+    }
+
+    fn workers(&self) -> usize {
+        1 + self.inner.lock().unwrap().extra.len()
+    }
+
+    fn close(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ends = None;
+        inner.runtime = None;
+    }
+}
+
+/// Closes a scaled stage when its first worker is done, even if it panicked
+pub struct CloseOnDrop(pub Arc<dyn Scale>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
@@ -0,0 +1,336 @@
+//! Processing steps that run between the queues of a pipeline
+//!
+//! A stage sees one input item at a time and hands any number of outputs
+//! to emit. The pipeline runs the pop/process/push loop around it, so stages
+//! only describe what happens to an item. Stages joined with then run on the
+//! same thread, with nothing queued between them.
+//!
+//! ```
+//! use pipeline::pipeline::Pipeline;
+//! use pipeline::pipeline::stage::{Stage, filter, flat_map, map};
+//! use std::sync::{Arc, Mutex};
+//!
+//! let out = Arc::new(Mutex::new(Vec::new()));
+//! let sink_out = out.clone();
+//! Pipeline::from_iter(vec!["a b", "", "c"])
+//!     .then(filter(|s: &&str| !s.is_empty())
+//!         .then(flat_map(|s: &str| s.split(' ').collect::<Vec<_>>()))
+//!         .then(map(|s: &str| s.to_uppercase())))
+//!     .sink(move |s| sink_out.lock().unwrap().push(s))
+//!     .run()
+//!     .join()
+//!     .unwrap();
+//! assert_eq!(vec!["A", "B", "C"], *out.lock().unwrap());
+//! ```
+
+use std::marker::PhantomData;
+
+pub trait Stage<In, Out>: Send + 'static {
+    /// Processes one item, passing each output to emit
+    fn process(&mut self, item: In, emit: &mut dyn FnMut(Out));
+
+    /// Called once the input has run dry, to emit anything still held back
+    fn finish(&mut self, _emit: &mut dyn FnMut(Out)) {}
+
+    /// Whatever the stage carries from one item to the next, for a pipeline
+    /// checkpoint to save. Stages that carry nothing can leave this as None
+    fn checkpoint(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Picks up from state returned by checkpoint in an earlier run,
+    /// before the stage sees any items
+    fn restore(&mut self, _state: &[u8]) {}
+
+    /// Feeds this stage's output into next, on the same thread
+    fn then<Next, Final>(self, next: Next) -> Chain<Self, Next, Out>
+        where Self: Sized,
+              Next: Stage<Out, Final>
+    {
+        Chain {
+            first: self,
+            second: next,
+            _marker: PhantomData,
+        }
+    }
+}
+
+pub struct Map<F> {
+    f: F,
+}
+
+pub struct Filter<F> {
+    f: F,
+}
+
+pub struct FilterMap<F> {
+    f: F,
+}
+
+pub struct FlatMap<F> {
+    f: F,
+}
+
+pub struct Inspect<F> {
+    f: F,
+}
+
+pub struct Chain<A, B, Mid> {
+    first: A,
+    second: B,
+    _marker: PhantomData<fn(Mid)>,
+}
+
+/// Turns each item into one output
+pub fn map<In, Out, F>(f: F) -> Map<F>
+    where F: FnMut(In) -> Out + Send + 'static
+{
+    Map { f: f }
+}
+
+/// Passes on the items for which f returns true
+pub fn filter<T, F>(f: F) -> Filter<F>
+    where F: FnMut(&T) -> bool + Send + 'static
+{
+    Filter { f: f }
+}
+
+/// Passes on the outputs of f that are Some
+pub fn filter_map<In, Out, F>(f: F) -> FilterMap<F>
+    where F: FnMut(In) -> Option<Out> + Send + 'static
+{
+    FilterMap { f: f }
+}
+
+/// Passes on everything in the iterator f returns for each item
+pub fn flat_map<In, I, F>(f: F) -> FlatMap<F>
+    where I: IntoIterator,
+          F: FnMut(In) -> I + Send + 'static
+{
+    FlatMap { f: f }
+}
+
+/// Calls f on each item and passes it on unchanged
+pub fn inspect<T, F>(f: F) -> Inspect<F>
+    where F: FnMut(&T) + Send + 'static
+{
+    Inspect { f: f }
+}
+
+impl<In, Out, F> Stage<In, Out> for Map<F>
+    where F: FnMut(In) -> Out + Send + 'static
+{
+    fn process(&mut self, item: In, emit: &mut dyn FnMut(Out)) {
+        emit((self.f)(item))
+    }
+}
+
+impl<T, F> Stage<T, T> for Filter<F>
+    where F: FnMut(&T) -> bool + Send + 'static
+{
+    fn process(&mut self, item: T, emit: &mut dyn FnMut(T)) {
+        if (self.f)(&item) {
+            emit(item)
+        }
+    }
+}
+
+impl<In, Out, F> Stage<In, Out> for FilterMap<F>
+    where F: FnMut(In) -> Option<Out> + Send + 'static
+{
+    fn process(&mut self, item: In, emit: &mut dyn FnMut(Out)) {
+        if let Some(out) = (self.f)(item) {
+            emit(out)
+        }
+    }
+}
+
+impl<In, I, F> Stage<In, I::Item> for FlatMap<F>
+    where I: IntoIterator,
+          F: FnMut(In) -> I + Send + 'static
+{
+    fn process(&mut self, item: In, emit: &mut dyn FnMut(I::Item)) {
+        for out in (self.f)(item) {
+            emit(out)
+        }
+    }
+}
+
+impl<T, F> Stage<T, T> for Inspect<F>
+    where F: FnMut(&T) + Send + 'static
+{
+    fn process(&mut self, item: T, emit: &mut dyn FnMut(T)) {
+        (self.f)(&item);
+        emit(item)
+    }
+}
+
+impl<In, Mid, Out, A, B> Stage<In, Out> for Chain<A, B, Mid>
+    where A: Stage<In, Mid>,
+          B: Stage<Mid, Out>,
+          Mid: 'static
+{
+    fn process(&mut self, item: In, emit: &mut dyn FnMut(Out)) {
+        let second = &mut self.second;
+        self.first.process(item, &mut |mid| second.process(mid, emit))
+    }
+
+    fn finish(&mut self, emit: &mut dyn FnMut(Out)) {
+        {
+            let second = &mut self.second;
+            self.first.finish(&mut |mid| second.process(mid, emit));
+        }
+        self.second.finish(emit)
+    }
+
+    fn checkpoint(&self) -> Option<Vec<u8>> {
+        let halves = [self.first.checkpoint(), self.second.checkpoint()];
+        if halves.iter().all(|half| half.is_none()) {
+            return None;
+        }
+        // Each half as its length, or u32::MAX for none, then its bytes
+        let mut out = Vec::new();
+        for half in &halves {
+            match *half {
+                Some(ref state) => {
+                    out.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                    out.extend_from_slice(state);
+                }
+                None => out.extend_from_slice(&u32::MAX.to_le_bytes()),
+            }
+        }
+        Some(out)
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        let mut rest = state;
+        let mut half = || {
+            assert!(rest.len() >= 4, "state of chained stages is truncated");
+            let mut len = [0; 4];
+            len.copy_from_slice(&rest[..4]);
+            rest = &rest[4..];
+            let len = u32::from_le_bytes(len);
+            if len == u32::MAX {
+                return None;
+            }
+            assert!(rest.len() >= len as usize, "state of chained stages is truncated");
+            let (state, tail) = rest.split_at(len as usize);
+            rest = tail;
+            Some(state)
+        };
+        if let Some(state) = half() {
+            self.first.restore(state);
+        }
+        if let Some(state) = half() {
+            self.second.restore(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn run<In, Out, S: Stage<In, Out>>(mut stage: S, input: Vec<In>) -> Vec<Out> {
+        let mut out = Vec::new();
+        for item in input {
+            stage.process(item, &mut |val| out.push(val));
+        }
+        stage.finish(&mut |val| out.push(val));
+        out
+    }
+
+    /// Holds every item back until finish
+    struct Collect(Vec<u32>);
+
+    impl Stage<u32, Vec<u32>> for Collect {
+        fn process(&mut self, item: u32, _emit: &mut dyn FnMut(Vec<u32>)) {
+            self.0.push(item);
+        }
+
+        fn finish(&mut self, emit: &mut dyn FnMut(Vec<u32>)) {
+            emit(::std::mem::take(&mut self.0))
+        }
+    }
+
+    #[test]
+    fn combinators() {
+        assert_eq!(vec![2, 4, 6], run(map(|x: u32| x * 2), vec![1, 2, 3]));
+        assert_eq!(vec![1, 3], run(filter(|x: &u32| x % 2 == 1), vec![1, 2, 3]));
+        assert_eq!(vec![1, 3],
+                   run(filter_map(|x: u32| if x != 2 { Some(x) } else { None }),
+                       vec![1, 2, 3]));
+        assert_eq!(vec![1, 2, 2, 3, 3, 3],
+                   run(flat_map(|x: u32| vec![x; x as usize]), vec![1, 2, 3]));
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counted = seen.clone();
+        let out = run(inspect(move |x: &u32| {
+                          counted.fetch_add(*x as usize, Ordering::Relaxed);
+                      }),
+                      vec![1, 2, 3]);
+        assert_eq!(vec![1, 2, 3], out);
+        assert_eq!(6, seen.load(Ordering::Relaxed));
+    }
+
+    impl Collect {
+        fn bytes(&self) -> Vec<u8> {
+            self.0.iter().map(|&x| x as u8).collect()
+        }
+    }
+
+    /// Collect, with its state saved
+    struct Saved(Collect);
+
+    impl Stage<u32, Vec<u32>> for Saved {
+        fn process(&mut self, item: u32, emit: &mut dyn FnMut(Vec<u32>)) {
+            self.0.process(item, emit)
+        }
+
+        fn finish(&mut self, emit: &mut dyn FnMut(Vec<u32>)) {
+            self.0.finish(emit)
+        }
+
+        fn checkpoint(&self) -> Option<Vec<u8>> {
+            Some(self.0.bytes())
+        }
+
+        fn restore(&mut self, state: &[u8]) {
+            (self.0).0 = state.iter().map(|&x| x as u32).collect();
+        }
+    }
+
+    #[test]
+    fn chain_saves_both_halves() {
+        let mut stage = Saved(Collect(Vec::new()))
+            .then(flat_map(|v: Vec<u32>| v))
+            .then(Saved(Collect(Vec::new())));
+        assert_eq!(None, map(|x: u32| x).then(map(|x: u32| x)).checkpoint());
+        stage.process(1, &mut |_| ());
+        stage.process(2, &mut |_| ());
+        let state = stage.checkpoint().unwrap();
+
+        let mut restored = Saved(Collect(Vec::new()))
+            .then(flat_map(|v: Vec<u32>| v))
+            .then(Saved(Collect(Vec::new())));
+        restored.restore(&state);
+        assert_eq!(Some(state), restored.checkpoint());
+        let mut out = Vec::new();
+        restored.process(3, &mut |_| ());
+        restored.finish(&mut |v| out.push(v));
+        assert_eq!(vec![vec![1, 2, 3]], out);
+    }
+
+    #[test]
+    fn chain_flushes_both_halves() {
+        let stage = map(|x: u32| x + 1)
+            .then(Collect(Vec::new()))
+            .then(flat_map(|v: Vec<u32>| v));
+        assert_eq!(vec![2, 3, 4], run(stage, vec![1, 2, 3]));
+
+        let stage = Collect(Vec::new()).then(map(|v: Vec<u32>| v.len()));
+        assert_eq!(vec![3], run(stage, vec![1, 2, 3]));
+    }
+}
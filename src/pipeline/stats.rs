@@ -0,0 +1,172 @@
+//! Throughput, latency, and queue depth for each stage of a running pipeline
+//!
+//! Workers record into a StageStats shared by every thread of their stage,
+//! and PipelineHandle::stats turns those into a StageReport per stage. The stage
+//! with a deep input queue and a busy time close to its workers' wall time
+//! is usually the one holding the pipeline back.
+
+use std::fmt;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::{Duration, Instant};
+
+// Latencies are bucketed by their highest set bit, then by the next
+// SUB_BITS bits below it, which keeps every bucket within 1/8 of its value
+const SUB_BITS: usize = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = 64 * SUB_BUCKETS;
+
+pub struct StageStats {
+    processed: AtomicUsize,
+    busy_ns: AtomicUsize,
+    depth: AtomicUsize,
+    latency: Vec<AtomicUsize>,
+}
+
+/// A stage's numbers at the time PipelineHandle::stats was called
+#[derive(Clone, Debug, PartialEq)]
+pub struct StageReport {
+    /// The stage's thread name, or the name its workers share
+    pub name: String,
+    /// Items taken from the stage's input
+    pub processed: usize,
+    /// Items per second since the pipeline started
+    pub throughput: f64,
+    /// Time spent processing, not counting time waiting for
+    /// input or for room in the output, summed across workers
+    pub busy: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// Items waiting in the stage's input queue when it last took one
+    pub depth: usize,
+}
+
+fn bucket_of(ns: usize) -> usize {
+    if ns < SUB_BUCKETS {
+        return ns;
+    }
+    let high = (0usize.leading_zeros() - ns.leading_zeros() - 1) as usize;
+    let sub = (ns >> (high - SUB_BITS)) & (SUB_BUCKETS - 1);
+    (high - SUB_BITS + 1) * SUB_BUCKETS + sub
+}
+
+/// The largest latency that lands in bucket
+fn upper_bound(bucket: usize) -> usize {
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let high = bucket / SUB_BUCKETS + SUB_BITS - 1;
+    let sub = bucket % SUB_BUCKETS;
+    let low = (1 << high) | (sub << (high - SUB_BITS));
+    low + ((1 << (high - SUB_BITS)) - 1)
+}
+
+impl StageStats {
+    pub fn new() -> StageStats {
+        StageStats {
+            processed: AtomicUsize::new(0),
+            busy_ns: AtomicUsize::new(0),
+            depth: AtomicUsize::new(0),
+            latency: (0..BUCKETS).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    /// Records one item that took spent to process
+    #[inline]
+    pub fn record(&self, spent: Duration) {
+        let ns = spent.as_secs() as usize * 1_000_000_000 + spent.subsec_nanos() as usize;
+        self.processed.fetch_add(1, Relaxed);
+        self.busy_ns.fetch_add(ns, Relaxed);
+        self.latency[bucket_of(ns)].fetch_add(1, Relaxed);
+    }
+
+    /// Notes how many items were left in the input after taking one
+    #[inline]
+    pub fn set_depth(&self, depth: usize) {
+        self.depth.store(depth, Relaxed);
+    }
+
+    pub fn report(&self, name: String, started: Instant) -> StageReport {
+        let counts: Vec<usize> = self.latency.iter().map(|c| c.load(Relaxed)).collect();
+        let total: usize = counts.iter().sum();
+        let percentile = |p: f64| {
+            let rank = (total as f64 * p).ceil() as usize;
+            let mut seen = 0;
+            for (bucket, &count) in counts.iter().enumerate() {
+                seen += count;
+                if count > 0 && seen >= rank {
+                    return Duration::from_nanos(upper_bound(bucket) as u64);
+                }
+            }
+            Duration::from_nanos(0)
+        };
+        let processed = self.processed.load(Relaxed);
+        let elapsed = started.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        StageReport {
+            name: name,
+            processed: processed,
+            throughput: if secs > 0.0 { processed as f64 / secs } else { 0.0 },
+            busy: Duration::from_nanos(self.busy_ns.load(Relaxed) as u64),
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: percentile(1.0),
+            depth: self.depth.load(Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for StageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}: {} items, {:.0}/s, p50 {:?}, p99 {:?}, depth {}",
+               self.name,
+               self.processed,
+               self.throughput,
+               self.p50,
+               self.p99,
+               self.depth)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_bound_their_values() {
+        let mut last = 0;
+        for &ns in &[0, 1, 7, 8, 9, 15, 16, 100, 1000, 123_456, 1 << 40, usize::MAX] {
+            let bucket = bucket_of(ns);
+            assert!(bucket >= last);
+            assert!(upper_bound(bucket) >= ns, "{} -> {}", ns, bucket);
+            assert!(upper_bound(bucket) - ns <= ns / SUB_BUCKETS, "{} -> {}", ns, bucket);
+            last = bucket;
+        }
+        assert!(bucket_of(usize::MAX) < BUCKETS);
+    }
+
+    #[test]
+    fn percentiles() {
+        let stats = StageStats::new();
+        for i in 1..101 {
+            stats.record(Duration::from_micros(i));
+        }
+        stats.set_depth(3);
+        let report = stats.report("stage".to_string(), Instant::now());
+        assert_eq!(100, report.processed);
+        assert_eq!(3, report.depth);
+        assert_eq!(Duration::from_micros(5050), report.busy);
+        let close = |d: Duration, us: u64| {
+            let want = Duration::from_micros(us);
+            d >= want && d <= want + want / 8
+        };
+        assert!(close(report.p50, 50), "{:?}", report);
+        assert!(close(report.p90, 90), "{:?}", report);
+        assert!(close(report.p99, 99), "{:?}", report);
+        assert!(close(report.max, 100), "{:?}", report);
+    }
+}
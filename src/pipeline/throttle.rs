@@ -0,0 +1,127 @@
+//! A stage that holds a stream to a rate, for downstreams that can only take so much
+//!
+//! Items are let through against a token bucket that refills at the given
+//! rate and holds up to a burst's worth of tokens, starting full. What happens
+//! to an item that finds the bucket empty depends on OverBudget.
+//!
+//! ```
+//! use pipeline::pipeline::Pipeline;
+//! use pipeline::pipeline::throttle::{OverBudget, throttle};
+//! use std::time::{Duration, Instant};
+//!
+//! let start = Instant::now();
+//! Pipeline::from_iter(0..30)
+//!     .then(throttle(1000.0, OverBudget::Delay).burst(10))
+//!     .sink(|_| ())
+//!     .run()
+//!     .join()
+//!     .unwrap();
+//! assert!(start.elapsed() >= Duration::from_millis(20));
+//! ```
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pipeline::stage::Stage;
+
+/// What Throttle does with an item when it's out of tokens
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverBudget {
+    /// Waits for a token, holding back the stage and everything upstream.
+    /// In a pooled pipeline, this holds up a pool thread as well
+    Delay,
+    /// Drops the item
+    Drop,
+}
+
+pub struct Throttle {
+    per_second: f64,
+    burst: f64,
+    over: OverBudget,
+    tokens: f64,
+    // When the tokens were last topped up
+    refilled: Option<Instant>,
+}
+
+/// Lets through at most per_second items a second, after a burst of up to
+/// one second's worth. Panics unless per_second is positive
+pub fn throttle(per_second: f64, over: OverBudget) -> Throttle {
+    assert!(per_second > 0.0, "a throttle needs a positive rate");
+    let burst = per_second.max(1.0);
+    Throttle {
+        per_second: per_second,
+        burst: burst,
+        over: over,
+        tokens: burst,
+        refilled: None,
+    }
+}
+
+impl Throttle {
+    /// Sets how many items may go through at once after a lull. Panics if burst is 0
+    pub fn burst(mut self, burst: u32) -> Throttle {
+        assert!(burst > 0, "a throttle needs a burst of at least one");
+        self.burst = burst as f64;
+        self.tokens = self.burst;
+        self
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(refilled) = self.refilled {
+            let earned = now.duration_since(refilled).as_secs_f64() * self.per_second;
+            self.tokens = (self.tokens + earned).min(self.burst);
+        }
+        self.refilled = Some(now);
+    }
+}
+
+impl<T> Stage<T, T> for Throttle
+    where T: Send + 'static
+{
+    fn process(&mut self, item: T, emit: &mut dyn FnMut(T)) {
+        self.refill(Instant::now());
+        if self.tokens < 1.0 {
+            match self.over {
+                OverBudget::Drop => return,
+                OverBudget::Delay => {
+                    thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.per_second));
+                    self.refill(Instant::now());
+                    // Sleeping may come up a hair short
+                    self.tokens = self.tokens.max(1.0);
+                }
+            }
+        }
+        self.tokens -= 1.0;
+        emit(item)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(mut stage: Throttle, items: usize) -> usize {
+        let mut out = 0;
+        for item in 0..items {
+            Stage::<usize, usize>::process(&mut stage, item, &mut |_| out += 1);
+        }
+        out
+    }
+
+    #[test]
+    fn drops_over_budget() {
+        let start = Instant::now();
+        let through = run(throttle(10.0, OverBudget::Drop).burst(5), 1000);
+        // Plus whatever tokens came in while the items went by
+        let slack = (start.elapsed().as_secs_f64() * 10.0).ceil() as usize;
+        assert!(through >= 5 && through <= 5 + slack, "{}", through);
+    }
+
+    #[test]
+    fn delays_over_budget() {
+        let start = Instant::now();
+        assert_eq!(25, run(throttle(500.0, OverBudget::Delay).burst(5), 25));
+        // 20 items past the burst at 2ms each
+        assert!(start.elapsed() >= Duration::from_millis(38), "{:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,282 @@
+//! Stages that gather items into windows and emit one result per window
+//!
+//! Count windows hold a number of items, and time windows hold the items whose
+//! timestamps fall in a stretch of time. Either kind can be tumbling, where
+//! each item lands in exactly one window, or sliding, where a new window
+//! starts every step so windows overlap. Once the input runs dry, windows
+//! still open are emitted with whatever they hold.
+//!
+//! Time windows go by the timestamp time gives each item rather than by the
+//! clock, and start at multiples of their step. A window is emitted once an
+//! item at or past its end arrives, so items only need to be in order across
+//! windows, not within one. An item older than every open window is dropped.
+//! Windows by arrival time are a matter of stamping items as they come in.
+//!
+//! What a window holds isn't saved in pipeline checkpoints.
+//!
+//! ```
+//! use pipeline::pipeline::Pipeline;
+//! use pipeline::pipeline::window::tumbling;
+//! use std::sync::{Arc, Mutex};
+//!
+//! let sums = Arc::new(Mutex::new(Vec::new()));
+//! let out = sums.clone();
+//! Pipeline::from_iter(1..8u32)
+//!     .then(tumbling(3, |items: &[u32]| items.iter().sum::<u32>()))
+//!     .sink(move |sum| out.lock().unwrap().push(sum))
+//!     .run()
+//!     .join()
+//!     .unwrap();
+//! assert_eq!(vec![6, 15, 7], *sums.lock().unwrap());
+//! ```
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use pipeline::stage::Stage;
+
+/// What a time window came to, for timestamps from start up to but not including end
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window<A> {
+    pub start: Duration,
+    pub end: Duration,
+    pub value: A,
+}
+
+pub struct CountWindow<T, F> {
+    size: usize,
+    step: usize,
+    // The last size items
+    items: VecDeque<T>,
+    // Items since the last window was emitted
+    fresh: usize,
+    aggregate: F,
+}
+
+pub struct TimeWindow<T, K, F> {
+    width: u64,
+    step: u64,
+    time: K,
+    // In timestamp order, all in or after the window at next
+    times: VecDeque<u64>,
+    items: VecDeque<T>,
+    // Where the oldest window not yet emitted starts
+    next: Option<u64>,
+    aggregate: F,
+    _marker: PhantomData<fn(T)>,
+}
+
+/// Windows of size items each, one after the other. Panics if size is 0
+pub fn tumbling<T, A, F>(size: usize, aggregate: F) -> CountWindow<T, F>
+    where F: FnMut(&[T]) -> A + Send + 'static
+{
+    sliding(size, size, aggregate)
+}
+
+/// A window every step items, of the last size items. Windows before the
+/// first size items have fewer. Panics if size or step is 0
+pub fn sliding<T, A, F>(size: usize, step: usize, aggregate: F) -> CountWindow<T, F>
+    where F: FnMut(&[T]) -> A + Send + 'static
+{
+    assert!(size > 0 && step > 0, "windows need a positive size and step");
+    CountWindow {
+        size: size,
+        step: step,
+        items: VecDeque::with_capacity(size),
+        fresh: 0,
+        aggregate: aggregate,
+    }
+}
+
+/// Windows width long, one after the other, of items stamped by time.
+/// Panics if width is zero
+pub fn tumbling_time<T, A, K, F>(width: Duration, time: K, aggregate: F) -> TimeWindow<T, K, F>
+    where K: FnMut(&T) -> Duration + Send + 'static,
+          F: FnMut(&[T]) -> A + Send + 'static
+{
+    sliding_time(width, width, time, aggregate)
+}
+
+/// Windows width long, starting every step, of items stamped by time.
+/// Panics if width or step is zero
+pub fn sliding_time<T, A, K, F>(width: Duration, step: Duration, time: K, aggregate: F) -> TimeWindow<T, K, F>
+    where K: FnMut(&T) -> Duration + Send + 'static,
+          F: FnMut(&[T]) -> A + Send + 'static
+{
+    assert!(width > Duration::from_secs(0) && step > Duration::from_secs(0),
+            "windows need a positive width and step");
+    TimeWindow {
+        width: nanos(width),
+        step: nanos(step),
+        time: time,
+        times: VecDeque::new(),
+        items: VecDeque::new(),
+        next: None,
+        aggregate: aggregate,
+        _marker: PhantomData,
+    }
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+impl<T, F> CountWindow<T, F> {
+    // Emits the newest count items
+    fn emit<A>(&mut self, count: usize, emit: &mut dyn FnMut(A))
+        where F: FnMut(&[T]) -> A
+    {
+        self.fresh = 0;
+        let items = self.items.make_contiguous();
+        emit((self.aggregate)(&items[items.len() - count..]))
+    }
+}
+
+impl<T, A, F> Stage<T, A> for CountWindow<T, F>
+    where T: Send + 'static,
+          F: FnMut(&[T]) -> A + Send + 'static
+{
+    fn process(&mut self, item: T, emit: &mut dyn FnMut(A)) {
+        if self.items.len() == self.size {
+            self.items.pop_front();
+        }
+        self.items.push_back(item);
+        self.fresh += 1;
+        if self.fresh == self.step {
+            let count = self.items.len();
+            self.emit(count, emit);
+        }
+    }
+
+    fn finish(&mut self, emit: &mut dyn FnMut(A)) {
+        // What the next window would hold, were it to end now
+        let count = (self.size + self.fresh).saturating_sub(self.step).min(self.items.len());
+        if self.fresh > 0 && count > 0 {
+            self.emit(count, emit);
+        }
+    }
+}
+
+impl<T, K, F> TimeWindow<T, K, F> {
+    // Where the first window holding time t starts
+    fn first_holding(&self, t: u64) -> u64 {
+        if t < self.width {
+            0
+        } else {
+            ((t - self.width) / self.step + 1) * self.step
+        }
+    }
+
+    // Emits the window at next, if anything's in it, and moves on to the one after
+    fn close<A>(&mut self, emit: &mut dyn FnMut(Window<A>))
+        where F: FnMut(&[T]) -> A
+    {
+        let start = self.next.unwrap();
+        let end = start + self.width;
+        let count = self.times.iter().take_while(|&&t| t < end).count();
+        if count > 0 {
+            let value = (self.aggregate)(&self.items.make_contiguous()[..count]);
+            emit(Window {
+                start: Duration::from_nanos(start),
+                end: Duration::from_nanos(end),
+                value: value,
+            });
+        }
+        let next = start + self.step;
+        while self.times.front().is_some_and(|&t| t < next) {
+            self.times.pop_front();
+            self.items.pop_front();
+        }
+        self.next = Some(next);
+    }
+}
+
+impl<T, A, K, F> Stage<T, Window<A>> for TimeWindow<T, K, F>
+    where T: Send + 'static,
+          K: FnMut(&T) -> Duration + Send + 'static,
+          F: FnMut(&[T]) -> A + Send + 'static
+{
+    fn process(&mut self, item: T, emit: &mut dyn FnMut(Window<A>)) {
+        let t = nanos((self.time)(&item));
+        let first = self.first_holding(t);
+        let mut next = *self.next.get_or_insert(first);
+        while next + self.width <= t {
+            if self.items.is_empty() {
+                // Nothing falls in the windows between here and t
+                next = next.max(first);
+                self.next = Some(next);
+                break;
+            }
+            self.close(emit);
+            next = self.next.unwrap();
+        }
+        if t < next {
+            return;
+        }
+        let at = self.times.iter().rposition(|&other| other <= t).map_or(0, |i| i + 1);
+        self.times.insert(at, t);
+        self.items.insert(at, item);
+    }
+
+    fn finish(&mut self, emit: &mut dyn FnMut(Window<A>)) {
+        while !self.items.is_empty() {
+            self.close(emit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run<In, Out, S: Stage<In, Out>>(mut stage: S, input: Vec<In>) -> Vec<Out> {
+        let mut out = Vec::new();
+        for item in input {
+            stage.process(item, &mut |val| out.push(val));
+        }
+        stage.finish(&mut |val| out.push(val));
+        out
+    }
+
+    fn window(start: u64, end: u64, value: Vec<u64>) -> Window<Vec<u64>> {
+        Window {
+            start: Duration::from_secs(start),
+            end: Duration::from_secs(end),
+            value: value,
+        }
+    }
+
+    #[test]
+    fn count_windows() {
+        let all = |items: &[u32]| items.to_vec();
+        assert_eq!(vec![vec![1, 2, 3], vec![4, 5, 6], vec![7]],
+                   run(tumbling(3, all), vec![1, 2, 3, 4, 5, 6, 7]));
+        assert_eq!(Vec::<Vec<u32>>::new(), run(tumbling(2, all), vec![]));
+        assert_eq!(vec![vec![1], vec![1, 2], vec![1, 2, 3], vec![2, 3, 4]],
+                   run(sliding(3, 1, all), vec![1, 2, 3, 4]));
+        assert_eq!(vec![vec![1, 2], vec![2, 3, 4], vec![4, 5]],
+                   run(sliding(3, 2, all), vec![1, 2, 3, 4, 5]));
+        // Every third item, with the window ending on 7 still to start
+        assert_eq!(vec![vec![3], vec![6]], run(sliding(1, 3, all), vec![1, 2, 3, 4, 5, 6, 7]));
+    }
+
+    #[test]
+    fn time_windows() {
+        let secs = |x: &u64| Duration::from_secs(*x);
+        let all = |items: &[u64]| items.to_vec();
+        // 2 arrives late but within its window, 0 too late for any
+        assert_eq!(vec![window(0, 10, vec![1, 2, 9]),
+                        window(10, 20, vec![10]),
+                        window(40, 50, vec![45])],
+                   run(tumbling_time(Duration::from_secs(10), secs, all),
+                       vec![1, 9, 2, 10, 0, 45]));
+        assert_eq!(vec![window(0, 10, vec![3, 7]),
+                        window(5, 15, vec![7, 12]),
+                        window(10, 20, vec![12]),
+                        window(30, 40, vec![38]),
+                        window(35, 45, vec![38])],
+                   run(sliding_time(Duration::from_secs(10), Duration::from_secs(5), secs, all),
+                       vec![3, 7, 12, 38]));
+    }
+}
@@ -0,0 +1,702 @@
+//! What a stage's threads run for each item
+//!
+//! Most tasks are Jobs, which can either run to the end on a thread of their
+//! own or be stepped by a pool, doing whatever they can without waiting each
+//! time. A job never blocks in step: output that doesn't fit is held until
+//! the next one, and a checkpoint is waited out by parking with a Parker.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use pipeline::link::{Control, Outlet, Receiver, Recv, recv};
+use pipeline::pause::{Parker, Slot};
+use pipeline::stage::Stage;
+use pipeline::stats::StageStats;
+#[cfg(feature = "affinity")]
+use util::affinity;
+
+/// What a task's thread runs
+pub type Body = Box<dyn FnOnce(&Control, &Slot) + Send>;
+
+/// How many items a job handles in one step before letting others run
+const BATCH: usize = 64;
+
+/// What a task runs
+pub enum Work {
+    /// Only runs on a thread of its own
+    Body(Body),
+    Job(Box<dyn Job>),
+}
+
+/// What a job got done in one step
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Poll {
+    Busy,
+    /// Waiting on its input or on room in its output
+    Idle,
+    Done,
+}
+
+/// A task that can also run a bit at a time on a thread it shares
+pub trait Job: Send {
+    /// Runs the task to the end on the calling thread
+    fn run(self: Box<Self>, control: &Control, slot: &Slot);
+
+    /// Does what can be done without waiting
+    fn step(&mut self, control: &Control, slot: &Slot) -> Poll;
+}
+
+/// Runs a worker between an input and an outlet
+pub struct Pump<T, U, W> {
+    worker: W,
+    input: Box<dyn Receiver<T>>,
+    outlet: Outlet<U>,
+    stats: Arc<StageStats>,
+    // Outputs that didn't fit in the queue yet
+    pending: VecDeque<U>,
+    parker: Parker,
+    restored: bool,
+    finished: bool,
+}
+
+/// Hands everything from an input to a function
+pub struct Sink<T, F> {
+    sink: F,
+    input: Box<dyn Receiver<T>>,
+    stats: Arc<StageStats>,
+    parker: Parker,
+}
+
+/// Calls a function for items until it returns None
+pub struct Source<T, F> {
+    source: F,
+    outlet: Outlet<T>,
+    pending: Option<T>,
+    // What was produced before the checkpoint being resumed from
+    skip: Option<usize>,
+    exhausted: bool,
+    parker: Parker,
+}
+
+/// Sends a Tick every interval
+pub struct Ticker {
+    interval: Duration,
+    outlet: Outlet<Tick>,
+    // When the next tick is due
+    next: Option<Instant>,
+    count: u64,
+    pending: Option<Tick>,
+    parker: Parker,
+}
+
+/// One beat of a tick source, or of the ticks mixed into a stream by tick_every
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tick {
+    /// How many ticks came before this one
+    pub count: u64,
+    /// When it was due
+    pub at: Instant,
+}
+
+/// Sends each item to one of several outlets, picked by its hash
+pub struct Partition<T, H> {
+    hash: H,
+    input: Box<dyn Receiver<T>>,
+    outlets: Vec<Outlet<T>>,
+    // An item that didn't fit in its lane yet
+    pending: Option<(usize, T)>,
+    parker: Parker,
+    finished: bool,
+}
+
+pub trait Worker<T, U>: Send + 'static {
+    fn process(&mut self, item: T, emit: &mut dyn FnMut(U), control: &Control);
+
+    fn finish(&mut self, _emit: &mut dyn FnMut(U)) {}
+
+    fn checkpoint(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn restore(&mut self, _state: &[u8]) {}
+}
+
+/// Runs a Stage, which doesn't need to know about the pipeline around it
+pub struct StageWorker<S> {
+    stage: S,
+}
+
+impl<S> StageWorker<S> {
+    pub fn new(stage: S) -> StageWorker<S> {
+        StageWorker { stage: stage }
+    }
+}
+
+impl<T, U, S: Stage<T, U>> Worker<T, U> for StageWorker<S> {
+    #[inline(always)]
+    fn process(&mut self, item: T, emit: &mut dyn FnMut(U), _control: &Control) {
+        self.stage.process(item, emit)
+    }
+
+    fn finish(&mut self, emit: &mut dyn FnMut(U)) {
+        self.stage.finish(emit)
+    }
+
+    fn checkpoint(&self) -> Option<Vec<u8>> {
+        self.stage.checkpoint()
+    }
+
+    fn restore(&mut self, state: &[u8]) {
+        self.stage.restore(state)
+    }
+}
+
+/// Feeds everything from input through worker into outlet, until the
+/// input closes, the pipeline stops, or the outlet's link loses its readers
+pub fn work<T, U, W>(worker: &mut W,
+                     input: &mut dyn Receiver<T>,
+                     outlet: Outlet<U>,
+                     stats: &StageStats,
+                     control: &Control,
+                     slot: &Slot)
+    where T: 'static,
+          W: Worker<T, U>
+{
+    if let Some(state) = slot.take_restore() {
+        worker.restore(&state);
+    }
+    let open = Cell::new(true);
+    let waited = Cell::new(Duration::from_secs(0));
+    let outlet = RefCell::new(outlet);
+    {
+        let mut emit = |val: U| {
+            let mut outlet = outlet.borrow_mut();
+            if open.get() && !outlet.send(val, control) {
+                open.set(false);
+            }
+            waited.set(outlet.waited());
+        };
+        while open.get() {
+            let next = {
+                // Nothing spilled can be left behind when parking for a checkpoint
+                let mut settle = || {
+                    outlet.borrow_mut().try_flush() && {
+                        slot.save(worker.checkpoint());
+                        true
+                    }
+                };
+                recv(input, control, slot, &mut settle)
+            };
+            match next {
+                Some(val) => {
+                    stats.set_depth(input.depth());
+                    // Time spent blocked on a full output isn't the stage's own
+                    let (start, waited_before) = (Instant::now(), waited.get());
+                    worker.process(val, &mut emit, control);
+                    stats.record(start.elapsed().saturating_sub(waited.get() - waited_before));
+                }
+                None => break,
+            }
+        }
+        if open.get() && !control.is_stopped() {
+            worker.finish(&mut emit);
+        }
+    }
+    if open.get() {
+        outlet.into_inner().flush(control);
+    }
+}
+
+impl Work {
+    pub fn into_body(self) -> Body {
+        match self {
+            Work::Body(body) => body,
+            Work::Job(job) => Box::new(move |control: &Control, slot: &Slot| job.run(control, slot)),
+        }
+    }
+}
+
+impl<T, U, W> Pump<T, U, W> {
+    pub fn new(worker: W, input: Box<dyn Receiver<T>>, outlet: Outlet<U>, stats: Arc<StageStats>) -> Pump<T, U, W> {
+        Pump {
+            worker: worker,
+            input: input,
+            outlet: outlet,
+            stats: stats,
+            pending: VecDeque::new(),
+            parker: Parker::new(),
+            restored: false,
+            finished: false,
+        }
+    }
+
+    // Pushes what's pending, returning whether all of it fit
+    fn drain(&mut self) -> bool {
+        while let Some(val) = self.pending.pop_front() {
+            if let Err(back) = self.outlet.try_send(val) {
+                self.pending.push_front(back);
+                return false;
+            }
+        }
+        self.outlet.try_flush()
+    }
+}
+
+impl<T, U, W> Job for Pump<T, U, W>
+    where T: 'static,
+          U: Send + 'static,
+          W: Worker<T, U>
+{
+    fn run(self: Box<Self>, control: &Control, slot: &Slot) {
+        let Pump { mut worker, mut input, outlet, stats, .. } = *self;
+        work(&mut worker, &mut *input, outlet, &stats, control, slot)
+    }
+
+    fn step(&mut self, control: &Control, slot: &Slot) -> Poll {
+        if !self.restored {
+            self.restored = true;
+            if let Some(state) = slot.take_restore() {
+                self.worker.restore(&state);
+            }
+        }
+        if control.is_stopped() {
+            self.parker.wake(control);
+            return Poll::Done;
+        }
+        if !control.is_pausing() {
+            self.parker.wake(control);
+        }
+        if !self.drain() {
+            return if self.outlet.is_orphaned() { Poll::Done } else { Poll::Idle };
+        }
+        if self.finished {
+            return Poll::Done;
+        }
+        let mut busy = false;
+        for _ in 0..BATCH {
+            match self.input.try_recv() {
+                Recv::Item(val) => {
+                    self.parker.wake(control);
+                    slot.consume();
+                    self.stats.set_depth(self.input.depth());
+                    let start = Instant::now();
+                    let pending = &mut self.pending;
+                    self.worker.process(val, &mut |out| pending.push_back(out), control);
+                    self.stats.record(start.elapsed());
+                    if !self.drain() {
+                        return Poll::Busy;
+                    }
+                    busy = true;
+                }
+                Recv::Empty if busy => return Poll::Busy,
+                Recv::Empty => {
+                    let worker = &self.worker;
+                    self.parker.idle(control, slot, || worker.checkpoint());
+                    return Poll::Idle;
+                }
+                Recv::Closed => {
+                    self.parker.wake(control);
+                    let pending = &mut self.pending;
+                    self.worker.finish(&mut |out| pending.push_back(out));
+                    self.finished = true;
+                    return Poll::Busy;
+                }
+            }
+        }
+        Poll::Busy
+    }
+}
+
+impl<T, F> Sink<T, F> {
+    pub fn new(sink: F, input: Box<dyn Receiver<T>>, stats: Arc<StageStats>) -> Sink<T, F> {
+        Sink {
+            sink: sink,
+            input: input,
+            stats: stats,
+            parker: Parker::new(),
+        }
+    }
+}
+
+impl<T, F> Job for Sink<T, F>
+    where T: Send + 'static,
+          F: FnMut(T) + Send + 'static
+{
+    fn run(self: Box<Self>, control: &Control, slot: &Slot) {
+        let Sink { mut sink, mut input, stats, .. } = *self;
+        while let Some(val) = recv(&mut *input, control, slot, &mut || {
+            slot.save(None);
+            true
+        }) {
+            stats.set_depth(input.depth());
+            let start = Instant::now();
+            sink(val);
+            stats.record(start.elapsed());
+        }
+    }
+
+    fn step(&mut self, control: &Control, slot: &Slot) -> Poll {
+        if control.is_stopped() {
+            self.parker.wake(control);
+            return Poll::Done;
+        }
+        if !control.is_pausing() {
+            self.parker.wake(control);
+        }
+        let mut busy = false;
+        for _ in 0..BATCH {
+            match self.input.try_recv() {
+                Recv::Item(val) => {
+                    self.parker.wake(control);
+                    slot.consume();
+                    self.stats.set_depth(self.input.depth());
+                    let start = Instant::now();
+                    (self.sink)(val);
+                    self.stats.record(start.elapsed());
+                    busy = true;
+                }
+                Recv::Empty if busy => return Poll::Busy,
+                Recv::Empty => {
+                    self.parker.idle(control, slot, || None);
+                    return Poll::Idle;
+                }
+                Recv::Closed => {
+                    self.parker.wake(control);
+                    return Poll::Done;
+                }
+            }
+        }
+        Poll::Busy
+    }
+}
+
+impl<T, F> Source<T, F> {
+    pub fn new(source: F, outlet: Outlet<T>) -> Source<T, F> {
+        Source {
+            source: source,
+            outlet: outlet,
+            pending: None,
+            skip: None,
+            exhausted: false,
+            parker: Parker::new(),
+        }
+    }
+}
+
+impl<T, F> Job for Source<T, F>
+    where T: Send + 'static,
+          F: FnMut() -> Option<T> + Send + 'static
+{
+    fn run(self: Box<Self>, control: &Control, slot: &Slot) {
+        let Source { mut source, mut outlet, .. } = *self;
+        let mut skip = slot.consumed();
+        while !control.is_draining() {
+            if control.is_pausing() {
+                if outlet.flush(control) {
+                    slot.park(control, || None::<()>);
+                }
+                continue;
+            }
+            match source() {
+                Some(_) if skip > 0 => skip -= 1,
+                Some(val) => {
+                    slot.consume();
+                    if !outlet.send(val, control) {
+                        return;
+                    }
+                }
+                None => break,
+            }
+        }
+        outlet.flush(control);
+    }
+
+    fn step(&mut self, control: &Control, slot: &Slot) -> Poll {
+        if control.is_stopped() {
+            self.parker.wake(control);
+            return Poll::Done;
+        }
+        if !control.is_pausing() {
+            self.parker.wake(control);
+        }
+        if let Some(val) = self.pending.take() {
+            if let Err(back) = self.outlet.try_send(val) {
+                self.pending = Some(back);
+                return if self.outlet.is_orphaned() { Poll::Done } else { Poll::Idle };
+            }
+        }
+        if !self.outlet.try_flush() {
+            return if self.outlet.is_orphaned() { Poll::Done } else { Poll::Idle };
+        }
+        if self.exhausted || control.is_draining() {
+            return Poll::Done;
+        }
+        if control.is_pausing() {
+            self.parker.idle(control, slot, || None);
+            return Poll::Idle;
+        }
+        let skip = self.skip.get_or_insert_with(|| slot.consumed());
+        for _ in 0..BATCH {
+            match (self.source)() {
+                Some(_) if *skip > 0 => *skip -= 1,
+                Some(val) => {
+                    slot.consume();
+                    if let Err(back) = self.outlet.try_send(val) {
+                        self.pending = Some(back);
+                        return Poll::Busy;
+                    }
+                }
+                None => {
+                    self.exhausted = true;
+                    return Poll::Busy;
+                }
+            }
+        }
+        Poll::Busy
+    }
+}
+
+impl Tick {
+    /// Returns the tick due now, or None if the one due at next isn't yet.
+    /// Ticks that were missed are skipped
+    pub fn due(interval: Duration, next: &mut Instant, count: &mut u64) -> Option<Tick> {
+        let now = Instant::now();
+        if now < *next {
+            return None;
+        }
+        let tick = Tick {
+            count: *count,
+            at: *next,
+        };
+        *count += 1;
+        while *next <= now {
+            *next += interval;
+        }
+        Some(tick)
+    }
+}
+
+impl Ticker {
+    pub fn new(interval: Duration, outlet: Outlet<Tick>) -> Ticker {
+        Ticker {
+            interval: interval,
+            outlet: outlet,
+            next: None,
+            count: 0,
+            pending: None,
+            parker: Parker::new(),
+        }
+    }
+}
+
+impl Job for Ticker {
+    fn run(self: Box<Self>, control: &Control, slot: &Slot) {
+        let Ticker { interval, mut outlet, mut count, .. } = *self;
+        let mut next = Instant::now() + interval;
+        while !control.is_draining() {
+            if control.is_pausing() {
+                if outlet.flush(control) {
+                    slot.park(control, || None::<()>);
+                }
+                continue;
+            }
+            match Tick::due(interval, &mut next, &mut count) {
+                Some(tick) => {
+                    slot.consume();
+                    if !outlet.send(tick, control) {
+                        return;
+                    }
+                }
+                // Short naps, so a drain or a checkpoint isn't kept waiting
+                None => thread::sleep((next - Instant::now()).min(Duration::from_millis(1))),
+            }
+        }
+        outlet.flush(control);
+    }
+
+    fn step(&mut self, control: &Control, slot: &Slot) -> Poll {
+        if control.is_stopped() {
+            self.parker.wake(control);
+            return Poll::Done;
+        }
+        if !control.is_pausing() {
+            self.parker.wake(control);
+        }
+        if let Some(tick) = self.pending.take() {
+            if let Err(back) = self.outlet.try_send(tick) {
+                self.pending = Some(back);
+                return if self.outlet.is_orphaned() { Poll::Done } else { Poll::Idle };
+            }
+        }
+        if !self.outlet.try_flush() {
+            return if self.outlet.is_orphaned() { Poll::Done } else { Poll::Idle };
+        }
+        if control.is_draining() {
+            return Poll::Done;
+        }
+        if control.is_pausing() {
+            self.parker.idle(control, slot, || None);
+            return Poll::Idle;
+        }
+        let interval = self.interval;
+        let next = self.next.get_or_insert_with(|| Instant::now() + interval);
+        match Tick::due(interval, next, &mut self.count) {
+            Some(tick) => {
+                slot.consume();
+                if let Err(back) = self.outlet.try_send(tick) {
+                    self.pending = Some(back);
+                }
+                Poll::Busy
+            }
+            None => Poll::Idle,
+        }
+    }
+}
+
+impl<T, H> Partition<T, H>
+    where H: FnMut(&T) -> u64
+{
+    pub fn new(hash: H, input: Box<dyn Receiver<T>>, outlets: Vec<Outlet<T>>) -> Partition<T, H> {
+        Partition {
+            hash: hash,
+            input: input,
+            outlets: outlets,
+            pending: None,
+            parker: Parker::new(),
+            finished: false,
+        }
+    }
+
+    fn lane(&mut self, val: &T) -> usize {
+        ((self.hash)(val) % self.outlets.len() as u64) as usize
+    }
+
+    // What to return when some lane is full
+    fn blocked(&self) -> Poll {
+        if self.outlets.iter().any(|outlet| outlet.is_orphaned()) {
+            Poll::Done
+        } else {
+            Poll::Idle
+        }
+    }
+}
+
+// Pushes whatever the outlets spilled, returning whether all of it fit
+fn try_flush_all<T>(outlets: &mut [Outlet<T>]) -> bool {
+    outlets.iter_mut().all(|outlet| outlet.try_flush())
+}
+
+impl<T, H> Job for Partition<T, H>
+    where T: Send + 'static,
+          H: FnMut(&T) -> u64 + Send + 'static
+{
+    fn run(mut self: Box<Self>, control: &Control, slot: &Slot) {
+        loop {
+            let next = {
+                let outlets = &mut self.outlets;
+                let mut settle = || {
+                    try_flush_all(outlets) && {
+                        slot.save(None);
+                        true
+                    }
+                };
+                recv(&mut *self.input, control, slot, &mut settle)
+            };
+            match next {
+                Some(val) => {
+                    let lane = self.lane(&val);
+                    // A lane that lost its readers takes the others down with it
+                    if !self.outlets[lane].send(val, control) {
+                        return;
+                    }
+                }
+                None => break,
+            }
+        }
+        for outlet in &mut self.outlets {
+            outlet.flush(control);
+        }
+    }
+
+    fn step(&mut self, control: &Control, slot: &Slot) -> Poll {
+        if control.is_stopped() {
+            self.parker.wake(control);
+            return Poll::Done;
+        }
+        if !control.is_pausing() {
+            self.parker.wake(control);
+        }
+        if let Some((lane, val)) = self.pending.take() {
+            if let Err(back) = self.outlets[lane].try_send(val) {
+                self.pending = Some((lane, back));
+                return self.blocked();
+            }
+        }
+        if !try_flush_all(&mut self.outlets) {
+            return self.blocked();
+        }
+        if self.finished {
+            return Poll::Done;
+        }
+        let mut busy = false;
+        for _ in 0..BATCH {
+            match self.input.try_recv() {
+                Recv::Item(val) => {
+                    self.parker.wake(control);
+                    slot.consume();
+                    let lane = self.lane(&val);
+                    if let Err(back) = self.outlets[lane].try_send(val) {
+                        self.pending = Some((lane, back));
+                        return Poll::Busy;
+                    }
+                    busy = true;
+                }
+                Recv::Empty if busy => return Poll::Busy,
+                Recv::Empty => {
+                    self.parker.idle(control, slot, || None);
+                    return Poll::Idle;
+                }
+                Recv::Closed => {
+                    self.parker.wake(control);
+                    self.finished = true;
+                    return Poll::Busy;
+                }
+            }
+        }
+        Poll::Busy
+    }
+}
+
+/// Starts a thread running body as one of slot's tasks
+pub fn spawn(name: String,
+             cores: Vec<usize>,
+             control: Arc<Control>,
+             slot: Arc<Slot>,
+             body: Body)
+             -> JoinHandle<()> {
+    control.enlist();
+    slot.enlist();
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            let _finish = slot.finish_on_drop(&control);
+            pin_to(&cores);
+            body(&control, &slot)
+        })
+        .expect("failed to spawn pipeline thread")
+}
+
+#[cfg(feature = "affinity")]
+fn pin_to(cores: &[usize]) {
+    if !cores.is_empty() {
+        if let Err(e) = affinity::pin_current(cores) {
+            panic!("failed to pin to cores {:?}: {}", cores, e);
+        }
+    }
+}
+
+#[cfg(not(feature = "affinity"))]
+fn pin_to(_cores: &[usize]) {}
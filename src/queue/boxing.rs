@@ -0,0 +1,220 @@
+//! A queue that boxes items too big to copy through the ring cheaply
+//!
+//! Pushing and popping copy an item into and out of its slot, which for
+//! a struct of a few KB costs more than the queue itself and pushes
+//! everything else out of cache. Queues from here decide when built
+//! whether T is over a size threshold, and if it is keep each item in a
+//! box of its own with just the pointer in the ring. Either way items go
+//! in and come out by value.
+//!
+//! Boxes can't be handed out bitwise to several streams, so the queue has
+//! just the one, whatever size T is. Clones of a reader share its items as
+//! usual.
+//!
+//! ```
+//! use pipeline::queue::boxing::boxing_queue;
+//!
+//! let (writer, reader) = boxing_queue::<[u8; 4096]>(16);
+//! assert!(writer.is_boxed());
+//! writer.push([7; 4096]).unwrap();
+//! assert_eq!(7, reader.pop().unwrap()[4095]);
+//! ```
+
+use std::fmt;
+use std::mem;
+
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
+use queue::multiqueue::{Delivery, MultiQueueBuilder, MultiReader, MultiWriter};
+
+/// Items bigger than this many bytes are boxed by boxing_queue
+pub const BOX_ABOVE: usize = 256;
+
+pub struct BoxingWriter<T> {
+    writer: Writer<T>,
+}
+
+pub struct BoxingReader<T> {
+    reader: Reader<T>,
+}
+
+enum Writer<T> {
+    Inline(MultiWriter<T>),
+    Boxed(MultiWriter<Box<T>>),
+}
+
+enum Reader<T> {
+    Inline(MultiReader<T>),
+    Boxed(MultiReader<Box<T>>),
+}
+
+/// A queue with room for capacity items, boxing them if T is bigger
+/// than BOX_ABOVE bytes
+pub fn boxing_queue<T>(capacity: u16) -> (BoxingWriter<T>, BoxingReader<T>) {
+    boxing_queue_with(&MultiQueueBuilder::new(capacity), BOX_ABOVE)
+}
+
+/// A queue configured by builder, which is made unicast if it isn't,
+/// boxing items if T is bigger than box_above bytes
+pub fn boxing_queue_with<T>(builder: &MultiQueueBuilder,
+                            box_above: usize)
+                            -> (BoxingWriter<T>, BoxingReader<T>) {
+    let builder = builder.clone().delivery(Delivery::Unicast);
+    let (writer, reader) = if mem::size_of::<T>() > box_above {
+        let (writer, reader) = builder.build();
+        (Writer::Boxed(writer), Reader::Boxed(reader))
+    } else {
+        let (writer, reader) = builder.build();
+        (Writer::Inline(writer), Reader::Inline(reader))
+    };
+    (BoxingWriter { writer: writer }, BoxingReader { reader: reader })
+}
+
+impl<T> BoxingWriter<T> {
+    /// Pushes val, or gives it back if the queue is full
+    pub fn push(&self, val: T) -> Result<usize, T> {
+        match self.writer {
+            Writer::Inline(ref writer) => writer.push(val),
+            Writer::Boxed(ref writer) => writer.push(Box::new(val)).map_err(|val| *val),
+        }
+    }
+
+    /// Like push, waiting for room as the queue was built to
+    pub fn push_wait(&self, val: T) -> Result<usize, T> {
+        match self.writer {
+            Writer::Inline(ref writer) => writer.push_wait(val),
+            Writer::Boxed(ref writer) => writer.push_wait(Box::new(val)).map_err(|val| *val),
+        }
+    }
+
+    pub fn close(&self) {
+        match self.writer {
+            Writer::Inline(ref writer) => writer.close(),
+            Writer::Boxed(ref writer) => writer.close(),
+        }
+    }
+
+    /// Whether items are kept in boxes rather than in the ring
+    pub fn is_boxed(&self) -> bool {
+        match self.writer {
+            Writer::Inline(_) => false,
+            Writer::Boxed(_) => true,
+        }
+    }
+}
+
+impl<T> BoxingReader<T> {
+    pub fn pop(&self) -> Option<T> {
+        match self.reader {
+            Reader::Inline(ref reader) => reader.pop(),
+            Reader::Boxed(ref reader) => reader.pop().map(|val| *val),
+        }
+    }
+
+    /// Pops, waiting for an item as the queue was built to. Returns None
+    /// once the queue is closed and empty
+    pub fn pop_wait(&self) -> Option<T> {
+        match self.reader {
+            Reader::Inline(ref reader) => reader.pop_wait(),
+            Reader::Boxed(ref reader) => reader.pop_wait().map(|val| *val),
+        }
+    }
+
+    pub fn is_boxed(&self) -> bool {
+        match self.reader {
+            Reader::Inline(_) => false,
+            Reader::Boxed(_) => true,
+        }
+    }
+}
+
+impl<T> Clone for BoxingWriter<T> {
+    fn clone(&self) -> BoxingWriter<T> {
+        let writer = match self.writer {
+            Writer::Inline(ref writer) => Writer::Inline(writer.clone()),
+            Writer::Boxed(ref writer) => Writer::Boxed(writer.clone()),
+        };
+        BoxingWriter { writer: writer }
+    }
+}
+
+impl<T> Clone for BoxingReader<T> {
+    fn clone(&self) -> BoxingReader<T> {
+        let reader = match self.reader {
+            Reader::Inline(ref reader) => Reader::Inline(reader.clone()),
+            Reader::Boxed(ref reader) => Reader::Boxed(reader.clone()),
+        };
+        BoxingReader { reader: reader }
+    }
+}
+
+impl<T> fmt::Debug for BoxingWriter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("BoxingWriter");
+        match self.writer {
+            Writer::Inline(ref writer) => debug.field("writer", writer),
+            Writer::Boxed(ref writer) => debug.field("writer", writer),
+        };
+        debug.field("boxed", &self.is_boxed()).finish()
+    }
+}
+
+impl<T> fmt::Debug for BoxingReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("BoxingReader");
+        match self.reader {
+            Reader::Inline(ref reader) => debug.field("reader", reader),
+            Reader::Boxed(ref reader) => debug.field("reader", reader),
+        };
+        debug.field("boxed", &self.is_boxed()).finish()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Big(Arc<AtomicUsize>, [u64; 128]);
+
+    impl Drop for Big {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn only_items_over_the_threshold_are_boxed() {
+        let (writer, reader) = boxing_queue::<[u64; 4]>(4);
+        assert!(!writer.is_boxed() && !reader.is_boxed());
+        writer.push([1, 2, 3, 4]).unwrap();
+        assert_eq!(Some([1, 2, 3, 4]), reader.pop());
+
+        let (writer, reader) = boxing_queue_with::<[u64; 4]>(&MultiQueueBuilder::new(2), 16);
+        assert!(writer.is_boxed() && reader.is_boxed());
+        writer.push([5; 4]).unwrap();
+        writer.push([6; 4]).unwrap();
+        assert_eq!(Err([7; 4]), writer.push([7; 4]));
+        assert_eq!(Some([5; 4]), reader.pop());
+        assert_eq!(Some([6; 4]), reader.pop());
+        assert_eq!(None, reader.pop());
+    }
+
+    #[test]
+    fn boxed_items_are_dropped_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (writer, reader) = boxing_queue(2);
+        assert!(writer.is_boxed());
+        assert!(writer.push(Big(drops.clone(), [0; 128])).is_ok());
+        assert!(writer.push(Big(drops.clone(), [1; 128])).is_ok());
+        drop(writer.push(Big(drops.clone(), [2; 128])));
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        assert_eq!(0, reader.pop().unwrap().1[127]);
+        assert_eq!(2, drops.load(Ordering::SeqCst));
+        drop((writer, reader));
+        assert_eq!(3, drops.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,762 @@
+//! Mirrors a queue onto another host over TCP
+//!
+//! A BridgeSender pops from a local stream and ships each item to a
+//! BridgeReceiver, which pushes it into a queue on the other side.
+//! Each frame carries a sequence number and the receiver acks what it has
+//! pushed, so when the connection drops the sender reconnects and resends
+//! only what the receiver hasn't seen. Sequence numbers start over with each
+//! BridgeSender, which picks a new session id to tell the receiver so.
+//!
+//! Backpressure flows through TCP itself: the receiver doesn't read the next
+//! frame until the previous item fits in its queue, the socket buffers fill,
+//! the sender stops popping once its window of unacked items is full, and the
+//! local queue backs up into its producers like it would with a slow consumer.
+//!
+//! Wire format, all integers little endian:
+//!
+//!  * sender, on connecting: its session id (u64)
+//!  * receiver, in reply: the last sequence number it pushed from that session,
+//!    or 0 if the session is new (u64)
+//!  * sender, per item: sequence (u64), length (u32), then the encoded item
+//!  * receiver, per item pushed: its sequence (u64)
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use queue::multiqueue::{MultiReader, MultiWriter};
+
+/// Turns items into bytes and back for the wire
+pub trait Codec<T>: Send + 'static {
+    fn encode(&mut self, item: &T, buf: &mut Vec<u8>);
+
+    /// A frame that gives an error is skipped, as counted by Bridge::skipped
+    fn decode(&mut self, buf: &[u8]) -> io::Result<T>;
+}
+
+/// Ships byte buffers as they are
+pub struct BytesCodec;
+
+impl Codec<Vec<u8>> for BytesCodec {
+    fn encode(&mut self, item: &Vec<u8>, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(item);
+    }
+
+    fn decode(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(buf.to_vec())
+    }
+}
+
+/// A codec from a pair of encode/decode closures
+impl<T, E, D> Codec<T> for (E, D)
+    where E: FnMut(&T, &mut Vec<u8>) + Send + 'static,
+          D: FnMut(&[u8]) -> io::Result<T> + Send + 'static
+{
+    fn encode(&mut self, item: &T, buf: &mut Vec<u8>) {
+        (self.0)(item, buf)
+    }
+
+    fn decode(&mut self, buf: &[u8]) -> io::Result<T> {
+        (self.1)(buf)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BridgeConfig {
+    /// How many items may be in flight without an ack
+    pub window: usize,
+    /// How long the sender waits between connection attempts
+    pub reconnect_delay: Duration,
+    /// How long either side sleeps when it has nothing to do
+    pub idle_sleep: Duration,
+    /// Largest frame the receiver accepts, to guard against garbage on the socket
+    pub max_frame: usize,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> BridgeConfig {
+        BridgeConfig {
+            window: 1024,
+            reconnect_delay: Duration::from_millis(100),
+            idle_sleep: Duration::from_micros(50),
+            max_frame: 16 << 20,
+        }
+    }
+}
+
+/// A running half of a bridge. Dropping it stops the thread
+pub struct Bridge {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+    local_addr: Option<SocketAddr>,
+    skipped: Arc<AtomicUsize>,
+}
+
+pub struct BridgeSender;
+
+pub struct BridgeReceiver;
+
+fn read_u64(stream: &mut TcpStream) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Whether an error only means the socket timed out, or was interrupted
+fn timed_out(e: &io::Error) -> bool {
+    let kind = e.kind();
+    kind == io::ErrorKind::WouldBlock || kind == io::ErrorKind::TimedOut || kind == io::ErrorKind::Interrupted
+}
+
+/// Reads into all of buf, carrying on through read timeouts and keeping what
+/// each read got. Gives back false before it's full if stop is set, or if
+/// waited, called at each timeout, says to give up
+fn fill<F>(stream: &mut TcpStream, buf: &mut [u8], stop: &AtomicBool, mut waited: F) -> io::Result<bool>
+    where F: FnMut() -> io::Result<bool>
+{
+    let mut filled = 0;
+    while filled < buf.len() {
+        if stop.load(Acquire) {
+            return Ok(false);
+        }
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(ref e) if timed_out(e) => {
+                if !waited()? {
+                    return Ok(false);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Writes all of buf, carrying on through write timeouts. Gives back false
+/// before it's all written if stop is set
+fn send(stream: &mut TcpStream, mut buf: &[u8], stop: &AtomicBool) -> io::Result<bool> {
+    while !buf.is_empty() {
+        if stop.load(Acquire) {
+            return Ok(false);
+        }
+        match stream.write(buf) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => buf = &buf[n..],
+            Err(ref e) if timed_out(e) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// An id for a new sender that a restarted one is unlikely to repeat
+fn new_session() -> u64 {
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    nanos ^ ((process::id() as u64) << 32) ^ STARTED.fetch_add(1, Relaxed) as u64
+}
+
+impl Bridge {
+    fn spawn<F>(local_addr: Option<SocketAddr>, skipped: Arc<AtomicUsize>, f: F) -> Bridge
+        where F: FnOnce(Arc<AtomicBool>) -> io::Result<()> + Send + 'static
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        Bridge {
+            stop: stop,
+            thread: Some(thread::spawn(move || f(thread_stop))),
+            local_addr: local_addr,
+            skipped: skipped,
+        }
+    }
+
+    /// The address a receiver is listening on
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// How many frames a receiver couldn't decode, and acked without pushing
+    /// so the sender moves past them. Always 0 for a sender
+    pub fn skipped(&self) -> usize {
+        self.skipped.load(Relaxed)
+    }
+
+    /// Stops the bridge thread and returns the error that ended it, if any.
+    /// Items in flight stay with the sender's stream or are dropped
+    pub fn stop(mut self) -> io::Result<()> {
+        self.stop_inner()
+    }
+
+    fn stop_inner(&mut self) -> io::Result<()> {
+        self.stop.store(true, Release);
+        match self.thread.take() {
+            Some(thread) => {
+                match thread.join() {
+                    Ok(rval) => rval,
+                    Err(_) => Err(io::Error::other("bridge thread panicked")),
+                }
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        let _ = self.stop_inner();
+    }
+}
+
+impl BridgeSender {
+    /// Starts forwarding everything popped from reader to the receiver at addr
+    pub fn spawn<T, C, A>(reader: MultiReader<T>, addr: A, codec: C, cfg: BridgeConfig) -> Bridge
+        where T: 'static,
+              C: Codec<T>,
+              A: ToSocketAddrs + Send + 'static
+    {
+        Bridge::spawn(None, Arc::new(AtomicUsize::new(0)), move |stop| {
+            let mut sender = Sender {
+                reader: reader,
+                codec: codec,
+                cfg: cfg,
+                session: new_session(),
+                unacked: VecDeque::new(),
+                next_seq: 1,
+                buf: Vec::new(),
+            };
+            while !stop.load(Acquire) {
+                if let Ok(stream) = TcpStream::connect(&addr) {
+                    // Any error just means a new connection
+                    let _ = sender.run(stream, &stop);
+                }
+                if !stop.load(Acquire) {
+                    thread::sleep(sender.cfg.reconnect_delay);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+struct Sender<T, C> {
+    reader: MultiReader<T>,
+    codec: C,
+    cfg: BridgeConfig,
+    session: u64,
+    // Sequence and frame of everything sent but not acked, oldest first
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    next_seq: u64,
+    buf: Vec<u8>,
+}
+
+impl<T, C: Codec<T>> Sender<T, C> {
+    fn run(&mut self, mut stream: TcpStream, stop: &AtomicBool) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        // Reads and writes time out so a receiver that's gone quiet or
+        // stopped taking items doesn't keep stop from being noticed
+        stream.set_read_timeout(Some(self.cfg.reconnect_delay))?;
+        stream.set_write_timeout(Some(self.cfg.reconnect_delay))?;
+        let mut seen = [0; 8];
+        if !send(&mut stream, &self.session.to_le_bytes(), stop)? || !fill(&mut stream, &mut seen, stop, || Ok(true))? {
+            return Ok(());
+        }
+        let seen = u64::from_le_bytes(seen);
+        self.release(seen);
+        for (_, frame) in self.unacked.iter() {
+            if !send(&mut stream, frame, stop)? {
+                return Ok(());
+            }
+        }
+
+        // Acks come back on their own thread so writes never wait on them
+        let acked = Arc::new(AtomicUsize::new(seen as usize));
+        let done = Arc::new(AtomicBool::new(false));
+        let ack_thread = {
+            let (acked, done) = (acked.clone(), done.clone());
+            let mut ack_stream = stream.try_clone()?;
+            thread::spawn(move || {
+                let mut seq = [0; 8];
+                while let Ok(true) = fill(&mut ack_stream, &mut seq, &done, || Ok(true)) {
+                    acked.store(u64::from_le_bytes(seq) as usize, Release);
+                }
+            })
+        };
+
+        let rval = self.pump(&mut stream, &acked, stop);
+        done.store(true, Release);
+        let _ = stream.shutdown(Shutdown::Both);
+        let _ = ack_thread.join();
+        self.release(acked.load(Acquire) as u64);
+        rval
+    }
+
+    fn pump(&mut self,
+            stream: &mut TcpStream,
+            acked: &AtomicUsize,
+            stop: &AtomicBool)
+            -> io::Result<()> {
+        while !stop.load(Acquire) {
+            self.release(acked.load(Acquire) as u64);
+            if self.unacked.len() >= self.cfg.window {
+                thread::sleep(self.cfg.idle_sleep);
+                continue;
+            }
+            match self.reader.pop() {
+                Some(item) => {
+                    self.buf.clear();
+                    self.buf.extend_from_slice(&self.next_seq.to_le_bytes());
+                    self.buf.extend_from_slice(&[0; 4]);
+                    self.codec.encode(&item, &mut self.buf);
+                    let len = (self.buf.len() - 12) as u32;
+                    self.buf[8..12].copy_from_slice(&len.to_le_bytes());
+                    // Kept before it's written, to be resent if the write doesn't finish
+                    self.unacked.push_back((self.next_seq, self.buf.clone()));
+                    self.next_seq += 1;
+                    if !send(stream, &self.buf, stop)? {
+                        break;
+                    }
+                }
+                None => thread::sleep(self.cfg.idle_sleep),
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets everything the receiver has confirmed
+    fn release(&mut self, seen: u64) {
+        while self.unacked.front().is_some_and(|&(seq, _)| seq <= seen) {
+            self.unacked.pop_front();
+        }
+    }
+}
+
+impl BridgeReceiver {
+    /// Accepts connections from a sender on listener, pushing what arrives into writer
+    ///
+    /// Connections are served one at a time, so a reconnecting sender
+    /// picks up where its last connection left off. A new connection takes
+    /// over from the one being served once that one goes quiet, since a
+    /// sender whose host went away may have left it open without ever
+    /// closing it. A bridge is meant for one sender at a time: anything else
+    /// connecting holds the sender up until it reconnects, and each side
+    /// still only gets what it hasn't pushed, since the receiver keeps the
+    /// last sequence number of each of the last few sessions it's seen
+    pub fn spawn<T, C>(listener: TcpListener, writer: MultiWriter<T>, codec: C, cfg: BridgeConfig) -> Bridge
+        where T: 'static,
+              C: Codec<T>
+    {
+        let local_addr = listener.local_addr().ok();
+        let skipped = Arc::new(AtomicUsize::new(0));
+        Bridge::spawn(local_addr, skipped.clone(), move |stop| {
+            listener.set_nonblocking(true)?;
+            let mut receiver = Receiver {
+                writer: writer,
+                codec: codec,
+                cfg: cfg,
+                listener: listener,
+                next: None,
+                sessions: VecDeque::new(),
+                last_seq: 0,
+                buf: Vec::new(),
+                skipped: skipped,
+            };
+            while !stop.load(Acquire) {
+                if let Some(stream) = receiver.next.take() {
+                    let _ = receiver.run(stream, &stop);
+                    continue;
+                }
+                match receiver.listener.accept() {
+                    Ok((stream, _)) => {
+                        let _ = receiver.run(stream, &stop);
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(receiver.cfg.reconnect_delay / 10);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// How many senders' sessions a receiver remembers
+const SESSIONS: usize = 16;
+
+struct Receiver<T, C> {
+    writer: MultiWriter<T>,
+    codec: C,
+    cfg: BridgeConfig,
+    listener: TcpListener,
+    // A connection that came in while another was being served, to serve next
+    next: Option<TcpStream>,
+    // The last sequence number pushed from each recent session but the one
+    // being served, whose is last_seq
+    sessions: VecDeque<(u64, u64)>,
+    last_seq: u64,
+    buf: Vec<u8>,
+    // Frames that wouldn't decode
+    skipped: Arc<AtomicUsize>,
+}
+
+impl<T, C: Codec<T>> Receiver<T, C> {
+    fn run(&mut self, mut stream: TcpStream, stop: &AtomicBool) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        // Reads time out so a quiet sender doesn't keep stop, or a new
+        // connection, from being noticed
+        stream.set_read_timeout(Some(self.cfg.reconnect_delay))?;
+        let mut session = [0; 8];
+        if !self.fill(&mut stream, &mut session, stop)? {
+            return Ok(());
+        }
+        let session = u64::from_le_bytes(session);
+        self.last_seq = match self.sessions.iter().position(|&(id, _)| id == session) {
+            Some(i) => self.sessions.remove(i).map_or(0, |(_, seq)| seq),
+            None => 0,
+        };
+        let mut buf = mem::take(&mut self.buf);
+        let rval = match send(&mut stream, &self.last_seq.to_le_bytes(), stop) {
+            Ok(true) => self.serve(&mut stream, &mut buf, stop),
+            sent => sent.map(|_| ()),
+        };
+        self.buf = buf;
+        if self.sessions.len() == SESSIONS {
+            self.sessions.pop_front();
+        }
+        self.sessions.push_back((session, self.last_seq));
+        rval
+    }
+
+    /// Pushes each frame that arrives on stream, using buf for its payload
+    fn serve(&mut self, stream: &mut TcpStream, buf: &mut Vec<u8>, stop: &AtomicBool) -> io::Result<()> {
+        let mut header = [0; 12];
+        loop {
+            if !self.fill(stream, &mut header, stop)? {
+                return Ok(());
+            }
+            let mut seq = [0; 8];
+            let mut len = [0; 4];
+            seq.copy_from_slice(&header[..8]);
+            len.copy_from_slice(&header[8..]);
+            let (seq, len) = (u64::from_le_bytes(seq), u32::from_le_bytes(len) as usize);
+            if len > self.cfg.max_frame {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+            }
+            buf.resize(len, 0);
+            if !self.fill(stream, buf, stop)? {
+                return Ok(());
+            }
+
+            // Resent after a reconnect but already pushed
+            if seq <= self.last_seq {
+                continue;
+            }
+            match self.codec.decode(buf) {
+                Ok(mut item) => {
+                    loop {
+                        match self.writer.push(item) {
+                            Ok(_) => break,
+                            Err(back) => {
+                                if stop.load(Acquire) {
+                                    return Ok(());
+                                }
+                                item = back;
+                                thread::sleep(self.cfg.idle_sleep);
+                            }
+                        }
+                    }
+                }
+                // Acked all the same, or the sender would resend it for good
+                Err(_) => {
+                    self.skipped.fetch_add(1, Relaxed);
+                }
+            }
+            self.last_seq = seq;
+            if !send(stream, &seq.to_le_bytes(), stop)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Like the free fill, but gives up on the connection once a new one
+    /// comes in, keeping the new one to serve next
+    fn fill(&mut self, stream: &mut TcpStream, buf: &mut [u8], stop: &AtomicBool) -> io::Result<bool> {
+        let (listener, next) = (&self.listener, &mut self.next);
+        fill(stream, buf, stop, || {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    *next = Some(stream);
+                    Ok(false)
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(true),
+                Err(e) => Err(e),
+            }
+        })
+    }
+}
+
+// Miri can't open sockets
+#[cfg(all(test, not(miri)))]
+mod test {
+    use super::*;
+
+    use queue::multiqueue::multiqueue;
+
+    type U64Codec = (fn(&u64, &mut Vec<u8>), fn(&[u8]) -> io::Result<u64>);
+
+    fn u64_codec() -> U64Codec {
+        fn encode(item: &u64, buf: &mut Vec<u8>) {
+            buf.extend_from_slice(&item.to_le_bytes());
+        }
+        fn decode(buf: &[u8]) -> io::Result<u64> {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(buf);
+            Ok(u64::from_le_bytes(bytes))
+        }
+        (encode, decode)
+    }
+
+    fn receive_all(reader: &MultiReader<u64>, count: u64) {
+        for i in 0..count {
+            loop {
+                if let Some(val) = reader.pop() {
+                    assert_eq!(i, val);
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+    }
+
+    #[test]
+    fn mirrors_in_order_through_a_small_queue() {
+        let (local_writer, local_reader) = multiqueue(4);
+        let (remote_writer, remote_reader) = multiqueue(2);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = BridgeConfig { window: 3, ..BridgeConfig::default() };
+        let receiver = BridgeReceiver::spawn(listener, remote_writer, u64_codec(), cfg.clone());
+        let addr = receiver.local_addr().unwrap();
+        let sender = BridgeSender::spawn(local_reader, addr, u64_codec(), cfg);
+
+        let pusher = thread::spawn(move || {
+            for i in 0..200 {
+                let mut item = i;
+                while let Err(back) = local_writer.push(item) {
+                    item = back;
+                    thread::yield_now();
+                }
+            }
+        });
+        receive_all(&remote_reader, 200);
+        pusher.join().unwrap();
+        assert!(sender.stop().is_ok());
+        assert!(receiver.stop().is_ok());
+    }
+
+    #[test]
+    fn sender_waits_for_receiver() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (local_writer, local_reader) = multiqueue(16);
+        let (remote_writer, remote_reader) = multiqueue(16);
+        let cfg = BridgeConfig { reconnect_delay: Duration::from_millis(10), ..BridgeConfig::default() };
+        let _sender = BridgeSender::spawn(local_reader, addr, u64_codec(), cfg.clone());
+        for i in 0..10 {
+            local_writer.push(i).unwrap();
+        }
+        thread::sleep(Duration::from_millis(30));
+
+        let listener = TcpListener::bind(addr).unwrap();
+        let _receiver = BridgeReceiver::spawn(listener, remote_writer, u64_codec(), cfg);
+        receive_all(&remote_reader, 10);
+    }
+
+    #[test]
+    fn restarted_sender_starts_a_new_session() {
+        let (remote_writer, remote_reader) = multiqueue(16);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = BridgeConfig { reconnect_delay: Duration::from_millis(10), ..BridgeConfig::default() };
+        let receiver = BridgeReceiver::spawn(listener, remote_writer, u64_codec(), cfg.clone());
+        let addr = receiver.local_addr().unwrap();
+        for _ in 0..2 {
+            let (local_writer, local_reader) = multiqueue(16);
+            for i in 0..5 {
+                local_writer.push(i).unwrap();
+            }
+            let sender = BridgeSender::spawn(local_reader, addr, u64_codec(), cfg.clone());
+            receive_all(&remote_reader, 5);
+            assert!(sender.stop().is_ok());
+        }
+    }
+
+    #[test]
+    fn header_split_across_read_timeouts() {
+        let (remote_writer, remote_reader) = multiqueue(16);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = BridgeConfig { reconnect_delay: Duration::from_millis(10), ..BridgeConfig::default() };
+        let receiver = BridgeReceiver::spawn(listener, remote_writer, u64_codec(), cfg);
+        let mut stream = TcpStream::connect(receiver.local_addr().unwrap()).unwrap();
+        stream.set_nodelay(true).unwrap();
+        stream.write_all(&7u64.to_le_bytes()).unwrap();
+        assert_eq!(0, read_u64(&mut stream).unwrap());
+
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&1u64.to_le_bytes());
+        frame.extend_from_slice(&8u32.to_le_bytes());
+        frame.extend_from_slice(&0u64.to_le_bytes());
+        stream.write_all(&frame[..5]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(&frame[5..]).unwrap();
+        receive_all(&remote_reader, 1);
+        assert_eq!(1, read_u64(&mut stream).unwrap());
+    }
+
+    fn connect(addr: SocketAddr, session: u64) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        // So a receiver that never answers fails the test instead of hanging it
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(&session.to_le_bytes()).unwrap();
+        stream
+    }
+
+    fn frame(seq: u64, val: u64) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&seq.to_le_bytes());
+        frame.extend_from_slice(&8u32.to_le_bytes());
+        frame.extend_from_slice(&val.to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn connection_dropped_mid_frame() {
+        let (remote_writer, remote_reader) = multiqueue(16);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = BridgeConfig { reconnect_delay: Duration::from_millis(10), ..BridgeConfig::default() };
+        let receiver = BridgeReceiver::spawn(listener, remote_writer, u64_codec(), cfg);
+        let addr = receiver.local_addr().unwrap();
+
+        let mut stream = connect(addr, 7);
+        assert_eq!(0, read_u64(&mut stream).unwrap());
+        stream.write_all(&frame(1, 0)[..15]).unwrap();
+        thread::sleep(Duration::from_millis(30));
+        drop(stream);
+
+        let mut stream = connect(addr, 7);
+        assert_eq!(0, read_u64(&mut stream).unwrap());
+        stream.write_all(&frame(1, 0)).unwrap();
+        receive_all(&remote_reader, 1);
+        assert_eq!(1, read_u64(&mut stream).unwrap());
+        assert!(receiver.stop().is_ok());
+    }
+
+    #[test]
+    fn stalled_connection_is_replaced() {
+        let (remote_writer, remote_reader) = multiqueue(16);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = BridgeConfig { reconnect_delay: Duration::from_millis(10), ..BridgeConfig::default() };
+        let receiver = BridgeReceiver::spawn(listener, remote_writer, u64_codec(), cfg);
+        let addr = receiver.local_addr().unwrap();
+
+        // Left open partway through a frame, as a sender whose host went away would
+        let mut stalled = connect(addr, 7);
+        assert_eq!(0, read_u64(&mut stalled).unwrap());
+        stalled.write_all(&frame(1, 0)[..15]).unwrap();
+        thread::sleep(Duration::from_millis(30));
+
+        let mut stream = connect(addr, 7);
+        assert_eq!(0, read_u64(&mut stream).unwrap());
+        stream.write_all(&frame(1, 0)).unwrap();
+        stream.write_all(&frame(2, 1)[..15]).unwrap();
+        receive_all(&remote_reader, 1);
+        assert_eq!(1, read_u64(&mut stream).unwrap());
+
+        // Stops even with the connection it's serving stuck mid-frame
+        assert!(receiver.stop().is_ok());
+        drop(stalled);
+    }
+
+    #[test]
+    fn undecodable_frames_are_skipped() {
+        fn decode(buf: &[u8]) -> io::Result<u64> {
+            if buf.len() != 8 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not a u64"));
+            }
+            u64_codec().1(buf)
+        }
+        let (remote_writer, remote_reader) = multiqueue(16);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let codec = (u64_codec().0, decode);
+        let receiver = BridgeReceiver::spawn(listener, remote_writer, codec, BridgeConfig::default());
+        let mut stream = connect(receiver.local_addr().unwrap(), 7);
+        assert_eq!(0, read_u64(&mut stream).unwrap());
+
+        let mut bad = Vec::new();
+        bad.extend_from_slice(&2u64.to_le_bytes());
+        bad.extend_from_slice(&3u32.to_le_bytes());
+        bad.extend_from_slice(&[1, 2, 3]);
+        stream.write_all(&frame(1, 0)).unwrap();
+        stream.write_all(&bad).unwrap();
+        stream.write_all(&frame(3, 1)).unwrap();
+        receive_all(&remote_reader, 2);
+        for seq in 1..4 {
+            assert_eq!(seq, read_u64(&mut stream).unwrap());
+        }
+        assert_eq!(1, receiver.skipped());
+    }
+
+    #[test]
+    fn other_connections_dont_lose_a_sessions_place() {
+        let (remote_writer, remote_reader) = multiqueue(16);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = BridgeConfig { reconnect_delay: Duration::from_millis(10), ..BridgeConfig::default() };
+        let receiver = BridgeReceiver::spawn(listener, remote_writer, u64_codec(), cfg);
+        let addr = receiver.local_addr().unwrap();
+
+        let mut stream = connect(addr, 7);
+        assert_eq!(0, read_u64(&mut stream).unwrap());
+        stream.write_all(&frame(1, 0)).unwrap();
+        stream.write_all(&frame(2, 1)).unwrap();
+        receive_all(&remote_reader, 2);
+        assert_eq!(1, read_u64(&mut stream).unwrap());
+        assert_eq!(2, read_u64(&mut stream).unwrap());
+
+        let mut stray = connect(addr, 9);
+        assert_eq!(0, read_u64(&mut stray).unwrap());
+        let mut stream = connect(addr, 7);
+        assert_eq!(2, read_u64(&mut stream).unwrap());
+        stream.write_all(&frame(2, 1)).unwrap();
+        stream.write_all(&frame(3, 2)).unwrap();
+        assert_eq!(3, read_u64(&mut stream).unwrap());
+        assert_eq!(Some(2), remote_reader.pop());
+        assert_eq!(None, remote_reader.pop());
+        assert!(receiver.stop().is_ok());
+    }
+
+    #[test]
+    fn sender_stops_while_receiver_is_full() {
+        let (local_writer, local_reader) = multiqueue(64);
+        let (remote_writer, _remote_reader) = multiqueue(1);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = BridgeConfig { reconnect_delay: Duration::from_millis(10), ..BridgeConfig::default() };
+        let receiver = BridgeReceiver::spawn(listener, remote_writer, BytesCodec, cfg.clone());
+        let addr = receiver.local_addr().unwrap();
+        let sender = BridgeSender::spawn(local_reader, addr, BytesCodec, cfg);
+
+        // Far more than the socket buffers hold, so the sender's writes back up
+        for _ in 0..64 {
+            local_writer.push(vec![0; 256 << 10]).unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(sender.stop().is_ok());
+        assert!(receiver.stop().is_ok());
+    }
+}
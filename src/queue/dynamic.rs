@@ -0,0 +1,167 @@
+//! A queue of items whose types are only known at runtime
+//!
+//! Items go in boxed as `dyn Any`, so writers can push whatever types they
+//! like and readers downcast what they pop, for plugin systems and the like
+//! where the queue's owner doesn't know the message types. Each item is
+//! a separate allocation, so this is for control traffic rather than data.
+//!
+//! Boxes can't be handed out bitwise to several streams, so the queue has
+//! just the one. Clones of a reader share its items as usual.
+//!
+//! ```
+//! use pipeline::queue::dynamic::dyn_queue;
+//!
+//! let (writer, reader) = dyn_queue(16);
+//! writer.push(7u32).unwrap();
+//! writer.push("reload").unwrap();
+//! assert_eq!(7, reader.pop_as::<u32>().unwrap().ok().unwrap());
+//! let reload = reader.pop().unwrap();
+//! assert_eq!(Some(&"reload"), reload.downcast_ref::<&str>());
+//! ```
+
+use std::any::Any;
+use std::fmt;
+
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
+use queue::multiqueue::{Delivery, MultiQueueBuilder, MultiReader, MultiWriter};
+
+pub type DynItem = Box<dyn Any + Send>;
+
+pub struct DynWriter {
+    writer: MultiWriter<DynItem>,
+}
+
+pub struct DynReader {
+    reader: MultiReader<DynItem>,
+}
+
+/// A dynamic queue with room for capacity items
+pub fn dyn_queue(capacity: u16) -> (DynWriter, DynReader) {
+    dyn_queue_with(&MultiQueueBuilder::new(capacity))
+}
+
+/// A dynamic queue configured by builder, which is made unicast if it isn't
+pub fn dyn_queue_with(builder: &MultiQueueBuilder) -> (DynWriter, DynReader) {
+    let (writer, reader) = builder.clone().delivery(Delivery::Unicast).build();
+    (DynWriter { writer: writer }, DynReader { reader: reader })
+}
+
+impl DynWriter {
+    /// Boxes val and pushes it, or gives it back if the queue is full
+    pub fn push<T: Any + Send>(&self, val: T) -> Result<usize, T> {
+        self.writer.push(Box::new(val)).map_err(DynWriter::unbox)
+    }
+
+    /// Like push, waiting for room as the queue was built to
+    pub fn push_wait<T: Any + Send>(&self, val: T) -> Result<usize, T> {
+        self.writer.push_wait(Box::new(val)).map_err(DynWriter::unbox)
+    }
+
+    /// Pushes an item that's already boxed
+    pub fn push_boxed(&self, item: DynItem) -> Result<usize, DynItem> {
+        self.writer.push(item)
+    }
+
+    pub fn close(&self) {
+        self.writer.close()
+    }
+
+    fn unbox<T: Any>(item: DynItem) -> T {
+        *item.downcast::<T>().expect("a pushed item came back as another type")
+    }
+}
+
+impl DynReader {
+    pub fn pop(&self) -> Option<DynItem> {
+        self.reader.pop()
+    }
+
+    /// Pops the next item as a T. One of any other type is still popped,
+    /// and comes back as the Err for the caller to deal with
+    pub fn pop_as<T: Any>(&self) -> Option<Result<T, DynItem>> {
+        self.pop().map(|item| item.downcast::<T>().map(|val| *val))
+    }
+
+    /// Pops the next item, waiting for one as the queue was built to.
+    /// Returns None once the queue is closed and empty
+    pub fn pop_wait(&self) -> Option<DynItem> {
+        self.reader.pop_wait()
+    }
+
+    /// Like pop_as, waiting for an item as pop_wait does
+    pub fn pop_wait_as<T: Any>(&self) -> Option<Result<T, DynItem>> {
+        self.pop_wait().map(|item| item.downcast::<T>().map(|val| *val))
+    }
+}
+
+impl Clone for DynWriter {
+    fn clone(&self) -> DynWriter {
+        DynWriter { writer: self.writer.clone() }
+    }
+}
+
+impl Clone for DynReader {
+    fn clone(&self) -> DynReader {
+        DynReader { reader: self.reader.clone() }
+    }
+}
+
+impl fmt::Debug for DynWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DynWriter")
+            .field("writer", &self.writer)
+            .finish()
+    }
+}
+
+impl fmt::Debug for DynReader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DynReader")
+            .field("reader", &self.reader)
+            .finish()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn items_pop_as_the_type_they_were_pushed_as() {
+        let (writer, reader) = dyn_queue(2);
+        writer.push(String::from("start")).unwrap();
+        writer.push(3u64).unwrap();
+        assert_eq!(Err(4u8), writer.push(4u8));
+        let wrong = reader.pop_as::<u64>().unwrap().unwrap_err();
+        assert_eq!("start", *wrong.downcast::<String>().unwrap());
+        assert_eq!(3, reader.pop_as::<u64>().unwrap().ok().unwrap());
+        assert!(reader.pop_as::<u64>().is_none());
+    }
+
+    #[test]
+    fn unpopped_items_are_dropped_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (writer, reader) = dyn_queue(4);
+        let other = reader.clone();
+        for _ in 0..3 {
+            assert!(writer.push(Counted(drops.clone())).is_ok());
+        }
+        drop(reader.pop());
+        assert_eq!(1, drops.load(Ordering::SeqCst));
+        drop((writer, reader, other));
+        assert_eq!(3, drops.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,225 @@
+//! Callbacks the builder registers for when a queue fills up or a stream
+//! falls behind.
+//!
+//! Each callback is called at most once per interval however often the
+//! condition comes up, so a producer spinning on a full queue doesn't turn
+//! into a flood of alerts. The exception is on_evict, which goes off once
+//! for every stream evicted for sitting idle, and on_alloc, which goes off
+//! for every allocation. Without the `std` feature there are no callbacks
+//! and everything here compiles down to nothing.
+
+#[cfg(feature = "std")]
+mod theimpl {
+    use std::fmt;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::time::{Duration, Instant};
+
+    use util::alloc::{Counter, OnAlloc};
+
+    pub type OnFull = Arc<dyn Fn(&'static str) + Send + Sync>;
+    pub type OnLag = Arc<dyn Fn(&'static str, usize, usize) + Send + Sync>;
+    pub type OnEvict = Arc<dyn Fn(&'static str, usize) + Send + Sync>;
+
+    #[derive(Clone)]
+    pub struct Hooks {
+        full: Option<OnFull>,
+        lag: Option<(usize, OnLag)>,
+        idle: Option<(Duration, OnEvict)>,
+        alloc: Option<OnAlloc>,
+        interval: Duration,
+    }
+
+    /// The hooks of a built queue, along with when each last went off
+    pub struct Armed {
+        hooks: Hooks,
+        started: Instant,
+        // Nanoseconds from started, plus one so that 0 means never
+        last_full: AtomicU64,
+        last_lag: AtomicU64,
+    }
+
+    impl Hooks {
+        pub fn new() -> Hooks {
+            Hooks {
+                full: None,
+                lag: None,
+                idle: None,
+                alloc: None,
+                interval: Duration::from_secs(1),
+            }
+        }
+
+        pub fn on_full(&mut self, f: OnFull) {
+            self.full = Some(f);
+        }
+
+        pub fn on_lag(&mut self, threshold: usize, f: OnLag) {
+            self.lag = Some((threshold, f));
+        }
+
+        pub fn evict_idle(&mut self, timeout: Duration, f: OnEvict) {
+            self.idle = Some((timeout, f));
+        }
+
+        pub fn evicts(&self) -> bool {
+            self.idle.is_some()
+        }
+
+        pub fn on_alloc(&mut self, f: OnAlloc) {
+            self.alloc = Some(f);
+        }
+
+        /// Counts what the queue called name allocates, calling on_alloc
+        pub fn counter(&self, name: &'static str) -> Counter {
+            Counter::new(name, self.alloc.clone())
+        }
+
+        pub fn interval(&mut self, interval: Duration) {
+            self.interval = interval;
+        }
+
+        pub fn arm(&self) -> Armed {
+            Armed {
+                hooks: self.clone(),
+                started: Instant::now(),
+                last_full: AtomicU64::new(0),
+                last_lag: AtomicU64::new(0),
+            }
+        }
+    }
+
+    impl fmt::Debug for Hooks {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("Hooks")
+                .field("on_full", &self.full.is_some())
+                .field("lag_threshold", &self.lag.as_ref().map(|&(threshold, _)| threshold))
+                .field("idle_timeout", &self.idle.as_ref().map(|&(timeout, _)| timeout))
+                .field("on_alloc", &self.alloc.is_some())
+                .field("interval", &self.interval)
+                .finish()
+        }
+    }
+
+    impl Armed {
+        /// The lag past which on_lag wants to hear about a stream
+        #[inline(always)]
+        pub fn lag_threshold(&self) -> Option<usize> {
+            self.hooks.lag.as_ref().map(|&(threshold, _)| threshold)
+        }
+
+        #[inline(always)]
+        pub fn full(&self, queue: &'static str) {
+            if let Some(ref f) = self.hooks.full {
+                if self.due(&self.last_full) {
+                    f(queue);
+                }
+            }
+        }
+
+        /// Whether on_lag could go off now, so the caller can skip
+        /// working out which stream is furthest behind if it couldn't
+        pub fn lag_due(&self) -> bool {
+            self.hooks.lag.is_some() && self.elapsed(&self.last_lag).is_some()
+        }
+
+        pub fn lag(&self, queue: &'static str, stream: usize, lag: usize) {
+            if let Some((_, ref f)) = self.hooks.lag {
+                if self.due(&self.last_lag) {
+                    f(queue, stream, lag);
+                }
+            }
+        }
+
+        /// Milliseconds since the queue was built, along with how many a
+        /// stream may sit idle for before it's evicted, if they ever are
+        #[inline(always)]
+        pub fn idle_clock(&self) -> Option<(usize, usize)> {
+            self.hooks.idle.as_ref().map(|&(timeout, _)| {
+                (self.started.elapsed().as_millis() as usize, timeout.as_millis() as usize)
+            })
+        }
+
+        pub fn evicted(&self, queue: &'static str, stream: usize) {
+            if let Some((_, ref f)) = self.hooks.idle {
+                f(queue, stream);
+            }
+        }
+
+        /// What last held and the time now, if the interval since last is up
+        fn elapsed(&self, last: &AtomicU64) -> Option<(u64, u64)> {
+            let seen = last.load(Relaxed);
+            let now = self.started.elapsed().as_nanos() as u64 + 1;
+            if seen != 0 && now.saturating_sub(seen) < self.hooks.interval.as_nanos() as u64 {
+                None
+            } else {
+                Some((seen, now))
+            }
+        }
+
+        /// Claims the next call for whoever gets here first once the interval is up
+        fn due(&self, last: &AtomicU64) -> bool {
+            match self.elapsed(last) {
+                Some((seen, now)) => last.compare_exchange(seen, now, Relaxed, Relaxed).is_ok(),
+                None => false,
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod theimpl {
+    use util::alloc::Counter;
+
+    #[derive(Clone, Debug)]
+    pub struct Hooks;
+
+    pub struct Armed;
+
+    impl Hooks {
+        pub fn new() -> Hooks {
+            Hooks
+        }
+
+        pub fn counter(&self, name: &'static str) -> Counter {
+            Counter::new(name)
+        }
+
+        pub fn evicts(&self) -> bool {
+            false
+        }
+
+        pub fn arm(&self) -> Armed {
+            Armed
+        }
+    }
+
+    impl Armed {
+        #[inline(always)]
+        pub fn lag_threshold(&self) -> Option<usize> {
+            None
+        }
+
+        #[inline(always)]
+        pub fn full(&self, _queue: &'static str) {}
+
+        #[inline(always)]
+        pub fn lag_due(&self) -> bool {
+            false
+        }
+
+        #[inline(always)]
+        pub fn lag(&self, _queue: &'static str, _stream: usize, _lag: usize) {}
+
+        #[inline(always)]
+        pub fn idle_clock(&self) -> Option<(usize, usize)> {
+            None
+        }
+
+        #[inline(always)]
+        pub fn evicted(&self, _queue: &'static str, _stream: usize) {}
+    }
+}
+
+pub use self::theimpl::*;
@@ -0,0 +1,424 @@
+//! A queue for one thread, with plain counters in place of atomics
+//!
+//! LocalQueue goes by the same API as MultiQueue for pushing, popping and
+//! adding streams, but keeps the head and every stream's position in Cells
+//! and its handles in Rcs, so none of it is Send. That makes it a baseline
+//! for what the atomics cost, and spares paying for them in pipelines that
+//! run on a single thread anyway.
+//!
+//! Nothing else can pop or push while a thread waits on its own queue, so
+//! push_wait and pop_wait don't wait, and are the same as push and pop.
+//! As with MultiQueue, each stream gets a bitwise copy of every item.
+//!
+//! ```
+//! use pipeline::queue::local::LocalQueue;
+//!
+//! let (writer, reader) = LocalQueue::new(4);
+//! let other = reader.stream().add_stream().into_reader();
+//! writer.push(7).unwrap();
+//! assert_eq!((Some(7), Some(7)), (reader.pop(), other.pop()));
+//! ```
+
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::cmp;
+use std::fmt;
+use std::iter;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use queue::multiqueue::{PushError, TryPop};
+
+pub struct LocalQueue<T> {
+    ring: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    head: Cell<usize>,
+    // How far the furthest stream has popped. Items from there on haven't
+    // been handed out, so they're still the queue's to drop
+    taken: Cell<usize>,
+    streams: RefCell<Vec<Rc<Stream>>>,
+    next_stream: Cell<usize>,
+    writers: Cell<usize>,
+    paused: Cell<bool>,
+    closed: Cell<bool>,
+}
+
+struct Stream {
+    id: usize,
+    tail: Cell<usize>,
+    readers: Cell<usize>,
+}
+
+pub struct LocalWriter<T> {
+    queue: Rc<LocalQueue<T>>,
+}
+
+/// Pops from one stream. Clones share the stream's items, as with MultiReader
+pub struct LocalReader<T> {
+    queue: Rc<LocalQueue<T>>,
+    stream: Rc<Stream>,
+}
+
+/// A stream of a LocalQueue, to take readers from or add streams alongside
+pub struct LocalStream<T> {
+    reader: LocalReader<T>,
+}
+
+/// Like LocalQueue::new
+pub fn local_queue<T>(capacity: u16) -> (LocalWriter<T>, LocalReader<T>) {
+    LocalQueue::new(capacity)
+}
+
+impl<T> LocalQueue<T> {
+    /// A queue with room for capacity items and one stream. Panics if
+    /// capacity is 0
+    pub fn new(capacity: u16) -> (LocalWriter<T>, LocalReader<T>) {
+        assert!(capacity > 0, "a queue needs room for at least one item");
+        let stream = Rc::new(Stream {
+            id: 0,
+            tail: Cell::new(0),
+            readers: Cell::new(1),
+        });
+        let queue = Rc::new(LocalQueue {
+            ring: (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+            head: Cell::new(0),
+            taken: Cell::new(0),
+            streams: RefCell::new(iter::once(stream.clone()).collect()),
+            next_stream: Cell::new(1),
+            writers: Cell::new(1),
+            paused: Cell::new(false),
+            closed: Cell::new(false),
+        });
+        (LocalWriter { queue: queue.clone() }, LocalReader { queue: queue, stream: stream })
+    }
+
+    fn slot(&self, seq: usize) -> *mut MaybeUninit<T> {
+        self.ring[seq % self.ring.len()].get()
+    }
+
+    /// Room left behind the slowest stream, and none once every stream is gone
+    fn space(&self) -> usize {
+        let slowest = self.streams.borrow().iter().map(|stream| stream.tail.get()).min();
+        match slowest {
+            Some(tail) => self.ring.len() - (self.head.get() - tail),
+            None => 0,
+        }
+    }
+
+    fn push(&self, val: T) -> Result<usize, T> {
+        if self.paused.get() || self.closed.get() || self.space() == 0 {
+            return Err(val);
+        }
+        let seq = self.head.get();
+        unsafe { ptr::write(self.slot(seq), MaybeUninit::new(val)) };
+        self.head.set(seq + 1);
+        Ok(seq)
+    }
+
+    fn pop(&self, stream: &Stream) -> Option<(usize, T)> {
+        let seq = stream.tail.get();
+        if seq == self.head.get() {
+            return None;
+        }
+        let val = unsafe { ptr::read(self.slot(seq)).assume_init() };
+        stream.tail.set(seq + 1);
+        self.taken.set(cmp::max(self.taken.get(), seq + 1));
+        Some((seq, val))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.get() || self.writers.get() == 0
+    }
+}
+
+impl<T> LocalWriter<T> {
+    /// Returns the item's sequence number, counting every item ever pushed
+    /// from 0, or gives val back if the queue is full
+    pub fn push(&self, val: T) -> Result<usize, T> {
+        self.queue.push(val)
+    }
+
+    /// Like push, but says whether val came back because the queue was full
+    /// or because it's paused
+    pub fn try_push(&self, val: T) -> Result<usize, PushError<T>> {
+        self.push(val).map_err(|val| {
+            if self.queue.closed.get() {
+                PushError::Closed(val)
+            } else if self.queue.paused.get() {
+                PushError::Paused(val)
+            } else {
+                PushError::Full(val)
+            }
+        })
+    }
+
+    /// The same as push, since nothing could make room while it waited
+    pub fn push_wait(&self, val: T) -> Result<usize, T> {
+        self.push(val)
+    }
+
+    /// Stops the queue taking items until resume is called. Readers carry
+    /// on popping what's already in the queue
+    pub fn pause(&self) {
+        self.queue.paused.set(true);
+    }
+
+    pub fn resume(&self) {
+        self.queue.paused.set(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.queue.paused.get()
+    }
+
+    /// Closes the queue for good: pushes fail from then on, and once readers
+    /// have popped what's left they find it closed
+    pub fn close(&self) {
+        self.queue.closed.set(true);
+    }
+
+    /// How many more items fit before the queue is full. Unlike MultiQueue's
+    /// this is always exact
+    pub fn space_remaining(&self) -> usize {
+        self.queue.space()
+    }
+
+    /// Whether a push would find room. Always false while the queue is
+    /// paused or closed
+    pub fn poll_ready(&self) -> bool {
+        !self.queue.paused.get() && !self.queue.closed.get() && self.queue.space() > 0
+    }
+}
+
+impl<T> LocalReader<T> {
+    pub fn pop(&self) -> Option<T> {
+        self.pop_seq().map(|(_, val)| val)
+    }
+
+    /// Pops the next item along with its sequence number
+    pub fn pop_seq(&self) -> Option<(usize, T)> {
+        self.queue.pop(&self.stream)
+    }
+
+    /// Like pop, but tells an empty queue apart from one that's closed
+    /// with nothing left to pop
+    pub fn try_pop(&self) -> TryPop<T> {
+        match self.pop() {
+            Some(val) => TryPop::Item(val),
+            None if self.is_closed() => TryPop::Closed,
+            None => TryPop::Empty,
+        }
+    }
+
+    /// The same as pop, since nothing could push while it waited
+    pub fn pop_wait(&self) -> Option<T> {
+        self.pop()
+    }
+
+    pub fn pop_wait_seq(&self) -> Option<(usize, T)> {
+        self.pop_seq()
+    }
+
+    /// Whether the queue was closed or every writer is gone, so nothing
+    /// more will be popped once it's empty
+    pub fn is_closed(&self) -> bool {
+        self.queue.is_closed()
+    }
+
+    /// The id of this reader's stream, counting streams from 0 in the order
+    /// they were added
+    pub fn stream_id(&self) -> usize {
+        self.stream.id
+    }
+
+    /// Whether pop has an item to give, without taking it
+    pub fn is_ready(&self) -> bool {
+        self.stream.tail.get() != self.queue.head.get()
+    }
+
+    /// How many items this stream has yet to pop
+    pub fn lag(&self) -> usize {
+        self.queue.head.get() - self.stream.tail.get()
+    }
+
+    /// The stream this reader pops from, to take more readers from or to add streams alongside
+    pub fn stream(&self) -> LocalStream<T> {
+        LocalStream { reader: self.clone() }
+    }
+}
+
+impl<T> LocalStream<T> {
+    /// A reader sharing this stream's items with every other reader on it
+    pub fn reader(&self) -> LocalReader<T> {
+        self.reader.clone()
+    }
+
+    /// Adds another stream starting at this one's position.
+    /// It gets every item from then on, independently of this one
+    pub fn add_stream(&self) -> LocalStream<T> {
+        let queue = &self.reader.queue;
+        let stream = Rc::new(Stream {
+            id: queue.next_stream.get(),
+            tail: Cell::new(self.reader.stream.tail.get()),
+            readers: Cell::new(1),
+        });
+        queue.next_stream.set(stream.id + 1);
+        queue.streams.borrow_mut().push(stream.clone());
+        LocalStream {
+            reader: LocalReader {
+                queue: queue.clone(),
+                stream: stream,
+            },
+        }
+    }
+
+    /// Turns this handle into a reader on the stream, rather than taking another
+    pub fn into_reader(self) -> LocalReader<T> {
+        self.reader
+    }
+}
+
+impl<T> Clone for LocalWriter<T> {
+    fn clone(&self) -> LocalWriter<T> {
+        self.queue.writers.set(self.queue.writers.get() + 1);
+        LocalWriter { queue: self.queue.clone() }
+    }
+}
+
+impl<T> Clone for LocalReader<T> {
+    fn clone(&self) -> LocalReader<T> {
+        self.stream.readers.set(self.stream.readers.get() + 1);
+        LocalReader {
+            queue: self.queue.clone(),
+            stream: self.stream.clone(),
+        }
+    }
+}
+
+impl<T> Drop for LocalWriter<T> {
+    fn drop(&mut self) {
+        self.queue.writers.set(self.queue.writers.get() - 1);
+    }
+}
+
+impl<T> Drop for LocalReader<T> {
+    fn drop(&mut self) {
+        let readers = self.stream.readers.get() - 1;
+        self.stream.readers.set(readers);
+        if readers == 0 {
+            self.queue.streams.borrow_mut().retain(|stream| !Rc::ptr_eq(stream, &self.stream));
+        }
+    }
+}
+
+impl<T> Drop for LocalQueue<T> {
+    fn drop(&mut self) {
+        for seq in self.taken.get()..self.head.get() {
+            unsafe { ptr::drop_in_place((*self.slot(seq)).as_mut_ptr()) };
+        }
+    }
+}
+
+impl<T> fmt::Debug for LocalQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalQueue")
+            .field("capacity", &self.ring.len())
+            .field("head", &self.head.get())
+            .field("streams", &self.streams.borrow().len())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for LocalWriter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalWriter")
+            .field("queue", &self.queue)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for LocalReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalReader")
+            .field("stream", &self.stream.id)
+            .field("tail", &self.stream.tail.get())
+            .field("queue", &self.queue)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for LocalStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalStream")
+            .field("reader", &self.reader)
+            .finish()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn streams_each_get_every_item_and_hold_up_the_writer() {
+        let (writer, reader) = LocalQueue::new(2);
+        let other = reader.stream().add_stream().into_reader();
+        assert_eq!((0, 1), (reader.stream_id(), other.stream_id()));
+        assert_eq!(Ok(0), writer.push(1));
+        assert_eq!(Ok(1), writer.push(2));
+        assert_eq!(Err(3), writer.push(3));
+        assert_eq!(Some((0, 1)), reader.pop_seq());
+        assert_eq!(0, writer.space_remaining());
+        assert_eq!(Some(1), other.pop());
+        assert_eq!(1, writer.space_remaining());
+        assert_eq!(Ok(2), writer.push(3));
+        assert_eq!((2, 2), (other.lag(), reader.clone().lag()));
+        drop(other);
+        assert_eq!(0, writer.space_remaining());
+        assert_eq!(Some(2), reader.pop());
+        assert_eq!(Some(3), reader.pop());
+        assert!(!reader.is_ready());
+        drop(reader);
+        assert!(!writer.poll_ready());
+    }
+
+    #[test]
+    fn closing_pausing_and_dropping_writers() {
+        let (writer, reader) = local_queue(4);
+        writer.pause();
+        assert_eq!(Err(PushError::Paused(1)), writer.try_push(1));
+        writer.resume();
+        assert_eq!(Ok(0), writer.push_wait(1));
+        assert_eq!(TryPop::Item(1), reader.try_pop());
+        assert_eq!(TryPop::Empty, reader.try_pop());
+        writer.close();
+        assert_eq!(Err(PushError::Closed(2)), writer.try_push(2));
+        assert_eq!(TryPop::Closed, reader.try_pop());
+
+        let (writer, reader) = local_queue::<u8>(4);
+        let other = writer.clone();
+        drop(writer);
+        assert!(!reader.is_closed());
+        drop(other);
+        assert!(reader.is_closed());
+        assert_eq!(None, reader.pop_wait());
+    }
+
+    #[test]
+    fn items_left_in_the_queue_are_dropped_once() {
+        let item = Rc::new(());
+        let (writer, reader) = LocalQueue::new(4);
+        for _ in 0..3 {
+            writer.push(item.clone()).unwrap();
+        }
+        drop(reader.pop());
+        assert_eq!(3, Rc::strong_count(&item));
+        drop((writer, reader));
+        assert_eq!(1, Rc::strong_count(&item));
+    }
+}
@@ -0,0 +1,132 @@
+//! One reader over several queues of the same item type
+//!
+//! Pops go round the inputs in turn, starting after whichever one gave
+//! the last item, so a busy input can't starve the others.
+
+use std::cell::Cell;
+use std::fmt;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use queue::multiqueue::{MultiReader, WaitStrategy};
+use queue::wait::Waiter;
+
+pub struct MergedReader<T> {
+    readers: Vec<MultiReader<T>>,
+    // Where the next pop starts looking
+    next: Cell<usize>,
+    waiter: Waiter,
+}
+
+impl<T> MergedReader<T> {
+    pub fn new(readers: Vec<MultiReader<T>>) -> MergedReader<T> {
+        MergedReader {
+            readers: readers,
+            next: Cell::new(0),
+            // None of the queues know to wake a merged reader, so parking is out
+            waiter: Waiter::new(WaitStrategy::default()),
+        }
+    }
+
+    pub fn add(&mut self, reader: MultiReader<T>) {
+        self.readers.push(reader);
+    }
+
+    /// Pops from the next input in turn that has an item
+    pub fn pop(&self) -> Option<T> {
+        self.pop_from().map(|(_, val)| val)
+    }
+
+    /// Like pop, along with the index of the input the item came from
+    pub fn pop_from(&self) -> Option<(usize, T)> {
+        let start = self.next.get();
+        for i in 0..self.readers.len() {
+            let index = (start + i) % self.readers.len();
+            if let Some(val) = self.readers[index].pop() {
+                self.next.set(index + 1);
+                return Some((index, val));
+            }
+        }
+        None
+    }
+
+    /// Pops the next item from any input, waiting for one if they're all
+    /// empty. Returns None once every input's writers are gone and it's empty
+    pub fn pop_wait(&self) -> Option<T> {
+        self.waiter.until(|| {
+            match self.pop() {
+                Some(val) => Some(Some(val)),
+                // A writer may have pushed on its way out
+                None if self.readers.iter().all(|reader| reader.is_closed()) => Some(self.pop()),
+                None => None,
+            }
+        })
+    }
+
+    /// Whether any input has an item to pop
+    pub fn is_ready(&self) -> bool {
+        self.readers.iter().any(|reader| reader.is_ready())
+    }
+
+    pub fn readers(&self) -> &[MultiReader<T>] {
+        &self.readers
+    }
+
+    pub fn into_readers(self) -> Vec<MultiReader<T>> {
+        self.readers
+    }
+}
+
+impl<T> fmt::Debug for MergedReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MergedReader")
+            .field("readers", &self.readers)
+            .field("next", &self.next.get())
+            .finish()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+
+    use queue::multiqueue::multiqueue;
+
+    use std::thread;
+
+    #[test]
+    fn pops_take_turns() {
+        let (first, first_reader) = multiqueue::<usize>(4);
+        let (second, second_reader) = multiqueue::<usize>(4);
+        let merged = MergedReader::new(vec![first_reader, second_reader]);
+        for i in 0..3 {
+            first.push(i).unwrap();
+        }
+        second.push(10).unwrap();
+        assert_eq!(Some((0, 0)), merged.pop_from());
+        assert_eq!(Some((1, 10)), merged.pop_from());
+        assert_eq!(Some((0, 1)), merged.pop_from());
+        assert_eq!(Some((0, 2)), merged.pop_from());
+        assert!(!merged.is_ready());
+        assert_eq!(None, merged.pop());
+    }
+
+    #[test]
+    fn waits_until_every_input_closes() {
+        let (first, first_reader) = multiqueue::<usize>(4);
+        let (second, second_reader) = multiqueue::<usize>(4);
+        let merged = MergedReader::new(vec![first_reader, second_reader]);
+        let handle = thread::spawn(move || {
+            for i in 0..10 {
+                first.push_wait(i).unwrap();
+            }
+            drop(first);
+            second.push_wait(10).unwrap();
+        });
+        let mut popped: Vec<usize> = (0..).map_while(|_| merged.pop_wait()).collect();
+        handle.join().unwrap();
+        popped.sort();
+        assert_eq!((0..11).collect::<Vec<usize>>(), popped);
+    }
+}
@@ -0,0 +1,99 @@
+//! Queue metrics published through the `metrics` facade.
+//!
+//! Handles are registered once when the queue or stream is created,
+//! so the hot path only pays for an atomic add on whatever the installed
+//! recorder handed back. Without the `metrics` feature everything here
+//! compiles down to nothing.
+
+#[cfg(feature = "metrics")]
+mod theimpl {
+    use metrics::{Counter, Gauge, Label};
+
+    /// Metrics shared by every handle on a queue
+    pub struct QueueMetrics {
+        labels: Vec<Label>,
+        enqueued: Counter,
+        depth: Gauge,
+    }
+
+    /// Metrics for a single stream, shared by all of its consumers
+    #[derive(Clone)]
+    pub struct StreamMetrics {
+        dequeued: Counter,
+        lag: Gauge,
+    }
+
+    impl QueueMetrics {
+        pub fn new(name: &'static str, extra: &'static [(&'static str, &'static str)]) -> QueueMetrics {
+            let mut labels = vec![Label::new("queue", name)];
+            labels.extend(extra.iter().map(|&(k, v)| Label::new(k, v)));
+            QueueMetrics {
+                enqueued: ::metrics::counter!("pipeline_queue_enqueued_total", labels.clone()),
+                depth: ::metrics::gauge!("pipeline_queue_depth", labels.clone()),
+                labels: labels,
+            }
+        }
+
+        pub fn stream(&self, stream: usize, name: Option<&'static str>) -> StreamMetrics {
+            let mut labels = self.labels.clone();
+            labels.push(Label::new("stream", stream.to_string()));
+            if let Some(name) = name {
+                labels.push(Label::new("stream_name", name));
+            }
+            StreamMetrics {
+                dequeued: ::metrics::counter!("pipeline_queue_dequeued_total", labels.clone()),
+                lag: ::metrics::gauge!("pipeline_queue_lag", labels),
+            }
+        }
+
+        #[inline(always)]
+        pub fn record_push(&self, depth: usize) {
+            self.enqueued.increment(1);
+            self.depth.set(depth as f64);
+        }
+    }
+
+    impl StreamMetrics {
+        #[inline(always)]
+        pub fn record_pop(&self, lag: usize) {
+            self.dequeued.increment(1);
+            self.lag.set(lag as f64);
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod theimpl {
+    pub struct QueueMetrics;
+
+    #[derive(Clone)]
+    pub struct StreamMetrics;
+
+    impl QueueMetrics {
+        #[inline(always)]
+        pub fn new(_name: &'static str,
+                   _extra: &'static [(&'static str, &'static str)])
+                   -> QueueMetrics {
+            QueueMetrics
+        }
+
+        #[inline(always)]
+        pub fn stream(&self, _stream: usize, _name: Option<&'static str>) -> StreamMetrics {
+            StreamMetrics
+        }
+
+        #[inline(always)]
+        pub fn record_push(&self, _depth: usize) {}
+    }
+
+    impl StreamMetrics {
+        #[inline(always)]
+        pub fn record_pop(&self, _lag: usize) {}
+    }
+}
+
+pub use self::theimpl::{QueueMetrics, StreamMetrics};
+
+/// Whether recording is compiled in, so callers can skip computing
+/// depth and lag when nobody will look at them
+pub const ENABLED: bool = cfg!(feature = "metrics");
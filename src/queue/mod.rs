@@ -1,4 +1,23 @@
 
+mod hooks;
+mod metrics;
 mod read_cursor;
+mod trace;
+mod wait;
+mod waker;
 
+pub mod boxing;
+#[cfg(feature = "std")]
+pub mod bridge;
+pub mod dynamic;
+pub mod local;
+pub mod merge;
 pub mod multiqueue;
+pub mod mux;
+pub mod priority;
+pub mod route;
+#[cfg(target_has_atomic = "ptr")]
+pub mod shared;
+#[cfg(all(unix, feature = "shm"))]
+pub mod shm;
+pub mod steal;
@@ -1,322 +1,3799 @@
 
+use std::alloc::Layout;
 use std::cell::Cell;
-use std::mem;
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::mem::{self, MaybeUninit};
 use std::ptr;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicPtr, AtomicUsize, fence};
-use std::sync::atomic::Ordering::{Relaxed, Acquire, Release};
+use std::task::{Context, Poll, Waker};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release, SeqCst};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
-use util::alloc;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use util::alloc::{self, HEAP};
+use util::cache_padded::CachePadded;
 use util::countedu16::CountedU16;
 use util::maybe_acquire::{maybe_acquire_fence, MAYBE_ACQUIRE};
+use util::sync::{Arc, AtomicBool, AtomicPtr, AtomicUsize, fence};
 
+use queue::hooks::{Armed, Hooks};
+use queue::metrics::{self, QueueMetrics, StreamMetrics};
 use queue::read_cursor::{ReadCursor, Reader};
+use queue::route::{Route, Router};
+use queue::trace::{self, Transition};
+use queue::wait::Waiter;
+
+pub use queue::wait::WaitStrategy;
+pub use util::alloc::{Allocations, Heap, RawAlloc};
+#[cfg(all(unix, feature = "std", feature = "libc"))]
+pub use util::pages::{LazyPages, LAZY_PAGES, PREFAULTED_PAGES};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Set in an entry's wraps tag when its writer gave up the claim on it
+const SKIP: usize = 1 << (mem::size_of::<usize>() * 8 - 1);
+
+/// How many times in a row a writer on a fair queue can lose the race
+/// for the head before the others make way for it
+const STARVING: usize = 8;
+
+/// Whether a queue's items go to one stream or to several
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// Just the stream the queue is built with, whose readers share the items
+    Unicast,
+    /// Any number of streams, each getting every item
+    Broadcast,
+}
+
+/// What the queue knows about a popped item besides the item itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Meta {
+    /// The sequence number push gave it
+    pub seq: usize,
+    /// When it was published, if the queue was built to record timestamps
+    #[cfg(feature = "std")]
+    pub published: Option<Instant>,
+    /// What it was pushed to by MultiWriter::push_topic, 0 otherwise
+    pub topic: u8,
+}
+
+/// Every topic there is, which is what streams take items from until they subscribe
+pub const ALL_TOPICS: u64 = !0;
+
+/// Why MultiReader::seek couldn't move a stream to seq
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeekError {
+    pub seq: usize,
+    /// The oldest sequence number still in the ring for the stream
+    pub oldest: usize,
+    /// The sequence number of the first item that isn't published yet,
+    /// which is as far forward as a stream can go
+    pub newest: usize,
+}
+
+impl fmt::Display for SeekError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can't seek to {}, the stream can only go from {} to {}", self.seq, self.oldest, self.newest)
+    }
+}
+
+impl Error for SeekError {}
+
+/// Why MultiWriter::try_push gave an item back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushError<T> {
+    /// There was no room for it
+    Full(T),
+    /// The queue is paused, and takes nothing until it's resumed
+    Paused(T),
+    /// The queue is closed, and takes nothing ever again
+    Closed(T),
+}
+
+/// What MultiReader::try_pop found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryPop<T> {
+    Item(T),
+    /// Nothing right now, but there may be later
+    Empty,
+    /// Nothing now, and the queue is closed, every writer is gone or
+    /// the stream was evicted, so nothing ever will be
+    Closed,
+}
+
+impl<T> PushError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            PushError::Full(val) | PushError::Paused(val) | PushError::Closed(val) => val,
+        }
+    }
+}
+
+impl<T> fmt::Display for PushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PushError::Full(_) => f.write_str("the queue is full"),
+            PushError::Paused(_) => f.write_str("the queue is paused"),
+            PushError::Closed(_) => f.write_str("the queue is closed"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> Error for PushError<T> {}
+
+/// Where the head and every stream were, for checking up on a queue from outside
+///
+/// The head is read first, so every stream has consumed at least
+/// head - capacity items, though one may have gotten past the head since
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Positions {
+    /// How many slots writers have claimed, ever
+    pub head: u64,
+    /// How many times the head has gone around the ring
+    pub head_wraps: u64,
+    /// Each stream, in the order they were added
+    pub streams: Vec<StreamPosition>,
+    pub capacity: u64,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamPosition {
+    /// How many items the stream has consumed, ever
+    pub consumed: u64,
+    /// How many times it's gone around the ring
+    pub wraps: u64,
+}
+
+/// A stream registered on a queue, as listed by MultiWriter::streams
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamInfo {
+    /// Counting streams from 0 in the order they were added
+    pub id: usize,
+    /// What it was named by MultiStream::add_stream_named, if anything
+    pub name: Option<&'static str>,
+    /// How many items it has consumed, ever
+    pub consumed: u64,
+    /// Roughly how many items it has yet to pop
+    pub lag: u64,
+    /// How many readers and stream handles it has
+    pub consumers: usize,
+    pub evicted: bool,
+}
+
+/// Bytes a queue has asked its allocators for, as reported by memory_usage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The slots items are kept in, padding and all
+    pub ring: usize,
+    /// Every stream's reader and the sets writers check them through
+    pub streams: usize,
+    /// The queue's own bookkeeping, and its router if it has one
+    pub other: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.ring + self.streams + self.other
+    }
+}
 
-#[derive(Clone, Copy)]
+impl Positions {
+    /// How many items the slowest stream has consumed
+    pub fn slowest(&self) -> u64 {
+        self.streams.iter().map(|stream| stream.consumed).min().unwrap_or(self.head)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Meta {
+    /// How long ago the item was published, on queues with timestamps
+    pub fn age(&self) -> Option<Duration> {
+        self.published.map(|at| at.elapsed())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 enum QueueState {
     Single,
     Multi,
+    // Multi, without ever switching back to Single on its own
+    Locked,
 }
 
 struct QueueEntry<T> {
-    val: T,
+    // Only initialized between being written and the last stream reading it
+    val: MaybeUninit<T>,
     wraps: AtomicUsize,
+    // Written alongside val, but only on queues that record timestamps
+    #[cfg(feature = "std")]
+    stamp: MaybeUninit<Instant>,
+    // Written alongside val
+    topic: u8,
 }
 
-/// A bounded queue that supports multiple reader and writers
-/// and supports effecient methods for single consumers and producers
-#[repr(C)]
-struct MultiQueue<T> {
-    d1: [u8; 64],
-
-    // Writer data
+/// What writers read and write on every push
+struct WriterData {
     head: CountedU16,
     tail_cache: AtomicUsize,
     writers: AtomicUsize,
-    d2: [u8; 64],
+}
 
-    // Shared Data
-    // The data and the wraps flag are in the same location
-    // to reduce the # of distinct cache lines read when getting an item
-    // The tail itself is rarely modified, making it a suitable candidate
-    // to be in the shared space
+/// What writers and readers alike read on every push and pop
+///
+/// The data and the wraps flag are in the same location
+/// to reduce the # of distinct cache lines read when getting an item
+/// The tail itself is rarely modified, making it a suitable candidate
+/// to be in the shared space
+struct SharedData<T> {
     tail: ReadCursor,
     data: *mut QueueEntry<T>,
     capacity: isize,
-    d3: [u8; 64],
+    // log2 of the bytes from one entry to the next, if they're padded
+    stride_shift: Option<u32>,
+}
+
+/// A bounded queue that supports multiple reader and writers
+/// and supports effecient methods for single consumers and producers
+#[repr(C)]
+struct MultiQueue<T> {
+    // Each block starts a line of its own, clear of the Arc's counts
+    writes: CachePadded<WriterData>,
+    shared: CachePadded<SharedData<T>>,
+
+    name: &'static str,
+    labels: &'static [(&'static str, &'static str)],
+    metrics: QueueMetrics,
+    waiter: Waiter,
+    alloc: &'static dyn RawAlloc,
+    // Set once the queue is built if it's to never allocate again
+    realtime: bool,
+    delivery: Delivery,
+    timestamps: bool,
+    // Items older than this are dropped rather than popped
+    #[cfg(feature = "std")]
+    ttl: Option<Duration>,
+    // On fair queues, writers that lost the head too often take a ticket,
+    // and get the head to themselves in ticket order
+    fair: bool,
+    // With one writer and one reader, moving the head is what publishes an item
+    spsc: bool,
+    hooks: Armed,
+    tickets: AtomicUsize,
+    serving: AtomicUsize,
+    // Times a writer lost the head to another and had to try again
+    retries: AtomicUsize,
+    // While set, pushes fail and pops carry on
+    paused: AtomicBool,
+    // Once set, pushes fail for good and pops end once the queue drains
+    closed: AtomicBool,
+    // How many streams have async readers waiting on them
+    async_waiting: AtomicUsize,
+    // Set for good once an async reader has waited, and until then
+    // writers don't look for any
+    async_used: AtomicBool,
+    // Asked by each stream about each item, if the queue was built with one
+    router: Option<Box<dyn Router<T>>>,
+}
+
+/// Configuration for a queue, for when `multiqueue(capacity)` isn't enough
+///
+/// ```
+/// use pipeline::queue::multiqueue::{MultiQueueBuilder, WaitStrategy};
+///
+/// let (writer, reader) = MultiQueueBuilder::new(128)
+///     .name("orders")
+///     .labels(&[("shard", "3")])
+///     .wait(WaitStrategy::Park)
+///     .build::<u64>();
+/// ```
+#[derive(Clone, Debug)]
+pub struct MultiQueueBuilder {
+    capacity: u16,
+    name: &'static str,
+    labels: &'static [(&'static str, &'static str)],
+    wait: WaitStrategy,
+    alloc: &'static dyn RawAlloc,
+    // Where streams' readers come from, if not alloc
+    reader_alloc: Option<&'static dyn RawAlloc>,
+    max_streams: Option<usize>,
+    realtime: bool,
+    delivery: Delivery,
+    timestamps: bool,
+    #[cfg(feature = "std")]
+    ttl: Option<Duration>,
+    fair: bool,
+    spsc: bool,
+    pad_entries: bool,
+    hooks: Hooks,
+}
+
+/// The unconsumed contents of a queue along with how far each stream
+/// had gotten into them, as taken by MultiWriter::snapshot
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueSnapshot<T> {
+    /// Every item that some stream hadn't consumed yet, oldest first
+    pub items: Vec<T>,
+    /// For each stream in the order they were added, how many of
+    /// the items it had already consumed
+    pub streams: Vec<usize>,
 }
 
+/// A writer and one reader per stream, as given back by QueueSnapshot::restore
+pub type Restored<T> = (MultiWriter<T>, Vec<MultiReader<T>>);
+
 pub struct MultiWriter<T> {
     queue: Arc<MultiQueue<T>>,
     state: Cell<QueueState>,
+    full: Transition,
 }
 
+/// The queue's one and only writer, from MultiWriter::into_single
+///
+/// It can't be cloned, so no other writer can turn up while it's around.
+/// That lets every push claim its slot without a loop or a CAS: it either
+/// takes the next slot or finds the queue full. A push that races with a
+/// stream being added may find the queue full when it isn't, rather than
+/// scan the streams again
+///
+/// Only the claim is wait-free. Once the item is in, a push still calls
+/// any on_lag hook, which looks over every stream, and wakes readers
+/// waiting on the queue, which can mean taking a lock when they park
+pub struct SingleWriter<T> {
+    writer: MultiWriter<T>,
+}
+
+/// A position in the queue that gets every item written from when it was
+/// added, independently of any other stream
+///
+/// Readers taken from the same stream share its items. Holding a stream
+/// counts as one of its readers, so writers wait on it all the same
+pub struct MultiStream<T> {
+    reader: MultiReader<T>,
+}
+
+/// A slot in the queue taken by MultiWriter::claim, to be filled in by publish
+///
+/// Streams wait at the slot until it's published. Dropping the claim without
+/// publishing, as when a panic unwinds past it, gives the slot up instead:
+/// it's marked skipped and every stream steps over it, so one failed writer
+/// can't hold up the queue.
+pub struct Claim<'a, T: 'a> {
+    writer: &'a MultiWriter<T>,
+    cell: *mut QueueEntry<T>,
+    tag: usize,
+}
+
+/// Pops items from one stream. Clones share the stream's items between them,
+/// each item going to just one; MultiStream::add_stream gives a new stream
 pub struct MultiReader<T> {
     queue: Arc<MultiQueue<T>>,
     reader: AtomicPtr<Reader>,
+    metrics: StreamMetrics,
+    empty: Transition,
+    // How far the head was when an spsc reader last looked
+    head_cache: Cell<usize>,
 }
 
 impl<T> MultiQueue<T> {
     pub fn new(capacity: u16) -> (MultiWriter<T>, MultiReader<T>) {
-        let queuedat = alloc::allocate(capacity as usize);
+        MultiQueueBuilder::new(capacity).build()
+    }
+
+    fn with_config(cfg: &MultiQueueBuilder,
+                   router: Option<Box<dyn Router<T>>>)
+                   -> (MultiWriter<T>, MultiReader<T>) {
+        assert!(!cfg.realtime || cfg.wait == WaitStrategy::Spin,
+                "a realtime queue can only spin while it waits");
+        assert!(!cfg.spsc || cfg.delivery == Delivery::Unicast,
+                "an spsc queue has just the one stream");
+        let capacity = cfg.capacity;
+        let stride_shift = if cfg.pad_entries {
+            Some(mem::size_of::<QueueEntry<T>>().next_power_of_two().trailing_zeros())
+        } else {
+            None
+        };
+        // Zeroed memory already holds a wraps of 0 in every entry, so none of
+        // the ring has to be touched here. On a huge ring, allocators that
+        // map fresh pages leave them to be faulted in as writers get to them
+        let counter = cfg.hooks.counter(cfg.name);
+        let ring_layout = MultiQueue::<T>::ring_layout(capacity, stride_shift);
+        let queuedat = alloc::allocate_zeroed(&counter.tracked(cfg.alloc), ring_layout);
+        let queuedat = queuedat as *mut QueueEntry<T>;
+        // Loom's and shuttle's atomics aren't plain memory, so they're still written
+        #[cfg(any(loom, shuttle))]
         unsafe {
             for i in 0..capacity as isize {
-                let elem: &QueueEntry<T> = &*queuedat.offset(i);
-                elem.wraps.store(0, Relaxed);
+                let elem = MultiQueue::entry_in(queuedat, stride_shift, i);
+                ptr::write(&mut (*elem).wraps, AtomicUsize::new(0));
             }
         }
 
-        let (cursor, reader) = ReadCursor::new(capacity);
+        let (cursor, reader) = ReadCursor::new(capacity,
+                                               cfg.alloc,
+                                               cfg.reader_alloc.unwrap_or(cfg.alloc),
+                                               cfg.max_streams,
+                                               (cfg.name, cfg.labels),
+                                               counter);
 
         let queue = MultiQueue {
-            d1: unsafe { mem::uninitialized() },
-
-            head: CountedU16::new(0, capacity),
-            tail_cache: AtomicUsize::new(0),
-            writers: AtomicUsize::new(1),
-            d2: unsafe { mem::uninitialized() },
-
-            tail: cursor,
-            data: queuedat,
-            capacity: capacity as isize,
+            writes: CachePadded::new(WriterData {
+                head: CountedU16::new(0, capacity),
+                tail_cache: AtomicUsize::new(0),
+                writers: AtomicUsize::new(1),
+            }),
+            shared: CachePadded::new(SharedData {
+                tail: cursor,
+                data: queuedat,
+                capacity: capacity as isize,
+                stride_shift: stride_shift,
+            }),
 
-            d3: unsafe { mem::uninitialized() },
+            name: cfg.name,
+            labels: cfg.labels,
+            metrics: QueueMetrics::new(cfg.name, cfg.labels),
+            waiter: Waiter::new(cfg.wait),
+            alloc: cfg.alloc,
+            realtime: cfg.realtime,
+            delivery: cfg.delivery,
+            timestamps: cfg.timestamps,
+            #[cfg(feature = "std")]
+            ttl: cfg.ttl,
+            fair: cfg.fair,
+            spsc: cfg.spsc,
+            hooks: cfg.hooks.arm(),
+            tickets: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            paused: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            async_waiting: AtomicUsize::new(0),
+            async_used: AtomicBool::new(false),
+            router: router,
         };
 
         let qarc = Arc::new(queue);
+        // The queue itself comes from the global allocator, but counts too
+        qarc.shared.tail.counter().allocated(qarc.own_bytes());
 
         let mwriter = MultiWriter {
             queue: qarc.clone(),
             state: Cell::new(QueueState::Single),
+            full: Transition::new(),
         };
 
         let mreader = MultiReader {
+            metrics: qarc.metrics.stream(0, None),
             queue: qarc,
             reader: reader,
+            empty: Transition::new(),
+            head_cache: Cell::new(0),
         };
 
         (mwriter, mreader)
     }
 
-    pub fn push_multi(&self, val: T) -> Result<(), T> {
-        let mut transaction = self.head.load_transaction(Relaxed);
+    /// Takes the next slot, returning it with the tag that publishes it,
+    /// or None if the queue is full
+    #[inline(always)]
+    fn claim_multi(&self) -> Option<(*mut QueueEntry<T>, usize)> {
+        let mut transaction = self.writes.head.load_transaction(Relaxed);
 
         // This ensures that metadata about the cursor group is in cache
-        self.tail.prefetch_metadata();
+        self.shared.tail.prefetch_metadata();
+        let mut lost = 0;
+        let mut ticket = None;
         unsafe {
             loop {
-                let tail_cache = self.tail_cache.load(Acquire);
-                if transaction.matches_previous(tail_cache) {
-                    if transaction.matches_previous(self.reload_tail_multi(tail_cache)) {
-                        return Err(val);
+                if self.fair && ticket.is_none() && self.serving.load(Relaxed) != self.tickets.load(Relaxed) {
+                    // Make way for the writers holding tickets. This can be
+                    // inside a parked attempt, so it mustn't park itself
+                    while self.serving.load(Acquire) != self.tickets.load(Relaxed) {
+                        self.waiter.pause();
                     }
+                    transaction = self.writes.head.load_transaction(Relaxed);
+                }
+                let tail_cache = self.writes.tail_cache.load(Acquire);
+                if transaction.matches_previous(tail_cache) &&
+                   transaction.matches_previous(self.reload_tail_multi(tail_cache)) {
+                    self.served(ticket);
+                    return None;
                 }
                 // This isize conversion here helps performance on intel
                 // since many (all?) 16-bit register ops incur a 3-cycle decoding penalty
                 // The math works out anyways and the compiler can do it well
                 let chead = transaction.get() as isize;
-                let write_cell = &mut *self.data.offset(chead);
+                let write_cell = self.entry(chead);
                 let wrap_valid_tag = transaction.get_wraps().wrapping_add(1);
                 match transaction.commit(1, Relaxed) {
                     Some(new_transaction) => transaction = new_transaction,
                     None => {
-                        ptr::write(&mut write_cell.val, val);
-                        write_cell.wraps.store(wrap_valid_tag, Release);
-                        return Ok(());
+                        self.served(ticket);
+                        return Some((write_cell, wrap_valid_tag));
+                    }
+                }
+                lost += 1;
+                self.retries.fetch_add(1, Relaxed);
+                if self.fair && lost == STARVING && ticket.is_none() {
+                    let mine = self.tickets.fetch_add(1, Relaxed);
+                    while self.serving.load(Acquire) != mine {
+                        self.waiter.pause();
                     }
+                    ticket = Some(mine);
+                    transaction = self.writes.head.load_transaction(Relaxed);
                 }
             }
         }
     }
 
-    pub fn push_single(&self, val: T) -> Result<(), T> {
-        let transaction = self.head.load_transaction(Relaxed);
+    /// Hands the head on to the next ticket, if this writer held one
+    #[inline(always)]
+    fn served(&self, ticket: Option<usize>) {
+        if let Some(ticket) = ticket {
+            self.serving.store(ticket.wrapping_add(1), Release);
+        }
+    }
+
+    #[inline(always)]
+    fn claim_single(&self) -> Option<(*mut QueueEntry<T>, usize)> {
+        let transaction = self.writes.head.load_transaction(Relaxed);
         let chead = transaction.get() as isize;
-        self.tail.prefetch_metadata();
+        self.shared.tail.prefetch_metadata();
         unsafe {
-            if transaction.matches_previous(self.tail_cache.load(Relaxed)) {
-                if transaction.matches_previous(self.reload_tail_single()) {
-                    return Err(val);
+            if transaction.matches_previous(self.writes.tail_cache.load(Relaxed)) {
+                match self.reload_tail_single() {
+                    Some(tail) if !transaction.matches_previous(tail) => (),
+                    _ => return None,
                 }
             }
-            let write_cell = &mut *self.data.offset(chead);
-            ptr::write(&mut write_cell.val, val);
+            let write_cell = self.entry(chead);
             let wrap_valid_tag = transaction.get_wraps().wrapping_add(1);
-            write_cell.wraps.store(wrap_valid_tag, Release);
+            // Streams go by the slot's tag rather than the head, so the head
+            // can move past the slot before it's filled in
             transaction.commit_direct(1, Relaxed);
-            Ok(())
+            Some((write_cell, wrap_valid_tag))
         }
 
         // Might consider letting the queue update the tail cache here preemptively
         // so it doesn't waste time before sending a message to do so
     }
 
-    pub fn pop(&self, reader: &Reader) -> Option<T> {
+    /// Fills in the next slot and then moves the head past it, which is what
+    /// publishes it on an spsc queue. Slots still get their tags, since
+    /// pop_slice, peek, ready, snapshot and into_inner go by tag on every queue.
+    /// The tag shares a line with the item the writer is writing anyway, so
+    /// leaving it out measures no faster; what spsc saves is the reader
+    /// checking it, and writers racing for the head
+    #[inline(always)]
+    fn push_spsc(&self, topic: u8, val: T) -> Result<usize, T> {
+        let transaction = self.writes.head.load_transaction(Relaxed);
+        if transaction.matches_previous(self.writes.tail_cache.load(Relaxed)) &&
+           self.reload_tail_single().is_none_or(|tail| transaction.matches_previous(tail)) {
+            return Err(val);
+        }
+        unsafe {
+            let cell = self.entry(transaction.get() as isize);
+            let tag = transaction.get_wraps().wrapping_add(1);
+            self.fill(cell, tag, topic, val);
+            transaction.commit_direct(1, Release);
+            Ok(self.seq_of(cell, tag))
+        }
+    }
+
+    /// Fills in a claimed slot and hands it to the streams
+    #[inline(always)]
+    unsafe fn fill(&self, cell: *mut QueueEntry<T>, tag: usize, topic: u8, val: T) {
+        ptr::write(ptr::addr_of_mut!((*cell).val), MaybeUninit::new(val));
+        ptr::write(ptr::addr_of_mut!((*cell).topic), topic);
+        #[cfg(feature = "std")]
+        {
+            if self.timestamps {
+                ptr::write(ptr::addr_of_mut!((*cell).stamp), MaybeUninit::new(Instant::now()));
+            }
+        }
+        (*cell).wraps.store(tag, Release);
+    }
+
+    pub fn push_multi(&self, val: T) -> Result<usize, T> {
+        match self.claim_multi() {
+            Some((cell, tag)) => {
+                unsafe {
+                    self.fill(cell, tag, 0, val);
+                    Ok(self.seq_of(cell, tag))
+                }
+            }
+            None => Err(val),
+        }
+    }
+
+    pub fn push_single(&self, val: T) -> Result<usize, T> {
+        match self.claim_single() {
+            Some((cell, tag)) => {
+                unsafe {
+                    self.fill(cell, tag, 0, val);
+                    Ok(self.seq_of(cell, tag))
+                }
+            }
+            None => Err(val),
+        }
+    }
+
+    /// Whether the count'th item written was published, rather than
+    /// skipped or still claimed
+    unsafe fn published(&self, count: usize) -> bool {
+        let cell = self.entry(count as isize % self.shared.capacity);
+        (*cell).wraps.load(Acquire) == (count / self.shared.capacity as usize).wrapping_add(1)
+    }
+
+    /// Where the run of published or skipped items starting at from ends,
+    /// going no further than head: the first item a writer is still on
+    unsafe fn finished_through(&self, from: usize, head: usize) -> usize {
+        let mut count = from;
+        while head.wrapping_sub(count) as isize > 0 {
+            let cell = self.entry(count as isize % self.shared.capacity);
+            let wrap_valid_tag = (count / self.shared.capacity as usize).wrapping_add(1);
+            if (*cell).wraps.load(Acquire) & !SKIP != wrap_valid_tag {
+                break;
+            }
+            count = count.wrapping_add(1);
+        }
+        count
+    }
+
+    /// How many items the slowest stream has read, as of some point during the call
+    fn slowest(&self) -> usize {
+        loop {
+            let head = self.writes.head.load_count(Acquire);
+            if let Some(diff) = self.shared.tail.get_max_diff(head) {
+                return head.wrapping_sub(diff as usize);
+            }
+        }
+    }
+
+    fn positions(&self) -> Positions {
+        let raw = self.writes.head.load_raw(Acquire);
+        let mut streams = Vec::new();
+        self.shared.tail.for_each_reader(|reader| {
+            let (index, wraps) = reader.load_pos(Acquire);
+            streams.push(StreamPosition {
+                consumed: (index as usize + wraps * self.shared.capacity as usize) as u64,
+                wraps: wraps as u64,
+            });
+        });
+        Positions {
+            head: self.writes.head.count_of(raw) as u64,
+            head_wraps: (raw >> 16) as u64,
+            streams: streams,
+            capacity: self.shared.capacity as u64,
+        }
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            ring: MultiQueue::<T>::ring_layout(self.shared.capacity as u16, self.shared.stride_shift).size(),
+            streams: self.shared.tail.memory_usage(),
+            other: self.own_bytes(),
+        }
+    }
+
+    /// The bytes of the queue and its router, as allocated along with it
+    fn own_bytes(&self) -> usize {
+        // The Arc's counts sit alongside the queue
+        let mut bytes = mem::size_of::<MultiQueue<T>>() + 2 * mem::size_of::<usize>();
+        if let Some(ref router) = self.router {
+            bytes += mem::size_of_val(&**router);
+        }
+        bytes
+    }
+
+    fn streams(&self) -> Vec<StreamInfo> {
+        let head = self.writes.head.load_count(Acquire);
+        let mut streams = Vec::new();
+        self.shared.tail.for_each_reader(|reader| {
+            let consumed = reader.load_nread(Acquire);
+            streams.push(StreamInfo {
+                id: reader.id(),
+                name: reader.name(),
+                consumed: consumed as u64,
+                // A stale head can trail what the stream has already popped
+                lag: cmp::max(head.wrapping_sub(consumed) as isize, 0) as u64,
+                consumers: reader.consumers(),
+                evicted: reader.is_evicted(),
+            });
+        });
+        streams
+    }
+
+    /// Copies out the published items some stream has yet to read, leaving
+    /// every stream where it is. Items that every stream reads while this
+    /// runs may be left out, since their slots can be written over
+    fn peek(&self) -> Vec<T>
+        where T: Copy
+    {
+        let head = self.writes.head.load_count(Acquire);
+        let tail = self.slowest();
+        let mut items = Vec::new();
+        for count in (0..head.wrapping_sub(tail)).map(|i| tail.wrapping_add(i)) {
+            unsafe {
+                if !self.published(count) {
+                    continue;
+                }
+                let cell = self.entry(count as isize % self.shared.capacity);
+                let val = ptr::read(ptr::addr_of!((*cell).val));
+                // As in a seqlock, the copy only counts if the slot wasn't
+                // freed up for writers while it was being made
+                fence(Acquire);
+                if self.slowest().wrapping_sub(count) as isize <= 0 {
+                    items.push(val.assume_init());
+                }
+            }
+        }
+        items
+    }
+
+    /// Moves out every published item that no stream has read yet, marking
+    /// their slots skipped. Nothing else can be using the queue
+    unsafe fn take_unread(&self) -> Vec<T> {
+        // One that any stream has popped belongs to whoever popped it
+        let head = self.writes.head.load_count(Acquire);
+        let mut read = 0;
+        self.shared.tail.for_each_reader(|reader| read = cmp::max(read, reader.load_nread(Acquire)));
+        (read..head)
+            .filter(|&count| self.published(count))
+            .map(|count| {
+                let cell = self.entry(count as isize % self.shared.capacity);
+                (*cell).wraps.fetch_or(SKIP, Relaxed);
+                ptr::read((*cell).val.as_ptr())
+            })
+            .collect()
+    }
+
+    /// Pops the reader's next item along with what's known about it
+    pub fn pop(&self, reader: &Reader) -> Option<(T, Meta)> {
         let mut ctail_attempt = reader.load_attempt(Relaxed);
         unsafe {
             loop {
                 let ctail = ctail_attempt.get() as isize;
-                let read_cell = &*self.data.offset(ctail);
+                let read_cell = self.entry(ctail);
                 let wrap_valid_tag = ctail_attempt.get_wraps().wrapping_add(1);
-                if read_cell.wraps.load(MAYBE_ACQUIRE) != wrap_valid_tag {
+                let tag = (*read_cell).wraps.load(MAYBE_ACQUIRE);
+                if tag == wrap_valid_tag | SKIP {
+                    ctail_attempt = match ctail_attempt.commit_attempt(1, Release) {
+                        Some(new_attempt) => new_attempt,
+                        None => reader.load_attempt(Relaxed),
+                    };
+                    continue;
+                }
+                if tag != wrap_valid_tag {
                     return None;
                 }
                 maybe_acquire_fence();
-                let rval = ptr::read(&read_cell.val);
+                // Copied out uninterpreted, since the slot may be written over
+                // again if another consumer of this stream takes it first
+                let rval = ptr::read(ptr::addr_of!((*read_cell).val));
+                let meta = self.meta(read_cell, self.seq(ctail_attempt.get_wraps(), ctail));
                 match ctail_attempt.commit_attempt(1, Release) {
                     Some(new_attempt) => ctail_attempt = new_attempt,
-                    None => return Some(rval),
+                    // If the stream was evicted under us, writers weren't
+                    // waiting on it and the copy may be torn
+                    None if reader.is_evicted() => return None,
+                    None => return Some((rval.assume_init(), meta)),
                 }
             }
         }
     }
 
-    fn reload_tail_multi(&self, tail_cache: usize) -> usize {
-        // This shows how far behind from head the reader is
-        if let Some(max_diff_from_head) = self.tail.get_max_diff(self.head.load_count(Relaxed)) {
-            let current_tail = self.head.get_previous(max_diff_from_head);
-            match self.tail_cache.compare_exchange(tail_cache, current_tail, Relaxed, Acquire) {
-                Ok(val) => val,
-                Err(val) => val,
+    /// Whether an item has outlived the queue's ttl
+    #[inline(always)]
+    fn expired(&self, meta: &Meta) -> bool {
+        #[cfg(feature = "std")]
+        {
+            if let (Some(ttl), Some(published)) = (self.ttl, meta.published) {
+                return published.elapsed() > ttl;
             }
-        } else {
-            self.tail_cache.load(Acquire)
         }
+        let _ = meta;
+        false
     }
 
-    fn reload_tail_single(&self) -> usize {
-        if let Some(max_diff_from_head) = self.tail.get_max_diff(self.head.load_count(Relaxed)) {
-            let current_tail = self.head.get_previous(max_diff_from_head);
-            self.tail_cache.store(current_tail, Relaxed);
-            current_tail
-        } else {
-            // If this assert fires, memory has been corrupted
-            assert!(false,
-                    "The write head got ran over by consumers in isngle writer mode");
-            0
+    /// What's known about the item in cell, which has to be read before
+    /// the cell can be written over
+    #[inline(always)]
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    unsafe fn meta(&self, cell: *mut QueueEntry<T>, seq: usize) -> Meta {
+        Meta {
+            seq: seq,
+            #[cfg(feature = "std")]
+            published: if self.timestamps {
+                Some(ptr::read(ptr::addr_of!((*cell).stamp)).assume_init())
+            } else {
+                None
+            },
+            topic: ptr::read(ptr::addr_of!((*cell).topic)),
         }
     }
-}
 
-impl<T> MultiWriter<T> {
-    pub fn push(&self, val: T) -> Result<(), T> {
-        match self.state.get() {
-            QueueState::Single => self.queue.push_single(val),
-            QueueState::Multi => {
-                // This doesn't use the maybe_acquire framework since
-                // it is so rarely acquire that it makes sense to incur
-                // the rare extra cost of an acquire fence on architectures where it matters
-                if self.queue.writers.load(Relaxed) == 1 {
-                    fence(Acquire);
-                    self.state.set(QueueState::Single);
-                    self.queue.push_single(val)
-                } else {
-                    self.queue.push_multi(val)
-                }
+    /// Whether pops on reader have items to look past, for being too old
+    /// or on topics the stream doesn't take
+    #[inline(always)]
+    fn filtered(&self, reader: &Reader) -> bool {
+        #[cfg(feature = "std")]
+        {
+            if self.ttl.is_some() {
+                return true;
             }
         }
+        self.router.is_some() || reader.topics() != ALL_TOPICS
     }
-}
 
-impl<T> MultiReader<T> {
-    pub fn pop(&self) -> Option<T> {
-        unsafe { self.queue.pop(&*self.reader.load(Relaxed)) }
+    /// Whether the item goes to reader's stream, by its topic and the router
+    #[inline(always)]
+    fn routed(&self, reader: &Reader, item: &T, meta: &Meta) -> bool {
+        if !reader.subscribed(meta.topic) {
+            return false;
+        }
+        match self.router {
+            Some(ref router) => {
+                let route = Route {
+                    stream: reader.id(),
+                    name: reader.name(),
+                    streams: self.shared.tail.streams(),
+                };
+                router.routes(item, meta, route)
+            }
+            None => true,
+        }
     }
 
-    pub fn add_reader(&self) -> MultiReader<T> {
-        MultiReader {
-            queue: self.queue.clone(),
-            reader: unsafe { self.queue.tail.add_reader(&*self.reader.load(Relaxed)) },
+    /// Copies the run of published items reader is at into buf, as many as
+    /// fit, and moves reader past them all at once
+    fn pop_slice(&self, reader: &Reader, buf: &mut [T]) -> usize
+        where T: Copy
+    {
+        let max = cmp::min(buf.len(), self.shared.capacity as usize);
+        let mut ctail_attempt = reader.load_attempt(Relaxed);
+        unsafe {
+            loop {
+                let start = ctail_attempt.get() as isize;
+                let (mut index, mut valid_tag) = (start, ctail_attempt.get_wraps().wrapping_add(1));
+                let mut run = 0;
+                while run < max && (*self.entry(index)).wraps.load(MAYBE_ACQUIRE) == valid_tag {
+                    run += 1;
+                    index += 1;
+                    if index == self.shared.capacity {
+                        index = 0;
+                        valid_tag = valid_tag.wrapping_add(1);
+                    }
+                }
+                if run == 0 {
+                    // Nothing to copy, unless the run starts with a skipped slot
+                    // that pop knows to step over
+                    return match self.pop(reader) {
+                        Some((val, _)) if max > 0 => {
+                            buf[0] = val;
+                            1
+                        }
+                        _ => 0,
+                    };
+                }
+                maybe_acquire_fence();
+                // Copied in up to two pieces, as the run can wrap around the ring.
+                // If another consumer of the stream commits first these are
+                // overwritten on the next attempt
+                let first = cmp::min(run, (self.shared.capacity - start) as usize);
+                for (i, slot) in buf[..first].iter_mut().enumerate() {
+                    *slot = ptr::read((*self.entry(start + i as isize)).val.as_ptr());
+                }
+                for (i, slot) in buf[first..run].iter_mut().enumerate() {
+                    *slot = ptr::read((*self.entry(i as isize)).val.as_ptr());
+                }
+                match ctail_attempt.commit_attempt(run as u16, Release) {
+                    Some(new_attempt) => ctail_attempt = new_attempt,
+                    None if reader.is_evicted() => return 0,
+                    None => return run,
+                }
+            }
         }
     }
-}
 
-impl<T> Clone for MultiWriter<T> {
-    fn clone(&self) -> MultiWriter<T> {
-        self.state.set(QueueState::Multi);
-        let rval = MultiWriter {
-            queue: self.queue.clone(),
-            state: Cell::new(QueueState::Multi),
-        };
-        self.queue.writers.fetch_add(1, Release);
-        rval
+    /// Whether pop would find an item for reader, going only by the wraps tags.
+    /// Skipped slots are looked past, as pop would
+    #[inline(always)]
+    fn ready(&self, reader: &Reader) -> bool {
+        let (mut index, mut wraps) = reader.load_pos(Relaxed);
+        for _ in 0..self.shared.capacity {
+            let valid_tag = wraps.wrapping_add(1);
+            let tag = unsafe { (*self.entry(index as isize)).wraps.load(Relaxed) };
+            if tag != valid_tag | SKIP {
+                return tag == valid_tag;
+            }
+            index += 1;
+            if index as isize == self.shared.capacity {
+                index = 0;
+                wraps = wraps.wrapping_add(1);
+            }
+        }
+        false
     }
-}
 
-impl<T> Clone for MultiReader<T> {
-    fn clone(&self) -> MultiReader<T> {
-        let reader = self.reader.load(Relaxed);
-        let rval = MultiReader {
-            queue: self.queue.clone(),
-            reader: AtomicPtr::new(reader),
+    /// The sequence number of what goes in cell index on the given wrap around the ring
+    #[inline(always)]
+    fn seq(&self, wraps: usize, index: isize) -> usize {
+        wraps.wrapping_mul(self.shared.capacity as usize).wrapping_add(index as usize)
+    }
+
+    /// The sequence number of a claimed cell
+    #[inline(always)]
+    unsafe fn seq_of(&self, cell: *mut QueueEntry<T>, tag: usize) -> usize {
+        let index = match self.shared.stride_shift {
+            Some(shift) => ((cell as usize - self.shared.data as usize) >> shift) as isize,
+            None => cell.offset_from(self.shared.data),
         };
-        unsafe {
-            (*reader).dup_consumer();
+        self.seq(tag.wrapping_sub(1), index)
+    }
+
+    /// The entry at index in the ring
+    #[inline(always)]
+    unsafe fn entry(&self, index: isize) -> *mut QueueEntry<T> {
+        MultiQueue::entry_in(self.shared.data, self.shared.stride_shift, index)
+    }
+
+    #[inline(always)]
+    unsafe fn entry_in(data: *mut QueueEntry<T>,
+                       stride_shift: Option<u32>,
+                       index: isize)
+                       -> *mut QueueEntry<T> {
+        match stride_shift {
+            // A shift rather than a multiply by an awkward size
+            Some(shift) => (data as *mut u8).offset(index << shift) as *mut QueueEntry<T>,
+            None => data.offset(index),
         }
-        rval
     }
-}
 
-impl<T> Drop for MultiWriter<T> {
-    fn drop(&mut self) {
-        self.queue.writers.fetch_sub(1, Release);
+    /// How the ring is laid out in memory. Padded entries are aligned to
+    /// their stride, up to a cache line, so none straddles two lines
+    fn ring_layout(capacity: u16, stride_shift: Option<u32>) -> Layout {
+        match stride_shift {
+            Some(shift) => {
+                let align = cmp::max(mem::align_of::<QueueEntry<T>>(), cmp::min(1 << shift, 64));
+                Layout::from_size_align((capacity as usize) << shift, align).expect("allocation too large")
+            }
+            None => Layout::array::<QueueEntry<T>>(capacity as usize).expect("allocation too large"),
+        }
     }
-}
 
-impl<T> Drop for MultiReader<T> {
-    fn drop(&mut self) {
-        unsafe { (*self.reader.load(Relaxed)).remove_consumer() }
+    fn labels(&self) -> trace::Labels {
+        (self.name, self.labels)
     }
-}
 
-unsafe impl<T> Sync for MultiQueue<T> {}
-unsafe impl<T> Send for MultiQueue<T> {}
-unsafe impl<T> Send for MultiWriter<T> {}
-unsafe impl<T> Send for MultiReader<T> {}
+    /// Wakes whoever's waiting for an item, in pop_wait or async
+    #[inline(always)]
+    fn notify(&self) {
+        self.waiter.notify();
+        if !self.async_used.load(Relaxed) {
+            return;
+        }
+        // Pairs with the fence in WakerList::register
+        fence(SeqCst);
+        if self.async_waiting.load(Relaxed) != 0 {
+            self.shared.tail.for_each_reader(|reader| reader.wakers().wake(&self.async_waiting));
+        }
+    }
 
-pub fn multiqueue<T>(capacity: u16) -> (MultiWriter<T>, MultiReader<T>) {
-    MultiQueue::new(capacity)
-}
+    /// Adds waker to those waiting on reader's stream. Returns true for the
+    /// queue's first async reader, which a writer publishing right then may
+    /// not have known to look for
+    fn wait_async(&self, reader: &Reader, waker: &Waker) -> bool {
+        let first = !self.async_used.load(Relaxed) && !self.async_used.swap(true, SeqCst);
+        reader.wakers().register(waker, &self.async_waiting);
+        first
+    }
 
-#[cfg(test)]
-mod test {
+    fn pause(&self) {
+        self.paused.store(true, Release);
+    }
 
-    use super::*;
-    use super::MultiQueue;
+    fn resume(&self) {
+        self.paused.store(false, Release);
+        // Writers in push_wait are waiting on the pause as much as on room
+        self.notify();
+    }
 
-    extern crate crossbeam;
-    use self::crossbeam::scope;
+    #[inline(always)]
+    fn is_paused(&self) -> bool {
+        self.paused.load(Relaxed)
+    }
 
-    use std::sync::atomic::Ordering::*;
+    fn close(&self) {
+        self.closed.store(true, Release);
+        self.notify();
+    }
 
-    use std::sync::Barrier;
+    /// Whether nothing more will be pushed, because the queue was closed
+    /// or every writer is gone
+    fn is_closed(&self) -> bool {
+        self.closed.load(Acquire) || self.writes.writers.load(Acquire) == 0
+    }
 
-    #[test]
-    fn build_queue() {
-        let _ = MultiQueue::<usize>::new(10);
+    /// Whether every stream has lost all its consumers, so nothing will pop again
+    fn abandoned(&self) -> bool {
+        let mut consumers = 0;
+        self.shared.tail.for_each_reader(|reader| consumers += reader.consumers());
+        consumers == 0
     }
 
-    #[test]
-    fn push_pop_test() {
-        let (writer, reader) = MultiQueue::<usize>::new(1);
-        for _ in 0..100 {
-            assert!(reader.pop().is_none());
-            writer.push(1 as usize).expect("Push should succeed");
-            assert!(writer.push(1).is_err());
-            assert_eq!(1, reader.pop().unwrap());
+    fn record_push(&self) {
+        if metrics::ENABLED {
+            let tail = self.writes.head.count_of(self.writes.tail_cache.load(Relaxed));
+            self.metrics.record_push(self.writes.head.load_count(Relaxed).wrapping_sub(tail));
+        }
+        if let Some(threshold) = self.hooks.lag_threshold() {
+            self.check_lag(threshold);
         }
     }
 
-    fn spsc_broadcast(receivers: usize) {
-        let (writer, reader) = MultiQueue::<usize>::new(10);
-        let myb = Barrier::new(receivers + 1);
-        let bref = &myb;
-        let num_loop = 1000000;
-        scope(|scope| {
-            scope.spawn(move || {
-                bref.wait();
-                'outer: for i in 0..num_loop {
-                    loop {
+    /// Calls on_lag with the stream furthest behind if it's past threshold
+    fn check_lag(&self, threshold: usize) {
+        let head = self.writes.head.load_count(Relaxed);
+        // The cached tail never runs ahead of the slowest stream, so
+        // there's no looking at the streams unless it's far enough behind
+        let cached = self.writes.head.count_of(self.writes.tail_cache.load(Relaxed));
+        if head.wrapping_sub(cached) < threshold || !self.hooks.lag_due() {
+            return;
+        }
+        let mut slowest = None;
+        self.shared.tail.for_each_reader(|reader| {
+            if reader.is_evicted() {
+                return;
+            }
+            let lag = head.wrapping_sub(reader.load_nread(Relaxed));
+            // A stream that read past a stale head isn't behind at all
+            if lag <= self.shared.capacity as usize && slowest.is_none_or(|(_, most)| lag > most) {
+                slowest = Some((reader.id(), lag));
+            }
+        });
+        if let Some((stream, lag)) = slowest {
+            if lag >= threshold {
+                self.hooks.lag(self.name, stream, lag);
+            }
+        }
+    }
+
+    /// Evicts every stream that's been behind without moving for longer than
+    /// the queue allows, for when a push finds the queue full, and adds them
+    /// to evicted. It can run inside a parked attempt, so it wakes only async
+    /// readers and leaves the hook to be run on evicted once that's over
+    fn evict_idle(&self, evicted: &mut Vec<usize>) {
+        let (now, timeout) = match self.hooks.idle_clock() {
+            Some(clock) => clock,
+            None => return,
+        };
+        let head = self.writes.head.load_count(Acquire);
+        self.shared.tail.for_each_reader(|reader| {
+            if reader.is_evicted() {
+                return;
+            }
+            let nread = reader.load_nread(Acquire);
+            // A stream that read past a stale head is caught up
+            let behind = if head.wrapping_sub(nread) as isize > 0 { Some(nread) } else { None };
+            if reader.idle_for(behind, now) > timeout && self.evict_reader(reader) {
+                evicted.push(reader.id());
+            }
+        });
+    }
+
+    fn run_evicted(&self, evicted: Vec<usize>) {
+        for stream in evicted {
+            self.hooks.evicted(self.name, stream);
+        }
+    }
+
+    /// Evicts the stream with the given id, waking everyone so writers can
+    /// use the room and its readers see it's gone. Returns whether there was
+    /// such a stream that wasn't evicted already
+    fn evict(&self, stream: usize) -> bool {
+        let mut evicted = false;
+        self.shared.tail.for_each_reader(|reader| {
+            if reader.id() == stream {
+                evicted = self.evict_reader(reader);
+            }
+        });
+        if evicted {
+            self.notify();
+        }
+        evicted
+    }
+
+    fn evict_reader(&self, reader: &Reader) -> bool {
+        if !reader.evict() {
+            return false;
+        }
+        trace::stream_evicted(self.labels(), reader.id());
+        reader.wakers().wake(&self.async_waiting);
+        true
+    }
+
+    /// How many slots are free with the slowest stream at tail, as stored in tail_cache
+    fn space(&self, tail: usize) -> usize {
+        let used = self.writes.head.load_count(Relaxed).wrapping_sub(self.writes.head.count_of(tail));
+        (self.shared.capacity as usize).saturating_sub(used)
+    }
+
+    fn reload_tail_multi(&self, tail_cache: usize) -> usize {
+        // This shows how far behind from head the reader is
+        if let Some(max_diff_from_head) = self.shared.tail.get_max_diff(self.writes.head.load_count(Relaxed)) {
+            let current_tail = self.writes.head.get_previous(max_diff_from_head);
+            match self.writes.tail_cache.compare_exchange(tail_cache, current_tail, Relaxed, Acquire) {
+                Ok(_) => current_tail,
+                Err(val) => val,
+            }
+        } else {
+            self.writes.tail_cache.load(Acquire)
+        }
+    }
+
+    /// Scans the streams just the once, so the single writer never loops.
+    /// Returns None if a stream was added during the scan, which the
+    /// writer takes as the queue being full for now
+    fn reload_tail_single(&self) -> Option<usize> {
+        match self.shared.tail.get_max_diff_once(self.writes.head.load_count(Relaxed)) {
+            Some(Some(max_diff_from_head)) => {
+                let current_tail = self.writes.head.get_previous(max_diff_from_head);
+                self.writes.tail_cache.store(current_tail, Relaxed);
+                Some(current_tail)
+            }
+            Some(None) => {
+                // If this assert fires, memory has been corrupted
+                assert!(false,
+                        "The write head got ran over by consumers in isngle writer mode");
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+impl MultiQueueBuilder {
+    pub fn new(capacity: u16) -> MultiQueueBuilder {
+        MultiQueueBuilder {
+            capacity: capacity,
+            name: "multiqueue",
+            labels: &[],
+            wait: WaitStrategy::default(),
+            alloc: &HEAP,
+            reader_alloc: None,
+            max_streams: None,
+            realtime: false,
+            delivery: Delivery::Broadcast,
+            timestamps: false,
+            #[cfg(feature = "std")]
+            ttl: None,
+            fair: false,
+            spsc: false,
+            pad_entries: false,
+            hooks: Hooks::new(),
+        }
+    }
+
+    pub fn capacity(mut self, capacity: u16) -> MultiQueueBuilder {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Names the queue in Debug output, metrics, and tracing events
+    pub fn name(mut self, name: &'static str) -> MultiQueueBuilder {
+        self.name = name;
+        self
+    }
+
+    /// Extra key/value pairs attached alongside the name
+    pub fn labels(mut self, labels: &'static [(&'static str, &'static str)]) -> MultiQueueBuilder {
+        self.labels = labels;
+        self
+    }
+
+    /// How push_wait and pop_wait wait, yielding by default
+    pub fn wait(mut self, wait: WaitStrategy) -> MultiQueueBuilder {
+        self.wait = wait;
+        self
+    }
+
+    /// Where the ring and the streams' bookkeeping are allocated from,
+    /// rather than the global allocator
+    pub fn allocator(mut self, alloc: &'static dyn RawAlloc) -> MultiQueueBuilder {
+        self.alloc = alloc;
+        self
+    }
+
+    /// Allocates each stream's reader, which every writer checks on its way
+    /// around the ring, on the NUMA node of the thread that adds the stream,
+    /// with a page to itself. Worth it when streams are added from consumer
+    /// threads pinned across nodes; the first stream belongs to whoever builds
+    #[cfg(feature = "affinity")]
+    pub fn local_readers(mut self) -> MultiQueueBuilder {
+        self.reader_alloc = Some(&::util::affinity::NODE_LOCAL);
+        self
+    }
+
+    /// Allocates room for max streams when the queue is built, so adding a
+    /// stream later never allocates or swaps out the set writers check.
+    /// Adding more than max streams panics, and a stream's slot isn't given
+    /// back when it's dropped
+    pub fn max_streams(mut self, max: usize) -> MultiQueueBuilder {
+        assert!(max > 0, "a queue needs room for at least one stream");
+        self.max_streams = Some(max);
+        self
+    }
+
+    /// Sets the queue up so that once it's built, nothing that pushes or pops
+    /// allocates, parks or makes a syscall. Waiting only spins, and since a
+    /// new stream means allocating, every stream has to come from build_streams
+    /// unless max_streams set aside room for them.
+    /// Metrics and tracing, when enabled, still call into whatever recorder
+    /// or subscriber is installed
+    pub fn realtime(mut self) -> MultiQueueBuilder {
+        self.realtime = true;
+        self.wait = WaitStrategy::Spin;
+        self
+    }
+
+    /// Declares that the queue has one writer and one reader for good, so
+    /// pushing only has to move the head and popping only has to look at
+    /// it now and then. The writer and reader can't be cloned, there's only
+    /// the one stream, and items can only be pushed, not claimed
+    pub fn spsc(mut self) -> MultiQueueBuilder {
+        self.spsc = true;
+        self.delivery = Delivery::Unicast;
+        self
+    }
+
+    /// Pads each slot in the ring out to the next power of two bytes, so
+    /// finding one is a shift rather than a multiply and a slot of 64 bytes
+    /// or less never straddles two cache lines. Worth it for items of
+    /// awkward sizes, at the cost of the memory the padding takes
+    pub fn pad_entries(mut self) -> MultiQueueBuilder {
+        self.pad_entries = true;
+        self
+    }
+
+    /// Calls f with the queue's name and the bytes allocated every time the
+    /// queue allocates or frees memory, negative for frees, so memory can be
+    /// put down to the queue using it. It's called from within the queue,
+    /// so it mustn't call back into it
+    #[cfg(feature = "std")]
+    pub fn on_alloc<F>(mut self, f: F) -> MultiQueueBuilder
+        where F: Fn(&'static str, isize) + Send + Sync + 'static
+    {
+        self.hooks.on_alloc(::std::sync::Arc::new(f));
+        self
+    }
+
+    /// Calls f with the queue's name when a push finds the queue full,
+    /// at most once per hook_interval
+    #[cfg(feature = "std")]
+    pub fn on_full<F>(mut self, f: F) -> MultiQueueBuilder
+        where F: Fn(&'static str) + Send + Sync + 'static
+    {
+        self.hooks.on_full(::std::sync::Arc::new(f));
+        self
+    }
+
+    /// Calls f with the queue's name, a stream and its lag when a push leaves
+    /// that stream at least threshold items behind, at most once per hook_interval
+    #[cfg(feature = "std")]
+    pub fn on_lag<F>(mut self, threshold: usize, f: F) -> MultiQueueBuilder
+        where F: Fn(&'static str, usize, usize) + Send + Sync + 'static
+    {
+        self.hooks.on_lag(threshold, ::std::sync::Arc::new(f));
+        self
+    }
+
+    /// Evicts a stream that's been behind for timeout without popping
+    /// anything, calling f with the queue's name and the stream, so a
+    /// consumer that's deadlocked or stuck on I/O can't hold the queue up
+    /// for good. Writers stop waiting on an evicted stream, and its readers
+    /// find it closed from then on, losing whatever they were popping
+    ///
+    /// Streams are only looked at when a push finds the queue full, so a
+    /// writer parked in push_wait won't notice one until something wakes it
+    #[cfg(feature = "std")]
+    pub fn evict_idle<F>(mut self, timeout: Duration, f: F) -> MultiQueueBuilder
+        where F: Fn(&'static str, usize) + Send + Sync + 'static
+    {
+        self.hooks.evict_idle(timeout, ::std::sync::Arc::new(f));
+        self
+    }
+
+    /// How often on_full and on_lag can each be called at most, once a second by default
+    #[cfg(feature = "std")]
+    pub fn hook_interval(mut self, interval: Duration) -> MultiQueueBuilder {
+        self.hooks.interval(interval);
+        self
+    }
+
+    /// Makes sure every writer gets its turn under contention. A writer that
+    /// keeps losing the race for the next slot to others gets it once each
+    /// of them has taken at most one more, at the cost of a check per push
+    pub fn fair(mut self) -> MultiQueueBuilder {
+        self.fair = true;
+        self
+    }
+
+    /// Whether streams can be added to the queue, broadcasting by default.
+    /// Adding a stream to a unicast queue panics
+    pub fn delivery(mut self, delivery: Delivery) -> MultiQueueBuilder {
+        self.delivery = delivery;
+        self
+    }
+
+    /// Records when each item is published, for pop_with_meta to hand
+    /// back. Costs a clock read on every push
+    #[cfg(feature = "std")]
+    pub fn timestamps(mut self) -> MultiQueueBuilder {
+        self.timestamps = true;
+        self
+    }
+
+    /// Drops items that have been in the queue longer than ttl when they
+    /// come up to be popped, rather than handing them out. Turns on timestamps
+    #[cfg(feature = "std")]
+    pub fn ttl(mut self, ttl: Duration) -> MultiQueueBuilder {
+        self.ttl = Some(ttl);
+        self.timestamps = true;
+        self
+    }
+
+    /// Whether queues built this way can throw items away without every
+    /// stream popping them, by evicting idle streams or expiring old items
+    pub fn discards(&self) -> bool {
+        #[cfg(feature = "std")]
+        let expires = self.ttl.is_some();
+        #[cfg(not(feature = "std"))]
+        let expires = false;
+        expires || self.hooks.evicts()
+    }
+
+    pub fn build<T>(&self) -> (MultiWriter<T>, MultiReader<T>) {
+        MultiQueue::with_config(self, None)
+    }
+
+    /// Builds a queue whose streams each get only the items router routes
+    /// to them, as decided when they pop. See the route module
+    pub fn build_routed<T, R>(&self, router: R) -> (MultiWriter<T>, MultiReader<T>)
+        where R: Router<T> + 'static
+    {
+        MultiQueue::with_config(self, Some(Box::new(router)))
+    }
+
+    /// Builds the queue with its streams all registered up front, each
+    /// starting at the beginning. Panics if streams is 0
+    pub fn build_streams<T>(&self, streams: usize) -> (MultiWriter<T>, Vec<MultiStream<T>>) {
+        assert!(streams > 0, "a queue needs at least one stream");
+        assert!(streams == 1 || self.delivery == Delivery::Broadcast,
+                "a unicast queue has just the one stream");
+        let (writer, reader) = self.build();
+        let mut built = Vec::with_capacity(streams);
+        for _ in 1..streams {
+            built.push(MultiStream { reader: reader.register_stream(None) });
+        }
+        built.insert(0, MultiStream { reader: reader });
+        (writer, built)
+    }
+}
+
+impl<T> MultiWriter<T> {
+    /// Returns the item's sequence number, counting every item ever pushed
+    /// from 0, or gives val back if the queue is full
+    pub fn push(&self, val: T) -> Result<usize, T> {
+        self.push_topic(0, val)
+    }
+
+    /// Pushes val to one of 64 topics, for streams that subscribe to only
+    /// some of them. push goes to topic 0. Panics if topic is 64 or more
+    pub fn push_topic(&self, topic: u8, val: T) -> Result<usize, T> {
+        assert!(topic < 64, "topic {} is out of range, there are 64", topic);
+        let rval = self.push_inner(topic, val);
+        if rval.is_ok() {
+            self.queue.record_push();
+            self.queue.notify();
+        }
+        self.pushed(rval.is_ok());
+        rval
+    }
+
+    #[inline(always)]
+    fn pushed(&self, ok: bool) {
+        if !ok && (self.queue.is_paused() || self.queue.closed.load(Relaxed)) {
+            return;
+        }
+        if !ok {
+            self.queue.hooks.full(self.queue.name);
+            let mut evicted = Vec::new();
+            self.queue.evict_idle(&mut evicted);
+            self.queue.run_evicted(evicted);
+        }
+        self.full.push(self.queue.labels(), ok);
+    }
+
+    /// Like push, but says whether val came back because the queue was full
+    /// or because it's paused
+    pub fn try_push(&self, val: T) -> Result<usize, PushError<T>> {
+        self.push(val).map_err(|val| {
+            if self.queue.closed.load(Relaxed) {
+                PushError::Closed(val)
+            } else if self.queue.is_paused() {
+                PushError::Paused(val)
+            } else {
+                PushError::Full(val)
+            }
+        })
+    }
+
+    /// Stops the queue taking items until resume is called, for maintenance
+    /// or while swapping out a consumer. Pushes and claims fail, push_wait
+    /// waits, and readers carry on popping what's already in the queue.
+    /// A push that's already under way when this is called may still go in
+    pub fn pause(&self) {
+        self.queue.pause();
+    }
+
+    pub fn resume(&self) {
+        self.queue.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.queue.is_paused()
+    }
+
+    /// Closes the queue for good: pushes fail from then on, and once readers
+    /// have popped what's left they find it closed, as if every writer were
+    /// gone. A push already under way may still go in
+    pub fn close(&self) {
+        self.queue.close();
+    }
+
+    /// Every stream registered on the queue, in the order they were added
+    pub fn streams(&self) -> Vec<StreamInfo> {
+        self.queue.streams()
+    }
+
+    /// How much memory the queue has taken, for capacity planning
+    /// and accounting. The same from any writer or reader on it
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.queue.memory_usage()
+    }
+
+    /// Everything the queue has allocated and freed since it was built,
+    /// counting its own struct but not async readers' wakers
+    pub fn allocations(&self) -> Allocations {
+        self.queue.shared.tail.counter().load()
+    }
+
+    /// The first stream added with the given name
+    pub fn stream_named(&self, name: &str) -> Option<StreamInfo> {
+        self.queue.streams().into_iter().find(|stream| stream.name == Some(name))
+    }
+
+    /// Takes the stream with the given id out of the queue, for when its
+    /// consumer is given up for dead: writers stop waiting on it, and its
+    /// readers find it closed, losing whatever they hadn't popped. Returns
+    /// false if there's no such stream or it was evicted already
+    pub fn evict(&self, stream: usize) -> bool {
+        self.queue.evict(stream)
+    }
+
+    /// Pushes val even when the queue is full, popping the oldest items off
+    /// oldest to make room and adding them to evicted. Returns the item's
+    /// sequence number
+    ///
+    /// oldest is a reader of the queue's stream, usually a clone kept for
+    /// the purpose. Panics if the queue has more than one stream, since
+    /// an item's slot isn't free until every stream has popped it. Gives
+    /// val back if the queue is paused or closed, and as Full if the oldest
+    /// slot is claimed but not yet published, or other writers took the
+    /// room made for a whole lap of evictions
+    pub fn force_push(&self,
+                      val: T,
+                      oldest: &MultiReader<T>,
+                      evicted: &mut Vec<T>)
+                      -> Result<usize, PushError<T>> {
+        assert!(Arc::ptr_eq(&self.queue, &oldest.queue), "oldest reads a different queue");
+        let mut streams = 0;
+        self.queue.shared.tail.for_each_reader(|_| streams += 1);
+        assert!(streams == 1, "only a queue with one stream can evict to make room");
+        let mut val = val;
+        for _ in 0..self.queue.shared.capacity {
+            match self.try_push(val) {
+                Err(PushError::Full(back)) => {
+                    match oldest.pop() {
+                        Some(old) => evicted.push(old),
+                        None => return Err(PushError::Full(back)),
+                    }
+                    val = back;
+                }
+                rval => return rval,
+            }
+        }
+        self.try_push(val)
+    }
+
+    /// Pushes val, waiting for room as the queue was built to. Gives val
+    /// back if every reader is gone, since nothing would ever make room
+    pub fn push_wait(&self, val: T) -> Result<usize, T> {
+        let mut val = Some(val);
+        // Only started once the first attempt fails
+        let mut wait = None;
+        let mut evicted = Vec::new();
+        let rval = self.queue.waiter.until(|| {
+            match self.push_inner(0, val.take().unwrap()) {
+                Ok(seq) => Some(Ok(seq)),
+                Err(back) if self.queue.abandoned() || self.queue.closed.load(Acquire) => Some(Err(back)),
+                Err(back) => {
+                    if wait.is_none() {
+                        wait = Some(trace::PushWait::new(self.queue.labels()));
+                    }
+                    let before = evicted.len();
+                    self.queue.evict_idle(&mut evicted);
+                    if evicted.len() > before {
+                        // The evicted streams may have been all that held
+                        // the queue up, and nothing else is going to wake us
+                        return self.push_inner(0, back)
+                            .map(Ok)
+                            .map_err(|back| val = Some(back))
+                            .ok();
+                    }
+                    val = Some(back);
+                    None
+                }
+            }
+        });
+        if let Some(wait) = wait {
+            wait.done(rval.is_ok());
+        }
+        if rval.is_ok() {
+            self.queue.record_push();
+            self.queue.notify();
+        }
+        self.queue.run_evicted(evicted);
+        rval
+    }
+
+    /// Pushes as many items from the front of items as fit right now, without
+    /// waiting, and returns how many. Whatever didn't fit stays in items, in order
+    pub fn try_send_all(&self, items: &mut Vec<T>) -> usize {
+        // Items are moved out as slots are claimed and the rest shifted down
+        // after, so nothing is allocated along the way
+        let mut sent = 0;
+        while sent < items.len() {
+            if let Err(back) = self.push_inner(0, unsafe { ptr::read(items.as_ptr().add(sent)) }) {
+                // Still in items
+                mem::forget(back);
+                break;
+            }
+            sent += 1;
+        }
+        unsafe {
+            let rest = items.len() - sent;
+            ptr::copy(items.as_ptr().add(sent), items.as_mut_ptr(), rest);
+            items.set_len(rest);
+        }
+        // Not until items lets go of what was sent, since on_lag may panic
+        for _ in 0..sent {
+            self.queue.record_push();
+        }
+        if sent > 0 {
+            self.queue.notify();
+        }
+        self.pushed(items.is_empty());
+        sent
+    }
+
+    /// Pushes everything items gives, waiting for room as push_wait does.
+    /// If every reader goes away, gives back the item that couldn't be
+    /// pushed and leaves the rest in the iterator
+    pub fn extend_blocking<I: IntoIterator<Item = T>>(&self, items: I) -> Result<(), T> {
+        let mut items = items.into_iter();
+        let mut next = items.next();
+        while let Some(mut val) = next {
+            // Push whatever fits straight away, waking sleepers once for the lot
+            let mut pushed = false;
+            loop {
+                match self.push_inner(0, val) {
+                    Ok(_) => {
+                        self.queue.record_push();
+                        pushed = true;
+                        match items.next() {
+                            Some(item) => val = item,
+                            None => {
+                                self.queue.notify();
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Err(back) => {
+                        val = back;
+                        break;
+                    }
+                }
+            }
+            if pushed {
+                self.queue.notify();
+            }
+            self.push_wait(val)?;
+            next = items.next();
+        }
+        Ok(())
+    }
+
+    /// Tears the queue down and hands back the items no stream got to, oldest
+    /// first, so they can be kept rather than lost. Gives the writer back if
+    /// any other handle on the queue is left
+    pub fn into_inner(self) -> Result<Vec<T>, MultiWriter<T>> {
+        if Arc::strong_count(&self.queue) != 1 {
+            return Err(self);
+        }
+        Ok(unsafe { self.queue.take_unread() })
+    }
+
+    /// Roughly how many more items fit before the queue is full, going by
+    /// where the streams were when a writer last checked. That's never more
+    /// than there really is room for, but streams may have freed up more since
+    pub fn space_remaining(&self) -> usize {
+        self.queue.space(self.queue.writes.tail_cache.load(Acquire))
+    }
+
+    /// Like space_remaining, but looks at where the streams are now first
+    pub fn space_remaining_now(&self) -> usize {
+        self.queue.reload_tail_multi(self.queue.writes.tail_cache.load(Acquire));
+        self.queue.space(self.queue.writes.tail_cache.load(Acquire))
+    }
+
+    /// Whether a push would find room right now, without pushing, for
+    /// deciding whether to take on work before there's an item to push.
+    /// Always false while the queue is paused or closed
+    pub fn poll_ready(&self) -> bool {
+        if self.queue.is_paused() || self.queue.closed.load(Relaxed) {
+            return false;
+        }
+        self.space_remaining() > 0 || self.space_remaining_now() > 0
+    }
+
+    /// Waits as push_wait does until poll_ready would be true. Returns false
+    /// rather than wait forever if the queue is closed or every reader is gone
+    pub fn wait_ready(&self) -> bool {
+        self.queue.waiter.until(|| {
+            if self.poll_ready() {
+                Some(true)
+            } else if self.queue.closed.load(Acquire) || self.queue.abandoned() {
+                Some(false)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Where the head and every stream are
+    pub fn positions(&self) -> Positions {
+        self.queue.positions()
+    }
+
+    /// How many times writers had to start a push over, after losing the head
+    /// to another writer or finding a stream added while they looked for room
+    pub fn retries(&self) -> usize {
+        self.queue.retries.load(Relaxed) + self.queue.shared.tail.rescans()
+    }
+
+    /// Turns this into the queue's single writer, if there are no others.
+    /// Gives the writer back if there are
+    pub fn into_single(self) -> Result<SingleWriter<T>, MultiWriter<T>> {
+        if self.queue.writes.writers.load(Relaxed) != 1 {
+            return Err(self);
+        }
+        // Pairs with the release of the last other writer going away
+        fence(Acquire);
+        match self.state.get() {
+            QueueState::Single => (),
+            _ => trace::writer_mode(self.queue.labels(), false),
+        }
+        self.state.set(QueueState::Single);
+        Ok(SingleWriter { writer: self })
+    }
+
+    /// Keeps this writer, and any cloned from it after, on the path for several
+    /// writers even once it's the only one left. That costs a little on every
+    /// push, but the writer behaves the same however many clones come and go
+    pub fn lock_multi(&self) {
+        if let QueueState::Single = self.state.get() {
+            trace::writer_mode(self.queue.labels(), true);
+        }
+        self.state.set(QueueState::Locked);
+    }
+
+    /// Takes the next slot in the queue, to be filled in with Claim::publish.
+    /// Returns None if the queue is full
+    ///
+    /// Streams can't read past the slot until it's published or the claim dropped
+    pub fn claim(&self) -> Option<Claim<'_, T>> {
+        assert!(!self.queue.spsc, "items can't be claimed on an spsc queue, only pushed");
+        let claimed = self.claim_inner();
+        self.pushed(claimed.is_some());
+        claimed.map(|(cell, tag)| {
+            Claim {
+                writer: self,
+                cell: cell,
+                tag: tag,
+            }
+        })
+    }
+
+    #[inline(always)]
+    fn push_inner(&self, topic: u8, val: T) -> Result<usize, T> {
+        if self.queue.is_paused() || self.queue.closed.load(Relaxed) {
+            return Err(val);
+        }
+        if self.queue.spsc {
+            return self.queue.push_spsc(topic, val);
+        }
+        match self.claim_inner() {
+            Some((cell, tag)) => unsafe {
+                self.queue.fill(cell, tag, topic, val);
+                Ok(self.queue.seq_of(cell, tag))
+            },
+            None => Err(val),
+        }
+    }
+
+    #[inline(always)]
+    fn claim_inner(&self) -> Option<(*mut QueueEntry<T>, usize)> {
+        if self.queue.is_paused() || self.queue.closed.load(Relaxed) {
+            return None;
+        }
+        match self.state.get() {
+            QueueState::Single => self.queue.claim_single(),
+            QueueState::Multi => {
+                // This doesn't use the maybe_acquire framework since
+                // it is so rarely acquire that it makes sense to incur
+                // the rare extra cost of an acquire fence on architectures where it matters
+                if self.queue.writes.writers.load(Relaxed) == 1 {
+                    fence(Acquire);
+                    self.state.set(QueueState::Single);
+                    trace::writer_mode(self.queue.labels(), false);
+                    self.queue.claim_single()
+                } else {
+                    self.queue.claim_multi()
+                }
+            }
+            QueueState::Locked => self.queue.claim_multi(),
+        }
+    }
+}
+
+impl<T: Clone> MultiWriter<T> {
+    /// Copies out everything still waiting in the queue along with
+    /// each stream's position, to be brought back with QueueSnapshot::restore
+    ///
+    /// This is meant for shutdown, and only works once every other handle
+    /// on the queue has been dropped. Returns None if any are left.
+    /// Items a faster stream already popped are cloned from the ring, so T
+    /// has the same restrictions as when broadcasting to several streams
+    pub fn snapshot(&self) -> Option<QueueSnapshot<T>> {
+        // With no other handles, nothing else can touch the queue
+        // while the items are cloned out of it
+        if Arc::strong_count(&self.queue) != 1 {
+            return None;
+        }
+        let head = self.queue.writes.head.load_count(Acquire);
+        let mut nread = Vec::new();
+        self.queue.shared.tail.for_each_reader(|reader| nread.push(reader.load_nread(Acquire)));
+        let tail = nread.iter().cloned().min().unwrap_or(head);
+        // Skipped slots aren't items, and neither is one this writer still has claimed
+        let published: Vec<bool> = (tail..head).map(|count| unsafe { self.queue.published(count) }).collect();
+        let items = (tail..head)
+            .filter(|&count| published[count - tail])
+            .map(|count| unsafe {
+                let cell = self.queue.entry(count as isize % self.queue.shared.capacity);
+                (*(*cell).val.as_ptr()).clone()
+            })
+            .collect();
+        Some(QueueSnapshot {
+            items: items,
+            streams: nread.into_iter().map(|n| published[..n - tail].iter().filter(|&&p| p).count()).collect(),
+        })
+    }
+}
+
+impl<T> SingleWriter<T> {
+    /// Pushes val, claiming its slot without ever looping as described on SingleWriter
+    pub fn push(&self, val: T) -> Result<usize, T> {
+        self.writer.push(val)
+    }
+
+    /// Like push, telling a full queue apart from a paused one
+    pub fn try_push(&self, val: T) -> Result<usize, PushError<T>> {
+        self.writer.try_push(val)
+    }
+
+    /// Pushes val, waiting for room as MultiWriter::push_wait does.
+    /// Each attempt claims its slot wait-free, as push does
+    pub fn push_wait(&self, val: T) -> Result<usize, T> {
+        self.writer.push_wait(val)
+    }
+
+    pub fn space_remaining(&self) -> usize {
+        self.writer.space_remaining()
+    }
+
+    pub fn poll_ready(&self) -> bool {
+        self.writer.poll_ready()
+    }
+
+    pub fn wait_ready(&self) -> bool {
+        self.writer.wait_ready()
+    }
+
+    /// See MultiWriter::retries, which never counts this writer's pushes
+    pub fn retries(&self) -> usize {
+        self.writer.retries()
+    }
+
+    /// See MultiWriter::close
+    pub fn close(&self) {
+        self.writer.close()
+    }
+
+    /// Turns this back into a writer that can be cloned
+    pub fn into_writer(self) -> MultiWriter<T> {
+        self.writer
+    }
+}
+
+impl<T> QueueSnapshot<T> {
+    /// Builds a new queue holding the snapshotted items, returning a writer
+    /// and a reader for each stream picking up where the snapshotted one left off
+    ///
+    /// Gives the snapshot back if the items don't fit in the configured capacity
+    pub fn restore(self, cfg: &MultiQueueBuilder) -> Result<Restored<T>, QueueSnapshot<T>> {
+        if self.streams.is_empty() || self.items.len() > cfg.capacity as usize ||
+           self.streams.iter().any(|&consumed| consumed > self.items.len()) {
+            return Err(self);
+        }
+        let QueueSnapshot { items, streams } = self;
+        let (writer, reader) = cfg.build();
+        let mut readers = Vec::with_capacity(streams.len());
+        for _ in 1..streams.len() {
+            readers.push(reader.stream().add_stream().into_reader());
+        }
+        readers.insert(0, reader);
+        for item in items {
+            assert!(writer.push(item).is_ok(), "restored items must fit in the queue");
+        }
+        for (reader, &consumed) in readers.iter().zip(streams.iter()) {
+            unsafe { (*reader.reader.load(Relaxed)).advance(consumed as u16) };
+        }
+        Ok((writer, readers))
+    }
+}
+
+impl<T> MultiReader<T> {
+    pub fn pop(&self) -> Option<T> {
+        self.pop_with_meta().map(|(val, _)| val)
+    }
+
+    /// Pops the next item along with the sequence number push gave it
+    pub fn pop_seq(&self) -> Option<(usize, T)> {
+        self.pop_with_meta().map(|(val, meta)| (meta.seq, val))
+    }
+
+    /// Pops the next item along with its sequence number and, on
+    /// queues built with timestamps, when it was published
+    pub fn pop_with_meta(&self) -> Option<(T, Meta)> {
+        let reader = unsafe { &*self.reader.load(Relaxed) };
+        let rval = self.pop_live(reader, &mut 0);
+        self.popped(reader, rval.is_some());
+        rval
+    }
+
+    /// Pops the next item that hasn't outlived the queue's ttl,
+    /// dropping and counting any that have along the way
+    #[inline(always)]
+    fn pop_live(&self, reader: &Reader, skipped: &mut usize) -> Option<(T, Meta)> {
+        self.pop_until(reader, skipped, |meta| !self.queue.expired(meta))
+    }
+
+    /// Pops the next item that keep accepts, dropping and counting any it doesn't.
+    /// Items that aren't routed to the stream are dropped without counting
+    #[inline(always)]
+    fn pop_until<F: Fn(&Meta) -> bool>(&self, reader: &Reader, skipped: &mut usize, keep: F) -> Option<(T, Meta)> {
+        loop {
+            let rval = if self.queue.spsc {
+                self.pop_spsc(reader)
+            } else {
+                self.queue.pop(reader)
+            };
+            match rval {
+                Some((ref val, ref meta)) if !self.queue.routed(reader, val, meta) => (),
+                Some((_, ref meta)) if !keep(meta) => *skipped += 1,
+                _ => return rval,
+            }
+        }
+    }
+
+    /// Pops the next item published less than max_age ago, dropping older
+    /// ones to get to it, for consumers that only care about what's current.
+    /// Items on a queue built without timestamps are never too old
+    #[cfg(feature = "std")]
+    pub fn pop_fresh(&self, max_age: Duration) -> Option<T> {
+        self.pop_fresh_counted(max_age).0
+    }
+
+    /// Like pop_fresh, along with how many items it dropped as too old
+    #[cfg(feature = "std")]
+    pub fn pop_fresh_counted(&self, max_age: Duration) -> (Option<T>, usize) {
+        let reader = unsafe { &*self.reader.load(Relaxed) };
+        let mut skipped = 0;
+        let rval = self.pop_until(reader, &mut skipped, |meta| {
+            !self.queue.expired(meta) && meta.age().is_none_or(|age| age < max_age)
+        });
+        self.popped(reader, rval.is_some());
+        (rval.map(|(val, _)| val), skipped)
+    }
+
+    /// Pops off an spsc queue, going by the head rather than the slot's tag.
+    /// The head is only loaded again once the reader catches up to it
+    #[inline(always)]
+    fn pop_spsc(&self, reader: &Reader) -> Option<(T, Meta)> {
+        let ctail_attempt = reader.load_attempt(Relaxed);
+        let ctail = ctail_attempt.get() as isize;
+        let seq = self.queue.seq(ctail_attempt.get_wraps(), ctail);
+        // Other ways of popping can have taken the reader past the cached head
+        if seq.wrapping_sub(self.head_cache.get()) as isize >= 0 {
+            self.head_cache.set(self.queue.writes.head.load_count(Acquire));
+            if seq == self.head_cache.get() {
+                return None;
+            }
+        }
+        unsafe {
+            let read_cell = self.queue.entry(ctail);
+            let rval = ptr::read(ptr::addr_of!((*read_cell).val));
+            let meta = self.queue.meta(read_cell, seq);
+            ctail_attempt.commit_attempt(1, Release);
+            if reader.is_evicted() {
+                return None;
+            }
+            Some((rval.assume_init(), meta))
+        }
+    }
+
+    /// Pops an item if there is one, and otherwise has cx woken once there
+    /// might be, for readers in async code. Gives Ready(None) once the queue
+    /// is closed and empty. Every waiting reader on a stream is woken, so
+    /// clones of a reader can wait on it together
+    pub fn poll_pop(&self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if let Some(val) = self.pop() {
+            return Poll::Ready(Some(val));
+        }
+        let reader = unsafe { &*self.reader.load(Relaxed) };
+        if self.queue.wait_async(reader, cx.waker()) {
+            // Rather than count on a wakeup from a writer that may have
+            // missed it, the first async reader looks again next poll
+            cx.waker().wake_by_ref();
+        }
+        match self.try_pop() {
+            TryPop::Item(val) => Poll::Ready(Some(val)),
+            TryPop::Empty => Poll::Pending,
+            TryPop::Closed => Poll::Ready(None),
+        }
+    }
+
+    /// Pops the next item, waiting for one as the queue was built to.
+    /// Returns None once every writer is gone and the stream is empty
+    pub fn pop_wait(&self) -> Option<T> {
+        self.pop_wait_seq().map(|(_, val)| val)
+    }
+
+    /// Like pop_wait, along with the item's sequence number
+    pub fn pop_wait_seq(&self) -> Option<(usize, T)> {
+        let reader = unsafe { &*self.reader.load(Relaxed) };
+        let rval = self.queue.waiter.until(|| {
+            match self.pop_live(reader, &mut 0) {
+                Some(val) => Some(Some(val)),
+                // A writer may have pushed on its way out
+                None if self.is_closed() => Some(self.pop_live(reader, &mut 0)),
+                None => None,
+            }
+        });
+        self.popped(reader, rval.is_some());
+        rval.map(|(val, meta)| (meta.seq, val))
+    }
+
+    /// Steps this stream back by up to by items so they're popped again,
+    /// after a consumer failed partway through handling them. Returns how
+    /// many it stepped back
+    ///
+    /// Only items the slowest other stream hasn't reached yet are still
+    /// sure to be in the ring, so that's as far back as it goes, and a lone
+    /// or slowest stream can't rewind at all. Since popping an item again
+    /// copies it out bitwise a second time, only Copy items can be rewound,
+    /// so nothing gets dropped twice. Panics if the stream has other readers
+    ///
+    /// ```compile_fail
+    /// use pipeline::queue::multiqueue::multiqueue;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// static DROPS: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// struct Counted;
+    ///
+    /// impl Drop for Counted {
+    ///     fn drop(&mut self) {
+    ///         DROPS.fetch_add(1, Ordering::SeqCst);
+    ///     }
+    /// }
+    ///
+    /// let (writer, reader) = multiqueue::<Counted>(4);
+    /// let _slow = reader.stream().add_stream().into_reader();
+    /// writer.push(Counted).ok();
+    /// drop(reader.pop());
+    /// reader.rewind(1);
+    /// drop(reader.pop());
+    /// ```
+    pub fn rewind(&self, by: usize) -> usize
+        where T: Copy
+    {
+        let reader = unsafe { &*self.reader.load(Relaxed) };
+        assert!(reader.consumers() == 1, "only a stream with one reader can rewind");
+        let lead = self.queue.shared.tail.lead(reader);
+        let back = cmp::min(by, cmp::max(lead, 0) as usize);
+        if back == 0 {
+            return 0;
+        }
+        reader.rewind(back as u16);
+        // A stream that passed where this one went back to while it was
+        // moving may have let a writer reuse the slots in between, so skip them
+        let lead = self.queue.shared.tail.lead(reader);
+        if lead < 0 {
+            let skip = cmp::min((-lead) as usize, back);
+            reader.advance(skip as u16);
+            return back - skip;
+        }
+        back
+    }
+
+    /// Moves this stream so the next item it pops is the one push gave seq,
+    /// for consumers that keep track of how far they got themselves.
+    /// Going back is limited as with rewind, and going forward skips items
+    /// without popping them. Panics if the stream has other readers
+    pub fn seek(&self, seq: usize) -> Result<(), SeekError>
+        where T: Copy
+    {
+        let reader = unsafe { &*self.reader.load(Relaxed) };
+        assert!(reader.consumers() == 1, "only a stream with one reader can seek");
+        let nread = reader.load_nread(Relaxed);
+        // Past a claimed slot the stream would skip an item still being written
+        let head = self.queue.writes.head.load_count(Acquire);
+        let newest = unsafe { self.queue.finished_through(nread, head) };
+        let oldest = nread.wrapping_sub(cmp::max(self.queue.shared.tail.lead(reader), 0) as usize);
+        let err = SeekError {
+            seq: seq,
+            oldest: oldest,
+            newest: newest,
+        };
+        if seq.wrapping_sub(oldest) > newest.wrapping_sub(oldest) {
+            return Err(err);
+        }
+        let back = nread.wrapping_sub(seq);
+        if back <= nread.wrapping_sub(oldest) {
+            let rewound = self.rewind(back);
+            if rewound != back {
+                // Another stream moved on in the meantime
+                reader.advance(rewound as u16);
+                return Err(err);
+            }
+        } else {
+            reader.advance(seq.wrapping_sub(nread) as u16);
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn popped(&self, reader: &Reader, ok: bool) {
+        if ok {
+            if metrics::ENABLED {
+                self.metrics.record_pop(self.lag());
+            }
+            self.queue.waiter.notify();
+        }
+        self.empty.pop(self.queue.labels(), reader.id(), ok);
+    }
+
+    /// Pops as many items as are ready into buf, up to its length, in one
+    /// go. Returns how many it popped
+    pub fn pop_slice(&self, buf: &mut [T]) -> usize
+        where T: Copy
+    {
+        let reader = unsafe { &*self.reader.load(Relaxed) };
+        if self.queue.filtered(reader) {
+            // Each item has to be checked, so there's no copying runs
+            let mut popped = 0;
+            while popped < buf.len() {
+                match self.pop_live(reader, &mut 0) {
+                    Some((val, _)) => buf[popped] = val,
+                    None => break,
+                }
+                popped += 1;
+            }
+            self.popped(reader, popped > 0);
+            return popped;
+        }
+        let popped = self.queue.pop_slice(reader, buf);
+        self.popped(reader, popped > 0);
+        popped
+    }
+
+    /// Whether the queue was closed, every writer is gone or this stream
+    /// was evicted, so nothing more will be popped once it's empty
+    pub fn is_closed(&self) -> bool {
+        self.queue.is_closed() || self.is_evicted()
+    }
+
+    /// The id of this reader's stream, counting streams from 0 in the order
+    /// they were added. Hooks and MultiWriter::evict go by it
+    pub fn stream_id(&self) -> usize {
+        unsafe { (*self.reader.load(Relaxed)).id() }
+    }
+
+    pub fn stream_name(&self) -> Option<&'static str> {
+        unsafe { (*self.reader.load(Relaxed)).name() }
+    }
+
+    /// The topics this reader's stream takes items from, as set by MultiStream::subscribe
+    pub fn topics(&self) -> u64 {
+        unsafe { (*self.reader.load(Relaxed)).topics() }
+    }
+
+    /// Whether this reader's stream was evicted, for sitting idle or by
+    /// MultiWriter::evict, after which it never pops anything again
+    pub fn is_evicted(&self) -> bool {
+        unsafe { (*self.reader.load(Relaxed)).is_evicted() }
+    }
+
+    /// Like pop, but tells an empty queue apart from one that's closed
+    /// with nothing left to pop
+    pub fn try_pop(&self) -> TryPop<T> {
+        if let Some(val) = self.pop() {
+            return TryPop::Item(val);
+        }
+        if !self.is_closed() {
+            return TryPop::Empty;
+        }
+        // A push may have gone in just before the queue closed
+        match self.pop() {
+            Some(val) => TryPop::Item(val),
+            None => TryPop::Closed,
+        }
+    }
+
+    /// Whether pop has an item to give, without taking it. Cheap enough
+    /// to check many queues with before popping from one
+    #[inline(always)]
+    pub fn is_ready(&self) -> bool {
+        self.queue.ready(unsafe { &*self.reader.load(Relaxed) })
+    }
+
+    /// Where the head and every stream are
+    pub fn positions(&self) -> Positions {
+        self.queue.positions()
+    }
+
+    /// Like MultiWriter::streams
+    pub fn streams(&self) -> Vec<StreamInfo> {
+        self.queue.streams()
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.queue.memory_usage()
+    }
+
+    pub fn allocations(&self) -> Allocations {
+        self.queue.shared.tail.counter().load()
+    }
+
+    /// Roughly how many items this stream has yet to pop, counting
+    /// ones that are claimed by a writer but not yet published
+    pub fn lag(&self) -> usize {
+        let reader = unsafe { &*self.reader.load(Relaxed) };
+        let nread = reader.load_nread(Relaxed);
+        let head = self.queue.writes.head.load_count(Relaxed);
+        // A stale head can trail what the reader has already popped
+        if head.wrapping_sub(nread) > isize::MAX as usize {
+            0
+        } else {
+            head.wrapping_sub(nread)
+        }
+    }
+
+    /// Copies out what's waiting in the queue for any stream, oldest first,
+    /// without popping anything. Meant for showing what's stuck while the
+    /// queue is in use, so it's only a best effort: items may be popped by
+    /// every stream or pushed while it runs
+    pub fn snapshot(&self) -> Vec<T>
+        where T: Copy
+    {
+        self.queue.peek()
+    }
+
+    /// Like MultiWriter::into_inner, for when a reader is the last handle left
+    pub fn into_inner(self) -> Result<Vec<T>, MultiReader<T>> {
+        if Arc::strong_count(&self.queue) != 1 {
+            return Err(self);
+        }
+        Ok(unsafe { self.queue.take_unread() })
+    }
+
+    /// The stream this reader pops from, to take more readers from or to add streams alongside
+    pub fn stream(&self) -> MultiStream<T> {
+        MultiStream { reader: self.clone() }
+    }
+
+    /// Adds a stream starting at this reader's position
+    #[deprecated(note = "use stream().add_stream(), or clone to share this stream's items")]
+    pub fn add_reader(&self) -> MultiReader<T> {
+        self.new_stream(None)
+    }
+
+    fn new_stream(&self, name: Option<&'static str>) -> MultiReader<T> {
+        assert!(self.queue.delivery == Delivery::Broadcast,
+                "a unicast queue has just the one stream, clone its readers to share it");
+        assert!(!self.queue.realtime || self.queue.shared.tail.is_fixed(),
+                "streams can't be added to a realtime queue once it's built, take them from build_streams");
+        self.register_stream(name)
+    }
+
+    fn register_stream(&self, name: Option<&'static str>) -> MultiReader<T> {
+        let reader = unsafe { self.queue.shared.tail.add_reader(&*self.reader.load(Relaxed), name) };
+        let id = unsafe { (*reader.load(Relaxed)).id() };
+        trace::stream_added(self.queue.labels(), id);
+        MultiReader {
+            queue: self.queue.clone(),
+            reader: reader,
+            metrics: self.queue.metrics.stream(id, name),
+            empty: Transition::new(),
+            head_cache: Cell::new(0),
+        }
+    }
+}
+
+impl<T> MultiStream<T> {
+    /// A reader sharing this stream's items with every other reader on it
+    pub fn reader(&self) -> MultiReader<T> {
+        self.reader.clone()
+    }
+
+    /// Adds another stream starting at this one's position.
+    /// It gets every item from then on, independently of this one
+    pub fn add_stream(&self) -> MultiStream<T> {
+        MultiStream { reader: self.reader.new_stream(None) }
+    }
+
+    /// Has the stream take items only from the topics set in topics, bit n
+    /// for topic n, and drop the rest as they come up. Every reader on the
+    /// stream goes by it, and a new stream added from this one starts with
+    /// every topic. Items already popped are left alone
+    pub fn subscribe(&self, topics: u64) {
+        unsafe { (*self.reader.reader.load(Relaxed)).subscribe(topics) }
+    }
+
+    /// Like add_stream, giving the stream a name to find it by in
+    /// MultiWriter::streams and to label its metrics with
+    pub fn add_stream_named(&self, name: &'static str) -> MultiStream<T> {
+        MultiStream { reader: self.reader.new_stream(Some(name)) }
+    }
+
+    /// Turns this handle into a reader on the stream, rather than taking another
+    pub fn into_reader(self) -> MultiReader<T> {
+        self.reader
+    }
+}
+
+/// Pushes every item, waiting for room as the queue was built to. Anything
+/// left once every reader is gone is dropped; extend_blocking gives it back
+impl<T> Extend<T> for MultiWriter<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, items: I) {
+        let _ = self.extend_blocking(items);
+    }
+}
+
+impl<T> Clone for MultiWriter<T> {
+    fn clone(&self) -> MultiWriter<T> {
+        assert!(!self.queue.spsc, "an spsc queue has just the one writer");
+        let state = match self.state.get() {
+            QueueState::Single => {
+                trace::writer_mode(self.queue.labels(), true);
+                QueueState::Multi
+            }
+            state => state,
+        };
+        self.state.set(state);
+        let rval = MultiWriter {
+            queue: self.queue.clone(),
+            state: Cell::new(state),
+            full: Transition::new(),
+        };
+        self.queue.writes.writers.fetch_add(1, Release);
+        rval
+    }
+}
+
+impl<T> Clone for MultiReader<T> {
+    fn clone(&self) -> MultiReader<T> {
+        assert!(!self.queue.spsc, "an spsc queue has just the one reader");
+        let reader = self.reader.load(Relaxed);
+        let rval = MultiReader {
+            queue: self.queue.clone(),
+            reader: AtomicPtr::new(reader),
+            metrics: self.metrics.clone(),
+            empty: Transition::new(),
+            head_cache: Cell::new(0),
+        };
+        unsafe {
+            (*reader).dup_consumer();
+            trace::consumer_added(self.queue.labels(), (*reader).id());
+        }
+        rval
+    }
+}
+
+impl<T> Drop for MultiWriter<T> {
+    fn drop(&mut self) {
+        self.queue.writes.writers.fetch_sub(1, Release);
+        self.queue.notify();
+    }
+}
+
+impl<T> Drop for MultiReader<T> {
+    fn drop(&mut self) {
+        unsafe { (*self.reader.load(Relaxed)).remove_consumer() }
+        self.queue.waiter.notify();
+    }
+}
+
+impl<'a, T> Claim<'a, T> {
+    /// The sequence number the item will have once it's published
+    pub fn seq(&self) -> usize {
+        unsafe { self.writer.queue.seq_of(self.cell, self.tag) }
+    }
+
+    /// Fills in the slot with val and hands it to the streams
+    pub fn publish(self, val: T) {
+        unsafe { self.writer.queue.fill(self.cell, self.tag, 0, val) };
+        self.writer.queue.record_push();
+        self.writer.queue.notify();
+        mem::forget(self);
+    }
+
+    /// Gives the slot up without an item, as when building it failed.
+    /// Streams step over it, the same as when a claim is dropped
+    pub fn cancel(self) {
+        drop(self)
+    }
+
+    /// Publishes whatever f builds, or gives the slot up if f fails
+    pub fn publish_with<E, F>(self, f: F) -> Result<usize, E>
+        where F: FnOnce() -> Result<T, E>
+    {
+        let seq = self.seq();
+        f().map(|val| {
+            self.publish(val);
+            seq
+        })
+    }
+}
+
+impl<'a, T> Drop for Claim<'a, T> {
+    fn drop(&mut self) {
+        unsafe { (*self.cell).wraps.store(self.tag | SKIP, Release) };
+        self.writer.queue.notify();
+    }
+}
+
+impl<T> Drop for MultiQueue<T> {
+    fn drop(&mut self) {
+        // Items no stream has read yet are still the queue's to drop
+        drop(unsafe { self.take_unread() });
+        let layout = MultiQueue::<T>::ring_layout(self.shared.capacity as u16, self.shared.stride_shift);
+        alloc::deallocate_layout(&self.shared.tail.counter().tracked(self.alloc), self.shared.data as *mut u8, layout);
+        self.shared.tail.counter().freed(self.own_bytes());
+    }
+}
+
+impl<T> fmt::Debug for MultiQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MultiQueue")
+            .field("name", &self.name)
+            .field("labels", &self.labels)
+            .field("capacity", &self.shared.capacity)
+            .field("stride", &self.shared.stride_shift.map(|shift| 1usize << shift))
+            .field("written", &self.writes.head.load_count(Relaxed))
+            .field("head", &self.writes.head)
+            .field("tail_cache", &self.writes.tail_cache.load(Relaxed))
+            .field("writers", &self.writes.writers.load(Relaxed))
+            .field("wait", &self.waiter.strategy())
+            .field("delivery", &self.delivery)
+            .field("timestamps", &self.timestamps)
+            .field("fair", &self.fair)
+            .field("spsc", &self.spsc)
+            .field("routed", &self.router.is_some())
+            .field("tail", &self.shared.tail)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for MultiWriter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MultiWriter")
+            .field("state", &self.state.get())
+            .field("queue", &*self.queue)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for SingleWriter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SingleWriter")
+            .field("writer", &self.writer)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for MultiStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MultiStream")
+            .field("reader", &self.reader)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for MultiReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MultiReader")
+            .field("reader", unsafe { &*self.reader.load(Relaxed) })
+            .field("queue", &*self.queue)
+            .finish()
+    }
+}
+
+unsafe impl<T> Sync for MultiQueue<T> {}
+unsafe impl<T> Send for MultiQueue<T> {}
+unsafe impl<T> Send for MultiWriter<T> {}
+unsafe impl<T> Send for SingleWriter<T> {}
+unsafe impl<T> Send for MultiReader<T> {}
+
+pub fn multiqueue<T>(capacity: u16) -> (MultiWriter<T>, MultiReader<T>) {
+    MultiQueue::new(capacity)
+}
+
+/// Like multiqueue, but the name is attached to everything the queue reports
+pub fn multiqueue_named<T>(capacity: u16, name: &'static str) -> (MultiWriter<T>, MultiReader<T>) {
+    MultiQueueBuilder::new(capacity).name(name).build()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::MultiQueue;
+
+    extern crate crossbeam;
+    use self::crossbeam::scope;
+
+    use std::sync::atomic::Ordering::*;
+
+    use std::iter;
+    use std::panic;
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn build_queue() {
+        let _ = MultiQueue::<usize>::new(10);
+    }
+
+    #[test]
+    fn push_pop_test() {
+        let (writer, reader) = MultiQueue::<usize>::new(1);
+        for _ in 0..100 {
+            assert!(reader.pop().is_none());
+            writer.push(1 as usize).expect("Push should succeed");
+            assert!(writer.push(1).is_err());
+            assert_eq!(1, reader.pop().unwrap());
+        }
+    }
+
+    #[test]
+    fn shared_stream_moves_each_value_once() {
+        let (writer, reader) = MultiQueue::<String>::new(8);
+        let total = Arc::new(AtomicUsize::new(0));
+        let mut consumers = Vec::new();
+        for _ in 0..3 {
+            let reader = reader.clone();
+            let total = total.clone();
+            consumers.push(thread::spawn(move || {
+                let mut seen = 0;
+                while seen < 1000 {
+                    match reader.pop() {
+                        Some(s) => {
+                            total.fetch_add(s.len(), Relaxed);
+                            seen += 1;
+                        }
+                        None => thread::yield_now(),
+                    }
+                    if total.load(Relaxed) == 3000 {
+                        break;
+                    }
+                }
+            }));
+        }
+        drop(reader);
+        for _ in 0..1000 {
+            let mut val = "abc".to_string();
+            while let Err(back) = writer.push(val) {
+                val = back;
+                thread::yield_now();
+            }
+        }
+        for consumer in consumers {
+            consumer.join().unwrap();
+        }
+        assert_eq!(3000, total.load(Relaxed));
+    }
+
+    #[test]
+    fn last_consumer_keeps_its_place() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let other = reader.clone();
+        writer.push(0).unwrap();
+        writer.push(1).unwrap();
+        // A pop that loaded its position before the other consumer read on and left
+        let stale = unsafe { (*reader.reader.load(Relaxed)).load_attempt(Relaxed) };
+        assert_eq!(0, other.pop().unwrap());
+        assert_eq!(1, other.pop().unwrap());
+        drop(other);
+        assert!(stale.commit_attempt(1, Release).is_some());
+        assert!(reader.pop().is_none());
+        writer.push(2).unwrap();
+        assert_eq!(2, reader.pop().unwrap());
+    }
+
+    #[test]
+    fn readers_added_at_once() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let bar = Barrier::new(4);
+        let streams: Vec<MultiReader<usize>> = scope(|scope| {
+            let added: Vec<_> = (0..4)
+                .map(|_| {
+                    let (bref, consumer) = (&bar, reader.clone());
+                    scope.spawn(move || {
+                        bref.wait();
+                        consumer.stream().add_stream().into_reader()
+                    })
+                })
+                .collect();
+            added.into_iter().map(|stream| stream.join()).collect()
+        });
+        writer.push(7).unwrap();
+        for stream in streams.iter().chain(Some(&reader)) {
+            assert_eq!(Some(7), stream.pop());
+        }
+    }
+
+    #[test]
+    fn drops_what_no_stream_read() {
+        struct Counted(Arc<AtomicUsize>);
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Relaxed);
+            }
+        }
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (writer, reader) = MultiQueue::<Counted>::new(4);
+        for _ in 0..3 {
+            assert!(writer.push(Counted(dropped.clone())).is_ok());
+        }
+        drop(reader.pop());
+        assert_eq!(1, dropped.load(Relaxed));
+        drop(writer);
+        drop(reader);
+        assert_eq!(3, dropped.load(Relaxed));
+
+        // What one stream popped isn't the queue's to drop, even if another hasn't
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (writer, reader) = MultiQueue::<Counted>::new(4);
+        let behind = reader.stream().add_stream().into_reader();
+        for _ in 0..3 {
+            assert!(writer.push(Counted(dropped.clone())).is_ok());
+        }
+        drop(reader.pop());
+        drop((writer, reader, behind));
+        assert_eq!(3, dropped.load(Relaxed));
+    }
+
+    #[cfg(feature = "affinity")]
+    #[test]
+    fn local_readers_work_like_any_other() {
+        let (writer, reader) = MultiQueueBuilder::new(4).local_readers().build::<usize>();
+        let streams: Vec<MultiReader<usize>> = (0..3)
+            .map(|_| {
+                let stream = reader.stream();
+                thread::spawn(move || stream.add_stream().into_reader()).join().unwrap()
+            })
+            .collect();
+        for i in 0..4 {
+            writer.push(i).unwrap();
+        }
+        assert_eq!(Err(4), writer.push(4));
+        for stream in streams.iter().chain(Some(&reader)) {
+            assert_eq!((0..4).collect::<Vec<usize>>(), (0..).map_while(|_| stream.pop()).collect::<Vec<usize>>());
+        }
+        assert_eq!(Ok(4), writer.push(4));
+    }
+
+    #[test]
+    fn allocates_through_the_given_allocator() {
+        use std::alloc::Layout;
+        use std::sync::atomic::AtomicUsize;
+
+        struct Counting(AtomicUsize);
+
+        unsafe impl RawAlloc for Counting {
+            fn alloc(&self, layout: Layout) -> *mut u8 {
+                self.0.fetch_add(1, SeqCst);
+                Heap.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                self.0.fetch_sub(1, SeqCst);
+                Heap.dealloc(ptr, layout)
+            }
+        }
+
+        static COUNTING: Counting = Counting(AtomicUsize::new(0));
+        let (writer, reader) = MultiQueueBuilder::new(4).allocator(&COUNTING).build::<usize>();
+        let built = COUNTING.0.load(SeqCst);
+        assert!(built > 0);
+        let stream = reader.stream().add_stream();
+        assert!(COUNTING.0.load(SeqCst) > built);
+        writer.push(1).unwrap();
+        drop((writer, reader, stream));
+        assert_eq!(0, COUNTING.0.load(SeqCst));
+    }
+
+    #[test]
+    fn memory_usage_counts_the_ring_and_streams() {
+        let (writer, reader) = MultiQueueBuilder::new(8).build::<[u8; 40]>();
+        let built = writer.memory_usage();
+        assert_eq!(8 * mem::size_of::<QueueEntry<[u8; 40]>>(), built.ring);
+        assert!(built.streams > 0 && built.other >= mem::size_of::<MultiQueue<[u8; 40]>>());
+        let _stream = reader.stream().add_stream();
+        let added = reader.memory_usage();
+        assert_eq!(built.ring, added.ring);
+        assert!(added.streams > built.streams);
+        assert_eq!(added.total(), added.ring + added.streams + added.other);
+
+        let (writer, _) = MultiQueueBuilder::new(8).pad_entries().build::<[u8; 40]>();
+        let stride = mem::size_of::<QueueEntry<[u8; 40]>>().next_power_of_two();
+        assert_eq!(8 * stride, writer.memory_usage().ring);
+    }
+
+    #[cfg(all(unix, feature = "libc"))]
+    #[test]
+    fn rings_can_be_mapped_lazily() {
+        for pages in [&LAZY_PAGES, &PREFAULTED_PAGES] {
+            let (writer, reader) = MultiQueueBuilder::new(60000)
+                .allocator(pages)
+                .build::<[u64; 64]>();
+            for i in 0..70000 {
+                writer.push([i; 64]).unwrap();
+                assert_eq!(Some([i; 64]), reader.pop());
+            }
+            assert_eq!(None, reader.pop());
+        }
+    }
+
+    #[test]
+    fn allocations_are_counted_per_queue() {
+        use std::sync::atomic::AtomicIsize;
+
+        static LIVE: AtomicIsize = AtomicIsize::new(0);
+        let (writer, reader) = MultiQueueBuilder::new(4)
+            .name("counted")
+            .on_alloc(|queue, bytes| {
+                assert_eq!("counted", queue);
+                LIVE.fetch_add(bytes, SeqCst);
+            })
+            .build::<usize>();
+        let built = writer.allocations();
+        assert!(built.allocs > 0);
+        assert_eq!(0, built.frees);
+        assert_eq!(built.live_bytes as isize, LIVE.load(SeqCst));
+        for i in 0..10 {
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), reader.pop());
+        }
+        assert_eq!(built, reader.allocations());
+        let stream = reader.stream().add_stream();
+        let added = writer.allocations();
+        assert!(added.allocs > built.allocs && added.live_bytes > built.live_bytes);
+        drop((writer, reader, stream));
+        assert_eq!(0, LIVE.load(SeqCst));
+    }
+
+    #[test]
+    fn realtime_queues_dont_allocate_once_built() {
+        let (writer, streams) = MultiQueueBuilder::new(4).realtime().build_streams::<usize>(2);
+        let readers: Vec<MultiReader<usize>> = streams.iter().map(|stream| stream.reader()).collect();
+        let shared = readers[0].clone();
+        let mut buffer = Vec::with_capacity(8);
+        let built = writer.allocations();
+        for i in 0..100 {
+            writer.push_wait(i).unwrap();
+            writer.claim().unwrap().publish(i);
+            buffer.extend(&[i, i]);
+            writer.try_send_all(&mut buffer);
+            assert_eq!(Some(i), readers[0].pop_wait());
+            assert_eq!(Some(i), readers[1].pop());
+            while shared.pop().is_some() {}
+            while readers[1].pop().is_some() {}
+        }
+        assert_eq!(built, writer.allocations());
+
+        let added = panic::catch_unwind(panic::AssertUnwindSafe(|| streams[0].add_stream()));
+        assert!(added.is_err());
+    }
+
+    #[test]
+    fn fixed_stream_slots_are_set_aside_up_front() {
+        let (writer, reader) = MultiQueueBuilder::new(4).realtime().max_streams(3).build::<usize>();
+        writer.push(1).unwrap();
+        let built = writer.allocations();
+        let first = reader.stream().add_stream().into_reader();
+        let second = first.stream().add_stream().into_reader();
+        assert_eq!(built, writer.allocations());
+        let full = panic::catch_unwind(panic::AssertUnwindSafe(|| reader.stream().add_stream()));
+        assert!(full.is_err());
+        writer.push(2).unwrap();
+        for stream in &[&first, &second, &reader] {
+            assert_eq!(Some(1), stream.pop());
+            assert_eq!(Some(2), stream.pop());
+        }
+    }
+
+    #[test]
+    fn fixed_stream_slots_fill_while_writers_push() {
+        let (writer, reader) = MultiQueueBuilder::new(4).max_streams(8).build::<usize>();
+        let adder = reader.clone();
+        let pusher = thread::spawn(move || for i in 0..20000 {
+            while writer.push(i).is_err() {
+                thread::yield_now();
+            }
+        });
+        // The first stream keeps moving on while the others are copied from it
+        let mut poppers = vec![thread::spawn(move || loop {
+            let closed = reader.is_closed();
+            while reader.pop().is_some() {}
+            if closed {
+                return;
+            }
+            thread::yield_now();
+        })];
+        for _ in 0..7 {
+            thread::yield_now();
+            let stream = adder.stream().add_stream().into_reader();
+            poppers.push(thread::spawn(move || {
+                let mut last = None;
+                loop {
+                    let closed = stream.is_closed();
+                    while let Some(val) = stream.pop() {
+                        // Nothing the stream hasn't read is written over
+                        if let Some(last) = last {
+                            assert_eq!(last + 1, val);
+                        }
+                        last = Some(val);
+                    }
+                    if closed {
+                        return;
+                    }
+                    thread::yield_now();
+                }
+            }));
+        }
+        drop(adder);
+        pusher.join().unwrap();
+        for popper in poppers {
+            popper.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn positions_count_every_wrap() {
+        let (writer, reader) = MultiQueue::<usize>::new(2);
+        let other = reader.stream().add_stream().into_reader();
+        for i in 0..5 {
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), reader.pop());
+            if i < 3 {
+                assert_eq!(Some(i), other.pop());
+            }
+        }
+        let positions = writer.positions();
+        assert_eq!(positions, reader.positions());
+        assert_eq!(5, positions.head);
+        assert_eq!(2, positions.head_wraps);
+        assert_eq!(2, positions.capacity);
+        assert_eq!(vec![StreamPosition { consumed: 5, wraps: 2 }, StreamPosition { consumed: 3, wraps: 1 }],
+                   positions.streams);
+        assert_eq!(3, positions.slowest());
+        assert!(positions.head - positions.slowest() <= positions.capacity);
+    }
+
+    #[test]
+    fn single_writers_never_retry() {
+        let (writer, reader) = MultiQueue::<usize>::new(8);
+        let other = writer.clone();
+        let writer = writer.into_single().unwrap_err();
+        drop(other);
+        let writer = writer.into_single().unwrap();
+        let handle = thread::spawn(move || {
+            // Streams come and go while the writer pushes
+            let mut streams = vec![reader];
+            let mut popped = 0;
+            loop {
+                if streams.len() < 4 {
+                    let added = streams[0].stream().add_stream().into_reader();
+                    streams.push(added);
+                }
+                let closed = streams[0].is_closed();
+                for stream in &streams[1..] {
+                    while stream.pop().is_some() {}
+                }
+                while streams[0].pop().is_some() {
+                    popped += 1;
+                }
+                if closed {
+                    return popped;
+                }
+                thread::yield_now();
+            }
+        });
+        for i in 0..1000 {
+            writer.push_wait(i).unwrap();
+        }
+        assert_eq!(0, writer.retries());
+        let writer = writer.into_writer();
+        assert_eq!(0, writer.retries());
+        drop(writer);
+        assert_eq!(1000, handle.join().unwrap());
+    }
+
+    #[test]
+    fn rewinds_stop_at_the_slowest_stream() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let slow = reader.stream().add_stream().into_reader();
+        for i in 0..4 {
+            writer.push(i).unwrap();
+        }
+        assert_eq!(Some(0), slow.pop());
+        for i in 0..3 {
+            assert_eq!(Some(i), reader.pop());
+        }
+        assert_eq!(0, slow.rewind(5));
+        assert_eq!(2, reader.rewind(5));
+        assert_eq!(Some(1), reader.pop());
+        assert_eq!(Some(2), reader.pop());
+        assert_eq!(Some(3), reader.pop());
+        assert_eq!(None, reader.pop());
+
+        // Wrapping around the ring
+        for i in 1..4 {
+            assert_eq!(Some(i), slow.pop());
+            writer.push(i + 3).unwrap();
+        }
+        assert_eq!(Some(4), reader.pop());
+        assert_eq!(Some(5), reader.pop());
+        assert_eq!(1, reader.rewind(1));
+        assert_eq!(Some(5), reader.pop());
+    }
+
+    #[test]
+    fn seeks_within_the_ring() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let slow = reader.stream().add_stream().into_reader();
+        for i in 0..4 {
+            writer.push(i).unwrap();
+        }
+        assert_eq!(Some(0), slow.pop());
+        assert_eq!(Ok(()), reader.seek(3));
+        assert_eq!(Some(3), reader.pop());
+        assert_eq!(Ok(()), reader.seek(1));
+        assert_eq!(Some(1), reader.pop());
+        assert_eq!(Ok(()), reader.seek(4));
+        assert_eq!(None, reader.pop());
+
+        let err = SeekError { seq: 0, oldest: 1, newest: 4 };
+        assert_eq!(Err(err), reader.seek(0));
+        assert_eq!(Err(SeekError { seq: 5, ..err }), reader.seek(5));
+        assert_eq!(Err(SeekError { seq: 0, oldest: 1, ..err }), slow.seek(0));
+        assert_eq!(Ok(()), slow.seek(2));
+        assert_eq!(Some(2), slow.pop());
+
+        // Not past a slot that's claimed but not yet published
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        writer.push(0).unwrap();
+        let claim = writer.claim().unwrap();
+        writer.push(2).unwrap();
+        assert_eq!(Err(SeekError { seq: 2, oldest: 0, newest: 1 }), reader.seek(2));
+        assert_eq!(Ok(()), reader.seek(1));
+        claim.publish(1);
+        assert_eq!(Ok(()), reader.seek(3));
+        assert_eq!(None, reader.pop());
+    }
+
+    #[test]
+    fn snapshots_leave_streams_alone() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let slow = reader.stream().add_stream().into_reader();
+        for i in 0..3 {
+            writer.push(i).unwrap();
+        }
+        let _claim = writer.claim().unwrap();
+        assert_eq!(Some(0), reader.pop());
+        assert_eq!(Some(1), reader.pop());
+        assert_eq!(vec![0, 1, 2], reader.snapshot());
+        assert_eq!(Some(0), slow.pop());
+        assert_eq!(vec![1, 2], slow.snapshot());
+        assert_eq!(Some(2), reader.pop());
+        assert_eq!(Some(1), slow.pop());
+    }
+
+    #[test]
+    fn ready_readers_have_something_to_pop() {
+        let (writer, reader) = MultiQueue::<usize>::new(2);
+        assert!(!reader.is_ready());
+        drop(writer.claim().unwrap());
+        assert!(!reader.is_ready());
+        let claim = writer.claim().unwrap();
+        assert!(!reader.is_ready());
+        claim.publish(1);
+        assert!(reader.is_ready());
+        assert_eq!(Some(1), reader.pop());
+        assert!(!reader.is_ready());
+        writer.push(2).unwrap();
+        assert!(reader.is_ready());
+    }
+
+    #[test]
+    fn space_remaining_counts_free_slots() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        assert_eq!(4, writer.space_remaining());
+        for i in 0..3 {
+            writer.push(i).unwrap();
+        }
+        assert_eq!(1, writer.space_remaining());
+        assert_eq!(Some(0), reader.pop());
+        assert_eq!(Some(1), reader.pop());
+        assert_eq!(1, writer.space_remaining());
+        assert_eq!(3, writer.space_remaining_now());
+        assert_eq!(3, writer.space_remaining());
+    }
+
+    #[test]
+    fn paused_queues_drain_but_take_nothing() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let other = writer.clone();
+        writer.push(1).unwrap();
+        writer.pause();
+        assert!(other.is_paused());
+        assert_eq!(Err(PushError::Paused(2)), other.try_push(2));
+        assert_eq!(Err(3), writer.push(3));
+        assert!(writer.claim().is_none());
+        assert_eq!(Err(PushError::Paused(4)), writer.force_push(4, &reader, &mut Vec::new()));
+        assert_eq!(Some(1), reader.pop());
+        assert_eq!(None, reader.pop());
+
+        let handle = thread::spawn(move || other.push_wait(5));
+        thread::sleep(::std::time::Duration::from_millis(20));
+        assert_eq!(None, reader.pop());
+        writer.resume();
+        assert_eq!(Ok(1), handle.join().unwrap());
+        assert_eq!(Some(5), reader.pop());
+        writer.push(6).unwrap();
+        writer.push(7).unwrap();
+        writer.push(8).unwrap();
+        writer.push(9).unwrap();
+        assert_eq!(Err(PushError::Full(10)), writer.try_push(10));
+    }
+
+    #[test]
+    fn ready_writers_have_room() {
+        let (writer, reader) = MultiQueueBuilder::new(2).wait(WaitStrategy::Park).build::<usize>();
+        assert!(writer.poll_ready());
+        writer.push(1).unwrap();
+        writer.push(2).unwrap();
+        assert!(!writer.poll_ready());
+        let handle = thread::spawn(move || {
+            let ready = writer.wait_ready();
+            (ready, writer.push(3))
+        });
+        thread::sleep(::std::time::Duration::from_millis(20));
+        assert_eq!(Some(1), reader.pop());
+        assert_eq!((true, Ok(2)), handle.join().unwrap());
+
+        let (writer, reader) = MultiQueue::<usize>::new(2);
+        writer.pause();
+        assert!(!writer.poll_ready());
+        writer.resume();
+        assert!(writer.poll_ready());
+        writer.push(1).unwrap();
+        writer.push(2).unwrap();
+        drop(reader);
+        assert!(!writer.wait_ready());
+    }
+
+    #[test]
+    fn async_readers_are_woken_by_pushes() {
+        // Wake is for std's Arc, not the one loom or shuttle swap in
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicUsize;
+        use std::task::{Wake, Waker};
+
+        struct Woken(AtomicUsize);
+
+        impl Wake for Woken {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, SeqCst);
+            }
+        }
+
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let shared = reader.clone();
+        let other = reader.stream().add_stream().into_reader();
+        let woken: Vec<Arc<Woken>> = (0..3).map(|_| Arc::new(Woken(AtomicUsize::new(0)))).collect();
+        let wakers: Vec<Waker> = woken.iter().map(|w| Waker::from(w.clone())).collect();
+        let poll = |reader: &MultiReader<usize>, index: usize| {
+            reader.poll_pop(&mut Context::from_waker(&wakers[index]))
+        };
+        assert_eq!(Poll::Pending, poll(&reader, 0));
+        // The queue's first async reader looks again straight away
+        assert_eq!(1, woken[0].0.load(SeqCst));
+        assert_eq!(Poll::Pending, poll(&shared, 1));
+        assert_eq!(Poll::Pending, poll(&reader, 0));
+        assert_eq!(0, woken[1].0.load(SeqCst));
+        writer.push(1).unwrap();
+        assert_eq!(2, woken[0].0.load(SeqCst));
+        assert_eq!(1, woken[1].0.load(SeqCst));
+        assert_eq!(Poll::Ready(Some(1)), poll(&shared, 1));
+        assert_eq!(Poll::Pending, poll(&reader, 0));
+        assert_eq!(Poll::Ready(Some(1)), poll(&other, 2));
+        assert_eq!(Poll::Pending, poll(&other, 2));
+        writer.close();
+        assert_eq!(3, woken[0].0.load(SeqCst));
+        assert_eq!(1, woken[2].0.load(SeqCst));
+        assert_eq!(Poll::Ready(None), poll(&reader, 0));
+        assert_eq!(Poll::Ready(None), poll(&other, 2));
+    }
+
+    #[test]
+    fn closed_queues_drain_and_then_end() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let other = writer.clone();
+        writer.push(1).unwrap();
+        writer.push(2).unwrap();
+        assert!(!reader.is_closed());
+        writer.close();
+        assert!(reader.is_closed());
+        assert_eq!(Err(PushError::Closed(3)), other.try_push(3));
+        assert_eq!(Err(4), other.push_wait(4));
+        assert_eq!(TryPop::Item(1), reader.try_pop());
+        assert_eq!(Some(2), reader.pop_wait());
+        assert_eq!(None, reader.pop_wait());
+        assert_eq!(TryPop::Closed, reader.try_pop());
+
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        assert_eq!(TryPop::Empty, reader.try_pop());
+        let handle = thread::spawn(move || reader.pop_wait());
+        writer.close();
+        assert_eq!(None, handle.join().unwrap());
+    }
+
+    #[test]
+    fn force_push_evicts_the_oldest() {
+        let (writer, reader) = MultiQueue::<usize>::new(2);
+        let oldest = reader.clone();
+        let mut evicted = Vec::new();
+        assert_eq!(Ok(0), writer.force_push(0, &oldest, &mut evicted));
+        assert_eq!(Ok(1), writer.force_push(1, &oldest, &mut evicted));
+        assert_eq!(Ok(2), writer.force_push(2, &oldest, &mut evicted));
+        assert_eq!(Ok(3), writer.force_push(3, &oldest, &mut evicted));
+        assert_eq!(vec![0, 1], evicted);
+        assert_eq!(Some(2), reader.pop());
+        assert_eq!(Ok(4), writer.force_push(4, &oldest, &mut evicted));
+        assert_eq!(2, evicted.len());
+        assert_eq!(Some(3), reader.pop());
+        assert_eq!(Some(4), reader.pop());
+
+        // A claimed slot can't be evicted, so there's no making room
+        let claim = writer.claim().unwrap();
+        writer.push(6).unwrap();
+        assert_eq!(Err(PushError::Full(7)), writer.force_push(7, &oldest, &mut evicted));
+        assert_eq!(2, evicted.len());
+        claim.publish(5);
+        assert_eq!(Some(5), reader.pop());
+        assert_eq!(Some(6), reader.pop());
+
+        let _stream = reader.stream().add_stream();
+        let pushed = panic::catch_unwind(panic::AssertUnwindSafe(|| writer.force_push(8, &oldest, &mut evicted)));
+        assert!(pushed.is_err());
+    }
+
+    #[test]
+    fn pop_slice_copies_runs() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let mut buf = [0; 3];
+        assert_eq!(0, reader.pop_slice(&mut buf));
+        for i in 0..3 {
+            writer.push(i).unwrap();
+        }
+        assert_eq!(2, reader.pop_slice(&mut buf[..2]));
+        assert_eq!([0, 1], buf[..2]);
+
+        // Around the end of the ring, stopping short of a claimed slot
+        for i in 3..5 {
+            writer.push(i).unwrap();
+        }
+        let claim = writer.claim().unwrap();
+        assert_eq!(3, reader.pop_slice(&mut buf));
+        assert_eq!([2, 3, 4], buf);
+        assert_eq!(0, reader.pop_slice(&mut buf));
+        drop(claim);
+        writer.push(6).unwrap();
+        assert_eq!(1, reader.pop_slice(&mut buf));
+        assert_eq!(6, buf[0]);
+    }
+
+    #[test]
+    fn fair_queues_take_every_writers_items() {
+        let (writer, reader) = MultiQueueBuilder::new(4).fair().build::<usize>();
+        let writers = 4;
+        let per_writer = 10000;
+        let handles: Vec<_> = (0..writers).map(|w| {
+            let writer = writer.clone();
+            thread::spawn(move || for i in 0..per_writer {
+                writer.push_wait(w * per_writer + i).unwrap();
+            })
+        }).collect();
+        drop(writer);
+        let mut next = vec![0; writers];
+        for _ in 0..writers * per_writer {
+            let val = reader.pop_wait().unwrap();
+            // Each writer's items arrive in the order it pushed them
+            assert_eq!(next[val / per_writer], val % per_writer);
+            next[val / per_writer] += 1;
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(None, reader.pop());
+        // Every ticket taken was served
+        assert_eq!(reader.queue.tickets.load(SeqCst), reader.queue.serving.load(SeqCst));
+    }
+
+    #[test]
+    fn spsc_queues_publish_with_the_head() {
+        let (writer, reader) = MultiQueueBuilder::new(3).spsc().build::<usize>();
+        for i in 0..3 {
+            assert_eq!(Ok(i), writer.push(i));
+        }
+        assert_eq!(Err(3), writer.push(3));
+        assert_eq!(Some((0, 0)), reader.pop_seq());
+        assert_eq!(Ok(3), writer.push(3));
+        // Popping by tag and by head mix
+        assert_eq!(Some(1), reader.pop_wait());
+        let mut buf = [0; 2];
+        assert_eq!(2, reader.pop_slice(&mut buf));
+        assert_eq!([2, 3], buf);
+        assert_eq!(None, reader.pop());
+        let mut items = vec![4, 5, 6, 7];
+        assert_eq!(3, writer.try_send_all(&mut items));
+        assert_eq!(vec![7], items);
+        let popped: Vec<usize> = iter::from_fn(|| reader.pop()).collect();
+        assert_eq!(vec![4, 5, 6], popped);
+
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| writer.clone())).is_err());
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| reader.clone())).is_err());
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| writer.claim().is_some())).is_err());
+    }
+
+    #[test]
+    fn spsc_queues_pass_items_across_threads() {
+        let (writer, reader) = MultiQueueBuilder::new(16).spsc().build::<usize>();
+        let handle = thread::spawn(move || for i in 0..100000 {
+            while writer.push(i).is_err() {
+                thread::yield_now();
+            }
+        });
+        for i in 0..100000 {
+            let val = loop {
+                match reader.pop() {
+                    Some(val) => break val,
+                    None => thread::yield_now(),
+                }
+            };
+            assert_eq!(i, val);
+        }
+        handle.join().unwrap();
+        assert_eq!(None, reader.pop());
+    }
+
+    #[test]
+    fn hot_data_starts_lines_of_its_own() {
+        let (_writer, reader) = multiqueue::<usize>(4);
+        let stream = reader.stream().add_stream().into_reader();
+        let writes = &*reader.queue.writes as *const WriterData as usize;
+        let shared = &*reader.queue.shared as *const SharedData<usize> as usize;
+        assert_eq!(0, writes % 64);
+        assert_eq!(0, shared % 64);
+        assert!(shared - writes >= mem::size_of::<WriterData>());
+        for reader in &[reader.reader.load(Relaxed), stream.reader.load(Relaxed)] {
+            assert_eq!(0, *reader as usize % 64);
+        }
+    }
+
+    #[test]
+    fn padded_entries_sit_a_power_of_two_apart() {
+        let (writer, reader) = MultiQueueBuilder::new(5).pad_entries().build::<[u8; 23]>();
+        let stream = reader.stream().add_stream().into_reader();
+        let stride = mem::size_of::<QueueEntry<[u8; 23]>>().next_power_of_two();
+        assert!(format!("{:?}", writer).contains(&format!("stride: Some({})", stride)));
+        unsafe {
+            let (first, second) = (reader.queue.entry(0) as usize, reader.queue.entry(1) as usize);
+            assert_eq!(stride, second - first);
+            assert_eq!(0, first % cmp::min(stride, 64));
+        }
+        for i in 0..12u8 {
+            assert_eq!(Ok(2 * i as usize), writer.push([i; 23]));
+            let claim = writer.claim().unwrap();
+            assert_eq!(2 * i as usize + 1, claim.seq());
+            claim.publish([i + 100; 23]);
+            assert_eq!(Some([i; 23]), reader.pop());
+            let mut buf = [[0; 23]; 2];
+            assert_eq!(1, reader.pop_slice(&mut buf[..1]));
+            assert_eq!([i + 100; 23], buf[0]);
+            assert_eq!(2, stream.pop_slice(&mut buf));
+            assert_eq!([[i; 23], [i + 100; 23]], buf);
+        }
+    }
+
+    #[test]
+    fn hooks_go_off_once_per_interval() {
+        use std::sync::Mutex;
+
+        let fulls = Arc::new(Mutex::new(Vec::new()));
+        let lags = Arc::new(Mutex::new(Vec::new()));
+        let (fref, lref) = (fulls.clone(), lags.clone());
+        let (writer, reader) = MultiQueueBuilder::new(4)
+            .name("hooked")
+            .on_full(move |queue| fref.lock().unwrap().push(queue))
+            .on_lag(3, move |queue, stream, lag| lref.lock().unwrap().push((queue, stream, lag)))
+            .hook_interval(::std::time::Duration::from_secs(3600))
+            .build::<usize>();
+        let ahead = reader.stream().add_stream().into_reader();
+        for i in 0..4 {
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), ahead.pop());
+        }
+        assert_eq!(Err(4), writer.push(4));
+        assert_eq!(Err(4), writer.push(4));
+        assert_eq!(vec!["hooked"], *fulls.lock().unwrap());
+        assert_eq!(vec![("hooked", 0, 3)], *lags.lock().unwrap());
+    }
+
+    #[test]
+    fn idle_streams_are_evicted() {
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        let evictions = Arc::new(Mutex::new(Vec::new()));
+        let eref = evictions.clone();
+        let (writer, stuck) = MultiQueueBuilder::new(4)
+            .name("idle")
+            .evict_idle(Duration::from_millis(20), move |queue, stream| eref.lock().unwrap().push((queue, stream)))
+            .build::<usize>();
+        let live = stuck.stream().add_stream().into_reader();
+        for i in 0..4 {
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), live.pop());
+        }
+        assert_eq!(Err(4), writer.push(4));
+        assert!(evictions.lock().unwrap().is_empty());
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(Err(4), writer.push(4));
+        assert_eq!(vec![("idle", 0)], *evictions.lock().unwrap());
+        // The caught up stream was never idle, and keeps getting items
+        for i in 4..12 {
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), live.pop());
+        }
+        assert!(stuck.is_evicted() && !live.is_evicted());
+        assert_eq!(None, stuck.pop());
+        assert_eq!(TryPop::Closed, stuck.try_pop());
+        assert_eq!(None, stuck.pop_wait());
+    }
+
+    #[test]
+    fn eviction_hooks_can_use_the_queue() {
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        let live = Arc::new(Mutex::new(None));
+        let hook = live.clone();
+        let (writer, stuck) = MultiQueueBuilder::new(2)
+            .wait(WaitStrategy::Park)
+            .evict_idle(Duration::from_millis(20), move |_, _| {
+                let live: &Option<MultiReader<usize>> = &hook.lock().unwrap();
+                assert_eq!(Some(2), live.as_ref().unwrap().pop());
+            })
+            .build::<usize>();
+        let reader = stuck.stream().add_stream().into_reader();
+        for i in 0..2 {
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), reader.pop());
+        }
+        *live.lock().unwrap() = Some(reader);
+        assert_eq!(Err(2), writer.push(2));
+        thread::sleep(Duration::from_millis(40));
+        // Evicts the stuck stream to make room, and runs the hook once pushed
+        writer.push_wait(2).unwrap();
+        assert!(stuck.is_evicted());
+        assert_eq!(None, live.lock().unwrap().as_ref().unwrap().pop());
+    }
+
+    #[test]
+    fn streams_can_be_found_by_name() {
+        let (writer, reader) = multiqueue::<usize>(4);
+        let risk = reader.stream().add_stream_named("risk-checker").into_reader();
+        let audit = reader.stream().add_stream_named("audit");
+        for i in 0..3 {
+            writer.push(i).unwrap();
+        }
+        assert_eq!(Some(0), risk.pop());
+        assert_eq!(Some("risk-checker"), risk.stream_name());
+        assert_eq!(None, reader.stream_name());
+        let found = writer.stream_named("risk-checker").unwrap();
+        assert_eq!(StreamInfo {
+                       id: risk.stream_id(),
+                       name: Some("risk-checker"),
+                       consumed: 1,
+                       lag: 2,
+                       consumers: 1,
+                       evicted: false,
+                   },
+                   found);
+        assert_eq!(None, writer.stream_named("missing"));
+        assert!(writer.evict(found.id));
+        let names: Vec<_> = reader.streams().iter().map(|stream| (stream.name, stream.evicted)).collect();
+        assert_eq!(vec![(None, false), (Some("risk-checker"), true), (Some("audit"), false)], names);
+        drop(audit);
+    }
+
+    #[test]
+    fn streams_only_pop_their_topics() {
+        let (writer, everything) = multiqueue::<usize>(8);
+        let odd = everything.stream().add_stream();
+        odd.subscribe(1 << 1 | 1 << 3);
+        let odd = odd.into_reader();
+        for i in 0..6 {
+            writer.push_topic(i as u8, i).unwrap();
+        }
+        writer.push(6).unwrap();
+        assert_eq!(1 << 1 | 1 << 3, odd.topics());
+        assert_eq!(Some(1), odd.pop());
+        let mut buf = [0; 4];
+        assert_eq!(1, odd.pop_slice(&mut buf));
+        assert_eq!(3, buf[0]);
+        assert_eq!(None, odd.pop());
+        let (items, topics): (Vec<_>, Vec<_>) =
+            (0..7).map(|_| everything.pop_with_meta().map(|(val, meta)| (val, meta.topic)).unwrap()).unzip();
+        assert_eq!(vec![0, 1, 2, 3, 4, 5, 6], items);
+        assert_eq!(vec![0, 1, 2, 3, 4, 5, 0], topics);
+    }
+
+    #[test]
+    fn writers_can_evict_a_stream() {
+        let (writer, stuck) = MultiQueueBuilder::new(2).wait(WaitStrategy::Park).build::<usize>();
+        let idle = stuck.stream().add_stream().into_reader();
+        let live = stuck.stream().add_stream().into_reader();
+        for i in 0..2 {
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), idle.pop());
+            assert_eq!(Some(i), live.pop());
+        }
+        assert_eq!(Err(2), writer.push(2));
+        assert!(!writer.evict(7));
+        assert!(writer.evict(stuck.stream_id()));
+        assert!(!writer.evict(stuck.stream_id()));
+        writer.push(2).unwrap();
+        assert_eq!(None, stuck.pop());
+        // Readers parked on an evicted stream are woken to find it closed
+        let idle_id = idle.stream_id();
+        let parked = thread::spawn(move || {
+            assert_eq!(Some(2), idle.pop_wait());
+            idle.pop_wait()
+        });
+        thread::sleep(::std::time::Duration::from_millis(20));
+        assert!(writer.evict(idle_id));
+        assert_eq!(None, parked.join().unwrap());
+        writer.push(3).unwrap();
+        assert_eq!(Some(2), live.pop());
+        assert_eq!(Some(3), live.pop());
+    }
+
+    #[test]
+    fn expired_items_are_dropped() {
+        let ttl = ::std::time::Duration::from_millis(50);
+        let (writer, reader) = MultiQueueBuilder::new(4).ttl(ttl).build::<usize>();
+        writer.push(1).unwrap();
+        writer.push(2).unwrap();
+        thread::sleep(ttl * 2);
+        writer.push(3).unwrap();
+        assert_eq!(Some((2, 3)), reader.pop_seq());
+        assert_eq!(None, reader.pop());
+
+        writer.push(4).unwrap();
+        thread::sleep(ttl * 2);
+        writer.push(5).unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(1, reader.pop_slice(&mut buf));
+        assert_eq!(5, buf[0]);
+    }
+
+    #[test]
+    fn fresh_pops_skip_old_items() {
+        let max_age = ::std::time::Duration::from_millis(50);
+        let (writer, reader) = MultiQueueBuilder::new(4).timestamps().build::<usize>();
+        let other = reader.stream().add_stream().into_reader();
+        writer.push(1).unwrap();
+        writer.push(2).unwrap();
+        thread::sleep(max_age * 2);
+        writer.push(3).unwrap();
+        assert_eq!((Some(3), 2), reader.pop_fresh_counted(max_age));
+        assert_eq!(None, reader.pop_fresh(max_age));
+        // Other consumers still get everything
+        assert_eq!(Some(1), other.pop());
+
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        writer.push(1).unwrap();
+        assert_eq!(Some(1), reader.pop_fresh(::std::time::Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn timestamps_are_taken_at_publish() {
+        let (writer, reader) = MultiQueueBuilder::new(4).timestamps().build::<usize>();
+        let before = Instant::now();
+        writer.push(1).unwrap();
+        let claim = writer.claim().unwrap();
+        let claimed = Instant::now();
+        claim.publish(2);
+        let (first, meta) = reader.pop_with_meta().unwrap();
+        assert_eq!((1, 0), (first, meta.seq));
+        assert!(meta.published.unwrap() >= before);
+        let (second, meta) = reader.pop_with_meta().unwrap();
+        assert_eq!((2, 1), (second, meta.seq));
+        assert!(meta.published.unwrap() >= claimed);
+        assert!(meta.age().is_some());
+
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        writer.push(1).unwrap();
+        assert_eq!(None, reader.pop_with_meta().unwrap().1.published);
+    }
+
+    #[test]
+    fn pushes_and_pops_agree_on_sequence_numbers() {
+        let (writer, reader) = MultiQueue::<usize>::new(3);
+        for i in 0..10 {
+            assert_eq!(Ok(i * 2), writer.push(i));
+            let claim = writer.claim().unwrap();
+            assert_eq!(i * 2 + 1, claim.seq());
+            claim.publish(i);
+            assert_eq!(Some((i * 2, i)), reader.pop_seq());
+            assert_eq!(Some((i * 2 + 1, i)), reader.pop_seq());
+        }
+        assert_eq!(None, reader.pop_seq());
+    }
+
+    #[test]
+    fn unicast_queues_have_one_stream() {
+        let (writer, reader) = MultiQueueBuilder::new(4).delivery(Delivery::Unicast).build::<usize>();
+        let shared = reader.clone();
+        writer.push(1).unwrap();
+        writer.push(2).unwrap();
+        assert_eq!(Some(1), shared.pop());
+        assert_eq!(Some(2), reader.pop());
+        let added = panic::catch_unwind(panic::AssertUnwindSafe(|| reader.stream().add_stream()));
+        assert!(added.is_err());
+    }
+
+    #[test]
+    fn streams_broadcast_and_readers_share() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let stream = reader.stream();
+        let other = stream.add_stream();
+        let (first, second) = (stream.reader(), other.into_reader());
+        writer.push(1).unwrap();
+        writer.push(2).unwrap();
+        assert_eq!(Some(1), reader.pop());
+        assert_eq!(Some(2), first.pop());
+        assert_eq!(None, reader.pop());
+        assert_eq!(Some(1), second.pop());
+        assert_eq!(Some(2), second.pop());
+    }
+
+    #[test]
+    fn into_inner_hands_back_unread_items() {
+        let (writer, reader) = MultiQueue::<String>::new(4);
+        for word in &["a", "b", "c"] {
+            writer.push(word.to_string()).unwrap();
+        }
+        assert_eq!(Some("a".to_string()), reader.pop());
+        let writer = writer.into_inner().unwrap_err();
+        drop(writer);
+        assert_eq!(vec!["b".to_string(), "c".to_string()], reader.into_inner().unwrap());
+    }
+
+    #[test]
+    fn locked_writers_stay_multi() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        writer.lock_multi();
+        let other = writer.clone();
+        drop(other);
+        writer.push(1).unwrap();
+        assert!(format!("{:?}", writer).starts_with("MultiWriter { state: Locked"));
+        let unlocked = MultiQueue::<usize>::new(4).0;
+        drop(unlocked.clone());
+        unlocked.push(1).unwrap();
+        assert!(format!("{:?}", unlocked).starts_with("MultiWriter { state: Single"));
+        assert_eq!(Some(1), reader.pop());
+    }
+
+    #[test]
+    fn claims_publish_in_place() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let first = writer.claim().unwrap();
+        let second = writer.claim().unwrap();
+        second.publish(2);
+        assert!(reader.pop().is_none());
+        first.publish(1);
+        assert_eq!(Some(1), reader.pop());
+        assert_eq!(Some(2), reader.pop());
+        let _held: Vec<Claim<usize>> = (0..4).map(|_| writer.claim().unwrap()).collect();
+        assert!(writer.claim().is_none());
+    }
+
+    #[test]
+    fn cancelled_claims_are_skipped() {
+        let (writer, reader) = MultiQueue::<usize>::new(2);
+        writer.claim().unwrap().cancel();
+        assert_eq!(Err("bad payload"), writer.claim().unwrap().publish_with(|| Err("bad payload")));
+        assert_eq!(None, reader.pop());
+        assert_eq!(Ok(2), writer.claim().unwrap().publish_with(|| Ok::<_, ()>(5)));
+        assert_eq!(Some((2, 5)), reader.pop_seq());
+        // The cancelled slots went back to the writer
+        writer.push(6).unwrap();
+        assert_eq!(Some(6), reader.pop());
+    }
+
+    #[test]
+    fn abandoned_claims_are_skipped() {
+        let (writer, reader) = MultiQueue::<usize>::new(2);
+        let stream = reader.stream().add_stream().into_reader();
+        let panicked = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _claim = writer.claim().unwrap();
+            panic!("failed before publishing");
+        }));
+        assert!(panicked.is_err());
+        writer.push(1).unwrap();
+        assert_eq!(Some(1), reader.pop());
+        assert!(reader.pop().is_none());
+        assert_eq!(Some(1), stream.pop());
+
+        // Skipped slots don't count toward what a snapshot's streams have consumed
+        drop(writer.claim().unwrap());
+        writer.push(2).unwrap();
+        assert_eq!(Some(2), reader.pop());
+        drop(reader);
+        drop(stream);
+        let snapshot = writer.snapshot().unwrap();
+        assert_eq!(vec![2], snapshot.items);
+        assert_eq!(vec![1, 0], snapshot.streams);
+    }
+
+    #[test]
+    fn waiting_push_pop() {
+        for &wait in &[WaitStrategy::Spin, WaitStrategy::Yield, WaitStrategy::Park] {
+            let (writer, reader) = MultiQueueBuilder::new(2).wait(wait).build::<usize>();
+            let handle = thread::spawn(move || {
+                for i in 0..1000 {
+                    writer.push_wait(i).unwrap();
+                }
+            });
+            for i in 0..1000 {
+                assert_eq!(Some(i), reader.pop_wait());
+            }
+            assert_eq!(None, reader.pop_wait());
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn extend_pushes_everything() {
+        let (mut writer, reader) = MultiQueueBuilder::new(2).wait(WaitStrategy::Park).build::<usize>();
+        let handle = thread::spawn(move || {
+            writer.extend(0..100);
+            let mut rest = 100..110;
+            assert_eq!(Ok(()), writer.extend_blocking(rest.by_ref()));
+            assert!(rest.next().is_none());
+        });
+        let popped: Vec<usize> = iter::from_fn(|| reader.pop_wait()).collect();
+        assert_eq!((0..110).collect::<Vec<_>>(), popped);
+        handle.join().unwrap();
+
+        let (writer, reader) = MultiQueue::<usize>::new(2);
+        drop(reader);
+        let mut rest = 0..5;
+        assert_eq!(Err(2), writer.extend_blocking(rest.by_ref()));
+        assert_eq!(Some(3), rest.next());
+    }
+
+    #[test]
+    fn try_send_all_keeps_what_doesnt_fit() {
+        let (writer, reader) = MultiQueue::<usize>::new(3);
+        let mut buffer = vec![1, 2];
+        assert_eq!(2, writer.try_send_all(&mut buffer));
+        assert!(buffer.is_empty());
+        buffer.extend(3..6);
+        assert_eq!(1, writer.try_send_all(&mut buffer));
+        assert_eq!(vec![4, 5], buffer);
+        assert_eq!(0, writer.try_send_all(&mut buffer));
+        assert_eq!(Some(1), reader.pop());
+        assert_eq!(1, writer.try_send_all(&mut buffer));
+        assert_eq!(vec![5], buffer);
+        let popped: Vec<usize> = iter::from_fn(|| reader.pop()).collect();
+        assert_eq!(vec![2, 3, 4], popped);
+    }
+
+    #[test]
+    fn try_send_all_survives_a_panicking_hook() {
+        struct Counted(Arc<AtomicUsize>);
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Relaxed);
+            }
+        }
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (writer, reader) = MultiQueueBuilder::new(4)
+            .on_lag(1, |_, _, _| panic!("lagging"))
+            .build::<Counted>();
+        let mut buffer: Vec<Counted> = (0..3).map(|_| Counted(dropped.clone())).collect();
+        let sent = panic::catch_unwind(panic::AssertUnwindSafe(|| writer.try_send_all(&mut buffer)));
+        assert!(sent.is_err());
+        assert!(buffer.is_empty());
+        drop((buffer, writer, reader));
+        // Each dropped once, by the queue rather than the buffer too
+        assert_eq!(3, dropped.load(Relaxed));
+    }
+
+    #[test]
+    fn waiting_stops_when_the_other_side_goes() {
+        let (writer, reader) = MultiQueueBuilder::new(1).wait(WaitStrategy::Park).build::<usize>();
+        writer.push(1).unwrap();
+        let handle = thread::spawn(move || writer.push_wait(2));
+        thread::sleep(::std::time::Duration::from_millis(10));
+        drop(reader);
+        assert_eq!(Err(2), handle.join().unwrap());
+    }
+
+    #[test]
+    fn debug_shows_positions() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let stream = reader.stream().add_stream().into_reader();
+        writer.push(1).unwrap();
+        writer.push(2).unwrap();
+        reader.pop().unwrap();
+        assert_eq!(1, reader.lag());
+        assert_eq!(2, stream.lag());
+        let dump = format!("{:?}", writer);
+        assert!(dump.contains("written: 2"), "{}", dump);
+        assert!(dump.contains("writers: 1"), "{}", dump);
+        assert!(dump.contains("nread: 1"), "{}", dump);
+        assert!(dump.contains("nread: 0"), "{}", dump);
+        let dump = format!("{:?}", stream);
+        assert!(dump.starts_with("MultiReader { reader: Reader { id: 1, nread: 0"), "{}", dump);
+    }
+
+    #[test]
+    fn debug_shows_labels() {
+        let (writer, _) = MultiQueueBuilder::new(4)
+            .name("orders")
+            .labels(&[("shard", "3")])
+            .build::<usize>();
+        let dump = format!("{:?}", writer);
+        assert!(dump.contains("name: \"orders\", labels: [(\"shard\", \"3\")]"), "{}", dump);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let (writer, reader) = MultiQueue::<usize>::new(4);
+        let behind = reader.stream().add_stream().into_reader();
+        for i in 0..3 {
+            writer.push(i).unwrap();
+        }
+        assert_eq!(0, reader.pop().unwrap());
+        assert_eq!(1, reader.pop().unwrap());
+        assert!(writer.snapshot().is_none());
+        drop(reader);
+        drop(behind);
+
+        let snapshot = writer.snapshot().unwrap();
+        assert_eq!(vec![0, 1, 2], snapshot.items);
+        assert_eq!(vec![2, 0], snapshot.streams);
+        assert!(snapshot.clone().restore(&MultiQueueBuilder::new(2)).is_err());
+
+        let (writer, readers) = snapshot.restore(&MultiQueueBuilder::new(4)).unwrap();
+        assert_eq!(2, readers[0].pop().unwrap());
+        assert!(readers[0].pop().is_none());
+        for i in 0..3 {
+            assert_eq!(i, readers[1].pop().unwrap());
+        }
+        writer.push(3).unwrap();
+        assert_eq!(3, readers[0].pop().unwrap());
+        assert_eq!(3, readers[1].pop().unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn snapshot_serde_roundtrip() {
+        extern crate serde_json;
+
+        let (writer, reader) = MultiQueue::<u32>::new(4);
+        writer.push(7).unwrap();
+        drop(reader);
+        let snapshot = writer.snapshot().unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(snapshot, serde_json::from_str(&json).unwrap());
+    }
+
+    fn spsc_broadcast(receivers: usize) {
+        let (writer, reader) = MultiQueue::<usize>::new(10);
+        let myb = Barrier::new(receivers + 1);
+        let bref = &myb;
+        let num_loop = if cfg!(miri) { 1000 } else { 1000000 };
+        scope(|scope| {
+            scope.spawn(move || {
+                bref.wait();
+                'outer: for i in 0..num_loop {
+                    loop {
                         if writer.push(i).is_ok() {
                             break;
                         }
@@ -324,7 +3801,7 @@ mod test {
                 }
             });
             for i in 0..(receivers - 1) {
-                let this_reader = reader.add_reader();
+                let this_reader = reader.stream().add_stream().into_reader();
                 scope.spawn(move || {
                     bref.wait();
                     'outer: for i in 0..num_loop {
@@ -361,3 +3838,294 @@ mod test {
     }
 
 }
+
+/// Model checks of the cursors' orderings, run under `--cfg loom`.
+/// Capacities are tiny so the writers wrap and wait on the readers
+#[cfg(all(test, loom))]
+mod loom_test {
+    use super::*;
+
+    use loom::model::Builder;
+    use loom::thread;
+
+    fn check<F: Fn() + Sync + Send + 'static>(f: F) {
+        let mut model = Builder::new();
+        model.preemption_bound = Some(3);
+        model.check(f);
+    }
+
+    fn push(writer: &MultiWriter<usize>, val: usize) {
+        while writer.push(val).is_err() {
+            thread::yield_now();
+        }
+    }
+
+    fn pop(reader: &MultiReader<usize>) -> usize {
+        loop {
+            match reader.pop() {
+                Some(val) => return val,
+                None => thread::yield_now(),
+            }
+        }
+    }
+
+    #[test]
+    fn loom_spsc() {
+        check(|| {
+            let (writer, reader) = multiqueue(2);
+            let producer = thread::spawn(move || for i in 0..3 {
+                push(&writer, i);
+            });
+            for i in 0..3 {
+                assert_eq!(i, pop(&reader));
+            }
+            producer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn loom_mpsc() {
+        check(|| {
+            // Room for every item, since writers waiting on the reader as
+            // well as racing each other is more than loom can get through
+            let (writer, reader) = multiqueue(4);
+            let other = writer.clone();
+            let producers = vec![thread::spawn(move || {
+                                     push(&writer, 0);
+                                     push(&writer, 1);
+                                 }),
+                                 thread::spawn(move || push(&other, 10))];
+            let got: Vec<usize> = (0..3).map(|_| pop(&reader)).collect();
+            for producer in producers {
+                producer.join().unwrap();
+            }
+            // Each writer's items stay in the order it pushed them
+            let first: Vec<usize> = got.iter().cloned().filter(|&val| val < 10).collect();
+            assert_eq!(vec![0, 1], first);
+            assert!(got.contains(&10));
+        });
+    }
+
+    #[test]
+    fn loom_reader_added_during_writes() {
+        check(|| {
+            let (writer, reader) = multiqueue(2);
+            let producer = thread::spawn(move || for i in 0..3 {
+                push(&writer, i);
+            });
+            let stream = reader.stream().add_stream().into_reader();
+            let (mut first, mut second) = (Vec::new(), Vec::new());
+            // Both streams have to keep up or the writer can't wrap
+            while first.len() < 3 || second.len() < 3 {
+                if first.len() < 3 {
+                    if let Some(val) = reader.pop() {
+                        first.push(val);
+                    }
+                }
+                if second.len() < 3 {
+                    if let Some(val) = stream.pop() {
+                        second.push(val);
+                    }
+                }
+                thread::yield_now();
+            }
+            producer.join().unwrap();
+            assert_eq!(vec![0, 1, 2], first);
+            assert_eq!(vec![0, 1, 2], second);
+        });
+    }
+
+    #[test]
+    fn loom_fixed_slot_filled_during_writes() {
+        check(|| {
+            let (writer, reader) = MultiQueueBuilder::new(1).max_streams(2).build::<usize>();
+            writer.push(0).unwrap();
+            let handle = reader.stream();
+            let producer = thread::spawn(move || {
+                let _ = writer.push(1);
+            });
+            let adder = thread::spawn(move || handle.add_stream().into_reader());
+            // The first stream moves on while the new one is copied from it
+            assert_eq!(Some(0), reader.pop());
+            producer.join().unwrap();
+            let stream = adder.join().unwrap();
+            while reader.pop().is_some() {}
+            while stream.pop().is_some() {}
+            // Nothing was written over before the new stream got to it
+            let positions = stream.positions();
+            assert!(positions.streams.iter().all(|stream| stream.consumed == positions.head),
+                    "{:?}",
+                    positions);
+        });
+    }
+}
+
+/// Randomized schedules of scenarios too big for loom, run under `--cfg shuttle`:
+/// many writers, streams added and evicted mid-traffic, and handles cloned and
+/// dropped so readers and writers keep flipping between Single and Multi
+#[cfg(all(test, shuttle))]
+mod shuttle_test {
+    use super::*;
+
+    use shuttle::thread;
+
+    const ITEMS: usize = 6;
+
+    fn check<F: Fn() + Sync + Send + 'static>(f: F) {
+        shuttle::check_random(f, 2000);
+    }
+
+    fn push(writer: &MultiWriter<usize>, val: usize) {
+        while writer.push(val).is_err() {
+            thread::yield_now();
+        }
+    }
+
+    fn pop(reader: &MultiReader<usize>) -> usize {
+        loop {
+            match reader.pop() {
+                Some(val) => return val,
+                None => thread::yield_now(),
+            }
+        }
+    }
+
+    /// Items from each writer, tagged by writer * 100, come in the order pushed
+    fn assert_ordered(got: &[usize]) {
+        for writer in 0..4 {
+            let from: Vec<usize> = got.iter().cloned().filter(|val| val / 100 == writer).collect();
+            assert!(from.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", got);
+        }
+    }
+
+    #[test]
+    fn shuttle_many_writers_two_streams() {
+        check(|| {
+            let (writer, reader) = multiqueue(4);
+            let stream = reader.stream().add_stream().into_reader();
+            let producers: Vec<_> = (0..4)
+                .map(|id| {
+                    let writer = writer.clone();
+                    thread::spawn(move || for i in 0..ITEMS {
+                        push(&writer, id * 100 + i);
+                    })
+                })
+                .collect();
+            drop(writer);
+            let (mut first, mut second) = (Vec::new(), Vec::new());
+            while first.len() < 4 * ITEMS || second.len() < 4 * ITEMS {
+                if let Some(val) = reader.pop() {
+                    first.push(val);
+                }
+                if let Some(val) = stream.pop() {
+                    second.push(val);
+                }
+                thread::yield_now();
+            }
+            for producer in producers {
+                producer.join().unwrap();
+            }
+            assert_ordered(&first);
+            assert_eq!(first, second);
+            assert_eq!(None, reader.pop());
+        });
+    }
+
+    #[test]
+    fn shuttle_streams_added_and_evicted_during_traffic() {
+        check(|| {
+            let (writer, reader) = multiqueue(4);
+            let evictor = writer.clone();
+            let producer = thread::spawn(move || for i in 0..ITEMS {
+                push(&writer, i);
+            });
+            let handle = reader.stream();
+            let visitor = thread::spawn(move || {
+                let stream = handle.add_stream_named("visitor").into_reader();
+                let mut got: Vec<usize> = Vec::new();
+                loop {
+                    match stream.pop() {
+                        Some(val) => got.push(val),
+                        None if stream.is_closed() => break,
+                        None => thread::yield_now(),
+                    }
+                }
+                // Whatever it got before going is a run of what was pushed
+                assert!(got.windows(2).all(|pair| pair[0] + 1 == pair[1]), "{:?}", got);
+            });
+            for i in 0..ITEMS / 2 {
+                assert_eq!(i, pop(&reader));
+            }
+            let id = loop {
+                match evictor.stream_named("visitor") {
+                    Some(info) => break info.id,
+                    None => thread::yield_now(),
+                }
+            };
+            assert!(evictor.evict(id));
+            drop(evictor);
+            for i in ITEMS / 2..ITEMS {
+                assert_eq!(i, pop(&reader));
+            }
+            producer.join().unwrap();
+            visitor.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn shuttle_handles_flapping_between_modes() {
+        check(|| {
+            let (writer, reader) = multiqueue(4);
+            let producers: Vec<_> = (0..2)
+                .map(|id| {
+                    let writer = writer.clone();
+                    thread::spawn(move || for i in 0..ITEMS {
+                        // A clone turns every writer Multi until it's dropped
+                        if i % 2 == 0 {
+                            push(&writer.clone(), id * 100 + i);
+                        } else {
+                            push(&writer, id * 100 + i);
+                        }
+                    })
+                })
+                .collect();
+            drop(writer);
+            let taken = Arc::new(AtomicUsize::new(0));
+            let consumers: Vec<_> = (0..2)
+                .map(|_| {
+                    let (reader, taken) = (reader.clone(), taken.clone());
+                    thread::spawn(move || {
+                        let mut got = Vec::new();
+                        let mut tries = 0;
+                        while taken.load(SeqCst) < 2 * ITEMS {
+                            tries += 1;
+                            let popped = if tries % 2 == 0 { reader.clone().pop() } else { reader.pop() };
+                            match popped {
+                                Some(val) => {
+                                    taken.fetch_add(1, SeqCst);
+                                    got.push(val);
+                                }
+                                None => thread::yield_now(),
+                            }
+                        }
+                        got
+                    })
+                })
+                .collect();
+            drop(reader);
+            let mut got: Vec<usize> = Vec::new();
+            for consumer in consumers {
+                let popped = consumer.join().unwrap();
+                assert_ordered(&popped);
+                got.extend(popped);
+            }
+            for producer in producers {
+                producer.join().unwrap();
+            }
+            got.sort();
+            let mut want: Vec<usize> = (0..ITEMS).chain(100..100 + ITEMS).collect();
+            want.sort();
+            assert_eq!(want, got);
+        });
+    }
+}
@@ -0,0 +1,278 @@
+//! Several small typed channels sharing one queue
+//!
+//! The queue carries an enum E with a variant per channel, and each channel
+//! goes over a topic of its own. Senders wrap their type into E and push
+//! it to the channel's topic; each receiver is a stream subscribed to just
+//! that topic, and unwraps what it pops. Every stream is handed every item
+//! bitwise, so E has to be Copy.
+//!
+//! The queue's own stream takes no topics and only ever skips ahead, which
+//! senders do for it when the queue looks full. A receiver that stops
+//! popping still holds up every channel.
+//!
+//! ```
+//! use pipeline::queue::multiqueue::MultiQueueBuilder;
+//! use pipeline::queue::mux::{Channel, Mux};
+//!
+//! #[derive(Clone, Copy)]
+//! enum Control {
+//!     Pause(u32),
+//! }
+//!
+//! #[derive(Debug, PartialEq)]
+//! struct Pause(u32);
+//!
+//! impl Channel<Control> for Pause {
+//!     const TOPIC: u8 = 1;
+//!
+//!     fn wrap(self) -> Control {
+//!         Control::Pause(self.0)
+//!     }
+//!
+//!     fn unwrap(from: Control) -> Option<Pause> {
+//!         match from {
+//!             Control::Pause(millis) => Some(Pause(millis)),
+//!         }
+//!     }
+//! }
+//!
+//! let mux = Mux::<Control>::new(&MultiQueueBuilder::new(16));
+//! let pauses = mux.receiver::<Pause>();
+//! mux.sender::<Pause>().push(Pause(10)).unwrap();
+//! assert_eq!(Some(Pause(10)), pauses.pop());
+//! ```
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use queue::multiqueue::{MultiQueueBuilder, MultiReader, MultiWriter};
+
+/// A type sent over one channel of a Mux carrying E
+pub trait Channel<E>: Sized {
+    /// Which of the queue's 64 topics the channel goes over.
+    /// No two channels on a queue should share one
+    const TOPIC: u8;
+
+    fn wrap(self) -> E;
+
+    /// Gives None for any E that isn't from this channel
+    fn unwrap(from: E) -> Option<Self>;
+}
+
+/// Hands out senders and receivers for the channels over one queue
+pub struct Mux<E> {
+    writer: MultiWriter<E>,
+    // Takes no topics, and gets skipped to the head to make room
+    root: MultiReader<E>,
+}
+
+pub struct MuxSender<E, V> {
+    writer: MultiWriter<E>,
+    root: MultiReader<E>,
+    channel: PhantomData<fn(V)>,
+}
+
+pub struct MuxReceiver<E, V> {
+    reader: MultiReader<E>,
+    channel: PhantomData<fn() -> V>,
+}
+
+impl<E: Copy> Mux<E> {
+    /// Builds the queue the channels share. Each receiver is a stream of its
+    /// own, so it has to be a broadcast queue, as queues are by default
+    pub fn new(builder: &MultiQueueBuilder) -> Mux<E> {
+        let (writer, root) = builder.build();
+        root.stream().subscribe(0);
+        Mux {
+            writer: writer,
+            root: root,
+        }
+    }
+
+    pub fn sender<V: Channel<E>>(&self) -> MuxSender<E, V> {
+        MuxSender {
+            writer: self.writer.clone(),
+            root: self.root.clone(),
+            channel: PhantomData,
+        }
+    }
+
+    /// A receiver on a new stream for V's channel, getting whatever is
+    /// sent on it from now on. Clones of it share the channel's items
+    pub fn receiver<V: Channel<E>>(&self) -> MuxReceiver<E, V> {
+        // The new stream starts where the root is, so catch it up first
+        self.root.pop();
+        let stream = self.root.stream().add_stream();
+        stream.subscribe(1 << V::TOPIC);
+        MuxReceiver {
+            reader: stream.into_reader(),
+            channel: PhantomData,
+        }
+    }
+}
+
+impl<E: Copy, V: Channel<E>> MuxSender<E, V> {
+    /// Sends val on the channel, or gives it back if a receiver on any
+    /// channel is a whole queue behind
+    pub fn push(&self, val: V) -> Result<usize, V> {
+        let back = match self.writer.push_topic(V::TOPIC, val.wrap()) {
+            Ok(seq) => return Ok(seq),
+            Err(back) => back,
+        };
+        // Maybe it's only the root holding the queue up
+        self.root.pop();
+        self.writer
+            .push_topic(V::TOPIC, back)
+            .map_err(|back| V::unwrap(back).expect("an item came back from another channel"))
+    }
+}
+
+impl<E: Copy, V: Channel<E>> MuxReceiver<E, V> {
+    pub fn pop(&self) -> Option<V> {
+        loop {
+            // Anything that doesn't unwrap is from a channel sharing the topic
+            if let Some(val) = V::unwrap(self.reader.pop()?) {
+                return Some(val);
+            }
+        }
+    }
+
+    /// Pops, waiting for an item as the queue was built to. Returns None
+    /// once the queue is closed and the channel is empty
+    pub fn pop_wait(&self) -> Option<V> {
+        loop {
+            if let Some(val) = V::unwrap(self.reader.pop_wait()?) {
+                return Some(val);
+            }
+        }
+    }
+}
+
+impl<E, V> Clone for MuxSender<E, V> {
+    fn clone(&self) -> MuxSender<E, V> {
+        MuxSender {
+            writer: self.writer.clone(),
+            root: self.root.clone(),
+            channel: PhantomData,
+        }
+    }
+}
+
+impl<E, V> Clone for MuxReceiver<E, V> {
+    fn clone(&self) -> MuxReceiver<E, V> {
+        MuxReceiver {
+            reader: self.reader.clone(),
+            channel: PhantomData,
+        }
+    }
+}
+
+impl<E> fmt::Debug for Mux<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mux")
+            .field("writer", &self.writer)
+            .finish()
+    }
+}
+
+impl<E, V> fmt::Debug for MuxSender<E, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MuxSender")
+            .field("writer", &self.writer)
+            .finish()
+    }
+}
+
+impl<E, V> fmt::Debug for MuxReceiver<E, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MuxReceiver")
+            .field("reader", &self.reader)
+            .finish()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Control {
+        Start(u32),
+        Stop,
+        Load(u64),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Run {
+        Start(u32),
+        Stop,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Load(u64);
+
+    impl Channel<Control> for Run {
+        const TOPIC: u8 = 0;
+
+        fn wrap(self) -> Control {
+            match self {
+                Run::Start(at) => Control::Start(at),
+                Run::Stop => Control::Stop,
+            }
+        }
+
+        fn unwrap(from: Control) -> Option<Run> {
+            match from {
+                Control::Start(at) => Some(Run::Start(at)),
+                Control::Stop => Some(Run::Stop),
+                _ => None,
+            }
+        }
+    }
+
+    impl Channel<Control> for Load {
+        const TOPIC: u8 = 1;
+
+        fn wrap(self) -> Control {
+            Control::Load(self.0)
+        }
+
+        fn unwrap(from: Control) -> Option<Load> {
+            match from {
+                Control::Load(load) => Some(Load(load)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn channels_only_see_their_own_items() {
+        let mux = Mux::<Control>::new(&MultiQueueBuilder::new(4));
+        let runs = mux.receiver::<Run>();
+        let loads = mux.receiver::<Load>();
+        let (run, load) = (mux.sender::<Run>(), mux.sender::<Load>());
+        run.push(Run::Start(3)).unwrap();
+        load.push(Load(70)).unwrap();
+        run.push(Run::Stop).unwrap();
+        assert_eq!(Some(Load(70)), loads.pop());
+        assert_eq!(None, loads.pop());
+        assert_eq!(Some(Run::Start(3)), runs.pop());
+        assert_eq!(Some(Run::Stop), runs.pop());
+        assert_eq!(None, runs.pop());
+    }
+
+    #[test]
+    fn senders_skip_the_root_ahead_for_room() {
+        let mux = Mux::<Control>::new(&MultiQueueBuilder::new(4));
+        let loads = mux.receiver::<Load>();
+        let load = mux.sender::<Load>();
+        for i in 0..12 {
+            load.push(Load(i)).unwrap();
+            assert_eq!(Some(Load(i)), loads.pop());
+        }
+        for i in 0..4 {
+            load.push(Load(i)).unwrap();
+        }
+        assert_eq!(Err(Load(4)), load.push(Load(4)));
+    }
+}
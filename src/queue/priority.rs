@@ -0,0 +1,158 @@
+//! A high and a low priority queue popped through one handle
+//!
+//! Each lane is a queue of its own, so a full low lane never holds up the
+//! high one. Pops drain the high lane before looking at the low one, which
+//! suits control messages sharing a consumer loop with data. A steady stream
+//! of high priority items starves the low lane, so keep it for the rare ones.
+
+use std::fmt;
+
+use queue::multiqueue::{MultiQueueBuilder, MultiReader, MultiWriter};
+
+/// Which lane an item went through
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lane {
+    High,
+    Low,
+}
+
+pub struct PriorityWriter<T> {
+    high: MultiWriter<T>,
+    low: MultiWriter<T>,
+}
+
+pub struct PriorityReader<T> {
+    high: MultiReader<T>,
+    low: MultiReader<T>,
+}
+
+impl<T> PriorityWriter<T> {
+    /// Pushes val onto the given lane, as MultiWriter::push does
+    pub fn push(&self, lane: Lane, val: T) -> Result<usize, T> {
+        self.lane(lane).push(val)
+    }
+
+    pub fn push_high(&self, val: T) -> Result<usize, T> {
+        self.high.push(val)
+    }
+
+    pub fn push_low(&self, val: T) -> Result<usize, T> {
+        self.low.push(val)
+    }
+
+    /// The writer for one lane, for anything push doesn't cover
+    pub fn lane(&self, lane: Lane) -> &MultiWriter<T> {
+        match lane {
+            Lane::High => &self.high,
+            Lane::Low => &self.low,
+        }
+    }
+}
+
+impl<T> PriorityReader<T> {
+    /// Pops from the high lane, or the low one if that's empty
+    pub fn pop(&self) -> Option<T> {
+        self.pop_lane().map(|(_, val)| val)
+    }
+
+    /// Like pop, along with which lane the item came from
+    pub fn pop_lane(&self) -> Option<(Lane, T)> {
+        match self.high.pop() {
+            Some(val) => Some((Lane::High, val)),
+            None => self.low.pop().map(|val| (Lane::Low, val)),
+        }
+    }
+
+    /// Whether either lane has an item to pop
+    pub fn is_ready(&self) -> bool {
+        self.high.is_ready() || self.low.is_ready()
+    }
+
+    /// The reader for one lane, for anything pop doesn't cover
+    pub fn lane(&self, lane: Lane) -> &MultiReader<T> {
+        match lane {
+            Lane::High => &self.high,
+            Lane::Low => &self.low,
+        }
+    }
+}
+
+impl<T> Clone for PriorityWriter<T> {
+    fn clone(&self) -> PriorityWriter<T> {
+        PriorityWriter {
+            high: self.high.clone(),
+            low: self.low.clone(),
+        }
+    }
+}
+
+impl<T> Clone for PriorityReader<T> {
+    fn clone(&self) -> PriorityReader<T> {
+        PriorityReader {
+            high: self.high.clone(),
+            low: self.low.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for PriorityWriter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PriorityWriter")
+            .field("high", &self.high)
+            .field("low", &self.low)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for PriorityReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PriorityReader")
+            .field("high", &self.high)
+            .field("low", &self.low)
+            .finish()
+    }
+}
+
+/// Creates a pair of lanes holding up to high and low items each
+pub fn priority<T>(high: u16, low: u16) -> (PriorityWriter<T>, PriorityReader<T>) {
+    priority_with(&MultiQueueBuilder::new(high), &MultiQueueBuilder::new(low))
+}
+
+/// Like priority, with each lane's queue built from its own configuration
+pub fn priority_with<T>(high: &MultiQueueBuilder, low: &MultiQueueBuilder) -> (PriorityWriter<T>, PriorityReader<T>) {
+    let (high_writer, high_reader) = high.build();
+    let (low_writer, low_reader) = low.build();
+    let writer = PriorityWriter {
+        high: high_writer,
+        low: low_writer,
+    };
+    let reader = PriorityReader {
+        high: high_reader,
+        low: low_reader,
+    };
+    (writer, reader)
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn high_lane_pops_first() {
+        let (writer, reader) = priority::<usize>(2, 4);
+        assert!(!reader.is_ready());
+        writer.push_low(1).unwrap();
+        writer.push_low(2).unwrap();
+        writer.push(Lane::High, 3).unwrap();
+        assert!(reader.is_ready());
+        assert_eq!(Some((Lane::High, 3)), reader.pop_lane());
+        assert_eq!(Some((Lane::Low, 1)), reader.pop_lane());
+        writer.push_high(4).unwrap();
+        writer.push_high(5).unwrap();
+        assert_eq!(Err(6), writer.push_high(6));
+        assert_eq!(Some(4), reader.pop());
+        assert_eq!(Some(5), reader.pop());
+        assert_eq!(Some(2), reader.pop());
+        assert_eq!(None, reader.pop());
+    }
+}
@@ -1,13 +1,19 @@
 use std::cell::Cell;
+use std::cmp;
+use std::fmt;
+use std::mem;
 use std::ptr;
-use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering, fence};
 
-use util::alloc;
+use queue::trace;
+use queue::waker::WakerList;
+use util::alloc::{self, Counter, RawAlloc};
+use util::cache_padded::CachePadded;
 use util::consume::Consume;
 use util::countedu16::{CountedU16, Transaction};
 use util::maybe_acquire::{MAYBE_ACQUIRE, maybe_acquire_fence};
+use util::sync::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering, fence, spin_loop};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 enum ReaderState {
     Single,
     Multi,
@@ -17,12 +23,26 @@ pub struct Reader {
     pos_data: CountedU16,
     state: Cell<ReaderState>,
     num_consumers: AtomicUsize,
+    id: usize,
+    name: Option<&'static str>,
+    // The queue's name and labels, for tracing
+    queue: trace::Labels,
+    // Async readers waiting on the stream
+    wakers: WakerList,
+    // Set for good once the stream stops holding writers back
+    evicted: AtomicBool,
+    // Bit n set for every topic n the stream takes items from
+    topics: AtomicU64,
+    // Where the stream was when a full queue last looked, and since when
+    // on the queue's clock. usize::MAX if it wasn't behind then
+    seen: AtomicUsize,
+    seen_since: AtomicUsize,
 }
 
 /// This represents the reader attempt at loading a transaction
 /// It behaves similarly to a Transaction but has logic for single/multi
 /// readers
-struct ReadAttempt<'a> {
+pub struct ReadAttempt<'a> {
     linked: Transaction<'a>,
     reader: &'a Reader,
     state: ReaderState,
@@ -32,12 +52,31 @@ struct ReadAttempt<'a> {
 /// This struct is held out of line from the cursor so it's easy to atomically replace it
 struct ReaderGroup {
     readers: *const *const Reader,
-    n_readers: usize,
+    // Only ever grows, and only on a cursor with fixed slots
+    n_readers: AtomicUsize,
+    // How many readers there's room for
+    slots: usize,
+    // Odd while a reader is being put in one of the slots. Groups with
+    // fixed slots are never replaced, so writers check this instead
+    generation: AtomicUsize,
+    // The group this one replaced. A writer may still be looking at it,
+    // so it's only freed along with the cursor
+    replaced: *mut ReaderGroup,
 }
 
 #[repr(C)]
 pub struct ReadCursor {
     readers: AtomicPtr<ReaderGroup>,
+    // Where the groups come from and go back to
+    alloc: &'static dyn RawAlloc,
+    // Where the readers do, allocated by whichever thread adds them
+    reader_alloc: &'static dyn RawAlloc,
+    // On a cursor with fixed slots, how many have been handed out.
+    // Readers are only ever added to the one group, in slot order
+    fixed: Option<AtomicUsize>,
+    rescans: AtomicUsize,
+    // Counts what the queue allocates, readers and ring alike
+    counter: Counter,
 }
 
 impl<'a> ReadAttempt<'a> {
@@ -62,19 +101,20 @@ impl<'a> ReadAttempt<'a> {
                 if self.reader.num_consumers.load(Ordering::Relaxed) == 1 {
                     fence(Ordering::Acquire);
                     self.reader.state.set(ReaderState::Single);
-                    self.linked.commit_direct(by, ord);
-                    None
-                } else {
-                    match self.linked.commit(by, ord) {
-                        Some(transaction) => {
-                            Some(ReadAttempt {
-                                linked: transaction,
-                                reader: self.reader,
-                                state: ReaderState::Multi,
-                            })
-                        }
-                        None => None,
+                    trace::reader_mode(self.reader.queue, self.reader.id, false);
+                }
+                // Even the last consumer left has to commit this attempt the
+                // careful way, since it may have been loaded before the others
+                // moved the stream on, and storing it would rewind the stream
+                match self.linked.commit(by, ord) {
+                    Some(transaction) => {
+                        Some(ReadAttempt {
+                            linked: transaction,
+                            reader: self.reader,
+                            state: ReaderState::Multi,
+                        })
                     }
+                    None => None,
                 }
             }
         }
@@ -96,11 +136,91 @@ impl Reader {
         self.pos_data.load_count(ord)
     }
 
+    /// The slot this reader reads next, and how many times it's wrapped around the ring
+    #[inline(always)]
+    pub fn load_pos(&self, ord: Ordering) -> (u16, usize) {
+        let raw = self.pos_data.load_raw(ord);
+        (raw as u16, raw >> 16)
+    }
+
+    /// The index of this reader's stream in the order streams were added
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// What the stream was named when it was added, if anything
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Skips this reader past items without reading them.
+    /// There must be at least that many items written that it hasn't read
+    pub fn advance(&self, by: u16) {
+        self.pos_data.load_transaction(Ordering::Relaxed).commit_direct(by, Ordering::Release);
+    }
+
+    /// Moves this reader back over items it already read.
+    /// Nothing may have written over them yet
+    pub fn rewind(&self, by: u16) {
+        self.pos_data.store_raw(self.pos_data.get_previous(by), Ordering::SeqCst);
+    }
+
     pub fn dup_consumer(&self) {
+        if let ReaderState::Single = self.state.get() {
+            trace::reader_mode(self.queue, self.id, true);
+        }
         self.state.set(ReaderState::Multi);
         self.num_consumers.fetch_add(1, Ordering::SeqCst);
     }
 
+    /// The async readers waiting on this stream
+    pub fn wakers(&self) -> &WakerList {
+        &self.wakers
+    }
+
+    /// Stops the stream holding writers back, and makes its pops come up
+    /// empty from then on. Returns whether it wasn't evicted already
+    pub fn evict(&self) -> bool {
+        !self.evicted.swap(true, Ordering::SeqCst)
+    }
+
+    #[inline(always)]
+    pub fn is_evicted(&self) -> bool {
+        self.evicted.load(Ordering::Acquire)
+    }
+
+    /// How long by now the stream has sat at nread, behind the head.
+    /// With None it's caught up, and the clock starts over
+    pub fn idle_for(&self, nread: Option<usize>, now: usize) -> usize {
+        let nread = nread.unwrap_or(usize::MAX);
+        if self.seen.swap(nread, Ordering::Relaxed) != nread {
+            self.seen_since.store(now, Ordering::Relaxed);
+        }
+        if nread == usize::MAX {
+            0
+        } else {
+            now.wrapping_sub(self.seen_since.load(Ordering::Relaxed))
+        }
+    }
+
+    #[inline(always)]
+    pub fn topics(&self) -> u64 {
+        self.topics.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    pub fn subscribed(&self, topic: u8) -> bool {
+        self.topics() & (1 << topic) != 0
+    }
+
+    pub fn subscribe(&self, topics: u64) {
+        self.topics.store(topics, Ordering::Relaxed);
+    }
+
+    pub fn consumers(&self) -> usize {
+        self.num_consumers.load(Ordering::SeqCst)
+    }
+
     pub fn remove_consumer(&self) {
         self.num_consumers.fetch_sub(1, Ordering::SeqCst);
 
@@ -108,49 +228,171 @@ impl Reader {
     }
 }
 
+impl fmt::Debug for Reader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reader")
+            .field("id", &self.id)
+            .field("nread", &self.pos_data.load_count(Ordering::Relaxed))
+            .field("pos", &self.pos_data)
+            .field("state", &self.state.get())
+            .field("consumers", &self.num_consumers.load(Ordering::Relaxed))
+            .field("evicted", &self.evicted.load(Ordering::Relaxed))
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
 impl ReaderGroup {
     pub fn new() -> ReaderGroup {
         ReaderGroup {
             readers: ptr::null(),
-            n_readers: 0,
+            n_readers: AtomicUsize::new(0),
+            slots: 0,
+            generation: AtomicUsize::new(0),
+            replaced: ptr::null_mut(),
         }
     }
 
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.n_readers.load(Ordering::Acquire)
+    }
+
     /// Only safe to call from a consumer of the queue!
+    /// The new group holds on to replaced, which should be where this group
+    /// lives, and new_reader, which should come from new_reader with this
+    /// group's length as its id
     pub unsafe fn add_reader(&self,
-                             raw: usize,
-                             wrap: u16)
-                             -> (*mut ReaderGroup, AtomicPtr<Reader>) {
-        let next_readers = self.n_readers + 1;
-        let new_reader = alloc::allocate(1);
-        let new_readers = alloc::allocate(next_readers);
-        let new_group = alloc::allocate(1);
-        ptr::write(new_reader,
-                   Reader {
-                       pos_data: CountedU16::from_usize(raw, wrap),
-                       state: Cell::new(ReaderState::Single),
-                       num_consumers: AtomicUsize::new(1),
-                   });
-        for i in 0..self.n_readers as isize {
-            *new_readers.offset(i) = *self.readers.offset(i);
+                             new_reader: *mut Reader,
+                             replaced: *mut ReaderGroup,
+                             from: &dyn RawAlloc)
+                             -> *mut ReaderGroup {
+        let n_readers = self.len();
+        let next_readers = n_readers + 1;
+        let new_readers: *mut *const Reader = alloc::allocate(from, next_readers);
+        let new_group: *mut ReaderGroup = alloc::allocate(from, 1);
+        for i in 0..n_readers as isize {
+            ptr::write(new_readers.offset(i), *self.readers.offset(i));
         }
-        *new_readers.offset((next_readers - 1) as isize) = new_reader;
+        ptr::write(new_readers.offset((next_readers - 1) as isize), new_reader);
         ptr::write(new_group,
                    ReaderGroup {
                        readers: new_readers as *const *const Reader,
-                       n_readers: next_readers,
+                       n_readers: AtomicUsize::new(next_readers),
+                       slots: next_readers,
+                       generation: AtomicUsize::new(0),
+                       replaced: replaced,
+                   });
+        new_group
+    }
+
+    /// A group with every one of slots readers allocated up front,
+    /// the first of them in use and starting at raw
+    unsafe fn with_slots(raw: usize,
+                         wrap: u16,
+                         slots: usize,
+                         queue: trace::Labels,
+                         from: &dyn RawAlloc,
+                         reader_from: &dyn RawAlloc)
+                         -> *mut ReaderGroup {
+        let readers: *mut *const Reader = alloc::allocate(from, slots);
+        let group: *mut ReaderGroup = alloc::allocate(from, 1);
+        for i in 0..slots {
+            ptr::write(readers.add(i), ReaderGroup::new_reader(raw, wrap, i, None, queue, reader_from));
+        }
+        ptr::write(group,
+                   ReaderGroup {
+                       readers: readers as *const *const Reader,
+                       n_readers: AtomicUsize::new(1),
+                       slots: slots,
+                       generation: AtomicUsize::new(0),
+                       replaced: ptr::null_mut(),
                    });
-        (new_group, AtomicPtr::new(new_reader))
+        group
+    }
+
+    unsafe fn new_reader(raw: usize,
+                         wrap: u16,
+                         id: usize,
+                         name: Option<&'static str>,
+                         queue: trace::Labels,
+                         from: &dyn RawAlloc)
+                         -> *mut Reader {
+        // Each on lines of its own, since its stream's readers keep writing
+        // to it while every writer keeps reading the others
+        let new_reader: *mut CachePadded<Reader> = alloc::allocate(from, 1);
+        ptr::write(new_reader,
+                   CachePadded::new(Reader {
+                       pos_data: CountedU16::from_usize(raw, wrap),
+                       state: Cell::new(ReaderState::Single),
+                       num_consumers: AtomicUsize::new(1),
+                       id: id,
+                       name: name,
+                       queue: queue,
+                       wakers: WakerList::new(),
+                       evicted: AtomicBool::new(false),
+                       topics: AtomicU64::new(!0),
+                       seen: AtomicUsize::new(usize::MAX),
+                       seen_since: AtomicUsize::new(0),
+                   }));
+        new_reader as *mut Reader
+    }
+
+    /// Puts the reader in slot to use starting where from is, once every slot
+    /// before it is in use. The slot must have been claimed by nobody else
+    unsafe fn fill_slot(&self, slot: usize, from: &Reader, name: Option<&'static str>) -> AtomicPtr<Reader> {
+        let reader = *self.readers.add(slot) as *mut Reader;
+        // Nobody looks at the slot before it's published below
+        (*reader).name = name;
+        // Whoever claimed the slot before is nearly done with it
+        while self.n_readers.load(Ordering::SeqCst) != slot {
+            spin_loop();
+        }
+        // A writer scanning the readers now may see from past where the
+        // copy starts without seeing the copy, so it has to start over
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        (*reader).pos_data.store_raw(from.pos_data.load_raw(Ordering::SeqCst), Ordering::Relaxed);
+        self.n_readers.store(slot + 1, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::Release);
+        AtomicPtr::new(reader)
+    }
+
+    /// Frees the group itself, leaving its readers alone
+    unsafe fn free(group: *mut ReaderGroup, from: &dyn RawAlloc) {
+        alloc::deallocate(from, (*group).readers as *mut *const Reader, (*group).slots);
+        alloc::deallocate(from, group, 1);
+    }
+
+    /// Frees the reader that add_reader added along with this group
+    unsafe fn free_newest(group: *mut ReaderGroup, reader_from: &dyn RawAlloc) {
+        let newest = *(*group).readers.offset((*group).slots as isize - 1) as *mut CachePadded<Reader>;
+        ptr::drop_in_place(newest);
+        alloc::deallocate(reader_from, newest, 1);
+    }
+
+    /// Frees every slot's reader, in use or not
+    unsafe fn free_slots(group: *mut ReaderGroup, reader_from: &dyn RawAlloc) {
+        for i in 0..(*group).slots {
+            let reader = *(*group).readers.add(i) as *mut CachePadded<Reader>;
+            ptr::drop_in_place(reader);
+            alloc::deallocate(reader_from, reader, 1);
+        }
     }
 
     pub fn get_max_diff(&self, cur_writer: usize) -> Option<u16> {
         let mut max_diff: usize = 0;
         unsafe {
-            for i in 0..self.n_readers as isize {
+            for i in 0..self.len() as isize {
+                let reader = &**self.readers.offset(i);
+                // Writers go on without an evicted stream, and can lap it
+                if reader.evicted.load(Ordering::Relaxed) {
+                    continue;
+                }
                 // If a reader has passed the writer during this function call
                 // then what must have happened is that somebody else has completed this
                 // written to the queue, and a reader has bypassed it. We should retry
-                let rpos = (**self.readers.offset(i)).pos_data.load_count(MAYBE_ACQUIRE);
+                let rpos = reader.pos_data.load_count(MAYBE_ACQUIRE);
                 let diff = cur_writer.wrapping_sub(rpos);
                 if diff > (::std::u16::MAX as usize) {
                     return None;
@@ -163,14 +405,70 @@ impl ReaderGroup {
         assert!(max_diff <= (::std::u16::MAX as usize));
         Some(max_diff as u16)
     }
+
+    /// get_max_diff for a group with fixed slots, returning None rather than
+    /// starting over if a reader was put in a slot during the scan
+    fn get_max_diff_fixed(&self, cur_writer: usize) -> Option<Option<u16>> {
+        let generation = self.generation.load(Ordering::Acquire);
+        if generation & 1 == 1 {
+            return None;
+        }
+        let rval = self.get_max_diff(cur_writer);
+        // Orders the loads of the readers before the one of the generation,
+        // against fill_slot bumping it before it copies a reader
+        fence(Ordering::SeqCst);
+        if self.generation.load(Ordering::Relaxed) == generation {
+            Some(rval)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Debug for ReaderGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        unsafe {
+            for i in 0..self.len() as isize {
+                list.entry(&**self.readers.offset(i));
+            }
+        }
+        list.finish()
+    }
 }
 
 impl ReadCursor {
-    pub fn new(wrap: u16) -> (ReadCursor, AtomicPtr<Reader>) {
-        let rg = ReaderGroup::new();
+    /// With slots, every reader the cursor will ever have is allocated now,
+    /// and adding one past that many panics
+    pub fn new(wrap: u16,
+               from: &'static dyn RawAlloc,
+               reader_from: &'static dyn RawAlloc,
+               slots: Option<usize>,
+               queue: trace::Labels,
+               counter: Counter)
+               -> (ReadCursor, AtomicPtr<Reader>) {
         unsafe {
-            let (real_group, reader) = rg.add_reader(0, wrap);
-            (ReadCursor { readers: AtomicPtr::new(real_group) }, reader)
+            let (tracked, reader_tracked) = (counter.tracked(from), counter.tracked(reader_from));
+            let (real_group, reader) = match slots {
+                Some(slots) => {
+                    assert!(slots > 0, "a queue needs room for at least one stream");
+                    let group = ReaderGroup::with_slots(0, wrap, slots, queue, &tracked, &reader_tracked);
+                    (group, AtomicPtr::new(*(*group).readers as *mut Reader))
+                }
+                None => {
+                    let reader = ReaderGroup::new_reader(0, wrap, 0, None, queue, &reader_tracked);
+                    (ReaderGroup::new().add_reader(reader, ptr::null_mut(), &tracked), AtomicPtr::new(reader))
+                }
+            };
+            let cursor = ReadCursor {
+                readers: AtomicPtr::new(real_group),
+                alloc: from,
+                reader_alloc: reader_from,
+                fixed: slots.map(|_| AtomicUsize::new(1)),
+                rescans: AtomicUsize::new(0),
+                counter: counter,
+            };
+            (cursor, reader)
         }
     }
 
@@ -178,53 +476,197 @@ impl ReadCursor {
     pub fn prefetch_metadata(&self) {
         unsafe {
             let rg = &*self.readers.load(Consume);
-            ptr::read_volatile(&rg.n_readers);
+            rg.n_readers.load(Ordering::Relaxed);
         }
     }
 
     #[inline(always)]
     pub fn get_max_diff(&self, cur_writer: usize) -> Option<u16> {
         loop {
-            unsafe {
-                let first_ptr = self.readers.load(Consume);
-                let rg = &*first_ptr;
-                let rval = rg.get_max_diff(cur_writer);
-                // This check ensures that the pointer hasn't changed
-                // We must first read the diff, *and then* check the pointer
-                // for changes. This is extremely similar to the seqlock except
-                // with the pointer as the sequence lock
-                // We don't need another acquire fence here sincea the
-                // relevant loads in get_max_diff are going to ordered
-                // before all loads after the function exit, and also
-                // ordered after the original pointer load
-                let second_ptr = self.readers.load(Ordering::Relaxed);
-                if second_ptr == first_ptr {
-                    return rval;
-                }
+            if let Some(rval) = self.get_max_diff_once(cur_writer) {
+                return rval;
+            }
+            self.rescans.fetch_add(1, Ordering::Relaxed);
+            // A reader is being put in a slot, which takes a moment
+            if self.fixed.is_some() {
+                spin_loop();
             }
         }
     }
 
-    pub fn add_reader(&self, reader: &Reader) -> AtomicPtr<Reader> {
-        // There's no fundamental reason this needs to leak, 
-        // I just haven't implemented the memory management yet.
-        // It's not too hard since we can track readers and writers active
+    /// Scans the readers just the once, returning None rather than
+    /// starting over if a reader was added during the scan
+    #[inline(always)]
+    pub fn get_max_diff_once(&self, cur_writer: usize) -> Option<Option<u16>> {
+        unsafe {
+            let first_ptr = self.readers.load(Consume);
+            let rg = &*first_ptr;
+            if self.fixed.is_some() {
+                return rg.get_max_diff_fixed(cur_writer);
+            }
+            let rval = rg.get_max_diff(cur_writer);
+            // This check ensures that the pointer hasn't changed
+            // We must first read the diff, *and then* check the pointer
+            // for changes. This is extremely similar to the seqlock except
+            // with the pointer as the sequence lock
+            // We don't need another acquire fence here sincea the
+            // relevant loads in get_max_diff are going to ordered
+            // before all loads after the function exit, and also
+            // ordered after the original pointer load
+            let second_ptr = self.readers.load(Ordering::Relaxed);
+            if second_ptr == first_ptr {
+                Some(rval)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// How many times get_max_diff had to start over
+    pub fn rescans(&self) -> usize {
+        self.rescans.load(Ordering::Relaxed)
+    }
+
+    /// Calls f with every reader currently in the group
+    pub fn for_each_reader<F: FnMut(&Reader)>(&self, mut f: F) {
+        unsafe {
+            let rg = &*self.readers.load(Consume);
+            for i in 0..rg.len() as isize {
+                f(&**rg.readers.offset(i));
+            }
+        }
+    }
+
+    /// How far reader is ahead of the slowest other reader that isn't
+    /// evicted, negative if they're all ahead of it and 0 if there are none
+    pub fn lead(&self, reader: &Reader) -> isize {
+        let nread = reader.load_nread(Ordering::SeqCst);
+        let mut lead: Option<isize> = None;
+        self.for_each_reader(|other| {
+            if other.id != reader.id && !other.is_evicted() {
+                let diff = nread.wrapping_sub(other.load_nread(Ordering::SeqCst)) as isize;
+                lead = Some(lead.map_or(diff, |lead| cmp::min(lead, diff)));
+            }
+        });
+        lead.unwrap_or(0)
+    }
+
+    /// How many readers are in the group
+    #[inline(always)]
+    pub fn streams(&self) -> usize {
+        unsafe { (*self.readers.load(Consume)).len() }
+    }
+
+    /// Bytes asked of the allocators for every reader, and for every group
+    /// of them still held on to. Wakers of async readers aren't counted
+    pub fn memory_usage(&self) -> usize {
+        unsafe {
+            let mut group = self.readers.load(Consume);
+            // Every reader ever added is in the newest group's slots
+            let mut bytes = (*group).slots * mem::size_of::<CachePadded<Reader>>();
+            while !group.is_null() {
+                bytes += mem::size_of::<ReaderGroup>() + (*group).slots * mem::size_of::<*const Reader>();
+                group = (*group).replaced;
+            }
+            bytes
+        }
+    }
+
+    /// What the queue has allocated through the cursor's counter
+    pub fn counter(&self) -> &Counter {
+        &self.counter
+    }
+
+    /// Whether every reader was allocated up front
+    pub fn is_fixed(&self) -> bool {
+        self.fixed.is_some()
+    }
+
+    /// Adds a reader for a new stream, starting where reader is
+    pub fn add_reader(&self, reader: &Reader, name: Option<&'static str>) -> AtomicPtr<Reader> {
+        if let Some(ref claimed) = self.fixed {
+            return self.fill_slot(claimed, reader, name);
+        }
+        // Replaced groups are kept until the cursor is dropped, since
+        // there's no telling when writers are done looking at them
         let mut current_ptr = self.readers.load(Consume);
         loop {
             unsafe {
                 let current_group = &*current_ptr;
                 let raw = reader.pos_data.load_raw(Ordering::Relaxed);
                 let wrap = reader.pos_data.wrap_at();
-                let (new_group, new_reader) = current_group.add_reader(raw, wrap);
+                let new_reader = ReaderGroup::new_reader(raw,
+                                                         wrap,
+                                                         current_group.len(),
+                                                         name,
+                                                         reader.queue,
+                                                         &self.counter.tracked(self.reader_alloc));
+                let new_group = current_group.add_reader(new_reader, current_ptr, &self.counter.tracked(self.alloc));
                 match self.readers
                     .compare_exchange(current_ptr, new_group, Ordering::SeqCst, Ordering::SeqCst) {
                     Ok(_) => {
                         fence(Ordering::SeqCst);
-                        return new_reader
+                        return AtomicPtr::new(new_reader)
                     },
-                    Err(val) => current_ptr = val,
+                    Err(val) => {
+                        // Nobody else saw the group, so it can go right away
+                        ReaderGroup::free_newest(new_group, &self.counter.tracked(self.reader_alloc));
+                        ReaderGroup::free(new_group, &self.counter.tracked(self.alloc));
+                        current_ptr = val
+                    }
                 }
             }
         }
     }
+
+    /// Claims the next free slot for a copy of reader
+    fn fill_slot(&self, claimed: &AtomicUsize, reader: &Reader, name: Option<&'static str>) -> AtomicPtr<Reader> {
+        unsafe {
+            let group = &*self.readers.load(Consume);
+            let mut slot = claimed.load(Ordering::Relaxed);
+            loop {
+                assert!(slot < group.slots, "all {} stream slots are in use", group.slots);
+                match claimed.compare_exchange(slot, slot + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => break,
+                    Err(val) => slot = val,
+                }
+            }
+            let rval = group.fill_slot(slot, reader, name);
+            fence(Ordering::SeqCst);
+            rval
+        }
+    }
+}
+
+impl Drop for ReadCursor {
+    fn drop(&mut self) {
+        unsafe {
+            if self.fixed.is_some() {
+                let group = self.readers.load(Ordering::Relaxed);
+                ReaderGroup::free_slots(group, &self.counter.tracked(self.reader_alloc));
+                ReaderGroup::free(group, &self.counter.tracked(self.alloc));
+                return;
+            }
+            // Each group adds one reader to the one it replaced, so freeing
+            // the newest reader of every group frees them all exactly once
+            let mut group = self.readers.load(Ordering::Relaxed);
+            while !group.is_null() {
+                let replaced = (*group).replaced;
+                ReaderGroup::free_newest(group, &self.counter.tracked(self.reader_alloc));
+                ReaderGroup::free(group, &self.counter.tracked(self.alloc));
+                group = replaced;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ReadCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Groups are never freed, so the loaded group stays valid
+        // even if a reader is added while this is printing
+        let rg = unsafe { &*self.readers.load(Consume) };
+        f.debug_struct("ReadCursor")
+            .field("readers", rg)
+            .finish()
+    }
 }
@@ -0,0 +1,118 @@
+//! Deciding which streams get each item of a broadcast queue
+//!
+//! A router is asked by every stream as it comes to an item, rather than
+//! by the writer when pushing, so a routed queue pushes as fast as any
+//! other. A stream steps over whatever isn't routed to it, dropping its
+//! copy unseen. The ring still holds every item until every stream is past
+//! it, so a stream that's routed little still holds writers back if it
+//! stops popping.
+//!
+//! ```
+//! use pipeline::queue::multiqueue::{Meta, MultiQueueBuilder};
+//! use pipeline::queue::route::Route;
+//!
+//! // Items go to the stream their topic names
+//! let (writer, reader) = MultiQueueBuilder::new(16)
+//!     .build_routed(|_: &u64, meta: &Meta, route: Route| meta.topic as usize == route.stream);
+//! ```
+
+#[cfg(feature = "std")]
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "std")]
+use std::hash::{Hash, Hasher};
+
+use queue::multiqueue::Meta;
+
+/// The stream a router is asked about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route {
+    /// Counting streams from 0 in the order they were added
+    pub stream: usize,
+    /// What it was named by MultiStream::add_stream_named, if anything
+    pub name: Option<&'static str>,
+    /// How many streams the queue had as of asking, counting evicted ones
+    pub streams: usize,
+}
+
+/// Decides whether an item goes to a stream, for queues built with
+/// MultiQueueBuilder::build_routed
+///
+/// It has to give the same answer for the same item and stream every
+/// time, since every reader on a stream asks it separately
+pub trait Router<T>: Send + Sync {
+    fn routes(&self, item: &T, meta: &Meta, route: Route) -> bool;
+}
+
+impl<T, F> Router<T> for F
+    where F: Fn(&T, &Meta, Route) -> bool + Send + Sync
+{
+    fn routes(&self, item: &T, meta: &Meta, route: Route) -> bool {
+        self(item, meta, route)
+    }
+}
+
+/// Every item to every stream, as an unrouted queue does
+#[derive(Clone, Copy, Debug, Default)]
+pub struct All;
+
+impl<T> Router<T> for All {
+    fn routes(&self, _item: &T, _meta: &Meta, _route: Route) -> bool {
+        true
+    }
+}
+
+/// Each item to one stream, picked by hashing its key
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub struct ByHash<F> {
+    key: F,
+}
+
+/// Sends each item to the one stream its key hashes to, so items with the
+/// same key always go to the same stream while the streams stay the same
+#[cfg(feature = "std")]
+pub fn by_hash<T, K, F>(key: F) -> ByHash<F>
+    where K: Hash,
+          F: Fn(&T) -> K + Send + Sync
+{
+    ByHash { key: key }
+}
+
+#[cfg(feature = "std")]
+impl<T, K, F> Router<T> for ByHash<F>
+    where K: Hash,
+          F: Fn(&T) -> K + Send + Sync
+{
+    fn routes(&self, item: &T, _meta: &Meta, route: Route) -> bool {
+        let mut hasher = DefaultHasher::new();
+        (self.key)(item).hash(&mut hasher);
+        hasher.finish() % route.streams as u64 == route.stream as u64
+    }
+}
+
+#[cfg(all(test, not(loom), feature = "std"))]
+mod test {
+    use super::*;
+
+    use queue::multiqueue::MultiQueueBuilder;
+
+    #[test]
+    fn hashed_items_go_to_exactly_one_stream() {
+        let (writer, reader) = MultiQueueBuilder::new(32).build_routed(by_hash(|x: &u64| *x));
+        let streams = vec![reader.stream().add_stream().into_reader(), reader.stream().add_stream().into_reader(), reader];
+        for i in 0..24 {
+            writer.push(i).unwrap();
+        }
+        let mut seen: Vec<u64> = Vec::new();
+        for stream in &streams {
+            let mut popped = 0;
+            while let Some(val) = stream.pop() {
+                seen.push(val);
+                popped += 1;
+            }
+            assert!(popped < 24);
+        }
+        seen.sort();
+        assert_eq!((0..24).collect::<Vec<_>>(), seen);
+    }
+}
@@ -0,0 +1,257 @@
+//! Broadcasting items behind an Arc, so every stream gets the same one
+//!
+//! Broadcast queues hand each stream a bitwise copy of every item, which
+//! is only safe for types that don't mind being copied that way. Here the
+//! ring holds bare pointers instead, and a push takes one reference to its
+//! item for every stream, so each stream's pop owns an Arc of its own and
+//! nothing is ever cloned deeply.
+//!
+//! That only adds up if the streams are known when items are pushed, so
+//! they're all registered when the queue is built. Dropping a stream's last
+//! reader gives up whatever it hadn't popped; anything pushed after that
+//! keeps one reference too many and is never freed. For the same reason the
+//! queue can't be one that throws items away unpopped, by evicting idle
+//! streams or expiring old items.
+//!
+//! ```
+//! use pipeline::queue::shared::shared_queue;
+//!
+//! let (writer, readers) = shared_queue::<Vec<u8>>(16, 2);
+//! writer.push_value(vec![0; 1024]).unwrap();
+//! let (a, b) = (readers[0].pop().unwrap(), readers[1].pop().unwrap());
+//! assert!(std::sync::Arc::ptr_eq(&a, &b));
+//! ```
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use alloc::sync::Arc;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use queue::multiqueue::{MultiQueueBuilder, MultiReader, MultiWriter};
+
+/// One stream's reference to an item, as it sits in the ring. Pushing one
+/// takes a reference for every stream, and each stream's bitwise copy
+/// gives exactly one of them back
+pub struct Shared<U>(*const U);
+
+pub struct SharedWriter<U> {
+    writer: MultiWriter<Shared<U>>,
+    streams: usize,
+    // Sendable only where an Arc<U> is
+    items: PhantomData<Arc<U>>,
+}
+
+/// Pops from one stream. Clones share the stream's items, as with MultiReader
+pub struct SharedReader<U> {
+    reader: MultiReader<Shared<U>>,
+    items: PhantomData<Arc<U>>,
+}
+
+/// A queue with room for capacity items, and streams streams to read them
+pub fn shared_queue<U>(capacity: u16, streams: usize) -> (SharedWriter<U>, Vec<SharedReader<U>>) {
+    shared_queue_with(&MultiQueueBuilder::new(capacity), streams)
+}
+
+/// Like shared_queue, configured by builder, which has to be for a
+/// broadcast queue that never discards items. Panics if streams is 0
+pub fn shared_queue_with<U>(builder: &MultiQueueBuilder, streams: usize) -> (SharedWriter<U>, Vec<SharedReader<U>>) {
+    assert!(!builder.discards(), "a shared queue can't evict streams or expire items");
+    let (writer, built) = builder.build_streams(streams);
+    let readers = built.into_iter()
+        .map(|stream| {
+            SharedReader {
+                reader: stream.into_reader(),
+                items: PhantomData,
+            }
+        })
+        .collect();
+    let writer = SharedWriter {
+        writer: writer,
+        streams: streams,
+        items: PhantomData,
+    };
+    (writer, readers)
+}
+
+impl<U> Shared<U> {
+    /// Takes a reference to val for each of streams streams
+    pub fn new(val: Arc<U>, streams: usize) -> Shared<U> {
+        let ptr = Arc::into_raw(val);
+        // The references have to be there before any stream can pop
+        // the item, and a fast one can pop it as soon as it's pushed
+        for _ in 1..streams {
+            unsafe { Arc::increment_strong_count(ptr) };
+        }
+        Shared(ptr)
+    }
+
+    /// Gives back every stream's reference to an item that was never pushed
+    ///
+    /// # Safety
+    ///
+    /// streams is what the item was made with, and no stream has it
+    pub unsafe fn unshare(self, streams: usize) -> Arc<U> {
+        for _ in 1..streams {
+            Arc::decrement_strong_count(self.0);
+        }
+        Arc::from_raw(self.0)
+    }
+
+    /// Takes one stream's reference
+    ///
+    /// # Safety
+    ///
+    /// Each stream takes it at most once
+    pub unsafe fn into_arc(self) -> Arc<U> {
+        Arc::from_raw(self.0)
+    }
+}
+
+impl<U> Clone for Shared<U> {
+    fn clone(&self) -> Shared<U> {
+        *self
+    }
+}
+
+impl<U> Copy for Shared<U> {}
+
+unsafe impl<U: Send + Sync> Send for Shared<U> {}
+
+impl<U> SharedWriter<U> {
+    /// Pushes val to every stream, or gives it back if the queue is full
+    pub fn push(&self, val: Arc<U>) -> Result<usize, Arc<U>> {
+        self.writer
+            .push(Shared::new(val, self.streams))
+            .map_err(|item| unsafe { item.unshare(self.streams) })
+    }
+
+    pub fn push_value(&self, val: U) -> Result<usize, Arc<U>> {
+        self.push(Arc::new(val))
+    }
+
+    /// Like push, waiting for room as the queue was built to
+    pub fn push_wait(&self, val: Arc<U>) -> Result<usize, Arc<U>> {
+        self.writer
+            .push_wait(Shared::new(val, self.streams))
+            .map_err(|item| unsafe { item.unshare(self.streams) })
+    }
+
+    pub fn close(&self) {
+        self.writer.close()
+    }
+}
+
+impl<U> SharedReader<U> {
+    pub fn pop(&self) -> Option<Arc<U>> {
+        self.reader.pop().map(|item| unsafe { item.into_arc() })
+    }
+
+    /// Pops the next item and hands f a reference to it, letting go of
+    /// this stream's hold on it after
+    pub fn pop_with<R, F: FnOnce(&U) -> R>(&self, f: F) -> Option<R> {
+        self.pop().map(|item| f(&item))
+    }
+
+    /// Pops, waiting for an item as the queue was built to. Returns None
+    /// once the queue is closed and the stream is empty
+    pub fn pop_wait(&self) -> Option<Arc<U>> {
+        self.reader.pop_wait().map(|item| unsafe { item.into_arc() })
+    }
+}
+
+impl<U> Clone for SharedWriter<U> {
+    fn clone(&self) -> SharedWriter<U> {
+        SharedWriter {
+            writer: self.writer.clone(),
+            streams: self.streams,
+            items: PhantomData,
+        }
+    }
+}
+
+impl<U> Clone for SharedReader<U> {
+    fn clone(&self) -> SharedReader<U> {
+        SharedReader {
+            reader: self.reader.clone(),
+            items: PhantomData,
+        }
+    }
+}
+
+impl<U> Drop for SharedReader<U> {
+    fn drop(&mut self) {
+        let stream = self.reader.stream_id();
+        let last = self.reader
+            .streams()
+            .iter()
+            .any(|info| info.id == stream && info.consumers == 1);
+        if last {
+            while self.pop().is_some() {}
+        }
+    }
+}
+
+impl<U> fmt::Debug for SharedWriter<U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedWriter")
+            .field("streams", &self.streams)
+            .field("writer", &self.writer)
+            .finish()
+    }
+}
+
+impl<U> fmt::Debug for SharedReader<U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedReader")
+            .field("reader", &self.reader)
+            .finish()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_stream_gets_the_same_item() {
+        let (writer, readers) = shared_queue::<String>(4, 3);
+        let item = Arc::new(String::from("snapshot"));
+        writer.push(item.clone()).unwrap();
+        assert_eq!(4, Arc::strong_count(&item));
+        let popped: Vec<_> = readers.iter().map(|reader| reader.pop().unwrap()).collect();
+        assert!(popped.iter().all(|other| Arc::ptr_eq(&item, other)));
+        drop(popped);
+        assert_eq!(1, Arc::strong_count(&item));
+        writer.push_value(String::from("next")).unwrap();
+        assert_eq!(Some(4), readers[0].pop_with(|val| val.len()));
+    }
+
+    #[test]
+    fn references_are_given_back_on_failure_and_drop() {
+        let (writer, mut readers) = shared_queue::<u64>(2, 2);
+        let item = Arc::new(7);
+        writer.push(item.clone()).unwrap();
+        writer.push(item.clone()).unwrap();
+        assert_eq!(5, Arc::strong_count(&item));
+        let back = writer.push(item.clone()).unwrap_err();
+        assert_eq!(6, Arc::strong_count(&item));
+        drop(back);
+        assert_eq!(Some(7), readers[1].pop_with(|val| *val));
+        let other = readers[1].clone();
+        drop(readers.pop());
+        assert_eq!(4, Arc::strong_count(&item));
+        drop(other);
+        assert_eq!(3, Arc::strong_count(&item));
+        drop(readers);
+        assert_eq!(1, Arc::strong_count(&item));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't evict streams or expire items")]
+    fn queues_that_discard_are_refused() {
+        let builder = MultiQueueBuilder::new(4).ttl(::std::time::Duration::from_secs(1));
+        shared_queue_with::<u64>(&builder, 2);
+    }
+}
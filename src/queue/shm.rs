@@ -0,0 +1,1416 @@
+//! A MultiQueue whose ring, cursors, and stream set live in shared memory
+//!
+//! Everything in the segment is addressed by offset from the start of the mapping,
+//! since each process maps it at a different address. The reader group can't be
+//! swapped out by pointer like the in-process ReadCursor does, so the segment instead
+//! holds a fixed number of stream slots chosen at creation. Adding a stream takes
+//! a slot under a sequence lock that writers check when recomputing the tail,
+//! which is the same trick ReadCursor::get_max_diff plays with the group pointer.
+//!
+//! Items are copied bitwise between processes, so T must be Copy and must not
+//! contain pointers or references. Every process must also agree on the layout of T,
+//! which in practice means they were built from the same source by the same compiler.
+//! Handles in other processes can't be tracked cheaply, so both pushes and pops
+//! always take the CAS path rather than switching to the single-producer/consumer path.
+//!
+//! Each handle also registers in a peer table with its pid, so a process that dies
+//! while holding a handle can be cleaned up after by the survivors (see ShmQueue::reap).
+//! A dead consumer's stream stops gating writers once it has no live consumers left,
+//! and a slot claimed by a dead writer but never published is tagged as skipped
+//! so readers step over it instead of waiting on it forever. The stream lock records
+//! its holder's pid as well, and a process that finds the holder dead unlocks it.
+//!
+//! The segment can also be a regular file, which makes the queue a small durable
+//! buffer. Stream positions live in the file, so a consumer that restarts picks
+//! up at its last committed pop. The wraps tags double as commit markers: when
+//! the first process to open the file finds nobody else attached, it walks forward
+//! from the slowest stream and cuts the head back to the first unpublished slot,
+//! discarding anything a crashed writer claimed but never finished.
+//!
+//! Queues built with a Checksum also store a CRC of each entry next to it, checked
+//! on pop, so a torn write left behind by a crash or a segment corrupted on disk
+//! comes back as an error from try_pop, or is counted and skipped by pop, instead of
+//! as garbage. The sum covers the raw bytes of T, so T should have no padding
+//! for it to be meaningful.
+
+use std::cell::Cell;
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::path::Path;
+use std::process;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, fence};
+use std::sync::atomic::Ordering::{Relaxed, Acquire, Release, AcqRel, SeqCst};
+use std::time::Duration;
+
+use libc;
+
+use util::countedu16::CountedU16;
+use util::crc32::Crc32;
+
+const MAGIC: usize = 0x7069_7065_6d71_0001;
+const VERSION: usize = 4;
+
+const MAX_PEERS: usize = 64;
+
+/// Set in an entry's wraps tag when its writer died before publishing it
+const SKIP: usize = 1 << (mem::size_of::<usize>() * 8 - 1);
+
+/// How many failed pushes or pops in a row a handle sees before looking for dead peers
+const REAP_INTERVAL: usize = 1 << 12;
+
+const WRITER: usize = 1;
+const READER: usize = 2;
+
+/// Marks a peer slot that is being torn down
+const RELEASING: u64 = u64::MAX;
+
+#[repr(C)]
+struct ShmHeader {
+    // Written last by the creator, so openers know the rest is initialized
+    magic: AtomicUsize,
+    version: usize,
+    entry_size: usize,
+    capacity: usize,
+    max_streams: usize,
+    max_peers: usize,
+    checksum: usize,
+    d1: [u8; 64],
+
+    // Writer data
+    head: CountedU16,
+    tail_cache: AtomicUsize,
+    writers: AtomicUsize,
+    d2: [u8; 64],
+
+    // Stream registration. streams_gen is odd while a stream is being added,
+    // and streams_owner holds the pid of whoever holds the lock, or 0
+    streams_gen: AtomicUsize,
+    streams_owner: AtomicUsize,
+    n_streams: AtomicUsize,
+    tickets: AtomicUsize,
+    d3: [u8; 64],
+}
+
+#[repr(C)]
+struct ShmStream {
+    pos: CountedU16,
+    consumers: AtomicUsize,
+    active: AtomicUsize,
+    // Bumped every time the slot is handed out, so handles on an
+    // evicted stream can tell that their slot was reused
+    epoch: AtomicUsize,
+    d1: [u8; 24],
+}
+
+/// One registered handle
+#[repr(C)]
+struct ShmPeer {
+    // (registration ticket << 32) | pid, or 0 for a free slot
+    owner: AtomicU64,
+    kind: AtomicUsize,
+    stream: AtomicUsize,
+    // Milliseconds on the monotonic clock, 0 if the peer never sent a heartbeat
+    heartbeat: AtomicUsize,
+    // Raw head value + 1 of the slot a writer is claiming, 0 otherwise
+    claim: AtomicUsize,
+    d1: [u8; 24],
+}
+
+#[repr(C)]
+struct ShmEntry<T> {
+    wraps: AtomicUsize,
+    // Checksum of the wraps tag and val, if the queue keeps them
+    sum: u32,
+    val: T,
+}
+
+/// How entries are checked for corruption on pop
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    None,
+    Crc32,
+}
+
+/// A shared mapping of a file descriptor
+struct Segment {
+    base: *mut u8,
+    len: usize,
+    fd: RawFd,
+}
+
+/// Where each part of the queue lives in the segment
+#[derive(Clone, Copy)]
+struct Layout {
+    streams: usize,
+    peers: usize,
+    entries: usize,
+    len: usize,
+}
+
+struct ShmInner<T> {
+    seg: Segment,
+    layout: Layout,
+    capacity: usize,
+    max_streams: usize,
+    checksum: Checksum,
+    _marker: PhantomData<T>,
+}
+
+/// A handle on a mapped shared-memory queue
+///
+/// This is the entry point for each process: create or open the segment,
+/// then take writers and readers from it.
+pub struct ShmQueue<T> {
+    inner: Arc<ShmInner<T>>,
+}
+
+/// Options for a new shared-memory queue
+#[derive(Clone, Debug)]
+pub struct ShmQueueBuilder {
+    capacity: u16,
+    max_streams: u16,
+    checksum: Checksum,
+}
+
+/// Which peer slot a handle registered in
+struct PeerHandle {
+    index: usize,
+    owner: u64,
+    failures: Cell<usize>,
+}
+
+pub struct ShmWriter<T> {
+    inner: Arc<ShmInner<T>>,
+    peer: PeerHandle,
+}
+
+pub struct ShmReader<T> {
+    inner: Arc<ShmInner<T>>,
+    stream: usize,
+    epoch: usize,
+    peer: PeerHandle,
+    // Entries pop stepped over for failing their checksum
+    skipped: Cell<usize>,
+}
+
+fn round_up(val: usize, to: usize) -> usize {
+    val.div_ceil(to) * to
+}
+
+fn last_error<V>() -> io::Result<V> {
+    Err(io::Error::last_os_error())
+}
+
+fn invalid<V>(msg: &'static str) -> io::Result<V> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn now_ms() -> usize {
+    unsafe {
+        let mut ts: libc::timespec = mem::zeroed();
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+        (ts.tv_sec as usize) * 1000 + (ts.tv_nsec as usize) / 1_000_000
+    }
+}
+
+fn pid_alive(pid: u32) -> bool {
+    unsafe {
+        libc::kill(pid as libc::pid_t, 0) == 0 ||
+        io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+    }
+}
+
+impl Layout {
+    fn new<T>(capacity: usize, max_streams: usize) -> Layout {
+        let streams = round_up(mem::size_of::<ShmHeader>(), 64);
+        let entry_align = if mem::align_of::<ShmEntry<T>>() > 64 {
+            mem::align_of::<ShmEntry<T>>()
+        } else {
+            64
+        };
+        let peers = round_up(streams + max_streams * mem::size_of::<ShmStream>(), 64);
+        let entries = round_up(peers + MAX_PEERS * mem::size_of::<ShmPeer>(), entry_align);
+        Layout {
+            streams: streams,
+            peers: peers,
+            entries: entries,
+            len: entries + capacity * mem::size_of::<ShmEntry<T>>(),
+        }
+    }
+}
+
+impl Segment {
+    /// Maps len bytes of fd, growing the file to that size first if asked to
+    fn map(fd: RawFd, len: usize, grow: bool) -> io::Result<Segment> {
+        unsafe {
+            if grow && libc::ftruncate(fd, len as libc::off_t) != 0 {
+                return last_error();
+            }
+            let base = libc::mmap(ptr::null_mut(),
+                                  len,
+                                  libc::PROT_READ | libc::PROT_WRITE,
+                                  libc::MAP_SHARED,
+                                  fd,
+                                  0);
+            if base == libc::MAP_FAILED {
+                return last_error();
+            }
+            Ok(Segment {
+                base: base as *mut u8,
+                len: len,
+                fd: fd,
+            })
+        }
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        unsafe {
+            if libc::msync(self.base as *mut libc::c_void, self.len, libc::MS_SYNC) != 0 {
+                return last_error();
+            }
+        }
+        Ok(())
+    }
+
+    fn file_len(fd: RawFd) -> io::Result<usize> {
+        unsafe {
+            let mut stat: libc::stat = mem::zeroed();
+            if libc::fstat(fd, &mut stat) != 0 {
+                return last_error();
+            }
+            Ok(stat.st_size as usize)
+        }
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl<T> ShmInner<T> {
+    /// Lays out a fresh queue in the segment, which must be zeroed
+    unsafe fn init(seg: Segment, cfg: &ShmQueueBuilder) -> ShmInner<T> {
+        let (capacity, max_streams) = (cfg.capacity, cfg.max_streams);
+        let layout = Layout::new::<T>(capacity as usize, max_streams as usize);
+        let header = &mut *(seg.base as *mut ShmHeader);
+        ptr::write(&mut header.head, CountedU16::new(0, capacity));
+        header.version = VERSION;
+        header.entry_size = mem::size_of::<ShmEntry<T>>();
+        header.capacity = capacity as usize;
+        header.max_streams = max_streams as usize;
+        header.max_peers = MAX_PEERS;
+        header.checksum = cfg.checksum as usize;
+
+        // Stream 0 always exists so writers have something to gate on
+        let first = &mut *(seg.base.add(layout.streams) as *mut ShmStream);
+        ptr::write(&mut first.pos, CountedU16::new(0, capacity));
+        first.active.store(1, Relaxed);
+        first.epoch.store(1, Relaxed);
+        header.n_streams.store(1, Relaxed);
+
+        header.magic.store(MAGIC, Release);
+        ShmInner {
+            seg: seg,
+            layout: layout,
+            capacity: capacity as usize,
+            max_streams: max_streams as usize,
+            checksum: cfg.checksum,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Checks that the segment holds a queue of T before trusting it
+    unsafe fn attach(fd: RawFd) -> io::Result<ShmInner<T>> {
+        let len = match Segment::file_len(fd) {
+            Ok(len) if len >= mem::size_of::<ShmHeader>() => len,
+            Ok(_) => {
+                libc::close(fd);
+                return invalid("segment is too small to hold a queue");
+            }
+            Err(e) => {
+                libc::close(fd);
+                return Err(e);
+            }
+        };
+        let seg = match Segment::map(fd, len, false) {
+            Ok(seg) => seg,
+            Err(e) => {
+                libc::close(fd);
+                return Err(e);
+            }
+        };
+        let (capacity, max_streams, checksum) = {
+            let header = &*(seg.base as *const ShmHeader);
+            if header.magic.load(Acquire) != MAGIC {
+                return invalid("segment does not hold an initialized queue");
+            }
+            if header.version != VERSION {
+                return invalid("segment was created by an incompatible version");
+            }
+            if header.entry_size != mem::size_of::<ShmEntry<T>>() {
+                return invalid("segment holds a different item type");
+            }
+            if header.max_peers != MAX_PEERS {
+                return invalid("segment was created with a different peer table size");
+            }
+            let checksum = match header.checksum {
+                0 => Checksum::None,
+                1 => Checksum::Crc32,
+                _ => return invalid("segment uses an unknown checksum"),
+            };
+            (header.capacity, header.max_streams, checksum)
+        };
+        let layout = Layout::new::<T>(capacity, max_streams);
+        if layout.len > len {
+            return invalid("segment is smaller than its header claims");
+        }
+        Ok(ShmInner {
+            seg: seg,
+            layout: layout,
+            capacity: capacity,
+            max_streams: max_streams,
+            checksum: checksum,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Brings a file-backed queue back to a consistent state after a crash.
+    /// Nothing else may have the segment mapped
+    unsafe fn recover(&self) {
+        let header = &mut *(self.seg.base as *mut ShmHeader);
+
+        // Every peer that was registered is gone
+        for i in 0..MAX_PEERS {
+            let peer = self.peer(i);
+            peer.kind.store(0, Relaxed);
+            peer.claim.store(0, Relaxed);
+            peer.heartbeat.store(0, Relaxed);
+            peer.owner.store(0, Relaxed);
+        }
+        header.writers.store(0, Relaxed);
+        let gen = header.streams_gen.load(Relaxed);
+        header.streams_gen.store((gen | 1).wrapping_add(1), Relaxed);
+        header.streams_owner.store(0, Relaxed);
+
+        let head = header.head.load_count(Relaxed);
+        let mut behind = 0;
+        for i in 0..header.n_streams.load(Relaxed) {
+            let stream = self.stream(i);
+            if stream.active.load(Relaxed) == 0 {
+                continue;
+            }
+            stream.consumers.store(0, Relaxed);
+            let diff = head.wrapping_sub(stream.pos.load_count(Relaxed));
+            if diff <= self.capacity && diff > behind {
+                behind = diff;
+            }
+        }
+        let tail = head - behind;
+
+        // Anything published is kept, up to the first slot that never was
+        let mut end = tail;
+        while end != head {
+            let raw = self.raw_of(end);
+            let wrap_valid_tag = (raw >> 16).wrapping_add(1);
+            let tag = (*self.entry(raw as u16 as usize)).wraps.load(Relaxed);
+            if tag != wrap_valid_tag && tag != wrap_valid_tag | SKIP {
+                break;
+            }
+            end += 1;
+        }
+        if end != head {
+            // Slots past the cut may have been published out of order by other writers,
+            // and must not look published when the head comes around to them again
+            for lost in end..head {
+                let raw = self.raw_of(lost);
+                (*self.entry(raw as u16 as usize)).wraps.store(0, Relaxed);
+            }
+            ptr::write(&mut header.head,
+                       CountedU16::from_usize(self.raw_of(end), self.capacity as u16));
+        }
+        header.tail_cache.store(self.raw_of(tail), Relaxed);
+        fence(SeqCst);
+    }
+
+    /// Checksum of an entry's value as it sits in the segment, under the given tag
+    unsafe fn sum_of(&self, entry: *const ShmEntry<T>, tag: usize) -> u32 {
+        let val = ::std::slice::from_raw_parts(&(*entry).val as *const T as *const u8,
+                                               mem::size_of::<T>());
+        let mut crc = Crc32::new();
+        crc.update(&tag.to_le_bytes());
+        crc.update(val);
+        crc.finish()
+    }
+
+    /// Converts a count back into the raw form CountedU16 stores
+    fn raw_of(&self, count: usize) -> usize {
+        ((count / self.capacity) << 16) | (count % self.capacity)
+    }
+
+    #[inline(always)]
+    fn header(&self) -> &ShmHeader {
+        unsafe { &*(self.seg.base as *const ShmHeader) }
+    }
+
+    #[inline(always)]
+    fn stream_ptr(&self, i: usize) -> *mut ShmStream {
+        debug_assert!(i < self.max_streams);
+        unsafe {
+            let streams = self.seg.base.add(self.layout.streams) as *mut ShmStream;
+            streams.add(i)
+        }
+    }
+
+    #[inline(always)]
+    fn stream(&self, i: usize) -> &ShmStream {
+        unsafe { &*self.stream_ptr(i) }
+    }
+
+    #[inline(always)]
+    fn peer(&self, i: usize) -> &ShmPeer {
+        debug_assert!(i < MAX_PEERS);
+        unsafe {
+            let peers = self.seg.base.add(self.layout.peers) as *const ShmPeer;
+            &*peers.add(i)
+        }
+    }
+
+    #[inline(always)]
+    fn entry(&self, i: usize) -> *mut ShmEntry<T> {
+        unsafe {
+            let entries = self.seg.base.add(self.layout.entries) as *mut ShmEntry<T>;
+            entries.add(i)
+        }
+    }
+
+    /// Returns how far behind the head the slowest stream is, or None
+    /// if a stream raced past cur_writer while scanning
+    fn get_max_diff(&self, cur_writer: usize) -> Option<u16> {
+        let header = self.header();
+        let mut spins = 0;
+        loop {
+            let gen = header.streams_gen.load(Acquire);
+            if gen & 1 == 1 {
+                self.wait_streams(&mut spins);
+                continue;
+            }
+            let mut max_diff = 0;
+            let mut raced = false;
+            for i in 0..header.n_streams.load(Acquire) {
+                let stream = self.stream(i);
+                if stream.active.load(Acquire) == 0 {
+                    continue;
+                }
+                let diff = cur_writer.wrapping_sub(stream.pos.load_count(Acquire));
+                if diff > self.capacity {
+                    raced = true;
+                    break;
+                }
+                max_diff = if diff > max_diff { diff } else { max_diff };
+            }
+            // Same seqlock shape as ReadCursor::get_max_diff, with the
+            // generation standing in for the group pointer
+            fence(Acquire);
+            if header.streams_gen.load(Relaxed) == gen {
+                return if raced { None } else { Some(max_diff as u16) };
+            }
+        }
+    }
+
+    fn reload_tail(&self, tail_cache: usize) -> usize {
+        let header = self.header();
+        if let Some(max_diff) = self.get_max_diff(header.head.load_count(Relaxed)) {
+            let current_tail = header.head.get_previous(max_diff);
+            match header.tail_cache.compare_exchange(tail_cache, current_tail, Relaxed, Acquire) {
+                Ok(_) => current_tail,
+                Err(val) => val,
+            }
+        } else {
+            header.tail_cache.load(Acquire)
+        }
+    }
+
+    fn push(&self, peer: usize, val: T) -> Result<(), T> {
+        let header = self.header();
+        let claim = &self.peer(peer).claim;
+        let mut transaction = header.head.load_transaction(Relaxed);
+        unsafe {
+            loop {
+                let tail_cache = header.tail_cache.load(Acquire);
+                if transaction.matches_previous(tail_cache) &&
+                   transaction.matches_previous(self.reload_tail(tail_cache)) {
+                    return Err(val);
+                }
+                let write_cell = self.entry(transaction.get() as usize);
+                let wrap_valid_tag = transaction.get_wraps().wrapping_add(1);
+                // Recorded before the claim is made so that if this process dies
+                // after claiming, whoever reaps it can find the slot
+                claim.store(transaction.raw() + 1, Relaxed);
+                match transaction.commit(1, Release) {
+                    Some(new_transaction) => transaction = new_transaction,
+                    None => {
+                        ptr::write(&mut (*write_cell).val, val);
+                        if self.checksum != Checksum::None {
+                            (*write_cell).sum = self.sum_of(write_cell, wrap_valid_tag);
+                        }
+                        (*write_cell).wraps.store(wrap_valid_tag, Release);
+                        claim.store(0, Relaxed);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn pop(&self, stream: usize, epoch: usize) -> io::Result<Option<T>> {
+        let slot = self.stream(stream);
+        let mut ctail_attempt = slot.pos.load_transaction(Relaxed);
+        unsafe {
+            loop {
+                let read_cell = self.entry(ctail_attempt.get() as usize);
+                let wrap_valid_tag = ctail_attempt.get_wraps().wrapping_add(1);
+                let tag = (*read_cell).wraps.load(Acquire);
+                if tag == wrap_valid_tag | SKIP {
+                    ctail_attempt = match ctail_attempt.commit(1, Release) {
+                        Some(new_attempt) => new_attempt,
+                        None => slot.pos.load_transaction(Relaxed),
+                    };
+                    continue;
+                }
+                if tag != wrap_valid_tag {
+                    return Ok(None);
+                }
+                // Computed before the commit, since the slot may be reused right after it
+                let intact = self.checksum == Checksum::None ||
+                             ptr::read_volatile(&(*read_cell).sum) == self.sum_of(read_cell, tag);
+                let rval = ptr::read_volatile(&(*read_cell).val);
+                match ctail_attempt.commit(1, Release) {
+                    Some(new_attempt) => ctail_attempt = new_attempt,
+                    None => {
+                        // If this stream was evicted under us, writers weren't
+                        // waiting on it and the value may have been overwritten
+                        if slot.epoch.load(Acquire) != epoch || slot.active.load(Relaxed) == 0 {
+                            return Ok(None);
+                        }
+                        if !intact {
+                            return invalid("queue entry failed its checksum");
+                        }
+                        return Ok(Some(rval));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes the stream lock, returning the generation to unlock with
+    fn lock_streams(&self) -> usize {
+        let header = self.header();
+        let pid = process::id() as usize;
+        let mut spins = 0;
+        while header.streams_owner.compare_exchange_weak(0, pid, SeqCst, Relaxed).is_err() {
+            self.wait_streams(&mut spins);
+        }
+        let gen = header.streams_gen.load(Relaxed);
+        header.streams_gen.store(gen + 1, Relaxed);
+        fence(Release);
+        gen
+    }
+
+    fn unlock_streams(&self, gen: usize) {
+        let header = self.header();
+        header.streams_gen.store(gen + 2, Release);
+        header.streams_owner.store(0, Release);
+    }
+
+    /// Called while spinning on the stream lock. Every so often checks that
+    /// the process holding it is still alive, and unlocks it if not
+    fn wait_streams(&self, spins: &mut usize) {
+        *spins += 1;
+        if *spins < REAP_INTERVAL {
+            return;
+        }
+        *spins = 0;
+        let header = self.header();
+        let owner = header.streams_owner.load(Acquire);
+        if owner == 0 || pid_alive(owner as u32) {
+            return;
+        }
+        // The owner may have died on either side of making the generation odd
+        let pid = process::id() as usize;
+        if header.streams_owner.compare_exchange(owner, pid, SeqCst, Relaxed).is_ok() {
+            self.unlock_streams(header.streams_gen.load(Relaxed) & !1);
+        }
+    }
+
+    /// Takes a free peer slot for a handle of the given kind
+    fn register(&self, kind: usize, stream: usize, pid: u32) -> io::Result<PeerHandle> {
+        let ticket = self.header().tickets.fetch_add(1, Relaxed) as u32;
+        let owner = ((ticket as u64) << 32) | pid as u64;
+        for i in 0..MAX_PEERS {
+            let peer = self.peer(i);
+            if peer.owner.compare_exchange(0, owner, AcqRel, Relaxed).is_ok() {
+                peer.stream.store(stream, Relaxed);
+                peer.kind.store(kind, Release);
+                return Ok(PeerHandle {
+                    index: i,
+                    owner: owner,
+                    failures: Cell::new(0),
+                });
+            }
+        }
+        Err(io::Error::other("every peer slot in the segment is in use"))
+    }
+
+    /// Frees a peer slot if it is still held by owner, returning what it was registered as
+    fn release(&self, index: usize, owner: u64) -> Option<(usize, usize, usize)> {
+        let peer = self.peer(index);
+        if peer.owner.compare_exchange(owner, RELEASING, AcqRel, Relaxed).is_err() {
+            return None;
+        }
+        let rval = (peer.kind.load(Acquire), peer.stream.load(Relaxed), peer.claim.load(Relaxed));
+        peer.kind.store(0, Relaxed);
+        peer.claim.store(0, Relaxed);
+        peer.heartbeat.store(0, Relaxed);
+        peer.owner.store(0, Release);
+        Some(rval)
+    }
+
+    /// Undoes what a handle's registration did to the queue. With evict, a stream
+    /// left without consumers stops gating writers instead of waiting for a new one
+    fn unregister(&self, kind: usize, stream: usize, evict: bool) {
+        match kind {
+            WRITER => {
+                self.header().writers.fetch_sub(1, Release);
+            }
+            READER => {
+                let slot = self.stream(stream);
+                if slot.consumers.fetch_sub(1, AcqRel) == 1 && evict {
+                    slot.active.store(0, Release);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Publishes a skip marker over a slot a dead writer claimed but never published
+    fn abandon_claim(&self, claim: usize) {
+        if claim == 0 {
+            return;
+        }
+        let raw = claim - 1;
+        let header = self.header();
+        let ahead = header.head.load_count(Acquire).wrapping_sub(header.head.count_of(raw));
+        // If the head hasn't moved past the claim, the dead writer's CAS never landed.
+        // If it's moved more than a lap past, the slot has since been reused
+        if ahead == 0 || ahead > self.capacity {
+            return;
+        }
+        // A live writer recording the same claim is the one whose CAS succeeded
+        for i in 0..MAX_PEERS {
+            let peer = self.peer(i);
+            let owner = peer.owner.load(Acquire);
+            if owner != 0 && owner != RELEASING && peer.kind.load(Acquire) == WRITER &&
+               peer.claim.load(Acquire) == claim {
+                return;
+            }
+        }
+        let entry = unsafe { &*self.entry(raw as u16 as usize) };
+        let wrap_valid_tag = (raw >> 16).wrapping_add(1);
+        let current = entry.wraps.load(Acquire);
+        if current != wrap_valid_tag {
+            let _ = entry.wraps.compare_exchange(current, wrap_valid_tag | SKIP, Release, Relaxed);
+        }
+    }
+
+    /// Cleans up after peers whose process has exited, or that haven't sent
+    /// a heartbeat within timeout if given. Returns how many peers were removed
+    fn reap(&self, timeout: Option<Duration>) -> usize {
+        let now = now_ms();
+        let timeout_ms = timeout.map(|t| t.as_secs() as usize * 1000 + t.subsec_nanos() as usize / 1_000_000);
+        let gen = self.lock_streams();
+        let mut reaped = 0;
+        for i in 0..MAX_PEERS {
+            let peer = self.peer(i);
+            let owner = peer.owner.load(Acquire);
+            if owner == 0 || owner == RELEASING {
+                continue;
+            }
+            let heartbeat = peer.heartbeat.load(Relaxed);
+            let stale = match timeout_ms {
+                Some(ms) if heartbeat != 0 => now.saturating_sub(heartbeat) > ms,
+                _ => false,
+            };
+            if !stale && pid_alive(owner as u32) {
+                continue;
+            }
+            if let Some((kind, stream, claim)) = self.release(i, owner) {
+                if kind == WRITER {
+                    self.abandon_claim(claim);
+                }
+                self.unregister(kind, stream, true);
+                reaped += 1;
+            }
+        }
+        self.unlock_streams(gen);
+        reaped
+    }
+
+    /// Takes a free stream slot starting at the same position as `from`,
+    /// returning the slot and its epoch
+    fn add_stream(&self, from: usize) -> Option<(usize, usize)> {
+        let header = self.header();
+        let gen = self.lock_streams();
+        let mut rval = None;
+        for i in 0..self.max_streams {
+            let stream = self.stream_ptr(i);
+            if unsafe { (*stream).active.load(Relaxed) } == 0 {
+                let raw = self.stream(from).pos.load_raw(Relaxed);
+                let stream = unsafe {
+                    ptr::write(&mut (*stream).pos,
+                               CountedU16::from_usize(raw, self.capacity as u16));
+                    &*stream
+                };
+                let epoch = stream.epoch.load(Relaxed).wrapping_add(1);
+                stream.epoch.store(epoch, Relaxed);
+                stream.consumers.store(1, Relaxed);
+                stream.active.store(1, Release);
+                if header.n_streams.load(Relaxed) <= i {
+                    header.n_streams.store(i + 1, Release);
+                }
+                rval = Some((i, epoch));
+                break;
+            }
+        }
+        self.unlock_streams(gen);
+        rval
+    }
+
+    fn writer(inner: &Arc<ShmInner<T>>, pid: u32) -> io::Result<ShmWriter<T>> {
+        let peer = inner.register(WRITER, 0, pid)?;
+        inner.header().writers.fetch_add(1, Relaxed);
+        Ok(ShmWriter {
+            inner: inner.clone(),
+            peer: peer,
+        })
+    }
+
+    fn reader(inner: &Arc<ShmInner<T>>, stream: usize, pid: u32) -> io::Result<ShmReader<T>> {
+        if stream >= inner.max_streams {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such stream"));
+        }
+        let peer = inner.register(READER, stream, pid)?;
+        let gen = inner.lock_streams();
+        let slot = inner.stream(stream);
+        let epoch = slot.epoch.load(Relaxed);
+        let active = slot.active.load(Relaxed) != 0;
+        if active {
+            slot.consumers.fetch_add(1, Relaxed);
+        }
+        inner.unlock_streams(gen);
+        if !active {
+            inner.release(peer.index, peer.owner);
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such stream"));
+        }
+        Ok(ShmReader {
+            inner: inner.clone(),
+            stream: stream,
+            epoch: epoch,
+            peer: peer,
+            skipped: Cell::new(0),
+        })
+    }
+}
+
+impl PeerHandle {
+    /// Counts a failed push or pop, and says whether it's time to look for dead peers
+    #[inline(always)]
+    fn failed(&self) -> bool {
+        let failures = self.failures.get() + 1;
+        self.failures.set(failures % REAP_INTERVAL);
+        failures == REAP_INTERVAL
+    }
+
+    #[inline(always)]
+    fn succeeded(&self) {
+        self.failures.set(0);
+    }
+}
+
+impl ShmQueueBuilder {
+    pub fn new(capacity: u16, max_streams: u16) -> ShmQueueBuilder {
+        ShmQueueBuilder {
+            capacity: capacity,
+            max_streams: max_streams,
+            checksum: Checksum::None,
+        }
+    }
+
+    /// Stores a checksum with every entry and checks it on pop
+    pub fn checksum(mut self, checksum: Checksum) -> ShmQueueBuilder {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Creates a named POSIX shared memory queue
+    ///
+    /// Fails if the name already exists; see ShmQueue::unlink
+    pub fn create<T: Copy>(&self, name: &str) -> io::Result<ShmQueue<T>> {
+        let cname = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let fd = unsafe {
+            libc::shm_open(cname.as_ptr(),
+                           libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+                           0o600 as libc::mode_t)
+        };
+        if fd < 0 {
+            return last_error();
+        }
+        self.init_fd(fd)
+    }
+
+    /// Creates a queue in an anonymous memfd, to be shared by passing
+    /// the descriptor to other processes (e.g. across fork or over a unix socket)
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn anonymous<T: Copy>(&self) -> io::Result<ShmQueue<T>> {
+        let fd = unsafe { libc::memfd_create(b"multiqueue\0".as_ptr() as *const libc::c_char, 0) };
+        if fd < 0 {
+            return last_error();
+        }
+        self.init_fd(fd)
+    }
+
+    /// Creates a queue backed by a new file at path, which persists
+    /// items and stream positions across restarts
+    pub fn create_file<T: Copy, P: AsRef<Path>>(&self, path: P) -> io::Result<ShmQueue<T>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        let queue = self.init_fd(file.into_raw_fd())?;
+        queue.lock_file(false)?;
+        Ok(queue)
+    }
+
+    fn init_fd<T: Copy>(&self, fd: RawFd) -> io::Result<ShmQueue<T>> {
+        assert!(self.capacity > 0 && self.max_streams > 0);
+        let layout = Layout::new::<T>(self.capacity as usize, self.max_streams as usize);
+        let seg = match Segment::map(fd, layout.len, true) {
+            Ok(seg) => seg,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+        unsafe { Ok(ShmQueue { inner: Arc::new(ShmInner::init(seg, self)) }) }
+    }
+}
+
+impl<T: Copy> ShmQueue<T> {
+    /// Creates a named POSIX shared memory queue with room for max_streams streams
+    ///
+    /// Fails if the name already exists; see unlink
+    pub fn create(name: &str, capacity: u16, max_streams: u16) -> io::Result<ShmQueue<T>> {
+        ShmQueueBuilder::new(capacity, max_streams).create(name)
+    }
+
+    /// Opens a queue created elsewhere with create
+    pub fn open(name: &str) -> io::Result<ShmQueue<T>> {
+        let cname = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let fd = unsafe { libc::shm_open(cname.as_ptr(), libc::O_RDWR, 0) };
+        if fd < 0 {
+            return last_error();
+        }
+        ShmQueue::from_fd(fd)
+    }
+
+    /// Creates a queue in an anonymous memfd, to be shared by passing
+    /// the descriptor to other processes (e.g. across fork or over a unix socket)
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn anonymous(capacity: u16, max_streams: u16) -> io::Result<ShmQueue<T>> {
+        ShmQueueBuilder::new(capacity, max_streams).anonymous()
+    }
+
+    /// Maps a queue from a descriptor, taking ownership of it
+    pub fn from_fd(fd: RawFd) -> io::Result<ShmQueue<T>> {
+        unsafe { Ok(ShmQueue { inner: Arc::new(ShmInner::attach(fd)?) }) }
+    }
+
+    /// Creates a queue backed by a new file at path, which persists
+    /// items and stream positions across restarts
+    pub fn create_file<P: AsRef<Path>>(path: P,
+                                       capacity: u16,
+                                       max_streams: u16)
+                                       -> io::Result<ShmQueue<T>> {
+        ShmQueueBuilder::new(capacity, max_streams).create_file(path)
+    }
+
+    /// Opens a queue created by create_file
+    ///
+    /// If no other process has the file open, the previous users are assumed to have
+    /// exited or crashed. Their registrations are cleared, and anything a writer claimed
+    /// but never published is discarded, so streams resume where they last popped
+    pub fn open_file<P: AsRef<Path>>(path: P) -> io::Result<ShmQueue<T>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let queue = ShmQueue::from_fd(file.into_raw_fd())?;
+        if queue.lock_file(true)? {
+            unsafe { queue.inner.recover() };
+        }
+        // Held until the segment's fd is closed, so later openers see this process attached
+        queue.lock_file(false)?;
+        Ok(queue)
+    }
+
+    /// Takes the file lock that marks this process as attached. With exclusive,
+    /// tries for sole access instead and returns whether it got it
+    fn lock_file(&self, exclusive: bool) -> io::Result<bool> {
+        let op = if exclusive {
+            libc::LOCK_EX | libc::LOCK_NB
+        } else {
+            libc::LOCK_SH
+        };
+        if unsafe { libc::flock(self.inner.seg.fd, op) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        if exclusive && err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(false);
+        }
+        Err(err)
+    }
+
+    /// Writes the segment back to its file, returning once it's durable.
+    /// Only meaningful for file-backed queues
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.seg.sync()
+    }
+
+    /// Removes a named queue. Processes that already have it mapped are unaffected
+    pub fn unlink(name: &str) -> io::Result<()> {
+        let cname = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if unsafe { libc::shm_unlink(cname.as_ptr()) } != 0 {
+            return last_error();
+        }
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity
+    }
+
+    pub fn checksum(&self) -> Checksum {
+        self.inner.checksum
+    }
+
+    /// Registers a writer for this process
+    ///
+    /// Fails if the segment's peer table is full
+    pub fn writer(&self) -> io::Result<ShmWriter<T>> {
+        ShmInner::writer(&self.inner, process::id())
+    }
+
+    /// Joins an existing stream as one more consumer, sharing its items
+    /// with every other consumer of that stream in any process
+    ///
+    /// Fails if the stream isn't active or the segment's peer table is full
+    pub fn reader(&self, stream: usize) -> io::Result<ShmReader<T>> {
+        ShmInner::reader(&self.inner, stream, process::id())
+    }
+
+    /// Removes peers whose process has exited, plus those that haven't called
+    /// heartbeat within timeout if one is given (peers that never call it are
+    /// only judged by their pid). Returns how many were removed
+    ///
+    /// A stream whose last consumer is removed this way is evicted and no longer
+    /// holds writers back. Handles call this on their own after a long run of
+    /// failed pushes or pops, so it's only needed to enforce heartbeat timeouts
+    pub fn reap(&self, timeout: Option<Duration>) -> usize {
+        self.inner.reap(timeout)
+    }
+}
+
+impl<T> AsRawFd for ShmQueue<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.seg.fd
+    }
+}
+
+impl<T: Copy> ShmWriter<T> {
+    pub fn push(&self, val: T) -> Result<(), T> {
+        let rval = self.inner.push(self.peer.index, val);
+        if rval.is_ok() {
+            self.peer.succeeded();
+        } else if self.peer.failed() {
+            self.inner.reap(None);
+        }
+        rval
+    }
+
+    /// Records that this peer is alive, for ShmQueue::reap timeouts
+    pub fn heartbeat(&self) {
+        self.inner.peer(self.peer.index).heartbeat.store(now_ms(), Relaxed);
+    }
+
+    /// Registers another writer for this process
+    pub fn try_clone(&self) -> io::Result<ShmWriter<T>> {
+        ShmInner::writer(&self.inner, process::id())
+    }
+}
+
+impl<T: Copy> ShmReader<T> {
+    /// Pops the next item, stepping over any that fail their checksum; see try_pop
+    pub fn pop(&self) -> Option<T> {
+        loop {
+            match self.try_pop() {
+                Ok(rval) => return rval,
+                Err(_) => self.skipped.set(self.skipped.get() + 1),
+            }
+        }
+    }
+
+    /// How many entries pop has stepped over for failing their checksum
+    pub fn skipped(&self) -> usize {
+        self.skipped.get()
+    }
+
+    /// Pops the next item, or returns an InvalidData error if it fails its checksum.
+    /// The bad entry is consumed either way, so the next call moves on past it
+    pub fn try_pop(&self) -> io::Result<Option<T>> {
+        let rval = self.inner.pop(self.stream, self.epoch);
+        match rval {
+            Ok(None) => {
+                if self.peer.failed() {
+                    self.inner.reap(None);
+                }
+            }
+            _ => self.peer.succeeded(),
+        }
+        rval
+    }
+
+    /// Records that this peer is alive, for ShmQueue::reap timeouts
+    pub fn heartbeat(&self) {
+        self.inner.peer(self.peer.index).heartbeat.store(now_ms(), Relaxed);
+    }
+
+    /// Whether this reader's stream was evicted by a reap, after which it never
+    /// returns anything again
+    pub fn is_evicted(&self) -> bool {
+        let slot = self.inner.stream(self.stream);
+        slot.epoch.load(Acquire) != self.epoch || slot.active.load(Relaxed) == 0
+    }
+
+    /// Registers another consumer of this reader's stream
+    pub fn try_clone(&self) -> io::Result<ShmReader<T>> {
+        ShmInner::reader(&self.inner, self.stream, process::id())
+    }
+
+    /// The slot index of this reader's stream, for other processes to join with
+    pub fn stream_id(&self) -> usize {
+        self.stream
+    }
+
+    /// Adds a new stream starting at this reader's position, like MultiReader::add_reader
+    ///
+    /// Fails if every stream slot or every peer slot is taken
+    pub fn add_stream(&self) -> io::Result<ShmReader<T>> {
+        let (stream, epoch) = match self.inner.add_stream(self.stream) {
+            Some(added) => added,
+            None => {
+                return Err(io::Error::other("every stream slot in the segment is in use"))
+            }
+        };
+        match self.inner.register(READER, stream, process::id()) {
+            Ok(peer) => {
+                Ok(ShmReader {
+                    inner: self.inner.clone(),
+                    stream: stream,
+                    epoch: epoch,
+                    peer: peer,
+                    skipped: Cell::new(0),
+                })
+            }
+            Err(e) => {
+                self.inner.unregister(READER, stream, true);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T> Drop for ShmWriter<T> {
+    fn drop(&mut self) {
+        // If this peer was reaped, whoever reaped it already unregistered it
+        if let Some((kind, stream, _)) = self.inner.release(self.peer.index, self.peer.owner) {
+            self.inner.unregister(kind, stream, false);
+        }
+    }
+}
+
+impl<T> Drop for ShmReader<T> {
+    fn drop(&mut self) {
+        if let Some((kind, stream, _)) = self.inner.release(self.peer.index, self.peer.owner) {
+            self.inner.unregister(kind, stream, false);
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for ShmInner<T> {}
+unsafe impl<T: Send> Sync for ShmInner<T> {}
+
+// Miri can't map shared memory
+#[cfg(all(test, not(miri)))]
+mod test {
+    use super::*;
+
+    use std::process::{self, Command};
+
+    fn attach_twice() -> (ShmQueue<u64>, ShmQueue<u64>) {
+        let first = ShmQueue::<u64>::anonymous(8, 4).unwrap();
+        let fd = unsafe { libc::dup(first.as_raw_fd()) };
+        (first, ShmQueue::from_fd(fd).unwrap())
+    }
+
+    /// A pid that belonged to a process which has since exited
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        pid
+    }
+
+    #[test]
+    fn push_pop_across_mappings() {
+        let (first, second) = attach_twice();
+        let writer = first.writer().unwrap();
+        let reader = second.reader(0).unwrap();
+        for i in 0..100 {
+            assert!(reader.pop().is_none());
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), reader.pop());
+        }
+    }
+
+    #[test]
+    fn full_until_every_stream_reads() {
+        let (first, second) = attach_twice();
+        let writer = first.writer().unwrap();
+        let reader = second.reader(0).unwrap();
+        let other = reader.add_stream().unwrap();
+        for i in 0..8 {
+            writer.push(i).unwrap();
+        }
+        assert_eq!(Err(8), writer.push(8));
+        for i in 0..8 {
+            assert_eq!(Some(i), reader.pop());
+        }
+        assert_eq!(Err(8), writer.push(8));
+        assert_eq!(Some(0), other.pop());
+        writer.push(8).unwrap();
+        assert_eq!(Err(9), writer.push(9));
+    }
+
+    #[test]
+    fn named_open_and_validation() {
+        let name = format!("/pipeline-test-{}", process::id());
+        let created = ShmQueue::<u32>::create(&name, 4, 1).unwrap();
+        assert!(ShmQueue::<u32>::create(&name, 4, 1).is_err());
+        let opened = ShmQueue::<u32>::open(&name).unwrap();
+        assert!(ShmQueue::<[u8; 64]>::open(&name).is_err());
+        ShmQueue::<u32>::unlink(&name).unwrap();
+
+        created.writer().unwrap().push(7).unwrap();
+        assert_eq!(Some(7), opened.reader(0).unwrap().pop());
+        assert!(opened.reader(1).is_err());
+    }
+
+    #[test]
+    fn stream_slots_run_out() {
+        let queue = ShmQueue::<u8>::anonymous(4, 2).unwrap();
+        let reader = queue.reader(0).unwrap();
+        let second = reader.add_stream().unwrap();
+        assert_eq!(1, second.stream_id());
+        assert!(reader.add_stream().is_err());
+    }
+
+    #[test]
+    fn dead_reader_stops_gating() {
+        let queue = ShmQueue::<u64>::anonymous(4, 2).unwrap();
+        let writer = queue.writer().unwrap();
+        let reader = queue.reader(0).unwrap();
+        let stuck = reader.add_stream().unwrap();
+        let dead = ShmInner::reader(&queue.inner, stuck.stream_id(), dead_pid()).unwrap();
+        drop(stuck);
+        for i in 0..4 {
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), reader.pop());
+        }
+        assert!(writer.push(4).is_err());
+
+        assert_eq!(1, queue.reap(None));
+        assert!(dead.is_evicted());
+        writer.push(4).unwrap();
+        assert_eq!(Some(4), reader.pop());
+        assert!(dead.pop().is_none());
+        assert_eq!(0, queue.reap(None));
+    }
+
+    #[test]
+    fn dead_writer_claim_is_skipped() {
+        let queue = ShmQueue::<u64>::anonymous(4, 1).unwrap();
+        let writer = queue.writer().unwrap();
+        let reader = queue.reader(0).unwrap();
+        writer.push(0).unwrap();
+
+        // Claim the next slot the way push does, then die before publishing
+        let dead = ShmInner::writer(&queue.inner, dead_pid()).unwrap();
+        let header = queue.inner.header();
+        let transaction = header.head.load_transaction(Relaxed);
+        queue.inner.peer(dead.peer.index).claim.store(transaction.raw() + 1, Relaxed);
+        assert!(transaction.commit(1, Relaxed).is_none());
+        writer.push(2).unwrap();
+
+        assert_eq!(Some(0), reader.pop());
+        assert!(reader.pop().is_none());
+        assert_eq!(1, queue.reap(None));
+        assert_eq!(Some(2), reader.pop());
+        mem::forget(dead);
+    }
+
+    #[test]
+    fn dead_stream_lock_owner() {
+        let queue = ShmQueue::<u64>::anonymous(4, 2).unwrap();
+        let writer = queue.writer().unwrap();
+        let reader = queue.reader(0).unwrap();
+
+        // Die holding the stream lock partway through adding a stream
+        let header = queue.inner.header();
+        header.streams_owner.store(dead_pid() as usize, Relaxed);
+        header.streams_gen.fetch_add(1, Relaxed);
+        for i in 0..4 {
+            writer.push(i).unwrap();
+            assert_eq!(Some(i), reader.pop());
+        }
+        // A full lap means recomputing the tail, which waits on the lock
+        writer.push(4).unwrap();
+        assert_eq!(0, header.streams_gen.load(Relaxed) & 1);
+        assert_eq!(1, reader.add_stream().unwrap().stream_id());
+        assert_eq!(0, queue.reap(None));
+    }
+
+    #[test]
+    fn heartbeat_timeout() {
+        let queue = ShmQueue::<u64>::anonymous(4, 1).unwrap();
+        let reader = queue.reader(0).unwrap();
+        assert_eq!(0, queue.reap(Some(Duration::from_millis(0))));
+        reader.heartbeat();
+        ::std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(0, queue.reap(Some(Duration::from_secs(60))));
+        assert_eq!(1, queue.reap(Some(Duration::from_millis(1))));
+        assert!(reader.is_evicted());
+    }
+
+    fn temp_path(name: &str) -> ::std::path::PathBuf {
+        let path = ::std::env::temp_dir().join(format!("pipeline-{}-{}", name, process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn file_queue_resumes_after_crash() {
+        let path = temp_path("resume");
+        {
+            let queue = ShmQueue::<u64>::create_file(&path, 8, 1).unwrap();
+            assert!(ShmQueue::<u64>::create_file(&path, 8, 1).is_err());
+            let writer = queue.writer().unwrap();
+            let reader = queue.reader(0).unwrap();
+            for i in 0..5 {
+                writer.push(i).unwrap();
+            }
+            assert_eq!(Some(0), reader.pop());
+            assert_eq!(Some(1), reader.pop());
+
+            // Crash with a claimed but unpublished slot and registrations left behind
+            let header = queue.inner.header();
+            let transaction = header.head.load_transaction(Relaxed);
+            assert!(transaction.commit(1, Relaxed).is_none());
+            for _ in 0..MAX_PEERS - 2 {
+                queue.inner.register(READER, 0, process::id()).unwrap();
+            }
+            assert!(queue.writer().is_err());
+            queue.flush().unwrap();
+        }
+
+        let queue = ShmQueue::<u64>::open_file(&path).unwrap();
+        let writer = queue.writer().unwrap();
+        let reader = queue.reader(0).unwrap();
+        for i in 2..5 {
+            assert_eq!(Some(i), reader.pop());
+        }
+        assert!(reader.pop().is_none());
+        writer.push(5).unwrap();
+        assert_eq!(Some(5), reader.pop());
+
+        // A second opener while the first is attached must not recover
+        writer.push(6).unwrap();
+        let second = ShmQueue::<u64>::open_file(&path).unwrap();
+        assert_eq!(1, second.inner.header().writers.load(Relaxed));
+        assert_eq!(Some(6), second.reader(0).unwrap().pop());
+
+        // Nor a third once only the second is attached
+        let second_writer = second.writer().unwrap();
+        drop((writer, reader, queue));
+        let third = ShmQueue::<u64>::open_file(&path).unwrap();
+        assert_eq!(1, third.inner.header().writers.load(Relaxed));
+        drop(second_writer);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checksum_catches_corrupted_entry() {
+        let path = temp_path("checksum");
+        {
+            let queue = ShmQueueBuilder::new(4, 1)
+                .checksum(Checksum::Crc32)
+                .create_file::<u64, _>(&path)
+                .unwrap();
+            let writer = queue.writer().unwrap();
+            for i in 0..3 {
+                writer.push(i).unwrap();
+            }
+            // Flip a bit in the second entry's value, as a bad sector would
+            unsafe { (*queue.inner.entry(1)).val ^= 1 << 40 };
+            queue.flush().unwrap();
+        }
+
+        let queue = ShmQueue::<u64>::open_file(&path).unwrap();
+        assert_eq!(Checksum::Crc32, queue.checksum());
+        let reader = queue.reader(0).unwrap();
+        assert_eq!(Some(0), reader.try_pop().unwrap());
+        let err = reader.try_pop().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(Some(2), reader.try_pop().unwrap());
+        assert!(reader.try_pop().unwrap().is_none());
+
+        // pop steps over it instead
+        let writer = queue.writer().unwrap();
+        writer.push(3).unwrap();
+        unsafe { (*queue.inner.entry(3)).val ^= 1 };
+        writer.push(4).unwrap();
+        assert_eq!(Some(4), reader.pop());
+        assert_eq!(1, reader.skipped());
+        ::std::fs::remove_file(&path).unwrap();
+    }
+}
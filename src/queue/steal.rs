@@ -0,0 +1,210 @@
+//! Partitioned queues whose workers steal from each other when idle
+//!
+//! Each worker has a partition of its own and pops from it first, so under
+//! load workers don't contend on a shared cursor. A worker whose partition
+//! runs dry pops from the others in turn, which keeps one slow item from
+//! stranding whatever was queued behind it. Stealing just pops through a
+//! second consumer on the other partition, so it needs the same clonable
+//! readers as any other competing consumer and spsc queues won't do.
+
+use std::cell::Cell;
+use std::fmt;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use queue::multiqueue::{MultiQueueBuilder, MultiReader, MultiWriter, WaitStrategy};
+use queue::wait::Waiter;
+
+pub struct StealWriter<T> {
+    writers: Vec<MultiWriter<T>>,
+    // Partition the next push starts at
+    next: Cell<usize>,
+}
+
+pub struct StealReader<T> {
+    // Every partition's reader, this worker's own among them
+    readers: Vec<MultiReader<T>>,
+    own: usize,
+    waiter: Waiter,
+}
+
+impl<T> StealWriter<T> {
+    /// Pushes val onto the next partition in turn, or the first after it
+    /// with room. Returns the partition and sequence number it went in at
+    pub fn push(&self, val: T) -> Result<(usize, usize), T> {
+        let start = self.next.get();
+        let mut val = val;
+        for i in 0..self.writers.len() {
+            let partition = (start + i) % self.writers.len();
+            match self.writers[partition].push(val) {
+                Ok(seq) => {
+                    self.next.set(partition + 1);
+                    return Ok((partition, seq));
+                }
+                Err(back) => val = back,
+            }
+        }
+        Err(val)
+    }
+
+    /// Pushes val onto the given partition, as MultiWriter::push does
+    pub fn push_to(&self, partition: usize, val: T) -> Result<usize, T> {
+        self.writers[partition].push(val)
+    }
+
+    pub fn partitions(&self) -> usize {
+        self.writers.len()
+    }
+}
+
+impl<T> StealReader<T> {
+    /// Pops from this worker's partition, or steals from the others if it's empty
+    pub fn pop(&self) -> Option<T> {
+        self.pop_from().map(|(_, val)| val)
+    }
+
+    /// Like pop, along with the partition the item came from
+    pub fn pop_from(&self) -> Option<(usize, T)> {
+        for i in 0..self.readers.len() {
+            let partition = (self.own + i) % self.readers.len();
+            if let Some(val) = self.readers[partition].pop() {
+                return Some((partition, val));
+            }
+        }
+        None
+    }
+
+    /// Pops from this worker's partition without stealing
+    pub fn pop_own(&self) -> Option<T> {
+        self.readers[self.own].pop()
+    }
+
+    /// Pops the next item from any partition, waiting for one if they're all
+    /// empty. Returns None once the writers are gone and nothing is left
+    pub fn pop_wait(&self) -> Option<T> {
+        self.waiter.until(|| {
+            match self.pop() {
+                Some(val) => Some(Some(val)),
+                // A writer may have pushed on its way out
+                None if self.readers.iter().all(|reader| reader.is_closed()) => Some(self.pop()),
+                None => None,
+            }
+        })
+    }
+
+    /// Whether any partition has an item to pop
+    pub fn is_ready(&self) -> bool {
+        self.readers.iter().any(|reader| reader.is_ready())
+    }
+
+    /// The index of this worker's own partition
+    pub fn partition(&self) -> usize {
+        self.own
+    }
+}
+
+impl<T> Clone for StealWriter<T> {
+    fn clone(&self) -> StealWriter<T> {
+        StealWriter {
+            writers: self.writers.clone(),
+            next: Cell::new(self.next.get()),
+        }
+    }
+}
+
+impl<T> fmt::Debug for StealWriter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StealWriter")
+            .field("writers", &self.writers)
+            .field("next", &self.next.get())
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for StealReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StealReader")
+            .field("readers", &self.readers)
+            .field("own", &self.own)
+            .finish()
+    }
+}
+
+/// Creates a partition of up to capacity items for each of workers readers
+pub fn stealing<T>(workers: usize, capacity: u16) -> (StealWriter<T>, Vec<StealReader<T>>) {
+    stealing_with(workers, &MultiQueueBuilder::new(capacity))
+}
+
+/// Like stealing, with every partition built from the given configuration
+pub fn stealing_with<T>(workers: usize,
+                        builder: &MultiQueueBuilder)
+                        -> (StealWriter<T>, Vec<StealReader<T>>) {
+    assert!(workers > 0, "Stealing needs at least one worker");
+    let (writers, owners): (Vec<MultiWriter<T>>, Vec<MultiReader<T>>) =
+        (0..workers).map(|_| builder.build()).unzip();
+    let readers = (0..workers)
+        .map(|own| {
+            StealReader {
+                readers: owners.clone(),
+                own: own,
+                // Pushes only wake a partition's own workers, so parking is out
+                waiter: Waiter::new(WaitStrategy::default()),
+            }
+        })
+        .collect();
+    let writer = StealWriter {
+        writers: writers,
+        next: Cell::new(0),
+    };
+    (writer, readers)
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn idle_workers_steal() {
+        let (writer, readers) = stealing::<usize>(2, 4);
+        assert_eq!(2, writer.partitions());
+        assert_eq!(Ok((0, 0)), writer.push(1));
+        assert_eq!(Ok((1, 0)), writer.push(2));
+        writer.push_to(0, 3).unwrap();
+        assert_eq!(Some((1, 2)), readers[1].pop_from());
+        assert_eq!(None, readers[1].pop_own());
+        assert_eq!(Some((0, 1)), readers[1].pop_from());
+        assert_eq!(Some(3), readers[0].pop());
+        assert!(!readers[0].is_ready());
+        assert_eq!(None, readers[1].pop());
+    }
+
+    #[test]
+    fn workers_drain_every_partition() {
+        let (writer, readers) = stealing::<usize>(3, 8);
+        let mut handles = Vec::new();
+        for reader in readers {
+            handles.push(thread::spawn(move || {
+                let mut popped = Vec::new();
+                while let Some(val) = reader.pop_wait() {
+                    popped.push(val);
+                }
+                popped
+            }));
+        }
+        // Everything lands in partition 0, so the others only get work by stealing
+        for i in 0..300 {
+            let mut val = i;
+            while let Err(back) = writer.push_to(0, val) {
+                val = back;
+                thread::yield_now();
+            }
+        }
+        drop(writer);
+        let mut popped: Vec<usize> = handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+        popped.sort();
+        assert_eq!((0..300).collect::<Vec<usize>>(), popped);
+    }
+}
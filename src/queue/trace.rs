@@ -0,0 +1,153 @@
+//! Tracing events for state changes in the queue.
+//!
+//! Only transitions are reported: a writer spinning on a full queue
+//! emits one event when it first fails and one when it gets through
+//! again, rather than one per attempt. A blocking push that has to wait
+//! does so in a span, closed by an event saying how long it waited. Without the `tracing` feature
+//! everything here compiles down to nothing.
+
+#[cfg(feature = "tracing")]
+mod theimpl {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::time::Instant;
+
+    use tracing::span::EnteredSpan;
+
+    use super::Labels;
+
+    /// Remembers whether the last attempt at something failed
+    pub struct Transition {
+        failing: AtomicBool,
+    }
+
+    impl Transition {
+        pub fn new() -> Transition {
+            Transition { failing: AtomicBool::new(false) }
+        }
+
+        /// Returns Some(now_failing) if this result differs from the last one
+        #[inline(always)]
+        fn update(&self, failed: bool) -> Option<bool> {
+            if self.failing.load(Relaxed) != failed {
+                self.failing.store(failed, Relaxed);
+                Some(failed)
+            } else {
+                None
+            }
+        }
+
+        #[inline(always)]
+        pub fn push(&self, queue: Labels, ok: bool) {
+            match self.update(!ok) {
+                Some(true) => debug!(queue = queue.0, labels = ?queue.1, "queue full"),
+                Some(false) => debug!(queue = queue.0, labels = ?queue.1, "queue no longer full"),
+                None => (),
+            }
+        }
+
+        #[inline(always)]
+        pub fn pop(&self, queue: Labels, stream: usize, ok: bool) {
+            match self.update(!ok) {
+                Some(true) => trace!(queue = queue.0, labels = ?queue.1, stream = stream, "stream empty"),
+                Some(false) => trace!(queue = queue.0, labels = ?queue.1, stream = stream, "stream has data"),
+                None => (),
+            }
+        }
+    }
+
+    /// A writer waiting for room, inside a span until it's through
+    pub struct PushWait {
+        _span: EnteredSpan,
+        since: Instant,
+    }
+
+    impl PushWait {
+        pub fn new(queue: Labels) -> PushWait {
+            PushWait {
+                _span: debug_span!("push_wait", queue = queue.0, labels = ?queue.1).entered(),
+                since: Instant::now(),
+            }
+        }
+
+        pub fn done(self, pushed: bool) {
+            debug!(waited_us = self.since.elapsed().as_micros() as u64, pushed = pushed, "push wait over");
+        }
+    }
+
+    pub fn stream_added(queue: Labels, stream: usize) {
+        info!(queue = queue.0, labels = ?queue.1, stream = stream, "stream registered");
+    }
+
+    pub fn stream_evicted(queue: Labels, stream: usize) {
+        warn!(queue = queue.0, labels = ?queue.1, stream = stream, "stream evicted");
+    }
+
+    pub fn consumer_added(queue: Labels, stream: usize) {
+        debug!(queue = queue.0, labels = ?queue.1, stream = stream, "consumer registered");
+    }
+
+    pub fn writer_mode(queue: Labels, multi: bool) {
+        debug!(queue = queue.0, labels = ?queue.1,
+               mode = if multi { "multi" } else { "single" },
+               "writer mode switch");
+    }
+
+    pub fn reader_mode(queue: Labels, stream: usize, multi: bool) {
+        debug!(queue = queue.0, labels = ?queue.1, stream = stream,
+               mode = if multi { "multi" } else { "single" },
+               "reader mode switch");
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod theimpl {
+    use super::Labels;
+
+    pub struct Transition;
+
+    impl Transition {
+        #[inline(always)]
+        pub fn new() -> Transition {
+            Transition
+        }
+
+        #[inline(always)]
+        pub fn push(&self, _queue: Labels, _ok: bool) {}
+
+        #[inline(always)]
+        pub fn pop(&self, _queue: Labels, _stream: usize, _ok: bool) {}
+    }
+
+    pub struct PushWait;
+
+    impl PushWait {
+        #[inline(always)]
+        pub fn new(_queue: Labels) -> PushWait {
+            PushWait
+        }
+
+        #[inline(always)]
+        pub fn done(self, _pushed: bool) {}
+    }
+
+    #[inline(always)]
+    pub fn stream_added(_queue: Labels, _stream: usize) {}
+
+    #[inline(always)]
+    pub fn stream_evicted(_queue: Labels, _stream: usize) {}
+
+    #[inline(always)]
+    pub fn consumer_added(_queue: Labels, _stream: usize) {}
+
+    #[inline(always)]
+    pub fn writer_mode(_queue: Labels, _multi: bool) {}
+
+    #[inline(always)]
+    pub fn reader_mode(_queue: Labels, _stream: usize, _multi: bool) {}
+}
+
+pub use self::theimpl::*;
+
+/// The name and static labels of a queue, as attached by the builder
+pub type Labels = (&'static str, &'static [(&'static str, &'static str)]);
@@ -0,0 +1,281 @@
+//! How blocking pushes and pops wait for the other side
+//!
+//! Spinning and yielding only ever touch the waiting thread. Parking puts
+//! it to sleep, which means every push and pop on the queue has to check
+//! for sleepers to wake; queues built with another strategy skip that check.
+//!
+//! Where the OS lets a thread sleep on an address, parked threads do that,
+//! so a wakeup is a single call with no lock taken: WaitOnAddress on Windows,
+//! and a futex on Linux when the libc dependency is in (the `shm` and
+//! `affinity` features bring it). Everywhere else, parking falls back on a
+//! condvar kept by the queue. Which one is picked at compile time, and
+//! either way Park works the same. Without std there's only spinning.
+
+#[cfg(all(feature = "std", not(any(windows, all(target_os = "linux", feature = "libc")))))]
+use std::sync::{Condvar, Mutex};
+#[cfg(all(feature = "std", any(windows, all(target_os = "linux", feature = "libc"))))]
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+#[cfg(feature = "std")]
+use std::thread;
+
+use util::sync::{AtomicUsize, fence};
+
+/// How a blocking push or pop waits for room or for an item
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Retries in a tight loop. Quickest to notice, but holds the core
+    #[cfg_attr(not(feature = "std"), default)]
+    Spin,
+    /// Yields to the scheduler between retries
+    #[cfg(feature = "std")]
+    #[default]
+    Yield,
+    /// Sleeps until the other side pushes, pops or goes away
+    #[cfg(feature = "std")]
+    Park,
+}
+
+pub struct Waiter {
+    strategy: WaitStrategy,
+    #[cfg(feature = "std")]
+    sleep: Sleep,
+}
+
+/// Where parked threads wait to be woken
+#[cfg(all(feature = "std", not(any(windows, all(target_os = "linux", feature = "libc")))))]
+struct Sleep {
+    sleepers: AtomicUsize,
+    lock: Mutex<()>,
+    wake: Condvar,
+}
+
+#[cfg(all(feature = "std", any(windows, all(target_os = "linux", feature = "libc"))))]
+struct Sleep {
+    sleepers: AtomicUsize,
+    // Bumped by every notify that finds sleepers, which wait on it changing
+    epoch: AtomicU32,
+}
+
+#[cfg(all(feature = "std", windows))]
+mod sys {
+    use std::os::raw::c_void;
+    use std::sync::atomic::AtomicU32;
+
+    const INFINITE: u32 = 0xffff_ffff;
+
+    #[link(name = "synchronization")]
+    extern "system" {
+        fn WaitOnAddress(address: *const c_void,
+                         compare: *const c_void,
+                         size: usize,
+                         millis: u32)
+                         -> i32;
+        fn WakeByAddressAll(address: *const c_void);
+    }
+
+    /// Sleeps until at wakes, unless it no longer holds seen
+    pub fn wait(at: &AtomicU32, seen: u32) {
+        unsafe {
+            WaitOnAddress(at as *const AtomicU32 as *const c_void,
+                          &seen as *const u32 as *const c_void,
+                          4,
+                          INFINITE);
+        }
+    }
+
+    pub fn wake_all(at: &AtomicU32) {
+        unsafe { WakeByAddressAll(at as *const AtomicU32 as *const c_void) };
+    }
+}
+
+#[cfg(all(feature = "std", target_os = "linux", feature = "libc"))]
+mod sys {
+    use std::ptr;
+    use std::sync::atomic::AtomicU32;
+    use libc;
+
+    /// Sleeps until at wakes, unless it no longer holds seen
+    pub fn wait(at: &AtomicU32, seen: u32) {
+        unsafe {
+            libc::syscall(libc::SYS_futex,
+                          at as *const AtomicU32,
+                          libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                          seen,
+                          ptr::null::<libc::timespec>());
+        }
+    }
+
+    pub fn wake_all(at: &AtomicU32) {
+        unsafe {
+            libc::syscall(libc::SYS_futex,
+                          at as *const AtomicU32,
+                          libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+                          libc::c_int::MAX);
+        }
+    }
+}
+
+impl Waiter {
+    pub fn new(strategy: WaitStrategy) -> Waiter {
+        Waiter {
+            strategy: strategy,
+            #[cfg(feature = "std")]
+            sleep: Sleep::new(),
+        }
+    }
+
+    pub fn strategy(&self) -> WaitStrategy {
+        self.strategy
+    }
+
+    /// Retries attempt until it gives Some. Whatever attempt does must
+    /// not call notify, since a parked attempt runs with the lock held
+    pub fn until<R, F: FnMut() -> Option<R>>(&self, mut attempt: F) -> R {
+        loop {
+            if let Some(done) = attempt() {
+                return done;
+            }
+            match self.strategy {
+                WaitStrategy::Spin => ::std::hint::spin_loop(),
+                #[cfg(feature = "std")]
+                WaitStrategy::Yield => thread::yield_now(),
+                #[cfg(feature = "std")]
+                WaitStrategy::Park => {
+                    if let Some(done) = self.sleep.park(&mut attempt) {
+                        return done;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits a moment without parking, for waits too short to be worth a
+    /// wakeup or ones that can happen inside an attempt
+    #[inline(always)]
+    pub fn pause(&self) {
+        match self.strategy {
+            WaitStrategy::Spin => ::std::hint::spin_loop(),
+            #[cfg(feature = "std")]
+            WaitStrategy::Yield | WaitStrategy::Park => thread::yield_now(),
+        }
+    }
+
+    /// Wakes anyone parked in until, after something they might be waiting on happened
+    #[inline(always)]
+    pub fn notify(&self) {
+        #[cfg(feature = "std")]
+        {
+            if self.strategy == WaitStrategy::Park {
+                self.sleep.notify();
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "std", not(any(windows, all(target_os = "linux", feature = "libc")))))]
+impl Sleep {
+    fn new() -> Sleep {
+        Sleep {
+            sleepers: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            wake: Condvar::new(),
+        }
+    }
+
+    /// Tries attempt once more and sleeps if it still fails. Trying again under
+    /// the lock, after counting ourselves as a sleeper, means a notify can't
+    /// slip in before the wait
+    fn park<R, F: FnMut() -> Option<R>>(&self, attempt: &mut F) -> Option<R> {
+        let guard = self.lock.lock().unwrap();
+        self.sleepers.fetch_add(1, SeqCst);
+        let done = attempt();
+        if done.is_none() {
+            drop(self.wake.wait(guard).unwrap());
+        }
+        self.sleepers.fetch_sub(1, SeqCst);
+        done
+    }
+
+    fn notify(&self) {
+        fence(SeqCst);
+        if self.sleepers.load(Relaxed) > 0 {
+            drop(self.lock.lock().unwrap());
+            self.wake.notify_all();
+        }
+    }
+}
+
+#[cfg(all(feature = "std", any(windows, all(target_os = "linux", feature = "libc"))))]
+impl Sleep {
+    fn new() -> Sleep {
+        Sleep {
+            sleepers: AtomicUsize::new(0),
+            epoch: AtomicU32::new(0),
+        }
+    }
+
+    /// Tries attempt once more and sleeps if it still fails. The epoch is
+    /// read before trying, so a notify after that changes it and the wait
+    /// returns straight away. It can also return for no reason, which until
+    /// takes as a cue to try again
+    fn park<R, F: FnMut() -> Option<R>>(&self, attempt: &mut F) -> Option<R> {
+        self.sleepers.fetch_add(1, SeqCst);
+        let seen = self.epoch.load(SeqCst);
+        let done = attempt();
+        if done.is_none() {
+            sys::wait(&self.epoch, seen);
+        }
+        self.sleepers.fetch_sub(1, SeqCst);
+        done
+    }
+
+    fn notify(&self) {
+        fence(SeqCst);
+        if self.sleepers.load(Relaxed) > 0 {
+            self.epoch.fetch_add(1, SeqCst);
+            sys::wake_all(&self.epoch);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn parked_waiters_wake_on_notify() {
+        let waiter = Arc::new(Waiter::new(WaitStrategy::Park));
+        let ready = Arc::new(AtomicBool::new(false));
+        let (wref, rref) = (waiter.clone(), ready.clone());
+        let handle = thread::spawn(move || wref.until(|| if rref.load(SeqCst) { Some(7) } else { None }));
+        while waiter.sleep.sleepers.load(SeqCst) == 0 {
+            thread::yield_now();
+        }
+        ready.store(true, SeqCst);
+        waiter.notify();
+        assert_eq!(7, handle.join().unwrap());
+    }
+
+    #[test]
+    fn one_notify_wakes_every_parked_waiter() {
+        let waiter = Arc::new(Waiter::new(WaitStrategy::Park));
+        let ready = Arc::new(AtomicBool::new(false));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let (wref, rref) = (waiter.clone(), ready.clone());
+                thread::spawn(move || wref.until(|| if rref.load(SeqCst) { Some(i) } else { None }))
+            })
+            .collect();
+        while waiter.sleep.sleepers.load(SeqCst) < 4 {
+            thread::yield_now();
+        }
+        ready.store(true, SeqCst);
+        waiter.notify();
+        let woken: Vec<usize> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(vec![0, 1, 2, 3], woken);
+    }
+}
@@ -0,0 +1,74 @@
+//! The wakers of a stream's async readers
+//!
+//! Each stream keeps its own list behind a spinlock, so streams never wait
+//! on each other, and the queue keeps a count of streams with wakers in
+//! them so that writers only look through the streams when someone's waiting.
+//! Until the first async reader waits, writers don't even check the count.
+
+use std::cell::UnsafeCell;
+use std::hint;
+use std::mem;
+use std::task::Waker;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use util::sync::{AtomicBool, AtomicUsize, Ordering, fence};
+
+pub struct WakerList {
+    locked: AtomicBool,
+    // Whether there are wakers in the list, checked without the lock
+    pending: AtomicBool,
+    wakers: UnsafeCell<Vec<Waker>>,
+}
+
+impl WakerList {
+    pub fn new() -> WakerList {
+        WakerList {
+            locked: AtomicBool::new(false),
+            pending: AtomicBool::new(false),
+            wakers: UnsafeCell::new(Vec::new()),
+        }
+    }
+
+    /// Adds waker to be woken the next time the stream might have something,
+    /// counting the stream in waiting if it had no wakers before. The caller
+    /// has to check for an item again after, or it may miss one
+    pub fn register(&self, waker: &Waker, waiting: &AtomicUsize) {
+        self.with_wakers(|wakers| {
+            if !wakers.iter().any(|other| other.will_wake(waker)) {
+                wakers.push(waker.clone());
+            }
+        });
+        if !self.pending.swap(true, Ordering::SeqCst) {
+            waiting.fetch_add(1, Ordering::SeqCst);
+        }
+        // Pairs with the fence in wake, so either the writer sees this
+        // stream waiting or the caller's next check sees the item
+        fence(Ordering::SeqCst);
+    }
+
+    /// Wakes and forgets every waker in the list
+    pub fn wake(&self, waiting: &AtomicUsize) {
+        if !self.pending.load(Ordering::SeqCst) || !self.pending.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        waiting.fetch_sub(1, Ordering::SeqCst);
+        // A waker registered since the swap gets woken early, which is harmless
+        let wakers = self.with_wakers(mem::take);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    fn with_wakers<R, F: FnOnce(&mut Vec<Waker>) -> R>(&self, f: F) -> R {
+        while self.locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err() {
+            hint::spin_loop();
+        }
+        let rval = f(unsafe { &mut *self.wakers.get() });
+        self.locked.store(false, Ordering::Release);
+        rval
+    }
+}
@@ -0,0 +1,204 @@
+//! Pinning threads to cores, and keeping memory near them
+//!
+//! Only Linux can pin a thread; elsewhere the functions here return Unsupported,
+//! and NODE_LOCAL is just the heap.
+
+use std::io;
+
+use util::alloc::RawAlloc;
+#[cfg(not(target_os = "linux"))]
+use util::alloc::HEAP;
+
+/// Restricts the calling thread to run on the given cores
+#[cfg(target_os = "linux")]
+pub fn pin_current(cores: &[usize]) -> io::Result<()> {
+    use std::mem;
+    use libc;
+
+    if cores.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no cores to pin to"));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        for &core in cores {
+            if core >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("core {} is out of range", core)));
+            }
+            libc::CPU_SET(core, &mut set);
+        }
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The cores the calling thread may run on
+#[cfg(target_os = "linux")]
+pub fn current() -> io::Result<Vec<usize>> {
+    use std::mem;
+    use libc;
+
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize).filter(|&core| libc::CPU_ISSET(core, &set)).collect())
+    }
+}
+
+/// The NUMA node the calling thread is running on right now
+#[cfg(target_os = "linux")]
+pub fn current_node() -> io::Result<usize> {
+    use std::ptr;
+    use libc;
+
+    let mut cpu: libc::c_uint = 0;
+    let mut node: libc::c_uint = 0;
+    unsafe {
+        if libc::syscall(libc::SYS_getcpu, &mut cpu, &mut node, ptr::null_mut::<libc::c_void>()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(node as usize)
+}
+
+/// Allocates whole pages on the NUMA node of whichever thread asks for them
+///
+/// Every allocation gets pages of its own, so this is for a few small hot
+/// structures that shouldn't share cache lines, not for rings
+#[derive(Clone, Copy, Debug)]
+pub struct NodeLocal;
+
+pub static NODE_LOCAL: NodeLocal = NodeLocal;
+
+#[cfg(target_os = "linux")]
+impl NodeLocal {
+    fn pages(size: usize) -> usize {
+        let page = unsafe { ::libc::sysconf(::libc::_SC_PAGESIZE) } as usize;
+        size.max(1).div_ceil(page) * page
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe impl RawAlloc for NodeLocal {
+    fn alloc(&self, layout: ::std::alloc::Layout) -> *mut u8 {
+        use std::ptr;
+        use libc;
+
+        // From mempolicy.h, which the libc crate leaves out
+        const MPOL_PREFERRED: libc::c_int = 1;
+
+        let len = NodeLocal::pages(layout.size());
+        if layout.align() > len {
+            return ptr::null_mut();
+        }
+        unsafe {
+            let mem = libc::mmap(ptr::null_mut(),
+                                 len,
+                                 libc::PROT_READ | libc::PROT_WRITE,
+                                 libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                                 -1,
+                                 0);
+            if mem == libc::MAP_FAILED {
+                return ptr::null_mut();
+            }
+            // Nothing's touched the pages yet, so the default policy would
+            // put them here anyway. Asking makes it so under other policies,
+            // and failing to ask only costs locality
+            if let Ok(node) = current_node() {
+                if node < 64 {
+                    let mask: libc::c_ulong = 1 << node;
+                    libc::syscall(libc::SYS_mbind,
+                                  mem,
+                                  len,
+                                  MPOL_PREFERRED,
+                                  &mask,
+                                  65 as libc::c_ulong,
+                                  0 as libc::c_uint);
+                }
+            }
+            mem as *mut u8
+        }
+    }
+
+    /// Fresh pages are always zeroed
+    fn alloc_zeroed(&self, layout: ::std::alloc::Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: ::std::alloc::Layout) {
+        ::libc::munmap(ptr as *mut ::libc::c_void, NodeLocal::pages(layout.size()));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe impl RawAlloc for NodeLocal {
+    fn alloc(&self, layout: ::std::alloc::Layout) -> *mut u8 {
+        HEAP.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: ::std::alloc::Layout) {
+        HEAP.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current(_cores: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "thread affinity is only supported on linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current() -> io::Result<Vec<usize>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "thread affinity is only supported on linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_node() -> io::Result<usize> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "numa nodes are only supported on linux"))
+}
+
+#[cfg(all(test, target_os = "linux", not(miri)))]
+mod test {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn pins_only_the_calling_thread() {
+        let before = current().unwrap();
+        let core = before[0];
+        let pinned = thread::spawn(move || {
+                pin_current(&[core]).unwrap();
+                current().unwrap()
+            })
+            .join()
+            .unwrap();
+        assert_eq!(vec![core], pinned);
+        assert_eq!(before, current().unwrap());
+    }
+
+    #[test]
+    fn node_local_memory_is_usable() {
+        use std::alloc::Layout;
+
+        current_node().unwrap();
+        let layout = Layout::new::<[u64; 16]>();
+        let mem = NODE_LOCAL.alloc(layout) as *mut [u64; 16];
+        assert!(!mem.is_null());
+        unsafe {
+            *mem = [7; 16];
+            assert_eq!([7; 16], *mem);
+            NODE_LOCAL.dealloc(mem as *mut u8, layout);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_core_sets() {
+        assert_eq!(io::ErrorKind::InvalidInput, pin_current(&[]).unwrap_err().kind());
+        assert_eq!(io::ErrorKind::InvalidInput,
+                   pin_current(&[1 << 20]).unwrap_err().kind());
+    }
+}
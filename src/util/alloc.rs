@@ -1,14 +1,225 @@
-use std::mem;
+use std::alloc::Layout;
+use std::fmt;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::Ordering::Relaxed;
 
-pub fn allocate<T>(num: usize) -> *mut T {
-    let vec = Vec::<T>::with_capacity(num);
-    let rptr = vec.as_ptr();
-    mem::forget(vec);
-    rptr as *mut T
+#[cfg(feature = "std")]
+use std::sync::Arc;
+
+use util::sync::AtomicUsize;
+
+/// Where a queue gets its memory from, for arenas, pools or pre-faulted regions
+///
+/// A queue allocates its ring when it's built, and a little bookkeeping
+/// whenever a stream is added. Everything goes back when the queue is dropped.
+///
+/// # Safety
+///
+/// The queue trusts what it gets: a block of at least layout's size and
+/// alignment that nothing else uses until it's handed back to dealloc
+pub unsafe trait RawAlloc: Sync {
+    /// Returns null if there's no room
+    fn alloc(&self, layout: Layout) -> *mut u8;
+
+    /// Like alloc, but zeroed. Queues build their rings with this, so an
+    /// allocator that gets fresh pages from the OS can skip the zeroing and
+    /// leave them to be faulted in as they're first written
+    fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let mem = self.alloc(layout);
+        if !mem.is_null() {
+            unsafe { ptr::write_bytes(mem, 0, layout.size()) };
+        }
+        mem
+    }
+
+    /// # Safety
+    ///
+    /// ptr came from alloc on this allocator with the same layout
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+}
+
+/// The global allocator, which queues use unless told otherwise
+#[derive(Clone, Copy, Debug)]
+pub struct Heap;
+
+pub static HEAP: Heap = Heap;
+
+unsafe impl RawAlloc for Heap {
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { ::alloc::alloc::alloc(layout) }
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        unsafe { ::alloc::alloc::alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ::alloc::alloc::dealloc(ptr, layout)
+    }
+}
+
+/// Called with a queue's name and how many bytes it just allocated,
+/// negative for bytes freed
+#[cfg(feature = "std")]
+pub type OnAlloc = Arc<dyn Fn(&'static str, isize) + Send + Sync>;
+
+/// What a queue has allocated since it was built
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Allocations {
+    pub allocs: usize,
+    pub frees: usize,
+    /// Bytes allocated and not yet freed
+    pub live_bytes: usize,
+}
+
+/// Counts every allocation a queue makes, and tells on_alloc about it
+pub struct Counter {
+    name: &'static str,
+    allocs: AtomicUsize,
+    frees: AtomicUsize,
+    live: AtomicUsize,
+    #[cfg(feature = "std")]
+    hook: Option<OnAlloc>,
+}
+
+impl Counter {
+    #[cfg(feature = "std")]
+    pub fn new(name: &'static str, hook: Option<OnAlloc>) -> Counter {
+        Counter {
+            name: name,
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            hook: hook,
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub fn new(name: &'static str) -> Counter {
+        Counter {
+            name: name,
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts an allocation made some other way than through tracked
+    pub fn allocated(&self, bytes: usize) {
+        self.allocs.fetch_add(1, Relaxed);
+        self.live.fetch_add(bytes, Relaxed);
+        self.tell(bytes as isize);
+    }
+
+    pub fn freed(&self, bytes: usize) {
+        self.frees.fetch_add(1, Relaxed);
+        self.live.fetch_sub(bytes, Relaxed);
+        self.tell(-(bytes as isize));
+    }
+
+    /// from, counting everything allocated through it here
+    pub fn tracked<'a>(&'a self, from: &'a dyn RawAlloc) -> Tracked<'a> {
+        Tracked {
+            from: from,
+            counter: self,
+        }
+    }
+
+    pub fn load(&self) -> Allocations {
+        Allocations {
+            allocs: self.allocs.load(Relaxed),
+            frees: self.frees.load(Relaxed),
+            live_bytes: self.live.load(Relaxed),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn tell(&self, bytes: isize) {
+        if let Some(ref f) = self.hook {
+            f(self.name, bytes);
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn tell(&self, _bytes: isize) {}
+}
+
+impl fmt::Debug for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.load(), f)
+    }
+}
+
+/// An allocator that a Counter counts everything through
+pub struct Tracked<'a> {
+    from: &'a dyn RawAlloc,
+    counter: &'a Counter,
+}
+
+unsafe impl<'a> RawAlloc for Tracked<'a> {
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mem = self.from.alloc(layout);
+        if !mem.is_null() {
+            self.counter.allocated(layout.size());
+        }
+        mem
+    }
+
+    fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let mem = self.from.alloc_zeroed(layout);
+        if !mem.is_null() {
+            self.counter.allocated(layout.size());
+        }
+        mem
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.from.dealloc(ptr, layout);
+        self.counter.freed(layout.size());
+    }
 }
 
-pub fn deallocate<T>(tofree: *mut T, num: usize) {
-    unsafe {
-        Vec::from_raw_parts(tofree, 0, num);
+impl fmt::Debug for dyn RawAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("RawAlloc")
     }
 }
+
+/// Room for num values of T from from, left uninitialized. Give it back with deallocate
+pub fn allocate<T>(from: &dyn RawAlloc, num: usize) -> *mut T {
+    let layout = Layout::array::<T>(num).expect("allocation too large");
+    if layout.size() == 0 {
+        return NonNull::dangling().as_ptr();
+    }
+    allocate_layout(from, layout) as *mut T
+}
+
+/// Room laid out as layout, which mustn't be empty. Give it back with deallocate_layout
+pub fn allocate_layout(from: &dyn RawAlloc, layout: Layout) -> *mut u8 {
+    checked(from.alloc(layout), layout)
+}
+
+/// Like allocate_layout, zeroed
+pub fn allocate_zeroed(from: &dyn RawAlloc, layout: Layout) -> *mut u8 {
+    checked(from.alloc_zeroed(layout), layout)
+}
+
+fn checked(rptr: *mut u8, layout: Layout) -> *mut u8 {
+    if rptr.is_null() {
+        ::alloc::alloc::handle_alloc_error(layout);
+    }
+    rptr
+}
+
+/// Frees what allocate(from, num) gave out, without dropping anything in it
+pub fn deallocate<T>(from: &dyn RawAlloc, tofree: *mut T, num: usize) {
+    let layout = Layout::array::<T>(num).expect("allocation too large");
+    if layout.size() != 0 {
+        deallocate_layout(from, tofree as *mut u8, layout)
+    }
+}
+
+/// Frees what allocate_layout(from, layout) gave out
+pub fn deallocate_layout(from: &dyn RawAlloc, tofree: *mut u8, layout: Layout) {
+    unsafe { from.dealloc(tofree, layout) }
+}
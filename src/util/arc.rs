@@ -0,0 +1,85 @@
+//! A bare Arc, for targets whose alloc has none for want of atomics
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use alloc::boxed::Box;
+
+use util::sync::{AtomicUsize, Ordering, fence};
+
+struct Inner<T> {
+    strong: AtomicUsize,
+    value: T,
+}
+
+/// Just as much of alloc's Arc as the queue uses, counted with
+/// portable-atomic's atomics. There are no weak references
+pub struct Arc<T> {
+    inner: NonNull<Inner<T>>,
+    owns: PhantomData<Inner<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+
+impl<T> Arc<T> {
+    pub fn new(value: T) -> Arc<T> {
+        let inner = Box::new(Inner {
+            strong: AtomicUsize::new(1),
+            value: value,
+        });
+        Arc {
+            inner: NonNull::from(Box::leak(inner)),
+            owns: PhantomData,
+        }
+    }
+
+    pub fn strong_count(this: &Arc<T>) -> usize {
+        this.inner().strong.load(Ordering::Acquire)
+    }
+
+    pub fn ptr_eq(this: &Arc<T>, other: &Arc<T>) -> bool {
+        this.inner == other.inner
+    }
+
+    fn inner(&self) -> &Inner<T> {
+        unsafe { self.inner.as_ref() }
+    }
+}
+
+impl<T> Clone for Arc<T> {
+    fn clone(&self) -> Arc<T> {
+        self.inner().strong.fetch_add(1, Ordering::Relaxed);
+        Arc {
+            inner: self.inner,
+            owns: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner().value
+    }
+}
+
+impl<T> Drop for Arc<T> {
+    fn drop(&mut self) {
+        if self.inner().strong.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        // Everything the other owners did happens before the drop
+        fence(Ordering::Acquire);
+        unsafe { drop(Box::from_raw(self.inner.as_ptr())) }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,72 @@
+//! Keeping hot data to cache lines of its own
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+/// Holds a T on cache lines that nothing else shares, so that writing to
+/// it doesn't keep taking the line away from whoever reads what's next to
+/// it. It starts on a line boundary wherever it's put, and the next field
+/// starts on a fresh line after it
+#[repr(C, align(64))]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    pub fn new(value: T) -> CachePadded<T> {
+        CachePadded { value: value }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CachePadded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::mem;
+
+    #[repr(C)]
+    struct Split {
+        first: CachePadded<u8>,
+        second: CachePadded<[u64; 9]>,
+    }
+
+    #[test]
+    fn padded_values_get_lines_of_their_own() {
+        assert_eq!(64, mem::align_of::<CachePadded<u8>>());
+        assert_eq!(64, mem::size_of::<CachePadded<u8>>());
+        assert_eq!(128, mem::size_of::<CachePadded<[u64; 9]>>());
+        let split = Split {
+            first: CachePadded::new(1),
+            second: CachePadded::new([2; 9]),
+        };
+        let (first, second) = (&*split.first as *const u8 as usize, &*split.second as *const _ as usize);
+        assert_eq!(0, first % 64);
+        assert_eq!(64, second - first);
+    }
+}
@@ -1,5 +1,9 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt;
 
+use util::sync::{AtomicUsize, Ordering};
+
+// repr(C) since this is also laid out in shared memory segments
+#[repr(C)]
 pub struct CountedU16 {
     val: AtomicUsize,
     wrap: usize,
@@ -46,9 +50,20 @@ impl CountedU16 {
         self.val.load(ord) >> 16
     }
 
+    /// Stores a raw value, as from load_raw or get_previous
+    #[inline(always)]
+    pub fn store_raw(&self, val: usize, ord: Ordering) {
+        self.val.store(val, ord)
+    }
+
     #[inline(always)]
     pub fn load_count(&self, ord: Ordering) -> usize {
-        let val = self.val.load(ord);
+        self.count_of(self.val.load(ord))
+    }
+
+    /// Converts a raw value (as from load_raw) into a count
+    #[inline(always)]
+    pub fn count_of(&self, val: usize) -> usize {
         let lower_half = (val as u16) as usize;
         let upper_half = val >> 16;
         lower_half + self.wrap * upper_half
@@ -78,6 +93,17 @@ impl CountedU16 {
     }
 }
 
+impl fmt::Debug for CountedU16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let raw = self.val.load(Ordering::Relaxed);
+        f.debug_struct("CountedU16")
+            .field("index", &(raw as u16))
+            .field("wraps", &(raw >> 16))
+            .field("wrap_at", &self.wrap)
+            .finish()
+    }
+}
+
 impl<'a> Transaction<'a> {
     #[inline(always)]
     pub fn get(&self) -> u16 {
@@ -89,6 +115,12 @@ impl<'a> Transaction<'a> {
         self.loaded_vals >> 16
     }
 
+    /// The raw value loaded, as from load_raw
+    #[inline(always)]
+    pub fn raw(&self) -> usize {
+        self.loaded_vals
+    }
+
     /// Returns true is the usize passed matches the value
     /// held by the transaction
     #[inline(always)]
@@ -201,6 +233,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn test_wrapu16() {
         test_incr_param(::std::u16::MAX, 2)
     }
@@ -216,6 +249,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn test_wrapu16_mt() {
         test_incr_param_threaded(::std::u16::MAX, 2, 10)
     }
@@ -3,12 +3,13 @@ use std::sync::atomic::{Ordering, fence};
 
 #[cfg(any(target_arch = "x64", target_arch = "x64_64", target_arch = "aarch64"))]
 mod theimpl {
-    use std::sync::atomic::{Ordering, fence};
+    use std::sync::atomic::Ordering;
+    use util::sync::fence;
     pub const MAYBE_ACQUIRE: Ordering = Ordering::Relaxed;
 
     #[inline(always)]
     pub fn maybe_acquire_fence() {
-        fence(Ordering: Acquire)
+        fence(Ordering::Acquire)
     }
 }
 
@@ -1,5 +1,14 @@
 
+#[cfg(feature = "affinity")]
+pub mod affinity;
 pub mod alloc;
+#[cfg(not(target_has_atomic = "ptr"))]
+pub mod arc;
+pub mod cache_padded;
 pub mod consume;
 pub mod countedu16;
+pub mod crc32;
 pub mod maybe_acquire;
+#[cfg(all(unix, feature = "std", feature = "libc"))]
+pub mod pages;
+pub mod sync;
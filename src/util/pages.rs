@@ -0,0 +1,93 @@
+//! Rings mapped straight from the OS, for queues too big to zero up front
+
+use std::alloc::Layout;
+use std::ptr;
+use std::thread;
+
+use libc;
+
+use util::alloc::RawAlloc;
+
+/// Maps each allocation fresh from the OS, reserving its address space
+/// but leaving every page to be committed when it's first written
+///
+/// Building a queue of a few GB from the heap can block for hundreds of
+/// milliseconds while the memory is zeroed. From here building only maps
+/// it, and the first lap around the ring pays for the page faults instead,
+/// unless the pages are prefaulted by a thread of their own as the queue
+/// starts out. Every allocation is whole pages, so this is for rings only
+#[derive(Clone, Copy, Debug)]
+pub struct LazyPages {
+    prefault: bool,
+}
+
+/// Leaves pages to be faulted in by whoever writes them first
+pub static LAZY_PAGES: LazyPages = LazyPages { prefault: false };
+
+/// Faults pages in from a background thread, so writers mostly find them
+/// ready. Only on Linux 5.14 and later; elsewhere it's the same as LAZY_PAGES
+pub static PREFAULTED_PAGES: LazyPages = LazyPages { prefault: true };
+
+// Linux counts unreserved pages against nothing until they're touched
+#[cfg(target_os = "linux")]
+const RESERVE_ONLY: libc::c_int = libc::MAP_NORESERVE;
+#[cfg(not(target_os = "linux"))]
+const RESERVE_ONLY: libc::c_int = 0;
+
+impl LazyPages {
+    fn pages(size: usize) -> usize {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        size.max(1).div_ceil(page) * page
+    }
+
+    #[cfg(target_os = "linux")]
+    fn prefault(mem: *mut libc::c_void, len: usize) {
+        let at = mem as usize;
+        // Failing only leaves the pages to be faulted in as they're written,
+        // as on kernels without MADV_POPULATE_WRITE
+        let spawned = thread::Builder::new()
+            .name("prefault".into())
+            .spawn(move || unsafe {
+                libc::madvise(at as *mut libc::c_void, len, libc::MADV_POPULATE_WRITE);
+            });
+        drop(spawned);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn prefault(_mem: *mut libc::c_void, _len: usize) {}
+}
+
+unsafe impl RawAlloc for LazyPages {
+    fn alloc(&self, layout: Layout) -> *mut u8 {
+        let len = LazyPages::pages(layout.size());
+        if layout.align() > len {
+            return ptr::null_mut();
+        }
+        unsafe {
+            let mem = libc::mmap(ptr::null_mut(),
+                                 len,
+                                 libc::PROT_READ | libc::PROT_WRITE,
+                                 libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | RESERVE_ONLY,
+                                 -1,
+                                 0);
+            if mem == libc::MAP_FAILED {
+                return ptr::null_mut();
+            }
+            if self.prefault {
+                LazyPages::prefault(mem, len);
+            }
+            mem as *mut u8
+        }
+    }
+
+    /// Fresh pages are always zeroed, so there's nothing more to do
+    fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // A prefault still going stops at the first page no longer mapped,
+        // or at worst faults in whatever gets mapped here next
+        libc::munmap(ptr as *mut libc::c_void, LazyPages::pages(layout.size()));
+    }
+}
@@ -0,0 +1,45 @@
+//! The atomics the queue's cursors are built on
+//!
+//! Building with `RUSTFLAGS="--cfg loom"` swaps these for loom's, so the
+//! model checks in the queue's loom_test modules can run every interleaving
+//! of small scenarios with `cargo test --release --lib loom`.
+//!
+//! Scenarios too big for that are run under shuttle instead, which tries a
+//! few thousand random schedules of each: build with `RUSTFLAGS="--cfg shuttle"`
+//! and run `cargo test --release --lib shuttle`.
+//!
+//! Targets without compare-and-swap, or without 64-bit atomics, take them
+//! from portable-atomic instead, which only emulates what the target lacks.
+//! Where alloc has no Arc either, the queue brings its own.
+
+#[cfg(loom)]
+pub use loom::sync::Arc;
+#[cfg(loom)]
+pub use loom::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering, fence};
+
+#[cfg(all(shuttle, not(loom)))]
+pub use shuttle::sync::Arc;
+#[cfg(all(shuttle, not(loom)))]
+pub use shuttle::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering, fence};
+
+#[cfg(all(target_has_atomic = "ptr", not(any(loom, shuttle))))]
+pub use alloc::sync::Arc;
+#[cfg(all(not(target_has_atomic = "ptr"), not(any(loom, shuttle))))]
+pub use util::arc::Arc;
+
+#[cfg(not(any(loom, shuttle, feature = "portable-atomic")))]
+pub use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering, fence};
+#[cfg(all(feature = "portable-atomic", not(any(loom, shuttle))))]
+pub use portable_atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering, fence};
+
+// Waits on other threads spin with this, which hands over to them under
+// the model checkers rather than spinning through every step
+#[cfg(loom)]
+pub use loom::thread::yield_now as spin_loop;
+#[cfg(all(shuttle, not(loom)))]
+pub use shuttle::thread::yield_now as spin_loop;
+#[cfg(not(any(loom, shuttle)))]
+pub use std::hint::spin_loop;
+
+#[cfg(not(any(all(target_has_atomic = "ptr", target_has_atomic = "64"), feature = "portable-atomic")))]
+compile_error!("this target is missing atomics the queue needs; enable the portable-atomic feature");